@@ -0,0 +1,291 @@
+use std::{collections::BTreeMap, hash::Hash, ops::Bound};
+
+use crate::{
+    error::TxError,
+    key::{Key, PrefixKey},
+    model::{Model, Version, VersionType},
+};
+
+use super::{
+    is_empty_range, tombstones::Tombstones, Backend, BackendModel, ModelVisitor, OrderedBackend,
+    Tombstone, TombstonePolicy,
+};
+
+/// A backend that keeps the models in a `BTreeMap`, ordered by id.
+/// Unlike `HashmapBackend`, it can serve the queries on ranges of ids.
+pub struct BTreeMapBackend<IdType: Ord + Hash + Clone, Data: Clone, V: Version = VersionType> {
+    map: BTreeMap<IdType, Model<IdType, Data, V>>,
+    tombstones: Tombstones<IdType, V>,
+}
+
+impl<IdType: Ord + Hash + Clone, Data: Clone, V: Version> BTreeMapBackend<IdType, Data, V> {
+    pub fn new() -> Self {
+        Self {
+            map: BTreeMap::default(),
+            tombstones: Tombstones::new(),
+        }
+    }
+
+    /// Sets the policy used to compact the tombstones of the deleted models.
+    pub fn with_tombstone_policy(mut self, policy: TombstonePolicy) -> Self {
+        self.tombstones.set_policy(policy);
+        self
+    }
+}
+
+impl<IdType: Ord + Hash + Clone, Data: Clone, V: Version> Default
+    for BTreeMapBackend<IdType, Data, V>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<IdType: Ord + Hash + Clone + Key, Data: Clone, V: Version> Backend<Data>
+    for BTreeMapBackend<IdType, Data, V>
+{
+    type IdType = IdType;
+    type VersionType = V;
+
+    fn fetch_one(&self, id: &Self::IdType) -> Result<BackendModel<Data, Self>, TxError> {
+        self.fetch_option_one(id)?
+            .ok_or_else(|| TxError::FetchNotFoundError {
+                id: id.to_key_string(),
+            })
+    }
+
+    fn fetch_option_one(
+        &self,
+        id: &Self::IdType,
+    ) -> Result<Option<BackendModel<Data, Self>>, TxError> {
+        Ok(self.map.get(id).cloned())
+    }
+
+    fn fetch_version(&self, id: &Self::IdType) -> Result<Self::VersionType, TxError> {
+        self.fetch_option_version(id)?
+            .ok_or_else(|| TxError::FetchNotFoundError {
+                id: id.to_key_string(),
+            })
+    }
+
+    fn fetch_option_version(
+        &self,
+        id: &Self::IdType,
+    ) -> Result<Option<Self::VersionType>, TxError> {
+        Ok(self.map.get(id).map(|model| model.version))
+    }
+
+    fn update(&mut self, model: BackendModel<Data, Self>) -> Result<(), TxError> {
+        self.map.insert(model.id.clone(), model);
+        Ok(())
+    }
+
+    fn delete(&mut self, id: &Self::IdType) -> Result<(), TxError> {
+        match self.delete_option(id)? {
+            true => Ok(()),
+            false => Err(TxError::DeleteError {
+                id: id.to_key_string(),
+                message: "It does not exist.".to_owned(),
+            }),
+        }
+    }
+
+    fn delete_option(&mut self, id: &Self::IdType) -> Result<bool, TxError> {
+        match self.map.remove(id) {
+            Some(model) => {
+                self.tombstones.add(model.id, model.version);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn fetch_option_tombstone(
+        &self,
+        id: &Self::IdType,
+    ) -> Result<Option<Self::VersionType>, TxError> {
+        Ok(self.tombstones.get(id))
+    }
+
+    fn save(&mut self, model: BackendModel<Data, Self>) -> Result<(), TxError> {
+        self.tombstones.remove(&model.id);
+        self.map.insert(model.id.clone(), model);
+        Ok(())
+    }
+
+    fn fetch_all(&self) -> Result<Vec<BackendModel<Data, Self>>, TxError> {
+        Ok(self.map.values().cloned().collect())
+    }
+
+    fn for_each_model(&self, visit: &mut ModelVisitor<'_, Data, Self>) -> Result<(), TxError> {
+        for model in self.map.values() {
+            if visit(model)?.is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn count(&self) -> Result<usize, TxError> {
+        Ok(self.map.len())
+    }
+
+    fn fetch_all_tombstones(&self) -> Result<Vec<Tombstone<Data, Self>>, TxError> {
+        Ok(self.tombstones.all())
+    }
+
+    fn restore(
+        &mut self,
+        models: Vec<BackendModel<Data, Self>>,
+        tombstones: Vec<Tombstone<Data, Self>>,
+    ) -> Result<(), TxError> {
+        self.map = models
+            .into_iter()
+            .map(|model| (model.id.clone(), model))
+            .collect();
+        self.tombstones.restore(tombstones);
+        Ok(())
+    }
+}
+
+impl<IdType: Ord + Hash + Clone + Key, Data: Clone, V: Version> OrderedBackend<Data>
+    for BTreeMapBackend<IdType, Data, V>
+{
+    fn fetch_range(
+        &self,
+        range: (Bound<&Self::IdType>, Bound<&Self::IdType>),
+    ) -> Result<Vec<BackendModel<Data, Self>>, TxError> {
+        if is_empty_range(range.0, range.1) {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .map
+            .range::<IdType, _>(range)
+            .map(|(_, model)| model.clone())
+            .collect())
+    }
+
+    fn fetch_prefix<P>(&self, prefix: &P) -> Result<Vec<BackendModel<Data, Self>>, TxError>
+    where
+        IdType: PrefixKey<P>,
+    {
+        Ok(self
+            .map
+            .range(IdType::first_with_prefix(prefix)..)
+            .take_while(|(id, _)| id.has_prefix(prefix))
+            .map(|(_, model)| model.clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn fetch_range_should_return_the_models_ordered_by_id() {
+        // Arrange
+        let mut backend = BTreeMapBackend::<i32, i32>::new();
+        for id in [5, 3, 1, 4, 2] {
+            backend.save(Model::from((id, id * 10))).unwrap();
+        }
+
+        // Act
+        let range = backend
+            .fetch_range((Bound::Included(&2), Bound::Excluded(&5)))
+            .unwrap();
+        let unbounded = backend
+            .fetch_range((Bound::Unbounded, Bound::Unbounded))
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            vec![20, 30, 40],
+            range
+                .into_iter()
+                .map(|model| model.data)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![1, 2, 3, 4, 5],
+            unbounded
+                .into_iter()
+                .map(|model| model.id)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn fetch_range_should_return_nothing_if_the_bounds_select_no_id() {
+        // Arrange
+        let mut backend = BTreeMapBackend::<i32, i32>::new();
+        for id in [1, 2, 3] {
+            backend.save(Model::from((id, id * 10))).unwrap();
+        }
+
+        // Act
+        let reversed = backend
+            .fetch_range((Bound::Included(&3), Bound::Included(&1)))
+            .unwrap();
+        let equal_excluded = backend
+            .fetch_range((Bound::Excluded(&2), Bound::Excluded(&2)))
+            .unwrap();
+        let equal_included = backend
+            .fetch_range((Bound::Included(&2), Bound::Included(&2)))
+            .unwrap();
+
+        // Assert
+        assert!(reversed.is_empty());
+        assert!(equal_excluded.is_empty());
+        assert_eq!(1, equal_included.len());
+    }
+
+    #[test]
+    fn delete_should_keep_a_tombstone() {
+        // Arrange
+        let mut backend = BTreeMapBackend::<i32, i32>::new();
+        backend.save(Model::from((1, 3, 1123))).unwrap();
+
+        // Act
+        backend.delete(&1).unwrap();
+        let tombstone_after_delete = backend.fetch_option_tombstone(&1).unwrap();
+        let delete_again = backend.delete(&1);
+        backend.save(Model::from((1, 4, 1123))).unwrap();
+        let tombstone_after_save = backend.fetch_option_tombstone(&1).unwrap();
+
+        // Assert
+        assert_eq!(Some(3), tombstone_after_delete);
+        assert!(delete_again.is_err());
+        assert_eq!(None, tombstone_after_save);
+        assert_eq!(4, backend.fetch_version(&1).unwrap());
+    }
+
+    #[test]
+    fn fetch_prefix_should_return_the_models_with_the_leading_component() {
+        // Arrange
+        let mut backend = BTreeMapBackend::<(String, u32), i32>::new();
+        for (owner, id) in [
+            ("bob", 2),
+            ("alice", 3),
+            ("bob", 1),
+            ("bobby", 0),
+            ("al", 9),
+        ] {
+            backend
+                .save(Model::from(((owner.to_owned(), id), 0)))
+                .unwrap();
+        }
+
+        // Act
+        let bob = backend.fetch_prefix(&"bob".to_owned()).unwrap();
+        let nobody = backend.fetch_prefix(&"carl".to_owned()).unwrap();
+
+        // Assert
+        assert_eq!(
+            vec![("bob".to_owned(), 1), ("bob".to_owned(), 2)],
+            bob.into_iter().map(|model| model.id).collect::<Vec<_>>()
+        );
+        assert!(nobody.is_empty());
+    }
+}
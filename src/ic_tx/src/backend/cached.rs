@@ -0,0 +1,460 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    ops::Bound,
+};
+
+use crate::{error::TxError, key::PrefixKey, RefCell};
+
+use super::{Backend, BackendModel, ModelVisitor, OrderedBackend, Tombstone};
+
+/// The number of models cached by `CachedBackend::default`.
+pub const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// The hits and the misses of the cache of a `CachedBackend`.
+/// Only the fetches of models count: the fetches of versions, e.g. by the commits checking the versions
+/// of the changed models, read the cache without changing the statistics.
+/// On the IC the changes to the heap made by query calls are discarded, and so are their hits and misses:
+/// the statistics count only the lookups of update calls.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Returns the fraction of the lookups served by the cache, 0 if there were no lookups.
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+/// A backend that keeps the most recently used models of another backend on the heap,
+/// so the fetches of the hot models do not pay the cost of the wrapped backend, e.g. decoding them from stable memory.
+///
+/// The fetches of single models and versions read through the cache; the writes go to the wrapped backend
+/// and then update or invalidate the cached models, so the cache is always coherent with the wrapped backend
+/// as long as it is changed only through the wrapper.
+/// The bulk reads, like `fetch_all` and `fetch_range`, are forwarded and do not fill the cache.
+///
+/// On the IC the changes to the heap made by query calls are discarded, so the cache is filled only by
+/// the fetches of update calls and by the writes: query calls read through a cache they cannot fill.
+/// The models that query calls read most can be loaded with `warm_up`, e.g. from a timer or from `post_upgrade`.
+pub struct CachedBackend<Data, B: Backend<Data>> {
+    backend: B,
+    cache: RefCell<Lru<BackendModel<Data, B>, B::IdType>>,
+}
+
+impl<Data, B: Backend<Data>> CachedBackend<Data, B> {
+    /// Wraps the backend with a cache of at most `capacity` models.
+    /// A capacity of 0 disables the cache.
+    pub fn new(backend: B, capacity: usize) -> Self {
+        Self {
+            backend,
+            cache: RefCell::new(Lru::new(capacity)),
+        }
+    }
+
+    /// Returns the wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns the hits and the misses of the cache since the creation or the last `reset_stats`.
+    pub fn stats(&self) -> CacheStats {
+        self.cache.borrow().stats
+    }
+
+    pub fn reset_stats(&self) {
+        self.cache.borrow_mut().stats = CacheStats::default();
+    }
+
+    /// Returns the number of cached models.
+    pub fn cached_len(&self) -> usize {
+        self.cache.borrow().entries.len()
+    }
+
+    /// Discards all the cached models. The statistics are kept.
+    pub fn clear_cache(&self) {
+        self.cache.borrow_mut().clear();
+    }
+}
+
+impl<Data, B: Backend<Data> + Default> Default for CachedBackend<Data, B> {
+    fn default() -> Self {
+        Self::new(B::default(), DEFAULT_CACHE_CAPACITY)
+    }
+}
+
+impl<Data: Clone, B: Backend<Data>> Backend<Data> for CachedBackend<Data, B> {
+    type IdType = B::IdType;
+    type VersionType = B::VersionType;
+
+    fn fetch_one(&self, id: &Self::IdType) -> Result<BackendModel<Data, Self>, TxError> {
+        if let Some(model) = self.cache.borrow_mut().get(id) {
+            return Ok(model);
+        }
+        let model = self.backend.fetch_one(id)?;
+        self.cache.borrow_mut().put(id.clone(), model.clone());
+        Ok(model)
+    }
+
+    fn fetch_option_one(
+        &self,
+        id: &Self::IdType,
+    ) -> Result<Option<BackendModel<Data, Self>>, TxError> {
+        if let Some(model) = self.cache.borrow_mut().get(id) {
+            return Ok(Some(model));
+        }
+        let model = self.backend.fetch_option_one(id)?;
+        if let Some(model) = &model {
+            self.cache.borrow_mut().put(id.clone(), model.clone());
+        }
+        Ok(model)
+    }
+
+    fn fetch_version(&self, id: &Self::IdType) -> Result<Self::VersionType, TxError> {
+        match self.cached_version(id) {
+            Some(version) => Ok(version),
+            None => self.backend.fetch_version(id),
+        }
+    }
+
+    fn fetch_option_version(
+        &self,
+        id: &Self::IdType,
+    ) -> Result<Option<Self::VersionType>, TxError> {
+        match self.cached_version(id) {
+            Some(version) => Ok(Some(version)),
+            None => self.backend.fetch_option_version(id),
+        }
+    }
+
+    fn update(&mut self, model: BackendModel<Data, Self>) -> Result<(), TxError> {
+        let id = model.id.clone();
+        self.write_through(id, model, |backend, model| backend.update(model))
+    }
+
+    fn delete(&mut self, id: &Self::IdType) -> Result<(), TxError> {
+        self.cache.borrow_mut().remove(id);
+        self.backend.delete(id)
+    }
+
+    fn delete_option(&mut self, id: &Self::IdType) -> Result<bool, TxError> {
+        self.cache.borrow_mut().remove(id);
+        self.backend.delete_option(id)
+    }
+
+    fn fetch_option_tombstone(
+        &self,
+        id: &Self::IdType,
+    ) -> Result<Option<Self::VersionType>, TxError> {
+        self.backend.fetch_option_tombstone(id)
+    }
+
+    fn save(&mut self, model: BackendModel<Data, Self>) -> Result<(), TxError> {
+        let id = model.id.clone();
+        self.write_through(id, model, |backend, model| backend.save(model))
+    }
+
+    fn fetch_all(&self) -> Result<Vec<BackendModel<Data, Self>>, TxError> {
+        self.backend.fetch_all()
+    }
+
+    fn for_each_model(&self, visit: &mut ModelVisitor<'_, Data, Self>) -> Result<(), TxError> {
+        self.backend.for_each_model(visit)
+    }
+
+    fn count(&self) -> Result<usize, TxError> {
+        self.backend.count()
+    }
+
+    fn fetch_all_tombstones(&self) -> Result<Vec<Tombstone<Data, Self>>, TxError> {
+        self.backend.fetch_all_tombstones()
+    }
+
+    fn restore(
+        &mut self,
+        models: Vec<BackendModel<Data, Self>>,
+        tombstones: Vec<Tombstone<Data, Self>>,
+    ) -> Result<(), TxError> {
+        self.cache.borrow_mut().clear();
+        self.backend.restore(models, tombstones)
+    }
+
+    fn restore_models(&mut self, models: Vec<BackendModel<Data, Self>>) -> Result<(), TxError> {
+        self.backend.restore_models(models)
+    }
+}
+
+impl<Data: Clone, B: Backend<Data>> CachedBackend<Data, B> {
+    /// Loads the models with the ids into the cache, without counting hits or misses.
+    /// On the IC it must run in an update call, as the cache filled by a query call is discarded.
+    pub fn warm_up<'a>(&self, ids: impl IntoIterator<Item = &'a B::IdType>) -> Result<(), TxError>
+    where
+        B::IdType: 'a,
+    {
+        for id in ids {
+            if let Some(model) = self.backend.fetch_option_one(id)? {
+                self.cache.borrow_mut().put(id.clone(), model);
+            }
+        }
+        Ok(())
+    }
+
+    fn cached_version(&self, id: &B::IdType) -> Option<B::VersionType> {
+        self.cache.borrow().peek(id).map(|model| model.version)
+    }
+
+    /// Writes the model to the wrapped backend and caches it.
+    /// If the write fails the model is evicted, as the state of the wrapped backend is unknown.
+    fn write_through(
+        &mut self,
+        id: B::IdType,
+        model: BackendModel<Data, B>,
+        write: impl FnOnce(&mut B, BackendModel<Data, B>) -> Result<(), TxError>,
+    ) -> Result<(), TxError> {
+        let cached = model.clone();
+        match write(&mut self.backend, model) {
+            Ok(()) => {
+                self.cache.borrow_mut().put(id, cached);
+                Ok(())
+            }
+            Err(err) => {
+                self.cache.borrow_mut().remove(&id);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl<Data: Clone, B: OrderedBackend<Data>> OrderedBackend<Data> for CachedBackend<Data, B>
+where
+    B::IdType: Ord,
+{
+    fn fetch_range(
+        &self,
+        range: (Bound<&Self::IdType>, Bound<&Self::IdType>),
+    ) -> Result<Vec<BackendModel<Data, Self>>, TxError> {
+        self.backend.fetch_range(range)
+    }
+
+    fn fetch_prefix<P>(&self, prefix: &P) -> Result<Vec<BackendModel<Data, Self>>, TxError>
+    where
+        Self::IdType: PrefixKey<P>,
+    {
+        self.backend.fetch_prefix(prefix)
+    }
+}
+
+/// A least recently used cache.
+/// Each access takes a new tick; the entry with the lowest tick is the least recently used one.
+struct Lru<M, IdType> {
+    capacity: usize,
+    entries: HashMap<IdType, (M, u64)>,
+    order: BTreeMap<u64, IdType>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl<M, IdType: Clone + Eq + Hash> Lru<M, IdType> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::default(),
+            order: BTreeMap::default(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Caches the model as the most recently used one, evicting the least recently used one if the cache is full.
+    fn put(&mut self, id: IdType, model: M) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&id);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, evicted)) = self.order.pop_first() {
+                self.entries.remove(&evicted);
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, id.clone());
+        self.entries.insert(id, (model, self.tick));
+    }
+
+    /// Returns the cached model without marking it as used nor counting the hit or the miss.
+    fn peek(&self, id: &IdType) -> Option<&M> {
+        self.entries.get(id).map(|(model, _)| model)
+    }
+
+    fn remove(&mut self, id: &IdType) {
+        if let Some((_, tick)) = self.entries.remove(id) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+impl<M: Clone, IdType: Clone + Eq + Hash> Lru<M, IdType> {
+    /// Returns the cached model, marking it as the most recently used one, and counts the hit or the miss.
+    fn get(&mut self, id: &IdType) -> Option<M> {
+        self.tick += 1;
+        match self.entries.get_mut(id) {
+            Some((model, tick)) => {
+                self.order.remove(tick);
+                *tick = self.tick;
+                self.order.insert(self.tick, id.clone());
+                self.stats.hits += 1;
+                Some(model.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::{
+        backend::{btreemap::BTreeMapBackend, hashmap::HashmapBackend},
+        model::Model,
+    };
+
+    use super::*;
+
+    #[test]
+    fn fetch_should_read_through_the_cache() {
+        // Arrange
+        let mut backend = CachedBackend::new(HashmapBackend::<i32, i32>::new(), 10);
+        backend.save(Model::from((1, 10))).unwrap();
+        backend.clear_cache();
+
+        // Act
+        let first = backend.fetch_one(&1).unwrap();
+        let second = backend.fetch_one(&1).unwrap();
+        let version = backend.fetch_version(&1).unwrap();
+        let missing = backend.fetch_option_one(&2).unwrap();
+
+        // Assert
+        assert_eq!(first, second);
+        assert_eq!(first.version, version);
+        assert!(missing.is_none());
+        assert_eq!(CacheStats { hits: 1, misses: 2 }, backend.stats());
+        assert_eq!(1.0 / 3.0, backend.stats().hit_ratio());
+    }
+
+    #[test]
+    fn fetch_version_should_not_count_lookups() {
+        // Arrange
+        let mut backend = CachedBackend::new(HashmapBackend::<i32, i32>::new(), 10);
+        backend.save(Model::from((1, 10))).unwrap();
+
+        // Act
+        let cached = backend.fetch_version(&1).unwrap();
+        let missing = backend.fetch_option_version(&2).unwrap();
+
+        // Assert
+        assert_eq!(0, cached);
+        assert!(missing.is_none());
+        assert_eq!(CacheStats::default(), backend.stats());
+    }
+
+    #[test]
+    fn warm_up_should_fill_the_cache_without_counting_lookups() {
+        // Arrange
+        let mut backend = CachedBackend::new(HashmapBackend::<i32, i32>::new(), 10);
+        backend.save(Model::from((1, 10))).unwrap();
+        backend.save(Model::from((2, 20))).unwrap();
+        backend.clear_cache();
+
+        // Act
+        backend.warm_up(&[1, 2, 3]).unwrap();
+        let model = backend.fetch_one(&2).unwrap();
+
+        // Assert
+        assert_eq!(20, model.data);
+        assert_eq!(2, backend.cached_len());
+        assert_eq!(CacheStats { hits: 1, misses: 0 }, backend.stats());
+    }
+
+    #[test]
+    fn writes_should_keep_the_cache_coherent() {
+        // Arrange
+        let mut backend = CachedBackend::new(HashmapBackend::<i32, i32>::new(), 10);
+        backend.save(Model::from((1, 10))).unwrap();
+        backend.save(Model::from((2, 20))).unwrap();
+        backend.fetch_one(&1).unwrap();
+
+        // Act
+        backend.update(Model::from((1, 1, 11))).unwrap();
+        let updated = backend.fetch_one(&1).unwrap();
+        let stored = backend.inner().fetch_one(&1).unwrap();
+        backend.delete(&2).unwrap();
+        let deleted = backend.fetch_option_one(&2).unwrap();
+        backend.restore(vec![Model::from((3, 30))], vec![]).unwrap();
+        let restored = backend.fetch_option_one(&1).unwrap();
+
+        // Assert
+        assert_eq!(Model::from((1, 1, 11)), updated);
+        assert_eq!(updated, stored);
+        assert!(deleted.is_none());
+        assert!(restored.is_none());
+        assert_eq!(0, backend.cached_len());
+    }
+
+    #[test]
+    fn cache_should_evict_the_least_recently_used_model() {
+        // Arrange
+        let mut backend = CachedBackend::new(HashmapBackend::<i32, i32>::new(), 2);
+        backend.save(Model::from((1, 10))).unwrap();
+        backend.save(Model::from((2, 20))).unwrap();
+        backend.fetch_one(&1).unwrap();
+
+        // Act
+        backend.save(Model::from((3, 30))).unwrap();
+        backend.reset_stats();
+        backend.fetch_one(&1).unwrap();
+        backend.fetch_one(&3).unwrap();
+        backend.fetch_one(&2).unwrap();
+
+        // Assert
+        assert_eq!(CacheStats { hits: 2, misses: 1 }, backend.stats());
+        assert_eq!(2, backend.cached_len());
+    }
+
+    #[test]
+    fn ordered_fetches_should_be_forwarded() {
+        // Arrange
+        let mut backend = CachedBackend::new(BTreeMapBackend::<(String, u32), i32>::new(), 10);
+        for (owner, id) in [("bob", 2), ("alice", 1), ("bob", 1)] {
+            backend
+                .save(Model::from(((owner.to_owned(), id), 0)))
+                .unwrap();
+        }
+
+        // Act
+        let bob = backend.fetch_prefix(&"bob".to_owned()).unwrap();
+        let all = backend
+            .fetch_range((Bound::Unbounded, Bound::Unbounded))
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            vec![("bob".to_owned(), 1), ("bob".to_owned(), 2)],
+            bob.into_iter().map(|model| model.id).collect::<Vec<_>>()
+        );
+        assert_eq!(3, all.len());
+    }
+}
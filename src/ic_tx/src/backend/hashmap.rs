@@ -1,45 +1,65 @@
-use std::{collections::HashMap, fmt::Display, hash::Hash};
+use std::{collections::HashMap, hash::Hash};
 
 use crate::{
     error::TxError,
-    model::{Model, NewModel, VersionType},
+    key::Key,
+    model::{Model, Version, VersionType},
 };
 
-use super::Backend;
+use super::{
+    tombstones::Tombstones, Backend, BackendModel, ModelVisitor, Tombstone, TombstonePolicy,
+};
 
-pub struct HashmapBackend<IdType: Eq + Hash + Clone, Data: Clone> {
-    map: HashMap<IdType, Model<IdType, Data>>,
+pub struct HashmapBackend<IdType: Eq + Hash + Clone, Data: Clone, V: Version = VersionType> {
+    map: HashMap<IdType, Model<IdType, Data, V>>,
+    tombstones: Tombstones<IdType, V>,
 }
 
-impl<IdType: Eq + Hash + Clone, Data: Clone> HashmapBackend<IdType, Data> {
+impl<IdType: Eq + Hash + Clone, Data: Clone, V: Version> HashmapBackend<IdType, Data, V> {
     pub fn new() -> Self {
+        Self::with_map(HashMap::default())
+    }
+
+    pub fn with_map(map: HashMap<IdType, Model<IdType, Data, V>>) -> Self {
         HashmapBackend {
-            map: HashMap::default(),
+            map,
+            tombstones: Tombstones::new(),
         }
     }
 
-    pub fn with_map(map: HashMap<IdType, Model<IdType, Data>>) -> Self {
-        HashmapBackend { map }
+    /// Sets the policy used to compact the tombstones of the deleted models.
+    pub fn with_tombstone_policy(mut self, policy: TombstonePolicy) -> Self {
+        self.tombstones.set_policy(policy);
+        self
+    }
+}
+
+impl<IdType: Eq + Hash + Clone, Data: Clone, V: Version> Default
+    for HashmapBackend<IdType, Data, V>
+{
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl<IdType: Eq + Hash + Clone, Data: Clone> From<HashMap<IdType, Model<IdType, Data>>>
-    for HashmapBackend<IdType, Data>
+impl<IdType: Eq + Hash + Clone, Data: Clone, V: Version>
+    From<HashMap<IdType, Model<IdType, Data, V>>> for HashmapBackend<IdType, Data, V>
 {
-    fn from(map: HashMap<IdType, Model<IdType, Data>>) -> Self {
+    fn from(map: HashMap<IdType, Model<IdType, Data, V>>) -> Self {
         Self::with_map(map)
     }
 }
 
-impl<IdType: Eq + Hash + Clone + Display, Data: Clone> Backend<Data>
-    for HashmapBackend<IdType, Data>
+impl<IdType: Eq + Hash + Clone + Key, Data: Clone, V: Version> Backend<Data>
+    for HashmapBackend<IdType, Data, V>
 {
     type IdType = IdType;
+    type VersionType = V;
 
-    fn fetch_one(&self, id: &Self::IdType) -> Result<Model<Self::IdType, Data>, TxError> {
+    fn fetch_one(&self, id: &Self::IdType) -> Result<BackendModel<Data, Self>, TxError> {
         match self.fetch_option_one(id) {
             Ok(opt) => opt.ok_or_else(|| TxError::FetchNotFoundError {
-                message: format!("Cannot find model with id [{id}]"),
+                id: id.to_key_string(),
             }),
             Err(e) => Err(e),
         }
@@ -48,24 +68,27 @@ impl<IdType: Eq + Hash + Clone + Display, Data: Clone> Backend<Data>
     fn fetch_option_one(
         &self,
         id: &Self::IdType,
-    ) -> Result<Option<Model<Self::IdType, Data>>, TxError> {
+    ) -> Result<Option<BackendModel<Data, Self>>, TxError> {
         Ok(self.map.get(id).map(|val| (*val).clone()))
     }
 
-    fn fetch_version(&self, id: &Self::IdType) -> Result<VersionType, TxError> {
+    fn fetch_version(&self, id: &Self::IdType) -> Result<Self::VersionType, TxError> {
         match self.fetch_option_version(id) {
             Ok(opt) => opt.ok_or_else(|| TxError::FetchNotFoundError {
-                message: format!("Cannot find model with id [{id}]"),
+                id: id.to_key_string(),
             }),
             Err(e) => Err(e),
         }
     }
 
-    fn fetch_option_version(&self, id: &Self::IdType) -> Result<Option<VersionType>, TxError> {
+    fn fetch_option_version(
+        &self,
+        id: &Self::IdType,
+    ) -> Result<Option<Self::VersionType>, TxError> {
         Ok(self.map.get(id).map(|val| val.version))
     }
 
-    fn update(&mut self, model: Model<Self::IdType, Data>) -> Result<(), TxError> {
+    fn update(&mut self, model: BackendModel<Data, Self>) -> Result<(), TxError> {
         self.map.insert(model.id.clone(), model);
         Ok(())
     }
@@ -77,10 +100,8 @@ impl<IdType: Eq + Hash + Clone + Display, Data: Clone> Backend<Data>
                     Ok(())
                 } else {
                     Err(TxError::DeleteError {
-                        message: format!(
-                            "Cannot delete model with id [{}] because it does not exist.",
-                            id
-                        ),
+                        id: id.to_key_string(),
+                        message: "It does not exist.".to_owned(),
                     })
                 }
             }
@@ -89,11 +110,59 @@ impl<IdType: Eq + Hash + Clone + Display, Data: Clone> Backend<Data>
     }
 
     fn delete_option(&mut self, id: &Self::IdType) -> Result<bool, TxError> {
-        Ok(self.map.remove(id).is_some())
+        match self.map.remove(id) {
+            Some(model) => {
+                self.tombstones.add(model.id, model.version);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn fetch_option_tombstone(
+        &self,
+        id: &Self::IdType,
+    ) -> Result<Option<Self::VersionType>, TxError> {
+        Ok(self.tombstones.get(id))
+    }
+
+    fn save(&mut self, model: BackendModel<Data, Self>) -> Result<(), TxError> {
+        self.tombstones.remove(&model.id);
+        self.map.insert(model.id.clone(), model);
+        Ok(())
+    }
+
+    fn fetch_all(&self) -> Result<Vec<BackendModel<Data, Self>>, TxError> {
+        Ok(self.map.values().cloned().collect())
+    }
+
+    fn for_each_model(&self, visit: &mut ModelVisitor<'_, Data, Self>) -> Result<(), TxError> {
+        for model in self.map.values() {
+            if visit(model)?.is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn count(&self) -> Result<usize, TxError> {
+        Ok(self.map.len())
+    }
+
+    fn fetch_all_tombstones(&self) -> Result<Vec<Tombstone<Data, Self>>, TxError> {
+        Ok(self.tombstones.all())
     }
 
-    fn save(&mut self, model: NewModel<Self::IdType, Data>) -> Result<(), TxError> {
-        self.map.insert(model.id.clone(), model.into());
+    fn restore(
+        &mut self,
+        models: Vec<BackendModel<Data, Self>>,
+        tombstones: Vec<Tombstone<Data, Self>>,
+    ) -> Result<(), TxError> {
+        self.map = models
+            .into_iter()
+            .map(|model| (model.id.clone(), model))
+            .collect();
+        self.tombstones.restore(tombstones);
         Ok(())
     }
 }
@@ -102,15 +171,16 @@ impl<IdType: Eq + Hash + Clone + Display, Data: Clone> Backend<Data>
 mod test {
 
     use super::*;
+    use crate::model::NewModel;
 
     #[test]
     fn save_should_save_a_model() {
         // Arrange
-        let mut backend = HashmapBackend::new();
+        let mut backend = HashmapBackend::<i32, i32>::new();
         let model = NewModel { id: 1, data: 1123 };
 
         // Act
-        backend.save(model.clone()).unwrap();
+        backend.save(model.clone().into()).unwrap();
         let fetched_model = backend.fetch_one(&model.id).unwrap();
         let fetched_model_opt = backend.fetch_option_one(&model.id).unwrap();
 
@@ -149,11 +219,11 @@ mod test {
     #[test]
     fn should_return_the_version() {
         // Arrange
-        let mut backend = HashmapBackend::new();
+        let mut backend = HashmapBackend::<i32, i32>::new();
         let model = NewModel { id: 1, data: 1123 };
 
         // Act
-        backend.save(model.clone()).unwrap();
+        backend.save(model.clone().into()).unwrap();
         let fetched_version = backend.fetch_version(&model.id).unwrap();
 
         // Assert
@@ -187,9 +257,9 @@ mod test {
     #[test]
     fn update_should_update_a_model() {
         // Arrange
-        let mut backend = HashmapBackend::new();
+        let mut backend = HashmapBackend::<i32, i32>::new();
         let model = NewModel { id: 1, data: 1111 };
-        backend.save(model.clone()).unwrap();
+        backend.save(model.clone().into()).unwrap();
         let fetched_model_0 = backend.fetch_one(&model.id).unwrap();
 
         // Act
@@ -211,9 +281,9 @@ mod test {
     #[test]
     fn delete_should_delete_a_model() {
         // Arrange
-        let mut backend = HashmapBackend::new();
+        let mut backend = HashmapBackend::<i32, i32>::new();
         let model = NewModel { id: 1, data: 1123 };
-        backend.save(model.clone()).unwrap();
+        backend.save(model.clone().into()).unwrap();
 
         // Act
         let fetched_before = backend.fetch_option_one(&model.id).unwrap();
@@ -231,9 +301,9 @@ mod test {
     #[test]
     fn delete_option_should_delete_a_model() {
         // Arrange
-        let mut backend = HashmapBackend::new();
+        let mut backend = HashmapBackend::<i32, i32>::new();
         let model = NewModel { id: 1, data: 1123 };
-        backend.save(model.clone()).unwrap();
+        backend.save(model.clone().into()).unwrap();
 
         // Act
         let fetched_before = backend.fetch_option_one(&model.id).unwrap();
@@ -247,4 +317,81 @@ mod test {
         assert!(fetched_after.is_none());
         assert!(!delete_result_2);
     }
+
+    #[test]
+    fn delete_should_keep_a_tombstone() {
+        // Arrange
+        let mut backend = HashmapBackend::<i32, i32>::new();
+        backend.save(Model::from((1, 3, 1123))).unwrap();
+
+        // Act
+        let tombstone_before = backend.fetch_option_tombstone(&1).unwrap();
+        backend.delete(&1).unwrap();
+        let tombstone_after_delete = backend.fetch_option_tombstone(&1).unwrap();
+        backend.save(Model::from((1, 4, 1123))).unwrap();
+        let tombstone_after_save = backend.fetch_option_tombstone(&1).unwrap();
+
+        // Assert
+        assert_eq!(None, tombstone_before);
+        assert_eq!(Some(3), tombstone_after_delete);
+        assert_eq!(None, tombstone_after_save);
+    }
+
+    #[test]
+    fn tombstone_policy_should_discard_the_oldest_tombstones() {
+        // Arrange
+        let mut backend =
+            HashmapBackend::<i32, i32>::new().with_tombstone_policy(TombstonePolicy::KeepLatest(2));
+        let mut discard_backend =
+            HashmapBackend::<i32, i32>::new().with_tombstone_policy(TombstonePolicy::Discard);
+        for id in 0..3 {
+            backend.save(Model::from((id, 1123))).unwrap();
+            discard_backend.save(Model::from((id, 1123))).unwrap();
+        }
+
+        // Act
+        for id in 0..3 {
+            backend.delete(&id).unwrap();
+            discard_backend.delete(&id).unwrap();
+        }
+
+        // Assert
+        assert_eq!(None, backend.fetch_option_tombstone(&0).unwrap());
+        assert_eq!(Some(0), backend.fetch_option_tombstone(&1).unwrap());
+        assert_eq!(Some(0), backend.fetch_option_tombstone(&2).unwrap());
+        for id in 0..3 {
+            assert_eq!(None, discard_backend.fetch_option_tombstone(&id).unwrap());
+        }
+    }
+
+    #[test]
+    fn restore_should_replace_the_content() {
+        // Arrange
+        let mut backend = HashmapBackend::<i32, i32>::new();
+        backend.save(Model::from((1, 1111))).unwrap();
+        backend.save(Model::from((2, 2222))).unwrap();
+        backend.delete(&2).unwrap();
+
+        let mut restored_backend = HashmapBackend::<i32, i32>::new();
+        restored_backend.save(Model::from((3, 3333))).unwrap();
+
+        // Act
+        restored_backend
+            .restore(
+                backend.fetch_all().unwrap(),
+                backend.fetch_all_tombstones().unwrap(),
+            )
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            vec![Model::from((1, 1111))],
+            restored_backend.fetch_all().unwrap()
+        );
+        assert_eq!(
+            vec![(2, 0)],
+            restored_backend.fetch_all_tombstones().unwrap()
+        );
+        assert!(restored_backend.fetch_option_one(&3).unwrap().is_none());
+    }
 }
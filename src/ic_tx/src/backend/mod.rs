@@ -1,24 +1,140 @@
-use std::fmt::Display;
+use std::{
+    hash::Hash,
+    ops::{Bound, ControlFlow},
+};
 
 use crate::{
     error::TxError,
-    model::{Model, NewModel, VersionType},
+    key::{Key, PrefixKey},
+    model::{Model, Version},
 };
 
+pub mod btreemap;
+pub mod cached;
 pub mod hashmap;
+mod tombstones;
+
+/// The model type stored by a backend.
+pub type BackendModel<Data, B> =
+    Model<<B as Backend<Data>>::IdType, Data, <B as Backend<Data>>::VersionType>;
+
+/// Visits the models of a backend, see `Backend::for_each_model`.
+pub type ModelVisitor<'a, Data, B> =
+    dyn FnMut(&BackendModel<Data, B>) -> Result<ControlFlow<()>, TxError> + 'a;
+
+/// The id and the last version of a deleted model.
+pub type Tombstone<Data, B> = (
+    <B as Backend<Data>>::IdType,
+    <B as Backend<Data>>::VersionType,
+);
+
+/// Defines how many tombstones of deleted models a backend retains.
+/// A tombstone keeps the last version of a deleted model, so that a model created again
+/// with the same id continues from the next version instead of reusing the old ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum TombstonePolicy {
+    /// Tombstones are never discarded, so versions are never reused.
+    #[default]
+    KeepAll,
+    /// Keeps at most the specified number of tombstones, discarding the oldest ones first.
+    /// A model created again after its tombstone is discarded restarts from the initial version.
+    KeepLatest(usize),
+    /// Tombstones are not kept.
+    Discard,
+}
 
 pub trait Backend<Data> {
-    type IdType: Display + Clone;
+    type IdType: Key + Clone + Eq + Hash;
+    type VersionType: Version;
 
-    fn fetch_one(&self, id: &Self::IdType) -> Result<Model<Self::IdType, Data>, TxError>;
+    fn fetch_one(&self, id: &Self::IdType) -> Result<BackendModel<Data, Self>, TxError>;
     fn fetch_option_one(
         &self,
         id: &Self::IdType,
-    ) -> Result<Option<Model<Self::IdType, Data>>, TxError>;
-    fn fetch_version(&self, id: &Self::IdType) -> Result<VersionType, TxError>;
-    fn fetch_option_version(&self, id: &Self::IdType) -> Result<Option<VersionType>, TxError>;
-    fn update(&mut self, model: Model<Self::IdType, Data>) -> Result<(), TxError>;
+    ) -> Result<Option<BackendModel<Data, Self>>, TxError>;
+    fn fetch_version(&self, id: &Self::IdType) -> Result<Self::VersionType, TxError>;
+    fn fetch_option_version(&self, id: &Self::IdType)
+        -> Result<Option<Self::VersionType>, TxError>;
+    fn update(&mut self, model: BackendModel<Data, Self>) -> Result<(), TxError>;
     fn delete(&mut self, id: &Self::IdType) -> Result<(), TxError>;
     fn delete_option(&mut self, id: &Self::IdType) -> Result<bool, TxError>;
-    fn save(&mut self, model: NewModel<Self::IdType, Data>) -> Result<(), TxError>;
+    /// Returns the last version of a deleted model, if its tombstone is still retained.
+    fn fetch_option_tombstone(
+        &self,
+        id: &Self::IdType,
+    ) -> Result<Option<Self::VersionType>, TxError>;
+    /// Saves a new model. The version is assigned by the transaction.
+    fn save(&mut self, model: BackendModel<Data, Self>) -> Result<(), TxError>;
+    /// Returns all the models.
+    fn fetch_all(&self) -> Result<Vec<BackendModel<Data, Self>>, TxError>;
+    /// Calls the visitor with each model, in no particular order, until it breaks or fails.
+    /// The backends that keep the models in memory visit them without cloning them all first.
+    fn for_each_model(&self, visit: &mut ModelVisitor<'_, Data, Self>) -> Result<(), TxError> {
+        for model in self.fetch_all()? {
+            if visit(&model)?.is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+    /// Returns the number of models.
+    fn count(&self) -> Result<usize, TxError> {
+        Ok(self.fetch_all()?.len())
+    }
+    /// Returns the ids and versions of all the retained tombstones.
+    fn fetch_all_tombstones(&self) -> Result<Vec<Tombstone<Data, Self>>, TxError>;
+    /// Replaces the whole content of the backend with the specified models and tombstones.
+    fn restore(
+        &mut self,
+        models: Vec<BackendModel<Data, Self>>,
+        tombstones: Vec<Tombstone<Data, Self>>,
+    ) -> Result<(), TxError>;
+    /// Adds models restored from a snapshot, e.g. in batches after `restore` replaced the content
+    /// with the tombstones only. The ids are neither models nor tombstones of the backend yet.
+    fn restore_models(&mut self, models: Vec<BackendModel<Data, Self>>) -> Result<(), TxError> {
+        for model in models {
+            self.save(model)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if no key can be in the range, e.g. its bounds are reversed.
+/// `BTreeMap::range` panics on such ranges, so they must be checked first.
+pub(crate) fn is_empty_range<K: Ord + ?Sized>(start: Bound<&K>, end: Bound<&K>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start >= end,
+        _ => false,
+    }
+}
+
+/// A backend that keeps the models ordered by id, so ranges of ids can be fetched without a full scan.
+pub trait OrderedBackend<Data>: Backend<Data>
+where
+    Self::IdType: Ord,
+{
+    /// Returns the models with an id in the range, ordered by id.
+    fn fetch_range(
+        &self,
+        range: (Bound<&Self::IdType>, Bound<&Self::IdType>),
+    ) -> Result<Vec<BackendModel<Data, Self>>, TxError>;
+
+    /// Returns the models with a composite id that starts with the prefix, ordered by id.
+    fn fetch_prefix<P>(&self, prefix: &P) -> Result<Vec<BackendModel<Data, Self>>, TxError>
+    where
+        Self::IdType: PrefixKey<P>,
+    {
+        let first = Self::IdType::first_with_prefix(prefix);
+        let mut models = self.fetch_range((Bound::Included(&first), Bound::Unbounded))?;
+        let end = models
+            .iter()
+            .position(|model| !model.id.has_prefix(prefix))
+            .unwrap_or(models.len());
+        models.truncate(end);
+        Ok(models)
+    }
 }
@@ -0,0 +1,114 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+};
+
+use crate::model::Version;
+
+use super::TombstonePolicy;
+
+/// The tombstones of the deleted models, retained according to a `TombstonePolicy`.
+pub(crate) struct Tombstones<IdType, V> {
+    versions: HashMap<IdType, V>,
+    order: VecDeque<(IdType, V)>,
+    policy: TombstonePolicy,
+}
+
+impl<IdType: Eq + Hash + Clone, V: Version> Tombstones<IdType, V> {
+    pub(crate) fn new() -> Self {
+        Self {
+            versions: HashMap::default(),
+            order: VecDeque::default(),
+            policy: TombstonePolicy::default(),
+        }
+    }
+
+    pub(crate) fn set_policy(&mut self, policy: TombstonePolicy) {
+        self.policy = policy;
+        self.compact();
+    }
+
+    pub(crate) fn add(&mut self, id: IdType, version: V) {
+        if self.policy == TombstonePolicy::Discard {
+            return;
+        }
+        self.versions.insert(id.clone(), version);
+        self.order.push_back((id, version));
+        self.compact();
+    }
+
+    pub(crate) fn remove(&mut self, id: &IdType) {
+        self.versions.remove(id);
+    }
+
+    pub(crate) fn get(&self, id: &IdType) -> Option<V> {
+        self.versions.get(id).copied()
+    }
+
+    /// Returns the retained tombstones, the oldest first.
+    pub(crate) fn all(&self) -> Vec<(IdType, V)> {
+        self.order
+            .iter()
+            .filter(|(id, version)| self.versions.get(id) == Some(version))
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn restore(&mut self, tombstones: Vec<(IdType, V)>) {
+        self.versions = tombstones.iter().cloned().collect();
+        self.order = tombstones.into();
+        self.compact();
+    }
+
+    /// Discards the oldest tombstones exceeding the limit set by the policy.
+    fn compact(&mut self) {
+        let max = match self.policy {
+            TombstonePolicy::KeepAll => None,
+            TombstonePolicy::KeepLatest(max) => Some(max),
+            TombstonePolicy::Discard => Some(0),
+        };
+        if let Some(max) = max {
+            while self.versions.len() > max {
+                match self.order.pop_front() {
+                    Some((id, version)) => {
+                        if self.versions.get(&id) == Some(&version) {
+                            self.versions.remove(&id);
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+        // Drops the entries of the ids that have been created or deleted again,
+        // so the order grows with the retained tombstones and not with the number of deletes
+        if self.order.len() > 2 * self.versions.len() {
+            let versions = &self.versions;
+            self.order
+                .retain(|(id, version)| versions.get(id) == Some(version));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn order_should_not_grow_with_the_deletes_of_the_same_id() {
+        // Arrange
+        let mut tombstones = Tombstones::<u32, u32>::new();
+
+        // Act
+        for version in 0..1000 {
+            tombstones.add(1, version);
+            tombstones.remove(&1);
+        }
+        tombstones.add(1, 1000);
+        tombstones.add(2, 0);
+
+        // Assert
+        assert!(tombstones.order.len() <= 4);
+        assert_eq!(vec![(1, 1000), (2, 0)], tombstones.all());
+    }
+}
@@ -0,0 +1,24 @@
+use crate::{shared::shared, Ref};
+
+/// Returns the current time in nanoseconds since the epoch (1970-01-01).
+pub type Clock = Ref<shared!(Fn() -> u64)>;
+
+/// Returns the default clock.
+/// Inside a canister it uses `ic_cdk::api::time`, natively it falls back to the system time.
+pub fn default_clock() -> Clock {
+    Ref::new(time)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn time() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        // Saturates instead of wrapping around after the year 2554
+        .map(|duration| u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX))
+        .unwrap_or_default()
+}
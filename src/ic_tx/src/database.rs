@@ -0,0 +1,526 @@
+use std::io::Read;
+
+use candid::CandidType;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    backend::Backend,
+    db::IcTx,
+    error::TxError,
+    stable::{SectionReader, SectionWriter, StableMemory},
+};
+
+/// Declares the collections of a canister and generates:
+/// - a typed accessor for each collection, that returns a clone of the `IcTx` kept in a `thread_local`;
+/// - `pre_upgrade` and `post_upgrade`, that save and restore all the collections to and from the stable memory,
+///   each one in a section identified by its memory id;
+/// - `save_to_memory` and `restore_from_memory`, that do the same with the specified memory and offset;
+/// - `metrics` and `metrics_prometheus`, that return the metrics of all the collections.
+///
+/// The macro is available with the `candid` feature, that encodes the collections in the stable memory.
+///
+/// Each collection declares its memory id, from 0 to 254 as in `ic-stable-structures`, that must be unique
+/// and must not change between upgrades: a collection can be renamed, but its data is found by its memory id.
+///
+/// `pre_upgrade` writes the sections from the start of the stable memory of the canister. A canister that keeps
+/// other data there calls `save_to_memory` and `restore_from_memory` with an offset after it instead,
+/// see `write_sections` for the region the sections occupy.
+///
+/// The backend of each collection is created with `Default`. The optional expression after `=` receives
+/// the new `IcTx` and returns it configured, e.g. with a lock manager, indexes or validators.
+///
+/// The optional `hooks` generate the `#[ic_cdk::pre_upgrade]` and `#[ic_cdk::post_upgrade]` hooks of the canister,
+/// that save and restore the collections and trap if it fails. The `pre_upgrade` expression, if any, is called
+/// before the collections are saved, e.g. to reject the upgrade. The `post_upgrade` closure, if any, is called
+/// after the collections are restored with the argument of the upgrade, e.g. to run `init` again.
+/// A canister has only one pair of hooks, so only one `database!` can declare them.
+/// Without `hooks`, the canister calls `pre_upgrade` and `post_upgrade` from its own hooks.
+///
+/// Each thread has its own collections, as the `IcTx` is kept in a `thread_local`: a canister has one thread,
+/// but with the `sync` feature the collections are not shared by the threads of a native test or program.
+///
+/// ```ignore
+/// ic_tx::database! {
+///     pub struct Database {
+///         schema_tag: "my_canister_v1",
+///         users(memory_id = 0): IcTx<User, HashmapBackend<u32, User>> = |db| db
+///             .with_lock_manager()
+///             .with_index("username", |user: &User| user.username.clone()),
+///         orders(memory_id = 1): IcTx<Order, BTreeMapBackend<u64, Order>>,
+///     }
+///     hooks {
+///         pre_upgrade: || assert!(nothing_in_progress()),
+///         post_upgrade: |arg: InitArgs| init(arg),
+///     }
+/// }
+///
+/// let mut tx = Database::users().tx();
+/// ```
+#[macro_export]
+macro_rules! database {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            schema_tag: $schema_tag:expr,
+            $(
+                $(#[$collection_meta:meta])*
+                $collection:ident(memory_id = $memory_id:literal): IcTx<$data:ty, $backend:ty> $(= $configure:expr)?
+            ),* $(,)?
+        }
+        $(hooks $hooks:tt)?
+    ) => {
+        $(#[$meta])*
+        $vis struct $name;
+
+        const _: () = $crate::database::check_memory_ids(&[$($memory_id),*]);
+
+        $($crate::database!(@hooks $name $hooks);)?
+
+        // Not every canister uses all the generated functions
+        #[allow(dead_code)]
+        impl $name {
+            $(
+                $(#[$collection_meta])*
+                $vis fn $collection() -> $crate::db::IcTx<$data, $backend> {
+                    ::std::thread_local! {
+                        static COLLECTION: $crate::db::IcTx<$data, $backend> = {
+                            let db = $crate::db::IcTx::new($crate::Ref::new($crate::RefCell::new(
+                                <$backend as ::std::default::Default>::default(),
+                            )));
+                            $(let db = $crate::database::configure(db, $configure);)?
+                            db
+                        };
+                    }
+                    COLLECTION.with(|collection| collection.clone())
+                }
+            )*
+
+            /// Saves all the collections to the stable memory of the canister.
+            /// It is meant to be called from the `pre_upgrade` hook.
+            $vis fn pre_upgrade() -> ::std::result::Result<(), $crate::error::TxError> {
+                Self::save_to_memory($crate::stable::CanisterStableMemory::default(), 0).map(|_| ())
+            }
+
+            /// Restores all the collections from the stable memory of the canister.
+            /// It is meant to be called from the `post_upgrade` hook.
+            $vis fn post_upgrade() -> ::std::result::Result<(), $crate::error::TxError> {
+                Self::restore_from_memory($crate::stable::CanisterStableMemory::default(), 0)
+            }
+
+            /// Saves all the collections to the specified memory from the offset,
+            /// and returns the offset where they end.
+            $vis fn save_to_memory<M: $crate::stable::StableMemory>(
+                memory: M,
+                offset: u64,
+            ) -> ::std::result::Result<u64, $crate::error::TxError> {
+                $crate::database::save_collections(
+                    memory,
+                    offset,
+                    $schema_tag,
+                    &[$((
+                        $memory_id,
+                        &Self::$collection() as &dyn $crate::database::StableCollection,
+                    )),*],
+                )
+            }
+
+            /// Restores all the collections saved to the specified memory at the offset.
+            $vis fn restore_from_memory<M: $crate::stable::StableMemory + ::std::clone::Clone>(
+                memory: M,
+                offset: u64,
+            ) -> ::std::result::Result<(), $crate::error::TxError> {
+                $crate::database::restore_collections(
+                    memory,
+                    offset,
+                    $schema_tag,
+                    &[$((
+                        $memory_id,
+                        &Self::$collection() as &dyn $crate::database::StableCollection,
+                    )),*],
+                )
+            }
+
+            /// Returns the metrics of each collection with its name.
+            $vis fn metrics() -> ::std::result::Result<
+                ::std::vec::Vec<(::std::string::String, $crate::metrics::MetricsSnapshot)>,
+                $crate::error::TxError,
+            > {
+                ::std::result::Result::Ok(::std::vec![$((
+                    ::std::stringify!($collection).to_owned(),
+                    Self::$collection().metrics()?,
+                )),*])
+            }
+
+            /// Returns the metrics of all the collections in the Prometheus text format,
+            /// e.g. to be served by `http_request`.
+            $vis fn metrics_prometheus() -> ::std::result::Result<::std::string::String, $crate::error::TxError> {
+                let metrics = Self::metrics()?;
+                let collections: ::std::vec::Vec<_> = metrics
+                    .iter()
+                    .map(|(name, metrics)| (name.as_str(), metrics))
+                    .collect();
+                ::std::result::Result::Ok($crate::metrics::to_prometheus(&collections))
+            }
+        }
+    };
+    (
+        @hooks $name:ident {
+            $(pre_upgrade: $pre_upgrade:expr,)?
+            $(post_upgrade: |$arg:ident: $arg_ty:ty| $post_upgrade:expr,)?
+        }
+    ) => {
+        // The hooks are exported by name, so the functions are hidden to avoid clashing with the canister ones
+        const _: () = {
+            #[::ic_cdk::pre_upgrade]
+            fn pre_upgrade() {
+                $(($pre_upgrade)();)?
+                $name::pre_upgrade().expect("failed to save the database to the stable memory");
+            }
+
+            #[::ic_cdk::post_upgrade]
+            fn post_upgrade($($arg: $arg_ty)?) {
+                $name::post_upgrade().expect("failed to restore the database from the stable memory");
+                $($post_upgrade;)?
+            }
+        };
+    };
+}
+
+/// A collection that can be saved to a stable section, whatever the type of its data and backend.
+pub trait StableCollection {
+    fn write_stable_section(
+        &self,
+        writer: &mut SectionWriter<'_>,
+        name: &str,
+        schema_tag: &str,
+    ) -> Result<(), TxError>;
+
+    /// Decodes and checks the snapshot of a section from the reader without changing the collection,
+    /// and returns the function that restores the collection from the same snapshot, read again.
+    fn read_stable_section<'a>(
+        &'a self,
+        snapshot: &mut dyn Read,
+    ) -> Result<RestoreSection<'a>, TxError>;
+}
+
+/// Restores a checked section into its collection, from a reader of its snapshot.
+pub type RestoreSection<'a> = Box<dyn FnOnce(&mut dyn Read) -> Result<(), TxError> + 'a>;
+
+impl<Data, B: Backend<Data>> StableCollection for IcTx<Data, B>
+where
+    Data: CandidType + DeserializeOwned,
+    B::IdType: CandidType + DeserializeOwned,
+    B::VersionType: CandidType + DeserializeOwned,
+{
+    fn write_stable_section(
+        &self,
+        writer: &mut SectionWriter<'_>,
+        name: &str,
+        schema_tag: &str,
+    ) -> Result<(), TxError> {
+        IcTx::write_stable_section(self, writer, name, schema_tag)
+    }
+
+    fn read_stable_section<'a>(
+        &'a self,
+        snapshot: &mut dyn Read,
+    ) -> Result<RestoreSection<'a>, TxError> {
+        let tail = self.check_snapshot(snapshot)?;
+        Ok(Box::new(move |snapshot| {
+            self.restore_snapshot(snapshot, tail)
+        }))
+    }
+}
+
+/// The largest memory id of a collection, as `ic-stable-structures` reserves 255.
+pub const MAX_MEMORY_ID: u8 = 254;
+
+/// Fails to compile a `database!` whose memory ids are out of range or used twice.
+#[doc(hidden)]
+pub const fn check_memory_ids(memory_ids: &[u8]) {
+    let mut i = 0;
+    while i < memory_ids.len() {
+        assert!(memory_ids[i] <= MAX_MEMORY_ID, "the memory ids must be at most 254");
+        let mut j = i + 1;
+        while j < memory_ids.len() {
+            assert!(memory_ids[i] != memory_ids[j], "the memory ids must be unique");
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+/// Saves the collections to the memory from the offset, each one in a section identified by its memory id,
+/// and returns the offset where they end.
+/// Each collection is encoded straight into the memory, one after the other.
+pub fn save_collections<M: StableMemory>(
+    memory: M,
+    offset: u64,
+    schema_tag: &str,
+    collections: &[(u8, &dyn StableCollection)],
+) -> Result<u64, TxError> {
+    let mut writer = SectionWriter::new(memory, offset, collections.len() as u32)?;
+    for (memory_id, collection) in collections {
+        collection.write_stable_section(&mut writer, &memory_id.to_string(), schema_tag)?;
+    }
+    writer.finish()
+}
+
+/// Restores the collections from the sections written by `save_collections` at the offset,
+/// each one from the section of its memory id.
+/// A collection without a section, e.g. one added by the upgrade, is left unchanged.
+/// A section without a collection is an error, as its data would be lost by the next upgrade.
+///
+/// Every section is decoded straight from the memory and checked before the first collection is restored,
+/// so a missing collection, a wrong schema tag or a corrupted section changes nothing.
+/// The sections are then read a second time to restore the collections, without keeping their models on the heap.
+/// Rebuilding the indexes and the views of a restored collection can still fail, e.g. if a migration fails,
+/// after the previous collections are restored: `post_upgrade` should trap then, which reverts the upgrade.
+pub fn restore_collections<M: StableMemory + Clone>(
+    memory: M,
+    offset: u64,
+    schema_tag: &str,
+    collections: &[(u8, &dyn StableCollection)],
+) -> Result<(), TxError> {
+    let mut reader = SectionReader::new(memory.clone(), offset)?;
+    let mut restores = Vec::with_capacity(reader.remaining() as usize);
+    while reader.remaining() > 0 {
+        let restore = reader.read_section_with(|header, snapshot| {
+            let Some((_, collection)) = collections
+                .iter()
+                .find(|(memory_id, _)| memory_id.to_string() == header.name)
+            else {
+                return Err(TxError::StableMemoryError {
+                    message: format!(
+                        "There is no collection with the memory id [{}].",
+                        header.name
+                    ),
+                });
+            };
+            header.check_schema_tag(schema_tag)?;
+            collection.read_stable_section(snapshot)
+        })?;
+        restores.push(restore);
+    }
+    let mut reader = SectionReader::new(memory, offset)?;
+    for restore in restores {
+        reader.read_section_with(|_, snapshot| restore(snapshot))?;
+    }
+    Ok(())
+}
+
+/// Applies the configuration of a collection declared with `database!`.
+/// The function gives the closure the type of its argument.
+#[doc(hidden)]
+pub fn configure<T>(db: T, configure: impl FnOnce(T) -> T) -> T {
+    configure(db)
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::{model::NewModel, stable::test::VecMemory};
+
+    use super::*;
+
+    crate::database! {
+        /// The collections used by the tests
+        struct Database {
+            schema_tag: "test_v1",
+            users(memory_id = 0): IcTx<String, crate::backend::hashmap::HashmapBackend<u32, String>> = |db| db
+                .with_lock_manager()
+                .with_index("name", |name: &String| name.clone()),
+            counters(memory_id = 1): IcTx<u64, crate::backend::btreemap::BTreeMapBackend<String, u64>>,
+        }
+    }
+
+    crate::database! {
+        struct UsersOnly {
+            schema_tag: "test_v1",
+            users(memory_id = 0): IcTx<String, crate::backend::hashmap::HashmapBackend<u32, String>>,
+        }
+    }
+
+    crate::database! {
+        struct RenamedUsers {
+            schema_tag: "test_v1",
+            accounts(memory_id = 0): IcTx<String, crate::backend::hashmap::HashmapBackend<u32, String>>,
+        }
+    }
+
+    #[test]
+    fn accessors_should_return_the_same_configured_collection() {
+        // Arrange
+        let mut tx = Database::users().tx();
+        tx.save(NewModel::new(1, "ufo".to_owned())).unwrap();
+        tx.commit();
+
+        // Act
+        let user = Database::users().fetch_one(&1).unwrap();
+        let by_name = Database::users()
+            .query()
+            .index_eq("name", &"ufo".to_owned())
+            .fetch()
+            .unwrap();
+
+        // Assert
+        assert_eq!("ufo", user.data);
+        assert_eq!(vec![user], by_name);
+        assert!(Database::users().locks.is_some());
+        assert!(Database::counters().locks.is_none());
+    }
+
+    #[test]
+    fn collections_should_survive_a_round_trip_to_stable_memory() {
+        // Arrange
+        let memory = VecMemory::default();
+        let mut tx = Database::users().tx();
+        tx.save(NewModel::new(1, "ufo".to_owned())).unwrap();
+        tx.commit();
+        let mut tx = Database::counters().tx();
+        tx.save(NewModel::new("visits".to_owned(), 10)).unwrap();
+        tx.commit();
+        Database::save_to_memory(memory.clone(), 0).unwrap();
+
+        // Act
+        let mut tx = Database::users().tx();
+        let user = tx.fetch_one(&1).unwrap();
+        tx.delete(user).unwrap();
+        tx.commit();
+        Database::restore_from_memory(memory, 0).unwrap();
+
+        // Assert
+        assert_eq!("ufo", Database::users().fetch_one(&1).unwrap().data);
+        assert_eq!(
+            10,
+            Database::counters()
+                .fetch_one(&"visits".to_owned())
+                .unwrap()
+                .data
+        );
+        assert_eq!(
+            1,
+            Database::users()
+                .query()
+                .index_eq("name", &"ufo".to_owned())
+                .count()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn restore_should_fail_if_a_section_has_no_collection() {
+        // Arrange
+        let memory = VecMemory::default();
+        let mut tx = Database::users().tx();
+        tx.save(NewModel::new(1, "ufo".to_owned())).unwrap();
+        tx.commit();
+        Database::save_to_memory(memory.clone(), 0).unwrap();
+
+        // Act
+        let result = UsersOnly::restore_from_memory(memory, 0);
+
+        // Assert
+        assert!(matches!(result, Err(TxError::StableMemoryError { .. })));
+        assert!(UsersOnly::users().fetch_option_one(&1).unwrap().is_none());
+    }
+
+    #[test]
+    fn restore_should_find_a_renamed_collection_by_its_memory_id() {
+        // Arrange
+        let memory = VecMemory::default();
+        let mut tx = UsersOnly::users().tx();
+        tx.save(NewModel::new(1, "ufo".to_owned())).unwrap();
+        tx.commit();
+        UsersOnly::save_to_memory(memory.clone(), 0).unwrap();
+
+        // Act
+        RenamedUsers::restore_from_memory(memory, 0).unwrap();
+
+        // Assert
+        assert_eq!("ufo", RenamedUsers::accounts().fetch_one(&1).unwrap().data);
+    }
+
+    #[test]
+    fn restore_should_change_nothing_if_a_later_section_is_corrupted() {
+        // Arrange
+        let memory = VecMemory::default();
+        let mut tx = Database::users().tx();
+        tx.save(NewModel::new(1, "ufo".to_owned())).unwrap();
+        tx.commit();
+        let mut tx = Database::counters().tx();
+        tx.save(NewModel::new("visits".to_owned(), 10)).unwrap();
+        tx.commit();
+        Database::save_to_memory(memory.clone(), 0).unwrap();
+        let mut bytes = vec![0; memory.stable_size() as usize * 64 * 1024];
+        memory.stable_read(0, &mut bytes);
+        let counter = bytes
+            .windows(b"visits".len())
+            .rposition(|window| window == b"visits")
+            .unwrap();
+        memory.stable_write(counter as u64, b"V");
+        let mut tx = Database::users().tx();
+        let user = tx.fetch_one(&1).unwrap();
+        tx.delete(user).unwrap();
+        tx.commit();
+
+        // Act
+        let result = Database::restore_from_memory(memory, 0);
+
+        // Assert
+        assert!(matches!(result, Err(TxError::SnapshotError { .. })));
+        assert!(Database::users().fetch_option_one(&1).unwrap().is_none());
+        assert_eq!(
+            0,
+            Database::users()
+                .query()
+                .index_eq("name", &"ufo".to_owned())
+                .count()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn restore_should_leave_the_collections_without_a_section_unchanged() {
+        // Arrange
+        let memory = VecMemory::default();
+        let mut tx = UsersOnly::users().tx();
+        tx.save(NewModel::new(1, "ufo".to_owned())).unwrap();
+        tx.commit();
+        UsersOnly::save_to_memory(memory.clone(), 0).unwrap();
+        let mut tx = Database::counters().tx();
+        tx.save(NewModel::new("visits".to_owned(), 10)).unwrap();
+        tx.commit();
+
+        // Act
+        Database::restore_from_memory(memory, 0).unwrap();
+
+        // Assert
+        assert_eq!("ufo", Database::users().fetch_one(&1).unwrap().data);
+        assert_eq!(1, Database::counters().query().count().unwrap());
+    }
+
+    #[test]
+    fn metrics_should_be_reported_for_every_collection() {
+        // Arrange
+        let mut tx = Database::counters().tx();
+        tx.save(NewModel::new("clicks".to_owned(), 1)).unwrap();
+        tx.commit();
+
+        // Act
+        let metrics = Database::metrics().unwrap();
+        let text = Database::metrics_prometheus().unwrap();
+
+        // Assert
+        assert_eq!(
+            vec!["users", "counters"],
+            metrics
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, metrics[1].1.commits);
+        assert!(text.contains("ic_tx_commits_total{collection=\"users\"} 0\n"));
+        assert!(text.contains("ic_tx_commits_total{collection=\"counters\"} 1\n"));
+    }
+}
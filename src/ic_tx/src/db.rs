@@ -1,9 +1,48 @@
-use std::{cell::RefCell, marker::PhantomData};
+use std::{marker::PhantomData, time::Duration};
 
-use crate::{backend::Backend, error::TxError, model::Model, tx::Tx, Ref};
+use ic_principal::Principal;
+
+use crate::{
+    backend::{Backend, BackendModel},
+    clock::{default_clock, Clock},
+    error::TxError,
+    idempotency::{IdempotencyKeys, DEFAULT_IDEMPOTENCY_TTL},
+    index::Indexes,
+    key::Key,
+    lock::LockManager,
+    metadata::{default_author, Author},
+    metrics::{Metrics, MetricsSnapshot},
+    migration::{migrate, Migration, SchemaVersion},
+    reference::{InboundReference, Reference},
+    shared::CommitLock,
+    tx::TxReader,
+    tx::{Tx, ValidationMode},
+    validator::{DataId, GlobalCheck, Validator},
+    view::Views,
+    Ref, RefCell, Shareable,
+};
 
 pub struct IcTx<Data, B: Backend<Data>> {
-    backend: Ref<RefCell<B>>,
+    pub(crate) backend: Ref<RefCell<B>>,
+    pub(crate) locks: Option<Ref<RefCell<LockManager<B::IdType>>>>,
+    pub(crate) clock: Clock,
+    pub(crate) author: Author,
+    pub(crate) metadata: bool,
+    pub(crate) validation_mode: ValidationMode,
+    pub(crate) migrations: Vec<Migration<Data>>,
+    #[cfg(feature = "candid")]
+    pub(crate) legacy_data: Vec<(SchemaVersion, crate::migration::LegacyDecoder<Data>)>,
+    pub(crate) validators: Vec<Validator<Data>>,
+    pub(crate) data_id: Option<DataId<Data, B::IdType>>,
+    pub(crate) global_checks: Vec<(String, GlobalCheck<Data, B>)>,
+    pub(crate) references: Vec<(String, Reference<Data>)>,
+    // Shared by all the clones, so references can be declared after the database is cloned
+    pub(crate) referenced_by: Ref<RefCell<Vec<InboundReference<B::IdType>>>>,
+    pub(crate) views: Views<Data>,
+    pub(crate) indexes: Indexes<B::IdType, Data>,
+    pub(crate) idempotency_keys: Ref<RefCell<IdempotencyKeys<B::IdType, B::VersionType>>>,
+    pub(crate) metrics: Ref<RefCell<Metrics>>,
+    pub(crate) commit_lock: Ref<CommitLock>,
     phantom_data: PhantomData<Data>,
 }
 
@@ -11,6 +50,24 @@ impl<Data, B: Backend<Data>> Clone for IcTx<Data, B> {
     fn clone(&self) -> Self {
         Self {
             backend: self.backend.clone(),
+            locks: self.locks.clone(),
+            clock: self.clock.clone(),
+            author: self.author.clone(),
+            metadata: self.metadata,
+            validation_mode: self.validation_mode,
+            migrations: self.migrations.clone(),
+            #[cfg(feature = "candid")]
+            legacy_data: self.legacy_data.clone(),
+            validators: self.validators.clone(),
+            data_id: self.data_id.clone(),
+            global_checks: self.global_checks.clone(),
+            references: self.references.clone(),
+            referenced_by: self.referenced_by.clone(),
+            views: self.views.clone(),
+            indexes: self.indexes.clone(),
+            idempotency_keys: self.idempotency_keys.clone(),
+            metrics: self.metrics.clone(),
+            commit_lock: self.commit_lock.clone(),
             phantom_data: PhantomData,
         }
     }
@@ -20,26 +77,269 @@ impl<Data, B: Backend<Data>> IcTx<Data, B> {
     pub fn new(backend: Ref<RefCell<B>>) -> Self {
         Self {
             backend,
+            locks: None,
+            clock: default_clock(),
+            author: default_author(),
+            metadata: false,
+            validation_mode: ValidationMode::default(),
+            migrations: vec![],
+            #[cfg(feature = "candid")]
+            legacy_data: vec![],
+            validators: vec![],
+            data_id: None,
+            global_checks: vec![],
+            references: vec![],
+            referenced_by: Ref::default(),
+            views: Views::default(),
+            indexes: Indexes::default(),
+            idempotency_keys: Ref::new(RefCell::new(IdempotencyKeys::new(DEFAULT_IDEMPOTENCY_TTL))),
+            metrics: Ref::default(),
+            commit_lock: Ref::default(),
             phantom_data: PhantomData,
         }
     }
 
+    /// Enables the lock manager so transactions can take exclusive leases with `Tx::lock`.
+    pub fn with_lock_manager(mut self) -> Self {
+        self.locks = Some(Ref::new(RefCell::new(LockManager::new())));
+        self
+    }
+
+    /// Enables the audit metadata of the models.
+    /// When enabled, the transactions keep the creation and last update time and author of each model.
+    pub fn with_metadata(mut self) -> Self {
+        self.metadata = true;
+        self
+    }
+
+    /// Sets how the commits validate the actions of the transactions.
+    /// It can be overridden by each transaction with `Tx::with_validation_mode`.
+    pub fn with_validation_mode(mut self, validation_mode: ValidationMode) -> Self {
+        self.validation_mode = validation_mode;
+        self
+    }
+
+    /// Sets how long the receipts of the commits with an idempotency key are kept.
+    pub fn with_idempotency_ttl(self, ttl: Duration) -> Self {
+        self.idempotency_keys.borrow_mut().set_ttl(ttl);
+        self
+    }
+
+    /// Replaces the clock used to timestamp the leases and the metadata.
+    pub fn with_clock(mut self, clock: impl Fn() -> u64 + Shareable + 'static) -> Self {
+        self.clock = Ref::new(clock);
+        self
+    }
+
+    /// Replaces the function that returns the author of the changes stored in the metadata.
+    pub fn with_author(
+        mut self,
+        author: impl Fn() -> Option<Principal> + Shareable + 'static,
+    ) -> Self {
+        self.author = Ref::new(author);
+        self
+    }
+
+    /// Registers the migration that upgrades the data to the next schema version.
+    /// The n-th registered migration upgrades the data from schema version n - 1 to n.
+    /// Stored models are migrated when fetched and written back with the latest schema version on the next update;
+    /// migrations never change the optimistic lock version.
+    /// Migrations change the values of the data: if its type changes, the old type is registered with `with_legacy_data`.
+    pub fn with_migration(
+        mut self,
+        migration: impl Fn(Data) -> Data + Shareable + 'static,
+    ) -> Self {
+        self.migrations.push(Ref::new(migration));
+        self
+    }
+
+    /// Returns the latest schema version, that is, the number of registered migrations.
+    pub fn schema_version(&self) -> SchemaVersion {
+        self.migrations.len() as SchemaVersion
+    }
+
+    /// Registers a validator that checks the data of every model saved or updated by the transactions.
+    /// A commit fails with a `TxError::ValidationError` if any validator rejects the data.
+    pub fn with_validator(
+        mut self,
+        validator: impl Fn(&Data) -> Result<(), String> + Shareable + 'static,
+    ) -> Self {
+        self.validators.push(Ref::new(validator));
+        self
+    }
+
+    /// Sets the function that returns the id contained in the data, e.g. the `#[id]` field of an entity.
+    /// A commit fails with a `TxError::ValidationError` if a saved or updated model has a different id.
+    pub fn with_data_id(
+        mut self,
+        data_id: impl Fn(&Data) -> B::IdType + Shareable + 'static,
+    ) -> Self {
+        self.data_id = Some(Ref::new(data_id));
+        self
+    }
+
+    /// Registers a check of an invariant that spans several models, e.g. a total that must stay constant.
+    /// The check runs at every commit, after the validation of the single actions, against the database
+    /// as it would be after the commit. The commit fails with a `TxError::GlobalCheckError` if the check fails.
+    pub fn with_global_check(
+        mut self,
+        name: &str,
+        check: impl Fn(&TxReader<Data, B>) -> Result<(), String> + Shareable + 'static,
+    ) -> Self {
+        self.global_checks.push((name.to_owned(), Ref::new(check)));
+        self
+    }
+
+    /// Registers a materialized view: the fold of the data of all the models, kept up to date by the commits.
+    /// The commits call `add` with the data of the saved models and `remove` with the data of the deleted ones;
+    /// an update removes the previous data and adds the new one.
+    /// If the database already contains models, the view must be initialized with `rebuild_views`.
+    pub fn with_view<S: Clone + Shareable + 'static>(
+        self,
+        name: &str,
+        initial: S,
+        add: impl Fn(&mut S, &Data) + Shareable + 'static,
+        remove: impl Fn(&mut S, &Data) + Shareable + 'static,
+    ) -> Self
+    where
+        Data: 'static,
+    {
+        self.views.insert(name, initial, add, remove);
+        self
+    }
+
+    /// Returns the current state of a materialized view.
+    /// Returns `None` if there is no view with the name or if its state is not of type `S`.
+    pub fn view<S: Clone + 'static>(&self, name: &str) -> Option<S> {
+        self.views.get(name)
+    }
+
+    /// Registers a secondary index on the key extracted from the data.
+    /// The index is kept up to date by the commits and can serve the queries with `Query::index_eq`
+    /// and `Query::index_range`, that must use keys of the same type `K`.
+    /// If the database already contains models, the index must be initialized with `rebuild_indexes`.
+    pub fn with_index<K: Ord + Shareable + 'static>(
+        self,
+        name: &str,
+        key: impl Fn(&Data) -> K + Shareable + 'static,
+    ) -> Self
+    where
+        Data: 'static,
+        B::IdType: Shareable + 'static,
+    {
+        self.indexes.insert(name, key);
+        self
+    }
+
+    /// Registers a secondary index, like `with_index`, whose keys must be unique.
+    /// The commits fail with a `TxError::GlobalCheckError` if two models would have the same key.
+    pub fn with_unique_index<K: Ord + Shareable + 'static>(
+        self,
+        name: &str,
+        key: impl Fn(&Data) -> K + Shareable + 'static,
+    ) -> Self
+    where
+        Data: Clone + 'static,
+        B::IdType: Shareable + 'static,
+    {
+        let key = Ref::new(key);
+        let indexed_key = key.clone();
+        let indexes = self.indexes.clone();
+        let index_name = name.to_owned();
+        self.with_index(name, move |data| indexed_key(data))
+            .with_global_check(name, move |reader| {
+                let changed_ids: Vec<_> = reader.changed_ids().cloned().collect();
+                for id in &changed_ids {
+                    let Some(model) = reader.fetch_option_one(id).map_err(|err| err.to_string())? else {
+                        continue;
+                    };
+                    let model_key = key(&model.data);
+                    // The index does not contain the changes of the transaction yet
+                    let candidates = indexes
+                        .lookup::<K>(&index_name, &model_key..=&model_key)
+                        .map_err(|err| err.to_string())?;
+                    for other_id in candidates.iter().chain(&changed_ids) {
+                        if other_id == id {
+                            continue;
+                        }
+                        let other = reader.fetch_option_one(other_id).map_err(|err| err.to_string())?;
+                        if other.is_some_and(|other| key(&other.data) == model_key) {
+                            return Err(format!(
+                                "The models with id [{}] and [{}] have the same key in the unique index [{index_name}].",
+                                id.display(),
+                                other_id.display()
+                            ));
+                        }
+                    }
+                }
+                Ok(())
+            })
+    }
+
+    /// Recomputes all the secondary indexes from the models stored in the backend.
+    pub fn rebuild_indexes(&self) -> Result<(), TxError> {
+        if self.indexes.is_empty() {
+            return Ok(());
+        }
+        let models = self.fetch_all_migrated()?;
+        self.indexes
+            .rebuild(models.iter().map(|model| (&model.id, &model.data)));
+        Ok(())
+    }
+
+    /// Recomputes all the materialized views from the models stored in the backend.
+    pub fn rebuild_views(&self) -> Result<(), TxError> {
+        if self.views.is_empty() {
+            return Ok(());
+        }
+        let models = self.fetch_all_migrated()?;
+        self.views.rebuild(models.iter().map(|model| &model.data));
+        Ok(())
+    }
+
+    pub(crate) fn fetch_all_migrated(&self) -> Result<Vec<BackendModel<Data, B>>, TxError> {
+        self.backend
+            .borrow()
+            .fetch_all()?
+            .into_iter()
+            .map(|model| migrate(&self.migrations, model))
+            .collect()
+    }
+
     /// Starts a new atomic transaction
     pub fn tx(&self) -> Tx<Data, B> {
-        Tx::new(self.backend.clone())
+        Tx::new(self.clone())
     }
 
     /// Fetches a model from the database.
     /// Returns an error if no model is found with the specified id.
-    pub fn fetch_one(&self, id: &B::IdType) -> Result<Model<B::IdType, Data>, TxError> {
-        self.backend.borrow().fetch_one(id)
+    pub fn fetch_one(&self, id: &B::IdType) -> Result<BackendModel<Data, B>, TxError> {
+        migrate(&self.migrations, self.backend.borrow().fetch_one(id)?)
     }
 
     /// Fetches a model from the database.
     pub fn fetch_option_one(
         &self,
         id: &B::IdType,
-    ) -> Result<Option<Model<B::IdType, Data>>, TxError> {
-        self.backend.borrow().fetch_option_one(id)
+    ) -> Result<Option<BackendModel<Data, B>>, TxError> {
+        self.backend
+            .borrow()
+            .fetch_option_one(id)?
+            .map(|model| migrate(&self.migrations, model))
+            .transpose()
+    }
+
+    /// Returns the metrics of the commits and the rollbacks of the transactions, shared by all the clones.
+    pub fn metrics(&self) -> Result<MetricsSnapshot, TxError> {
+        let records = self.backend.borrow().count()?;
+        Ok(self.metrics.borrow().snapshot(records))
+    }
+
+    /// Returns true if the id is locked by a lease that is not yet expired.
+    pub fn is_locked(&self, id: &B::IdType) -> bool {
+        match &self.locks {
+            Some(locks) => locks.borrow().is_locked(id, (self.clock)()),
+            None => false,
+        }
     }
 }
@@ -0,0 +1,190 @@
+use crate::{backend::Backend, db::IcTx, model::NewModel, Shareable};
+
+#[cfg(feature = "derive")]
+pub use ic_tx_derive::Entity;
+
+/// The data of a collection that contains its own id.
+///
+/// It is usually implemented with `#[derive(Entity)]`, that also generates a typed repository,
+/// e.g. `UserRepo` for `User`, with a `fetch_by_<field>` method for each indexed field.
+/// The id field is marked with `#[id]` and the indexed fields with `#[index]` or `#[index(unique)]`.
+/// The commits of the repository fail if a model has an id different from the one of its `#[id]` field:
+///
+/// ```ignore
+/// #[derive(Clone, Entity)]
+/// struct User {
+///     #[id]
+///     id: u32,
+///     #[index(unique)]
+///     username: String,
+///     tokens: u32,
+/// }
+///
+/// let users = UserRepo::new(IcTx::new(Ref::new(RefCell::new(HashmapBackend::new()))));
+/// let mut tx = users.tx();
+/// tx.save(user.into_new_model())?;
+/// tx.try_commit()?;
+/// let user = users.fetch_by_username(&"ufo".to_owned())?;
+/// ```
+pub trait Entity: Clone + Sized + 'static {
+    type Id;
+
+    /// Returns the id of the entity.
+    fn id(&self) -> Self::Id;
+
+    /// Registers the indexes and the constraints of the entity.
+    fn register<B: Backend<Self, IdType = Self::Id>>(db: IcTx<Self, B>) -> IcTx<Self, B>
+    where
+        Self::Id: Shareable;
+
+    /// Returns the model to be saved for the entity, with the id of the entity.
+    fn into_new_model(self) -> NewModel<Self::Id, Self> {
+        NewModel::new(self.id(), self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use ic_tx_derive::Entity;
+
+    use crate::{backend::hashmap::HashmapBackend, error::TxError, Ref, RefCell};
+
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq, Entity)]
+    pub struct User {
+        #[id]
+        id: u32,
+        #[index(unique)]
+        username: String,
+        #[index]
+        country: String,
+        tokens: u32,
+    }
+
+    fn user(id: u32, username: &str, country: &str) -> User {
+        User {
+            id,
+            username: username.to_owned(),
+            country: country.to_owned(),
+            tokens: 0,
+        }
+    }
+
+    fn new_repo() -> UserRepo<HashmapBackend<u32, User>> {
+        UserRepo::new(IcTx::new(Ref::new(RefCell::new(HashmapBackend::new()))))
+    }
+
+    #[test]
+    fn repo_should_fetch_by_the_indexed_fields() {
+        // Arrange
+        let users = new_repo();
+        let mut tx = users.tx();
+        tx.save(user(1, "ufo", "it").into_new_model()).unwrap();
+        tx.save(user(2, "scout", "it").into_new_model()).unwrap();
+        tx.save(user(3, "cina", "ch").into_new_model()).unwrap();
+        tx.commit();
+
+        // Act
+        let by_username = users.fetch_by_username(&"scout".to_owned()).unwrap();
+        let missing = users.fetch_by_username(&"nobody".to_owned()).unwrap();
+        let by_country = users.fetch_by_country(&"it".to_owned()).unwrap();
+
+        // Assert
+        assert_eq!(
+            Some(user(2, "scout", "it")),
+            by_username.map(|model| model.data)
+        );
+        assert!(missing.is_none());
+        let mut ids: Vec<_> = by_country.into_iter().map(|model| model.id).collect();
+        ids.sort();
+        assert_eq!(vec![1, 2], ids);
+        assert_eq!(user(3, "cina", "ch"), users.fetch_one(&3).unwrap().data);
+    }
+
+    #[test]
+    fn commit_should_fail_if_a_unique_field_is_duplicated() {
+        // Arrange
+        let users = new_repo();
+        let mut tx = users.tx();
+        tx.save(user(1, "ufo", "it").into_new_model()).unwrap();
+        tx.commit();
+
+        // Act
+        let mut tx = users.tx();
+        tx.save(user(2, "ufo", "ch").into_new_model()).unwrap();
+        let duplicated_in_backend = tx.try_commit();
+
+        let mut tx = users.tx();
+        tx.save(user(2, "scout", "ch").into_new_model()).unwrap();
+        tx.save(user(3, "scout", "ch").into_new_model()).unwrap();
+        let duplicated_in_tx = tx.try_commit();
+
+        let mut tx = users.tx();
+        let mut renamed = users.fetch_one(&1).unwrap();
+        renamed.data.username = "scout".to_owned();
+        tx.update(renamed).unwrap();
+        tx.save(user(2, "ufo", "ch").into_new_model()).unwrap();
+        let swapped = tx.try_commit();
+
+        // Assert
+        assert!(matches!(
+            duplicated_in_backend,
+            Err(TxError::GlobalCheckError { name, .. }) if name == "username"
+        ));
+        assert!(matches!(
+            duplicated_in_tx,
+            Err(TxError::GlobalCheckError { .. })
+        ));
+        assert_eq!(Ok(()), swapped);
+        assert_eq!(
+            Some(2),
+            users
+                .fetch_by_username(&"ufo".to_owned())
+                .unwrap()
+                .map(|model| model.id)
+        );
+    }
+
+    #[test]
+    fn commit_should_fail_if_the_id_differs_from_the_id_field() {
+        // Arrange
+        let users = new_repo();
+        let mut tx = users.tx();
+        tx.save(user(1, "ufo", "it").into_new_model()).unwrap();
+        tx.commit();
+
+        // Act
+        let mut tx = users.tx();
+        tx.save(NewModel::new(2, user(3, "scout", "it"))).unwrap();
+        let saved = tx.try_commit();
+
+        let mut tx = users.tx();
+        let mut changed = users.fetch_one(&1).unwrap();
+        changed.data.id = 4;
+        tx.update(changed).unwrap();
+        let updated = tx.try_commit();
+
+        // Assert
+        assert!(matches!(
+            saved,
+            Err(TxError::ValidationError { id, reason }) if id == "2" && reason == "The data has the id [3]."
+        ));
+        assert!(matches!(
+            updated,
+            Err(TxError::ValidationError { id, .. }) if id == "1"
+        ));
+        assert!(users.fetch_option_one(&2).unwrap().is_none());
+        assert_eq!(1, users.fetch_one(&1).unwrap().data.id);
+    }
+
+    #[test]
+    fn new_model_should_have_the_id_of_the_entity() {
+        // Act
+        let model = user(7, "ufo", "it").into_new_model();
+
+        // Assert
+        assert_eq!(NewModel::new(7, user(7, "ufo", "it")), model);
+    }
+}
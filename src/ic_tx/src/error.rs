@@ -1,21 +1,99 @@
 use thiserror::Error;
 
-#[derive(Error, Debug, PartialEq)]
+/// The error returned by the storage.
+/// Ids are reported in their string representation and versions are widened to `u64`,
+/// so the error can be returned as is by the canister methods.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "candid", derive(candid::CandidType))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TxError {
     #[error("FetchError: {message}")]
     FetchError { message: String },
-    #[error("FetchNotFoundError: {message}")]
-    FetchNotFoundError { message: String },
-    #[error("UpdateOptimisticLockError: {message}")]
-    UpdateOptimisticLockError { message: String },
-    #[error("UpdateError: {message}")]
-    UpdateError { message: String },
-    #[error("SaveError: {message}")]
-    SaveError { message: String },
-    #[error("DeleteError: {message}")]
-    DeleteError { message: String },
-    #[error("DeleteNotFoundError: {message}")]
-    DeleteNotFoundError { message: String },
-    #[error("DeleteOptimisticLockError: {message}")]
-    DeleteOptimisticLockError { message: String },
+    #[error("FetchNotFoundError: Cannot find model with id [{id}]")]
+    FetchNotFoundError { id: String },
+    #[error("UpdateOptimisticLockError: Cannot update model with id [{id}]. Expected version [{expected}], version found [{found}]")]
+    UpdateOptimisticLockError {
+        id: String,
+        expected: u64,
+        found: u64,
+    },
+    #[error("UpdateError: Cannot update model with id [{id}]. {message}")]
+    UpdateError { id: String, message: String },
+    #[error("SaveError: Cannot save model with id [{id}]. {message}")]
+    SaveError { id: String, message: String },
+    #[error("DeleteError: Cannot delete model with id [{id}]. {message}")]
+    DeleteError { id: String, message: String },
+    #[error("DeleteNotFoundError: Cannot delete model with id [{id}] because it does not exist.")]
+    DeleteNotFoundError { id: String },
+    #[error("DeleteOptimisticLockError: Cannot delete model with id [{id}]. Expected version [{expected}], version found [{found}]")]
+    DeleteOptimisticLockError {
+        id: String,
+        expected: u64,
+        found: u64,
+    },
+    #[error("VersionOverflowError: Cannot create a new version of model with id [{id}] because version [{version}] cannot be incremented.")]
+    VersionOverflowError { id: String, version: u64 },
+    #[error("CommitError: Cannot commit the transaction. {message}")]
+    CommitError { message: String },
+    #[error("LockError: Cannot lock model with id [{id}]. {message}")]
+    LockError { id: String, message: String },
+    #[error("ConflictingActionsError: Cannot change model with id [{id}]. {message}")]
+    ConflictingActionsError { id: String, message: String },
+    #[error("ValidationError: The model with id [{id}] is not valid. {reason}")]
+    ValidationError { id: String, reason: String },
+    #[error("GlobalCheckError: The global check [{name}] failed. {reason}")]
+    GlobalCheckError { name: String, reason: String },
+    #[error("ReferenceError: The model with id [{id}] breaks a reference. {message}")]
+    ReferenceError { id: String, message: String },
+    #[error("MigrationError: Cannot migrate model with id [{id}]. {message}")]
+    MigrationError { id: String, message: String },
+    #[error("RemoteError: {message}")]
+    RemoteError { message: String },
+    #[error("TwoPhaseCommitError: The distributed transaction [{tx_id}] failed. {message}")]
+    TwoPhaseCommitError { tx_id: String, message: String },
+    #[error("SagaError: The saga [{id}] failed. {message}")]
+    SagaError { id: String, message: String },
+    #[error("QueryError: {message}")]
+    QueryError { message: String },
+    #[error("SnapshotError: {message}")]
+    SnapshotError { message: String },
+    #[error("StableMemoryError: {message}")]
+    StableMemoryError { message: String },
+    #[error("Conflicts: {} actions failed the validation", .0.len())]
+    Conflicts(Vec<Conflict>),
+}
+
+/// An action that failed the validation of a commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "candid", derive(candid::CandidType))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Conflict {
+    pub id: String,
+    pub reason: TxError,
+}
+
+#[cfg(all(test, feature = "candid"))]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn conflicts_should_be_candid_serializable() {
+        // Arrange
+        let error = TxError::Conflicts(vec![Conflict {
+            id: "1".to_owned(),
+            reason: TxError::UpdateOptimisticLockError {
+                id: "1".to_owned(),
+                expected: 0,
+                found: 1,
+            },
+        }]);
+
+        // Act
+        let bytes = candid::encode_one(&error).unwrap();
+        let decoded: TxError = candid::decode_one(&bytes).unwrap();
+
+        // Assert
+        assert_eq!(error, decoded);
+    }
 }
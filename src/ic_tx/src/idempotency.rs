@@ -0,0 +1,208 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+/// How long the receipt of a commit is kept if no TTL is specified.
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The outcome of a commit.
+/// When a transaction is retried with the same idempotency key, the receipt of the first commit is returned.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "candid", derive(candid::CandidType))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Receipt<IdType, V> {
+    /// The time of the commit in nanoseconds since the epoch
+    pub committed_at: u64,
+    /// The id and the new version of the written models. The version is `None` for the deleted ones.
+    pub changes: Vec<(IdType, Option<V>)>,
+    /// True if the receipt is the one of a previous commit with the same idempotency key
+    pub replayed: bool,
+}
+
+/// The receipts of the commits with an idempotency key, kept until their TTL elapses.
+pub(crate) struct IdempotencyKeys<IdType, V> {
+    ttl: Duration,
+    receipts: HashMap<String, (Receipt<IdType, V>, u64)>,
+    // The keys ordered by expiration time, to drop the expired ones without scanning all of them
+    expirations: BTreeSet<(u64, String)>,
+}
+
+impl<IdType: Clone, V: Clone> IdempotencyKeys<IdType, V> {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            receipts: HashMap::default(),
+            expirations: BTreeSet::default(),
+        }
+    }
+
+    pub(crate) fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Returns the receipt of the key, if it is not expired.
+    pub(crate) fn get(&mut self, key: &str, now: u64) -> Option<Receipt<IdType, V>> {
+        self.purge(now);
+        self.receipts.get(key).map(|(receipt, _)| Receipt {
+            replayed: true,
+            ..receipt.clone()
+        })
+    }
+
+    pub(crate) fn insert(&mut self, key: String, receipt: Receipt<IdType, V>, now: u64) {
+        self.purge(now);
+        let expires_at = now.saturating_add(self.ttl.as_nanos().try_into().unwrap_or(u64::MAX));
+        self.expirations.insert((expires_at, key.clone()));
+        self.receipts.insert(key, (receipt, expires_at));
+    }
+
+    /// Returns the keys with their receipt and their expiration time.
+    #[cfg(feature = "candid")]
+    pub(crate) fn all(&self) -> Vec<(String, Receipt<IdType, V>, u64)> {
+        self.expirations
+            .iter()
+            .filter_map(|(expires_at, key)| {
+                let (receipt, _) = self.receipts.get(key)?;
+                Some((key.clone(), receipt.clone(), *expires_at))
+            })
+            .collect()
+    }
+
+    /// Replaces all the keys
+    #[cfg(feature = "candid")]
+    pub(crate) fn restore(&mut self, keys: Vec<(String, Receipt<IdType, V>, u64)>) {
+        self.receipts.clear();
+        self.expirations.clear();
+        for (key, receipt, expires_at) in keys {
+            self.expirations.insert((expires_at, key.clone()));
+            self.receipts.insert(key, (receipt, expires_at));
+        }
+    }
+
+    fn purge(&mut self, now: u64) {
+        while let Some((expires_at, _)) = self.expirations.first() {
+            if *expires_at > now {
+                break;
+            }
+            if let Some((_, key)) = self.expirations.pop_first() {
+                self.receipts.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::{
+        backend::hashmap::HashmapBackend, db::IcTx, error::TxError, model::NewModel,
+        test_utils::new_db, Cell, Ref,
+    };
+
+    use super::*;
+
+    fn db_with_clock(now: &Ref<Cell<u64>>) -> IcTx<u32, HashmapBackend<u32, u32>> {
+        let clock = now.clone();
+        new_db()
+            .with_clock(move || clock.get())
+            .with_idempotency_ttl(Duration::from_nanos(100))
+    }
+
+    fn add_tokens(
+        db: &IcTx<u32, HashmapBackend<u32, u32>>,
+        key: &str,
+        tokens: u32,
+    ) -> Result<Receipt<u32, u32>, TxError> {
+        let mut tx = db.tx().with_idempotency_key(key);
+        let mut model = tx.fetch_one(&1)?;
+        model.data += tokens;
+        tx.update(model)?;
+        tx.try_commit_with_receipt()
+    }
+
+    #[test]
+    fn retry_should_return_the_receipt_of_the_first_commit() {
+        // Arrange
+        let now = Ref::new(Cell::new(10));
+        let db = db_with_clock(&now);
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, 100)).unwrap();
+        tx.commit();
+
+        // Act
+        let first = add_tokens(&db, "transfer_1", 10).unwrap();
+        now.set(20);
+        let retry = add_tokens(&db, "transfer_1", 10).unwrap();
+        let other = add_tokens(&db, "transfer_2", 10).unwrap();
+
+        // Assert
+        assert_eq!(
+            Receipt {
+                committed_at: 10,
+                changes: vec![(1, Some(1))],
+                replayed: false
+            },
+            first
+        );
+        assert_eq!(
+            Receipt {
+                replayed: true,
+                ..first
+            },
+            retry
+        );
+        assert_eq!(vec![(1, Some(2))], other.changes);
+        assert_eq!(120, db.fetch_one(&1).unwrap().data);
+    }
+
+    #[test]
+    fn key_should_expire_after_the_ttl() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let db = db_with_clock(&now);
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, 100)).unwrap();
+        tx.commit();
+        add_tokens(&db, "transfer_1", 10).unwrap();
+
+        // Act
+        now.set(99);
+        let retry_before_ttl = add_tokens(&db, "transfer_1", 10).unwrap();
+        now.set(100);
+        let retry_after_ttl = add_tokens(&db, "transfer_1", 10).unwrap();
+
+        // Assert
+        assert!(retry_before_ttl.replayed);
+        assert!(!retry_after_ttl.replayed);
+        assert_eq!(120, db.fetch_one(&1).unwrap().data);
+        assert_eq!(1, db.idempotency_keys.borrow().receipts.len());
+    }
+
+    #[test]
+    fn failed_commit_should_not_store_the_key() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let db = db_with_clock(&now);
+
+        // Act
+        let mut tx = db.tx().with_idempotency_key("create_1");
+        tx.update(crate::model::Model::from((1, 100))).unwrap();
+        let failed = tx.try_commit();
+
+        let mut tx = db.tx().with_idempotency_key("create_1");
+        tx.save(NewModel::new(1, 100)).unwrap();
+        let retry = tx.try_commit_with_receipt();
+
+        // Assert
+        assert!(failed.is_err());
+        assert_eq!(
+            Ok(Receipt {
+                committed_at: 0,
+                changes: vec![(1, Some(0))],
+                replayed: false
+            }),
+            retry
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::Hash,
+    ops::RangeBounds,
+};
+
+use crate::{backend::is_empty_range, error::TxError, shared::shared, Ref, RefCell, Shareable};
+
+/// A secondary index, kept up to date by the commits, that maps a key extracted from the data to the ids.
+trait Index<IdType, Data>: Shareable {
+    fn add(&mut self, id: &IdType, data: &Data);
+    fn remove(&mut self, id: &IdType, data: &Data);
+    fn reset(&mut self);
+    fn as_any(&self) -> &dyn Any;
+}
+
+struct KeyIndex<IdType, Data, K> {
+    key: Box<shared!(Fn(&Data) -> K)>,
+    entries: BTreeMap<K, HashSet<IdType>>,
+}
+
+impl<IdType, Data, K> Index<IdType, Data> for KeyIndex<IdType, Data, K>
+where
+    IdType: Eq + Hash + Clone + Shareable + 'static,
+    Data: 'static,
+    K: Ord + Shareable + 'static,
+{
+    fn add(&mut self, id: &IdType, data: &Data) {
+        self.entries
+            .entry((self.key)(data))
+            .or_default()
+            .insert(id.clone());
+    }
+
+    fn remove(&mut self, id: &IdType, data: &Data) {
+        let key = (self.key)(data);
+        if let Some(ids) = self.entries.get_mut(&key) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.entries.clear();
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+type IndexMap<IdType, Data> = HashMap<String, Box<dyn Index<IdType, Data>>>;
+
+/// The secondary indexes of a database, shared by all its clones.
+pub(crate) struct Indexes<IdType, Data>(Ref<RefCell<IndexMap<IdType, Data>>>);
+
+impl<IdType, Data> Clone for Indexes<IdType, Data> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<IdType, Data> Default for Indexes<IdType, Data> {
+    fn default() -> Self {
+        Self(Ref::default())
+    }
+}
+
+impl<IdType: Eq + Hash + Clone + 'static, Data: 'static> Indexes<IdType, Data> {
+    pub(crate) fn insert<K: Ord + Shareable + 'static>(
+        &self,
+        name: &str,
+        key: impl Fn(&Data) -> K + Shareable + 'static,
+    ) where
+        IdType: Shareable,
+    {
+        self.0.borrow_mut().insert(
+            name.to_owned(),
+            Box::new(KeyIndex {
+                key: Box::new(key),
+                entries: BTreeMap::new(),
+            }),
+        );
+    }
+
+    /// Returns the ids of the models with the key of the index in the range, ordered by key.
+    pub(crate) fn lookup<K: Ord + 'static>(
+        &self,
+        name: &str,
+        range: impl RangeBounds<K>,
+    ) -> Result<Vec<IdType>, TxError> {
+        let indexes = self.0.borrow();
+        let index = indexes
+            .get(name)
+            .ok_or_else(|| TxError::QueryError {
+                message: format!("There is no index named [{name}]."),
+            })?
+            .as_any()
+            .downcast_ref::<KeyIndex<IdType, Data, K>>()
+            .ok_or_else(|| TxError::QueryError {
+                message: format!("The key of the index [{name}] is not of the requested type."),
+            })?;
+        if is_empty_range(range.start_bound(), range.end_bound()) {
+            return Ok(vec![]);
+        }
+        Ok(index
+            .entries
+            .range(range)
+            .flat_map(|(_, ids)| ids.iter().cloned())
+            .collect())
+    }
+}
+
+impl<IdType, Data> Indexes<IdType, Data> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
+    /// Updates the indexes with the change of a model: the removed data is the previous one
+    /// and the added data is the new one.
+    pub(crate) fn apply(&self, id: &IdType, removed: Option<&Data>, added: Option<&Data>) {
+        for index in self.0.borrow_mut().values_mut() {
+            if let Some(data) = removed {
+                index.remove(id, data);
+            }
+            if let Some(data) = added {
+                index.add(id, data);
+            }
+        }
+    }
+
+    /// Recomputes the indexes from all the models.
+    pub(crate) fn rebuild<'a>(&self, models: impl Iterator<Item = (&'a IdType, &'a Data)>)
+    where
+        IdType: 'a,
+        Data: 'a,
+    {
+        let mut indexes = self.0.borrow_mut();
+        indexes.values_mut().for_each(|index| index.reset());
+        for (id, data) in models {
+            indexes.values_mut().for_each(|index| index.add(id, data));
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::fmt::{self, Display, Formatter};
+
+use ic_principal::Principal;
+
+/// The id of the models.
+///
+/// The errors report the ids as strings, so a key must be formatted, but it does not need to implement `Display`.
+/// It is implemented for the integers, the strings, the principals and the tuples of keys,
+/// that are the composite keys, e.g. `(Principal, u64)`.
+///
+/// The ids used to be bound by `Display`: a custom id type that implements `Display`
+/// becomes a key, formatted the same way, with `display_key!`:
+///
+/// ```ignore
+/// #[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// struct OrderId(u64);
+///
+/// impl Display for OrderId { ... }
+///
+/// ic_tx::display_key!(OrderId);
+/// ```
+pub trait Key {
+    /// Writes the key as it is reported by the errors.
+    fn fmt_key(&self, f: &mut Formatter<'_>) -> fmt::Result;
+
+    /// Returns a value that displays the key.
+    fn display(&self) -> KeyDisplay<'_, Self> {
+        KeyDisplay(self)
+    }
+
+    /// Returns the key as it is reported by the errors.
+    fn to_key_string(&self) -> String {
+        self.display().to_string()
+    }
+}
+
+/// Displays a key, see `Key::display`.
+pub struct KeyDisplay<'a, K: ?Sized>(&'a K);
+
+impl<K: Key + ?Sized> Display for KeyDisplay<'_, K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.0.fmt_key(f)
+    }
+}
+
+/// Implements `Key` for types that implement `Display`, formatting the keys with `Display`.
+#[macro_export]
+macro_rules! display_key {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl $crate::key::Key for $ty {
+                fn fmt_key(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                    ::std::fmt::Display::fmt(self, f)
+                }
+            }
+        )*
+    };
+}
+
+display_key!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, bool, char, str, String,
+    Principal
+);
+
+macro_rules! tuple_key {
+    ($first:ident $(, $other:ident)*) => {
+        impl<$first: Key, $($other: Key),*> Key for ($first, $($other),*) {
+            #[allow(non_snake_case)]
+            fn fmt_key(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                let ($first, $($other),*) = self;
+                f.write_str("(")?;
+                $first.fmt_key(f)?;
+                $(
+                    f.write_str(", ")?;
+                    $other.fmt_key(f)?;
+                )*
+                f.write_str(")")
+            }
+        }
+    };
+}
+
+tuple_key!(A, B);
+tuple_key!(A, B, C);
+tuple_key!(A, B, C, D);
+
+/// A key with a lowest value, so the composite keys that start with a prefix can be found in an ordered backend.
+pub trait LowestKey {
+    /// Returns the value that is lower than or equal to all the others.
+    fn lowest() -> Self;
+}
+
+macro_rules! lowest_integer_key {
+    ($($ty:ty),*) => {
+        $(
+            impl LowestKey for $ty {
+                fn lowest() -> Self {
+                    <$ty>::MIN
+                }
+            }
+        )*
+    };
+}
+
+lowest_integer_key!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+impl LowestKey for bool {
+    fn lowest() -> Self {
+        false
+    }
+}
+
+impl LowestKey for char {
+    fn lowest() -> Self {
+        '\0'
+    }
+}
+
+impl LowestKey for String {
+    fn lowest() -> Self {
+        String::new()
+    }
+}
+
+impl LowestKey for Principal {
+    fn lowest() -> Self {
+        Principal::from_slice(&[])
+    }
+}
+
+impl<A: LowestKey, B: LowestKey> LowestKey for (A, B) {
+    fn lowest() -> Self {
+        (A::lowest(), B::lowest())
+    }
+}
+
+impl<A: LowestKey, B: LowestKey, C: LowestKey> LowestKey for (A, B, C) {
+    fn lowest() -> Self {
+        (A::lowest(), B::lowest(), C::lowest())
+    }
+}
+
+/// A composite key whose leading components are of type `P`.
+/// In an ordered backend the keys with the same prefix are contiguous,
+/// so they can be fetched with `OrderedBackend::fetch_prefix` without a full scan.
+pub trait PrefixKey<P> {
+    /// Returns the lowest key that starts with the prefix.
+    fn first_with_prefix(prefix: &P) -> Self;
+
+    /// Returns true if the key starts with the prefix.
+    fn has_prefix(&self, prefix: &P) -> bool;
+}
+
+impl<A: Clone + Eq, B: LowestKey> PrefixKey<A> for (A, B) {
+    fn first_with_prefix(prefix: &A) -> Self {
+        (prefix.clone(), B::lowest())
+    }
+
+    fn has_prefix(&self, prefix: &A) -> bool {
+        &self.0 == prefix
+    }
+}
+
+impl<A: Clone + Eq, B: LowestKey, C: LowestKey> PrefixKey<A> for (A, B, C) {
+    fn first_with_prefix(prefix: &A) -> Self {
+        (prefix.clone(), B::lowest(), C::lowest())
+    }
+
+    fn has_prefix(&self, prefix: &A) -> bool {
+        &self.0 == prefix
+    }
+}
+
+impl<A: Clone + Eq, B: Clone + Eq, C: LowestKey> PrefixKey<(A, B)> for (A, B, C) {
+    fn first_with_prefix((a, b): &(A, B)) -> Self {
+        (a.clone(), b.clone(), C::lowest())
+    }
+
+    fn has_prefix(&self, (a, b): &(A, B)) -> bool {
+        &self.0 == a && &self.1 == b
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[derive(Clone, PartialEq, Eq, Hash)]
+    struct OrderId(u64);
+
+    impl Display for OrderId {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "order-{}", self.0)
+        }
+    }
+
+    crate::display_key!(OrderId);
+
+    #[test]
+    fn keys_should_be_formatted() {
+        // Arrange
+        let principal = Principal::from_slice(&[1, 2, 3]);
+
+        // Act
+        let formatted = [
+            42u32.to_key_string(),
+            "user".to_owned().to_key_string(),
+            principal.to_key_string(),
+            (principal, 7u64).to_key_string(),
+            ("a".to_owned(), 1i8, 'c').to_key_string(),
+            (OrderId(3), 1u8).to_key_string(),
+        ];
+
+        // Assert
+        assert_eq!(
+            [
+                "42".to_owned(),
+                "user".to_owned(),
+                principal.to_text(),
+                format!("({}, 7)", principal.to_text()),
+                "(a, 1, c)".to_owned(),
+                "(order-3, 1)".to_owned(),
+            ],
+            formatted
+        );
+    }
+
+    #[test]
+    fn first_with_prefix_should_be_the_lowest_key_with_the_prefix() {
+        // Act
+        let first: (String, u32, i64) = PrefixKey::first_with_prefix(&"user".to_owned());
+        let first_of_pair: (String, u32, i64) =
+            PrefixKey::first_with_prefix(&("user".to_owned(), 3));
+
+        // Assert
+        assert_eq!(("user".to_owned(), 0, i64::MIN), first);
+        assert_eq!(("user".to_owned(), 3, i64::MIN), first_of_pair);
+        assert!(first < ("user".to_owned(), 0, -1));
+        assert!(("use".to_owned(), u32::MAX, i64::MAX) < first);
+        assert!(Principal::lowest() <= Principal::anonymous());
+        assert!(Principal::lowest() <= Principal::management_canister());
+    }
+}
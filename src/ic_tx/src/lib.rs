@@ -1,9 +1,36 @@
-use std::rc::Rc;
+// Lets the code generated by ic_tx_derive for the tests refer to this crate as `ic_tx`
+#[cfg(test)]
+extern crate self as ic_tx;
 
 pub mod backend;
+pub mod clock;
+#[cfg(feature = "candid")]
+pub mod database;
 pub mod db;
+pub mod entity;
 pub mod error;
+pub mod idempotency;
+mod index;
+pub mod key;
+pub mod lock;
+pub mod metadata;
+pub mod metrics;
+pub mod migration;
 pub mod model;
+pub mod query;
+pub mod reference;
+pub mod remote;
+pub mod saga;
+mod shared;
+#[cfg(feature = "candid")]
+pub mod snapshot;
+#[cfg(feature = "candid")]
+pub mod stable;
+#[cfg(test)]
+mod test_utils;
+pub mod two_phase;
 pub mod tx;
+pub mod validator;
+pub mod view;
 
-pub type Ref<T> = Rc<T>;
+pub use shared::{Cell, Ref, RefCell, Shareable};
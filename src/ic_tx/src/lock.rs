@@ -0,0 +1,169 @@
+use std::{collections::HashMap, hash::Hash, time::Duration};
+
+use crate::{error::TxError, key::Key};
+
+/// Identifies the transaction that holds a lease.
+pub type LockOwner = u64;
+
+struct Lease {
+    owner: LockOwner,
+    expires_at: u64,
+}
+
+/// Keeps track of the exclusive leases taken by the transactions.
+/// A lease expires automatically, so a trapped message cannot lock a record forever.
+pub struct LockManager<IdType> {
+    leases: HashMap<IdType, Lease>,
+    next_owner: LockOwner,
+}
+
+impl<IdType: Eq + Hash + Clone + Key> LockManager<IdType> {
+    pub fn new() -> Self {
+        Self {
+            leases: HashMap::default(),
+            next_owner: 0,
+        }
+    }
+
+    pub(crate) fn next_owner(&mut self) -> LockOwner {
+        self.next_owner += 1;
+        self.next_owner
+    }
+
+    /// Takes an exclusive lease on the id.
+    /// It fails if another owner holds a lease that is not yet expired.
+    /// If the owner already holds the lease, the lease is extended.
+    pub fn acquire(
+        &mut self,
+        id: &IdType,
+        owner: LockOwner,
+        now: u64,
+        lease: Duration,
+    ) -> Result<(), TxError> {
+        self.check(id, Some(owner), now)?;
+        let expires_at = now.saturating_add(lease.as_nanos().try_into().unwrap_or(u64::MAX));
+        self.leases.insert(id.clone(), Lease { owner, expires_at });
+        Ok(())
+    }
+
+    /// Returns an error if the id is locked by an owner different from the specified one.
+    pub fn check(&self, id: &IdType, owner: Option<LockOwner>, now: u64) -> Result<(), TxError> {
+        match self.leases.get(id) {
+            Some(lease) if lease.expires_at > now && Some(lease.owner) != owner => {
+                Err(TxError::LockError {
+                    id: id.to_key_string(),
+                    message: format!(
+                        "It is locked by another transaction until [{}]",
+                        lease.expires_at
+                    ),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns true if the id is locked by a lease that is not yet expired.
+    pub fn is_locked(&self, id: &IdType, now: u64) -> bool {
+        self.check(id, None, now).is_err()
+    }
+
+    /// Releases all the leases held by the owner and drops the expired ones.
+    pub fn release_all(&mut self, owner: LockOwner, now: u64) {
+        self.leases
+            .retain(|_, lease| lease.owner != owner && lease.expires_at > now);
+    }
+}
+
+impl<IdType: Eq + Hash + Clone + Key> Default for LockManager<IdType> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn acquire_should_lock_an_id() {
+        // Arrange
+        let mut locks = LockManager::<i32>::new();
+        let owner_1 = locks.next_owner();
+        let owner_2 = locks.next_owner();
+
+        // Act
+        let result_1 = locks.acquire(&1, owner_1, 0, Duration::from_nanos(100));
+        let result_2 = locks.acquire(&1, owner_2, 10, Duration::from_nanos(100));
+        let result_3 = locks.acquire(&2, owner_2, 10, Duration::from_nanos(100));
+
+        // Assert
+        assert!(result_1.is_ok());
+        assert!(result_2.is_err());
+        assert!(result_3.is_ok());
+        assert!(locks.is_locked(&1, 10));
+        assert!(locks.check(&1, Some(owner_1), 10).is_ok());
+        assert!(locks.check(&1, Some(owner_2), 10).is_err());
+    }
+
+    #[test]
+    fn acquire_should_extend_the_lease_of_the_same_owner() {
+        // Arrange
+        let mut locks = LockManager::<i32>::new();
+        let owner = locks.next_owner();
+        locks
+            .acquire(&1, owner, 0, Duration::from_nanos(100))
+            .unwrap();
+
+        // Act
+        let result = locks.acquire(&1, owner, 50, Duration::from_nanos(100));
+
+        // Assert
+        assert!(result.is_ok());
+        assert!(locks.is_locked(&1, 120));
+        assert!(!locks.is_locked(&1, 150));
+    }
+
+    #[test]
+    fn lease_should_expire() {
+        // Arrange
+        let mut locks = LockManager::<i32>::new();
+        let owner_1 = locks.next_owner();
+        let owner_2 = locks.next_owner();
+        locks
+            .acquire(&1, owner_1, 0, Duration::from_nanos(100))
+            .unwrap();
+
+        // Act
+        let result = locks.acquire(&1, owner_2, 100, Duration::from_nanos(100));
+
+        // Assert
+        assert!(result.is_ok());
+        assert!(locks.check(&1, Some(owner_1), 150).is_err());
+    }
+
+    #[test]
+    fn release_all_should_release_the_owner_leases() {
+        // Arrange
+        let mut locks = LockManager::<i32>::new();
+        let owner_1 = locks.next_owner();
+        let owner_2 = locks.next_owner();
+        locks
+            .acquire(&1, owner_1, 0, Duration::from_nanos(100))
+            .unwrap();
+        locks
+            .acquire(&2, owner_1, 0, Duration::from_nanos(100))
+            .unwrap();
+        locks
+            .acquire(&3, owner_2, 0, Duration::from_nanos(100))
+            .unwrap();
+
+        // Act
+        locks.release_all(owner_1, 10);
+
+        // Assert
+        assert!(!locks.is_locked(&1, 10));
+        assert!(!locks.is_locked(&2, 10));
+        assert!(locks.is_locked(&3, 10));
+    }
+}
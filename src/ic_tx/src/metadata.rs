@@ -0,0 +1,54 @@
+use ic_principal::Principal;
+
+use crate::{shared::shared, Ref};
+
+/// Audit metadata of a model, maintained by the transactions when enabled with `IcTx::with_metadata`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "candid", derive(candid::CandidType))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metadata {
+    /// The creation time in nanoseconds since the epoch
+    pub created_at: u64,
+    /// The time of the last update in nanoseconds since the epoch
+    pub updated_at: u64,
+    pub created_by: Option<Principal>,
+    pub updated_by: Option<Principal>,
+}
+
+impl Metadata {
+    pub(crate) fn created(now: u64, author: Option<Principal>) -> Self {
+        Self {
+            created_at: now,
+            updated_at: now,
+            created_by: author,
+            updated_by: author,
+        }
+    }
+
+    pub(crate) fn updated(self, now: u64, author: Option<Principal>) -> Self {
+        Self {
+            updated_at: now,
+            updated_by: author,
+            ..self
+        }
+    }
+}
+
+/// Returns the principal that performs the changes.
+pub type Author = Ref<shared!(Fn() -> Option<Principal>)>;
+
+/// Returns the default author.
+/// Inside a canister it is the caller of the current message, natively it is always `None`.
+pub fn default_author() -> Author {
+    Ref::new(caller)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn caller() -> Option<Principal> {
+    Some(ic_cdk::api::caller())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn caller() -> Option<Principal> {
+    None
+}
@@ -0,0 +1,371 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Write,
+};
+
+use crate::error::TxError;
+
+/// The number of conflicting ids reported by `MetricsSnapshot::hottest_conflicts`.
+pub const HOTTEST_CONFLICTS: usize = 10;
+
+// Bounds the memory used to find the hottest conflicting ids
+const MAX_TRACKED_CONFLICTING_IDS: usize = 1000;
+
+/// The metrics of a collection at a point in time.
+/// The metrics are kept on the heap, so they restart from zero after an upgrade.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "candid", derive(candid::CandidType))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MetricsSnapshot {
+    /// The number of models stored in the collection
+    pub records: u64,
+    /// The committed transactions
+    pub commits: u64,
+    /// The transactions whose commit returned an error.
+    /// A failing `Tx::commit` panics: in a canister the trap reverts the call, this counter included,
+    /// so only the errors returned by `try_commit` and `try_commit_with_receipt` are counted
+    pub failed_commits: u64,
+    /// The transactions rolled back
+    pub rollbacks: u64,
+    /// The actions of all the committed transactions
+    pub committed_actions: u64,
+    /// The actions of the largest committed transaction
+    pub max_actions_per_tx: u64,
+    /// The number of conflicts with concurrent transactions by error variant, e.g. `UpdateOptimisticLockError`
+    pub conflicts: Vec<(String, u64)>,
+    /// The ids with the most conflicts and their number of conflicts, the hottest first
+    pub hottest_conflicts: Vec<(String, u64)>,
+}
+
+impl MetricsSnapshot {
+    /// Returns the metrics in the Prometheus text format, labelled with the name of the collection.
+    pub fn to_prometheus(&self, collection: &str) -> String {
+        to_prometheus(&[(collection, self)])
+    }
+}
+
+/// The metrics updated by the commits and the rollbacks of the transactions of a collection.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    commits: u64,
+    failed_commits: u64,
+    rollbacks: u64,
+    committed_actions: u64,
+    max_actions_per_tx: u64,
+    conflicts: BTreeMap<&'static str, u64>,
+    conflicting_ids: HashMap<String, ConflictCount>,
+    // The tracked ids in the order they are considered for eviction
+    eviction_queue: VecDeque<String>,
+}
+
+#[derive(Default)]
+struct ConflictCount {
+    count: u64,
+    // Set by each new conflict, so the id gets a second chance before it is evicted
+    referenced: bool,
+}
+
+impl Metrics {
+    pub(crate) fn record_commit(&mut self, actions: usize) {
+        let actions = actions as u64;
+        self.commits += 1;
+        self.committed_actions += actions;
+        self.max_actions_per_tx = self.max_actions_per_tx.max(actions);
+    }
+
+    pub(crate) fn record_failed_commit(&mut self, error: &TxError) {
+        self.failed_commits += 1;
+        match error {
+            TxError::Conflicts(conflicts) => {
+                for conflict in conflicts {
+                    self.record_conflict(&conflict.reason);
+                }
+            }
+            error => self.record_conflict(error),
+        }
+    }
+
+    pub(crate) fn record_rollback(&mut self) {
+        self.rollbacks += 1;
+    }
+
+    pub(crate) fn snapshot(&self, records: usize) -> MetricsSnapshot {
+        let mut hottest_conflicts: Vec<_> = self
+            .conflicting_ids
+            .iter()
+            .map(|(id, conflicts)| (id.clone(), conflicts.count))
+            .collect();
+        hottest_conflicts.sort_by(|(id, count), (other_id, other_count)| {
+            other_count.cmp(count).then_with(|| id.cmp(other_id))
+        });
+        hottest_conflicts.truncate(HOTTEST_CONFLICTS);
+        MetricsSnapshot {
+            records: records as u64,
+            commits: self.commits,
+            failed_commits: self.failed_commits,
+            rollbacks: self.rollbacks,
+            committed_actions: self.committed_actions,
+            max_actions_per_tx: self.max_actions_per_tx,
+            conflicts: self
+                .conflicts
+                .iter()
+                .map(|(variant, count)| ((*variant).to_owned(), *count))
+                .collect(),
+            hottest_conflicts,
+        }
+    }
+
+    /// Counts the error if it is caused by a concurrent transaction.
+    fn record_conflict(&mut self, error: &TxError) {
+        let (variant, id) = match error {
+            TxError::UpdateOptimisticLockError { id, .. } => ("UpdateOptimisticLockError", id),
+            TxError::DeleteOptimisticLockError { id, .. } => ("DeleteOptimisticLockError", id),
+            // The id was taken by a concurrent creation
+            TxError::SaveError { id, .. } => ("SaveError", id),
+            TxError::LockError { id, .. } => ("LockError", id),
+            _ => return,
+        };
+        *self.conflicts.entry(variant).or_default() += 1;
+        if let Some(conflicts) = self.conflicting_ids.get_mut(id) {
+            conflicts.count += 1;
+            conflicts.referenced = true;
+            return;
+        }
+        if self.conflicting_ids.len() >= MAX_TRACKED_CONFLICTING_IDS {
+            self.evict_conflicting_id();
+        }
+        self.conflicting_ids.insert(
+            id.clone(),
+            ConflictCount {
+                count: 1,
+                referenced: false,
+            },
+        );
+        self.eviction_queue.push_back(id.clone());
+    }
+
+    /// Makes room for a new id by forgetting the first queued id without a conflict since it was last queued,
+    /// in amortized constant time: the ids that had one are queued again.
+    fn evict_conflicting_id(&mut self) {
+        while let Some(id) = self.eviction_queue.pop_front() {
+            match self.conflicting_ids.get_mut(&id) {
+                Some(conflicts) if conflicts.referenced => {
+                    conflicts.referenced = false;
+                    self.eviction_queue.push_back(id);
+                }
+                _ => {
+                    self.conflicting_ids.remove(&id);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the metrics of the collections in the Prometheus text format,
+/// each one labelled with the name of its collection.
+pub fn to_prometheus(collections: &[(&str, &MetricsSnapshot)]) -> String {
+    let mut text = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+        let _ = writeln!(text, "# HELP {name} {help}");
+        let _ = writeln!(text, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(text, "{name}{{{labels}}} {value}");
+        }
+    };
+    let per_collection = |value: fn(&MetricsSnapshot) -> u64| {
+        collections
+            .iter()
+            .map(|(collection, metrics)| (collection_label(collection), value(metrics)))
+            .collect::<Vec<_>>()
+    };
+
+    family(
+        "ic_tx_records",
+        "gauge",
+        "The number of models stored in the collection.",
+        per_collection(|metrics| metrics.records),
+    );
+    family(
+        "ic_tx_commits_total",
+        "counter",
+        "The committed transactions.",
+        per_collection(|metrics| metrics.commits),
+    );
+    family(
+        "ic_tx_failed_commits_total",
+        "counter",
+        "The transactions whose commit returned an error.",
+        per_collection(|metrics| metrics.failed_commits),
+    );
+    family(
+        "ic_tx_rollbacks_total",
+        "counter",
+        "The transactions rolled back.",
+        per_collection(|metrics| metrics.rollbacks),
+    );
+    family(
+        "ic_tx_committed_actions_total",
+        "counter",
+        "The actions of all the committed transactions.",
+        per_collection(|metrics| metrics.committed_actions),
+    );
+    family(
+        "ic_tx_max_actions_per_tx",
+        "gauge",
+        "The actions of the largest committed transaction.",
+        per_collection(|metrics| metrics.max_actions_per_tx),
+    );
+    family(
+        "ic_tx_conflicts_total",
+        "counter",
+        "The conflicts with concurrent transactions by error variant.",
+        collections
+            .iter()
+            .flat_map(|(collection, metrics)| {
+                metrics.conflicts.iter().map(|(variant, count)| {
+                    let labels = format!(
+                        "{},variant=\"{}\"",
+                        collection_label(collection),
+                        escape_label(variant)
+                    );
+                    (labels, *count)
+                })
+            })
+            .collect(),
+    );
+    family(
+        "ic_tx_hottest_conflicts",
+        "gauge",
+        "The ids with the most conflicts with concurrent transactions.",
+        collections
+            .iter()
+            .flat_map(|(collection, metrics)| {
+                metrics.hottest_conflicts.iter().map(|(id, count)| {
+                    let labels = format!(
+                        "{},id=\"{}\"",
+                        collection_label(collection),
+                        escape_label(id)
+                    );
+                    (labels, *count)
+                })
+            })
+            .collect(),
+    );
+    text
+}
+
+fn collection_label(collection: &str) -> String {
+    format!("collection=\"{}\"", escape_label(collection))
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::error::Conflict;
+
+    use super::*;
+
+    fn update_conflict(id: &str) -> TxError {
+        TxError::UpdateOptimisticLockError {
+            id: id.to_owned(),
+            expected: 0,
+            found: 1,
+        }
+    }
+
+    #[test]
+    fn snapshot_should_report_the_hottest_conflicting_ids() {
+        // Arrange
+        let mut metrics = Metrics::default();
+        metrics.record_failed_commit(&update_conflict("1"));
+        metrics.record_failed_commit(&TxError::Conflicts(vec![
+            Conflict {
+                id: "1".to_owned(),
+                reason: update_conflict("1"),
+            },
+            Conflict {
+                id: "2".to_owned(),
+                reason: TxError::DeleteOptimisticLockError {
+                    id: "2".to_owned(),
+                    expected: 0,
+                    found: 1,
+                },
+            },
+        ]));
+        metrics.record_failed_commit(&TxError::ValidationError {
+            id: "3".to_owned(),
+            reason: "invalid".to_owned(),
+        });
+
+        // Act
+        let snapshot = metrics.snapshot(0);
+
+        // Assert
+        assert_eq!(3, snapshot.failed_commits);
+        assert_eq!(
+            vec![
+                ("DeleteOptimisticLockError".to_owned(), 1),
+                ("UpdateOptimisticLockError".to_owned(), 2)
+            ],
+            snapshot.conflicts
+        );
+        assert_eq!(
+            vec![("1".to_owned(), 2), ("2".to_owned(), 1)],
+            snapshot.hottest_conflicts
+        );
+    }
+
+    #[test]
+    fn tracked_conflicting_ids_should_be_bounded() {
+        // Arrange
+        let mut metrics = Metrics::default();
+        metrics.record_failed_commit(&update_conflict("hot"));
+        metrics.record_failed_commit(&update_conflict("hot"));
+
+        // Act
+        for id in 0..MAX_TRACKED_CONFLICTING_IDS {
+            metrics.record_failed_commit(&update_conflict(&id.to_string()));
+        }
+
+        // Assert
+        assert_eq!(MAX_TRACKED_CONFLICTING_IDS, metrics.conflicting_ids.len());
+        assert_eq!(MAX_TRACKED_CONFLICTING_IDS, metrics.eviction_queue.len());
+        assert_eq!(
+            Some(("hot".to_owned(), 2)),
+            metrics.snapshot(0).hottest_conflicts.into_iter().next()
+        );
+    }
+
+    #[test]
+    fn metrics_should_be_formatted_for_prometheus() {
+        // Arrange
+        let mut metrics = Metrics::default();
+        metrics.record_commit(3);
+        metrics.record_commit(1);
+        metrics.record_rollback();
+        metrics.record_failed_commit(&update_conflict("a\"b"));
+
+        // Act
+        let text = metrics.snapshot(2).to_prometheus("users");
+
+        // Assert
+        assert!(text.contains("# TYPE ic_tx_commits_total counter\n"));
+        assert!(text.contains("ic_tx_records{collection=\"users\"} 2\n"));
+        assert!(text.contains("ic_tx_commits_total{collection=\"users\"} 2\n"));
+        assert!(text.contains("ic_tx_rollbacks_total{collection=\"users\"} 1\n"));
+        assert!(text.contains("ic_tx_committed_actions_total{collection=\"users\"} 4\n"));
+        assert!(text.contains("ic_tx_max_actions_per_tx{collection=\"users\"} 3\n"));
+        assert!(text.contains(
+            "ic_tx_conflicts_total{collection=\"users\",variant=\"UpdateOptimisticLockError\"} 1\n"
+        ));
+        assert!(text.contains("ic_tx_hottest_conflicts{collection=\"users\",id=\"a\\\"b\"} 1\n"));
+    }
+}
@@ -0,0 +1,290 @@
+#[cfg(feature = "candid")]
+use candid::{de::IDLDeserialize, CandidType};
+#[cfg(feature = "candid")]
+use serde::de::DeserializeOwned;
+
+#[cfg(feature = "candid")]
+use crate::{backend::Backend, db::IcTx, Shareable};
+use crate::{error::TxError, key::Key, model::Model, shared::shared, Ref};
+
+/// The version of the schema of the data of a model.
+/// Models are written with the schema version of the `IcTx`, that is, the number of registered migrations.
+pub type SchemaVersion = u32;
+
+/// A function that upgrades the data by one schema version.
+/// It changes the values of the data, not its type: the data stored with an older type,
+/// e.g. before a field was renamed or removed, is converted when it is decoded, see `IcTx::with_legacy_data`.
+pub type Migration<Data> = Ref<shared!(Fn(Data) -> Data)>;
+
+/// Decodes the data stored with an older schema version, whose candid type differs from the one of `Data`.
+#[cfg(feature = "candid")]
+pub type LegacyDecoder<Data> =
+    Ref<shared!(for<'de> Fn(&mut IDLDeserialize<'de>) -> candid::Result<Data>)>;
+
+/// Applies, in order, the migrations needed to bring the data of the model to the latest schema version.
+/// The optimistic lock version and the metadata of the model are not changed.
+pub(crate) fn migrate<IdType: Key, Data, V>(
+    migrations: &[Migration<Data>],
+    mut model: Model<IdType, Data, V>,
+) -> Result<Model<IdType, Data, V>, TxError> {
+    let latest = migrations.len();
+    let current = model.schema_version as usize;
+    if current > latest {
+        return Err(TxError::MigrationError {
+            id: model.id.to_key_string(),
+            message: format!(
+                "The schema version [{current}] is newer than the latest known one [{latest}]."
+            ),
+        });
+    }
+    for migration in &migrations[current..] {
+        model.data = migration(model.data);
+    }
+    model.schema_version = latest as SchemaVersion;
+    Ok(model)
+}
+
+#[cfg(feature = "candid")]
+impl<Data, B: Backend<Data>> IcTx<Data, B> {
+    /// Registers the type of the data stored with the schema versions up to the specified one,
+    /// and after the one of the previous legacy type, if any.
+    /// When a snapshot or a stable memory section is restored, the data stored with those schema versions
+    /// is decoded as `Old` and converted to `Data`, and the migrations from its schema version
+    /// are then applied to the converted data as usual, when it is fetched.
+    /// It lets `Data` change its type, e.g. rename or remove a field, while the stored data keeps the old one.
+    pub fn with_legacy_data<Old: CandidType + DeserializeOwned>(
+        mut self,
+        schema_version: SchemaVersion,
+        convert: impl Fn(Old) -> Data + Shareable + 'static,
+    ) -> Self {
+        self.legacy_data.push((
+            schema_version,
+            Ref::new(move |message: &mut IDLDeserialize<'_>| {
+                message.get_value::<Old>().map(&convert)
+            }),
+        ));
+        self.legacy_data.sort_by_key(|(schema_version, _)| *schema_version);
+        self
+    }
+
+    /// Decodes the next value of the message as the data of the schema version.
+    pub(crate) fn decode_data(
+        &self,
+        schema_version: SchemaVersion,
+        message: &mut IDLDeserialize<'_>,
+    ) -> candid::Result<Data>
+    where
+        Data: CandidType + DeserializeOwned,
+    {
+        match self
+            .legacy_data
+            .iter()
+            .find(|(last_schema_version, _)| schema_version <= *last_schema_version)
+        {
+            Some((_, decode)) => decode(message),
+            None => message.get_value(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::{
+        backend::{hashmap::HashmapBackend, Backend},
+        db::IcTx,
+        model::NewModel,
+        Ref, RefCell,
+    };
+
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct User {
+        name: String,
+        tokens: u32,
+    }
+
+    fn user(name: &str, tokens: u32) -> User {
+        User {
+            name: name.to_owned(),
+            tokens,
+        }
+    }
+
+    #[test]
+    fn fetch_should_migrate_data_without_changing_the_version() {
+        // Arrange
+        let backend = Ref::new(RefCell::new(HashmapBackend::<u32, User>::new()));
+        let db_v0 = IcTx::new(backend.clone());
+        let mut tx = db_v0.tx();
+        tx.save(NewModel::new(1, user("ufoscout", 1))).unwrap();
+        tx.commit();
+        let mut tx = db_v0.tx();
+        tx.update(db_v0.fetch_one(&1).unwrap()).unwrap();
+        tx.commit();
+
+        let db_v2 = IcTx::new(backend.clone())
+            .with_migration(|user: User| User {
+                name: user.name.to_uppercase(),
+                ..user
+            })
+            .with_migration(|user: User| User {
+                tokens: user.tokens * 10,
+                ..user
+            });
+
+        // Act
+        let model = db_v2.fetch_one(&1).unwrap();
+        let tx_model = db_v2.tx().fetch_option_one(&1).unwrap().unwrap();
+
+        // Assert
+        assert_eq!(user("UFOSCOUT", 10), model.data);
+        assert_eq!(1, model.version());
+        assert_eq!(2, model.schema_version());
+        assert_eq!(model, tx_model);
+
+        // The stored data is not changed by the reads
+        assert_eq!(0, backend.borrow().fetch_one(&1).unwrap().schema_version());
+    }
+
+    #[test]
+    fn update_should_write_back_the_migrated_data() {
+        // Arrange
+        let backend = Ref::new(RefCell::new(HashmapBackend::<u32, User>::new()));
+        let mut tx = IcTx::new(backend.clone()).tx();
+        tx.save(NewModel::new(1, user("ufoscout", 1))).unwrap();
+        tx.commit();
+
+        let migrations = Ref::new(RefCell::new(0));
+        let db = {
+            let migrations = migrations.clone();
+            IcTx::new(backend.clone()).with_migration(move |user: User| {
+                *migrations.borrow_mut() += 1;
+                User {
+                    tokens: user.tokens + 1,
+                    ..user
+                }
+            })
+        };
+
+        // Act
+        let mut tx = db.tx();
+        let model = tx.fetch_one(&1).unwrap();
+        tx.update(model).unwrap();
+        tx.commit();
+        let model = db.fetch_one(&1).unwrap();
+
+        // Assert
+        assert_eq!(user("ufoscout", 2), model.data);
+        assert_eq!(1, model.version());
+        assert_eq!(1, backend.borrow().fetch_one(&1).unwrap().schema_version());
+        assert_eq!(1, *migrations.borrow());
+    }
+
+    #[test]
+    fn save_should_use_the_latest_schema_version() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<u32, User>::new())))
+            .with_migration(|user: User| User {
+                tokens: user.tokens + 1,
+                ..user
+            });
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, user("ufoscout", 1))).unwrap();
+        tx.commit();
+        let model = db.fetch_one(&1).unwrap();
+
+        // Assert
+        assert_eq!(user("ufoscout", 1), model.data);
+        assert_eq!(1, model.schema_version());
+    }
+
+    #[test]
+    fn fetch_should_fail_if_schema_version_is_unknown() {
+        // Arrange
+        let backend = Ref::new(RefCell::new(HashmapBackend::<u32, User>::new()));
+        let mut tx = IcTx::new(backend.clone())
+            .with_migration(|user: User| user)
+            .tx();
+        tx.save(NewModel::new(1, user("ufoscout", 1))).unwrap();
+        tx.commit();
+
+        // Act
+        let result = IcTx::new(backend).fetch_one(&1);
+
+        // Assert
+        assert!(matches!(result, Err(TxError::MigrationError { .. })));
+    }
+
+    #[cfg(feature = "candid")]
+    #[test]
+    fn import_should_decode_the_data_stored_with_a_legacy_type() {
+        // Arrange
+        #[derive(Clone, candid::CandidType, serde::Deserialize)]
+        struct UserV0 {
+            username: String,
+            tokens: u32,
+            active: bool,
+        }
+
+        #[derive(Clone, Debug, PartialEq, candid::CandidType, serde::Deserialize)]
+        struct UserV1 {
+            name: String,
+            tokens: u32,
+        }
+
+        let db_v0 = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<u32, UserV0>::new())));
+        let mut tx = db_v0.tx();
+        tx.save(NewModel::new(
+            1,
+            UserV0 {
+                username: "ufoscout".to_owned(),
+                tokens: 1,
+                active: true,
+            },
+        ))
+        .unwrap();
+        tx.commit();
+        let mut tx = db_v0.tx();
+        tx.update(db_v0.fetch_one(&1).unwrap()).unwrap();
+        tx.commit();
+        let snapshot = db_v0.export_snapshot().unwrap();
+
+        let new_db_v2 = || {
+            IcTx::new(Ref::new(RefCell::new(HashmapBackend::<u32, UserV1>::new())))
+                // The username is renamed and the active flag is removed
+                .with_migration(|user: UserV1| user)
+                .with_migration(|user: UserV1| UserV1 {
+                    tokens: user.tokens * 10,
+                    ..user
+                })
+        };
+        let db_v2 = new_db_v2().with_legacy_data(0, |user: UserV0| UserV1 {
+            name: user.username,
+            tokens: user.tokens,
+        });
+
+        // Act
+        let result_without_legacy_data = new_db_v2().import_snapshot(&snapshot);
+        db_v2.import_snapshot(&snapshot).unwrap();
+        let model = db_v2.fetch_one(&1).unwrap();
+
+        // Assert
+        assert!(matches!(
+            result_without_legacy_data,
+            Err(TxError::SnapshotError { .. })
+        ));
+        assert_eq!(
+            UserV1 {
+                name: "ufoscout".to_owned(),
+                tokens: 10
+            },
+            model.data
+        );
+        assert_eq!(1, model.version());
+        assert_eq!(2, model.schema_version());
+    }
+}
@@ -1,51 +1,102 @@
+use std::fmt::{Debug, Display};
+
+use crate::{metadata::Metadata, migration::SchemaVersion};
+
+/// The default type of the optimistic lock version.
 pub type VersionType = u32;
 
+/// An unsigned integer used as optimistic lock version.
+/// The default value is the version of a newly created model.
+/// Versions never wrap around: updating a model whose version cannot be incremented
+/// fails with a `TxError::VersionOverflowError`.
+pub trait Version: Copy + Eq + Ord + Default + Debug + Display + Into<u64> + 'static {
+    /// Returns the next version or `None` if it would overflow.
+    fn next(self) -> Option<Self>;
+}
+
+impl Version for u32 {
+    fn next(self) -> Option<Self> {
+        self.checked_add(1)
+    }
+}
+
+impl Version for u64 {
+    fn next(self) -> Option<Self> {
+        self.checked_add(1)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "candid", derive(candid::CandidType))]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
-pub struct Model<IdType, Data> {
+pub struct Model<IdType, Data, V = VersionType> {
     pub id: IdType,
-    pub(crate) version: VersionType,
+    pub(crate) version: V,
+    pub(crate) schema_version: SchemaVersion,
     pub data: Data,
+    pub(crate) metadata: Option<Metadata>,
 }
 
-impl<IdType, Data> Model<IdType, Data> {
-    pub(crate) fn into_new_version(self) -> Model<IdType, Data> {
-        Model {
+impl<IdType, Data, V: Version> Model<IdType, Data, V> {
+    /// Returns the model with the version incremented by one or `None` if the version would overflow.
+    pub(crate) fn into_new_version(self) -> Option<Model<IdType, Data, V>> {
+        Some(Model {
             id: self.id,
-            version: self.version + 1,
+            version: self.version.next()?,
+            schema_version: self.schema_version,
             data: self.data,
-        }
+            metadata: self.metadata,
+        })
     }
 
-    pub fn version(&self) -> VersionType {
+    pub fn version(&self) -> V {
         self.version
     }
+
+    /// Returns the schema version of the data, see `IcTx::with_migration`.
+    pub fn schema_version(&self) -> SchemaVersion {
+        self.schema_version
+    }
+
+    /// Returns the audit metadata, if enabled with `IcTx::with_metadata`.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
 }
 
-impl<IdType, Data> From<NewModel<IdType, Data>> for Model<IdType, Data> {
+impl<IdType, Data, V: Version> From<NewModel<IdType, Data>> for Model<IdType, Data, V> {
     fn from(new_model: NewModel<IdType, Data>) -> Self {
         Self {
             id: new_model.id,
-            version: 0,
+            version: V::default(),
+            schema_version: 0,
             data: new_model.data,
+            metadata: None,
         }
     }
 }
 
-impl<IdType, Data> From<(IdType, Data)> for Model<IdType, Data> {
+impl<IdType, Data, V: Version> From<(IdType, Data)> for Model<IdType, Data, V> {
     fn from((id, data): (IdType, Data)) -> Self {
         Self {
             id,
-            version: 0,
+            version: V::default(),
+            schema_version: 0,
             data,
+            metadata: None,
         }
     }
 }
 
-impl<IdType, Data> From<(IdType, VersionType, Data)> for Model<IdType, Data> {
-    fn from((id, version, data): (IdType, VersionType, Data)) -> Self {
-        Self { id, version, data }
+impl<IdType, Data, V: Version> From<(IdType, V, Data)> for Model<IdType, Data, V> {
+    fn from((id, version, data): (IdType, V, Data)) -> Self {
+        Self {
+            id,
+            version,
+            schema_version: 0,
+            data,
+            metadata: None,
+        }
     }
 }
 
@@ -78,10 +129,12 @@ mod test {
     fn model_should_impl_debug_if_data_is_debug() {
         let model = Model {
             id: 1,
-            version: 1,
+            version: 1u32,
+            schema_version: 0,
             data: SimpleData {
                 name: "test".to_owned(),
             },
+            metadata: None,
         };
 
         println!("Debug model: {:?}", model);
@@ -103,19 +156,45 @@ mod test {
     fn should_build_new_model_version() {
         let model = Model {
             id: 10,
-            version: 10,
+            version: 10u32,
+            schema_version: 0,
             data: SimpleData {
                 name: "test".to_owned(),
             },
+            metadata: None,
         };
 
-        let new_model_version = model.clone().into_new_version();
+        let new_model_version = model.clone().into_new_version().unwrap();
 
         assert_eq!(model.data, new_model_version.data);
         assert_eq!(model.id, new_model_version.id);
         assert_eq!(model.version + 1, new_model_version.version);
     }
 
+    #[test]
+    fn new_model_version_should_not_overflow() {
+        let model_u32 = Model {
+            id: 10,
+            version: u32::MAX,
+            schema_version: 0,
+            data: (),
+            metadata: None,
+        };
+        let model_u64 = Model {
+            id: 10,
+            version: u64::from(u32::MAX),
+            schema_version: 0,
+            data: (),
+            metadata: None,
+        };
+
+        assert!(model_u32.into_new_version().is_none());
+        assert_eq!(
+            Some(u64::from(u32::MAX) + 1),
+            model_u64.into_new_version().map(|model| model.version)
+        );
+    }
+
     #[derive(Clone, PartialEq, Debug)]
     struct SimpleData {
         name: String,
@@ -0,0 +1,472 @@
+use std::{
+    cmp::Ordering,
+    hash::Hash,
+    iter::Sum,
+    ops::{Bound, ControlFlow, RangeBounds},
+};
+
+use crate::{
+    backend::{Backend, BackendModel, OrderedBackend},
+    db::IcTx,
+    error::TxError,
+    key::PrefixKey,
+    migration::migrate,
+};
+
+type Predicate<'a, M> = Box<dyn Fn(&M) -> bool + 'a>;
+type Comparator<'a, M> = Box<dyn Fn(&M, &M) -> Ordering + 'a>;
+
+/// The models a query starts from.
+enum Source<IdType, M> {
+    /// A full scan of the backend.
+    All,
+    /// The ids returned by an index.
+    Ids(Vec<IdType>),
+    /// The models returned by a range of ids.
+    Models(Vec<M>),
+    Failed(TxError),
+}
+
+/// A query on the models of a database.
+///
+/// The models are read from a full scan, unless the query is served by an index with `index_eq`
+/// or `index_range`, or by a range of ids with `IcTx::query_range` or `IcTx::query_prefix`.
+/// The returned models carry their version, so they can be updated by a transaction.
+pub struct Query<'a, Data, B: Backend<Data>> {
+    db: &'a IcTx<Data, B>,
+    source: Source<B::IdType, BackendModel<Data, B>>,
+    filters: Vec<Predicate<'a, BackendModel<Data, B>>>,
+    sort: Option<Comparator<'a, BackendModel<Data, B>>>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl<'a, Data, B: Backend<Data>> Query<'a, Data, B> {
+    fn new(db: &'a IcTx<Data, B>, source: Source<B::IdType, BackendModel<Data, B>>) -> Self {
+        Self {
+            db,
+            source,
+            filters: vec![],
+            sort: None,
+            offset: 0,
+            limit: None,
+        }
+    }
+
+    /// Keeps only the models that match the predicate.
+    pub fn filter(mut self, predicate: impl Fn(&BackendModel<Data, B>) -> bool + 'a) -> Self {
+        self.filters.push(Box::new(predicate));
+        self
+    }
+
+    /// Sorts the models with the comparator.
+    pub fn sort_by(
+        mut self,
+        compare: impl Fn(&BackendModel<Data, B>, &BackendModel<Data, B>) -> Ordering + 'a,
+    ) -> Self {
+        self.sort = Some(Box::new(compare));
+        self
+    }
+
+    /// Skips the first models.
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Returns at most the specified number of models.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+impl<Data: Clone, B: Backend<Data>> Query<'_, Data, B> {
+    /// Returns the models that match the query.
+    /// Without a sort, the models are read only until the limit is reached.
+    pub fn fetch(self) -> Result<Vec<BackendModel<Data, B>>, TxError> {
+        // Without a sort, the first matching models are the returned ones
+        let wanted = match (&self.sort, self.limit) {
+            (None, Some(limit)) => Some(self.offset.saturating_add(limit)),
+            _ => None,
+        };
+        let mut result = vec![];
+        let mut collect = |model: BackendModel<Data, B>| -> Result<ControlFlow<()>, TxError> {
+            if wanted.is_some_and(|wanted| result.len() >= wanted) {
+                return Ok(ControlFlow::Break(()));
+            }
+            let model = migrate(&self.db.migrations, model)?;
+            if self.filters.iter().all(|filter| filter(&model)) {
+                result.push(model);
+            }
+            Ok(ControlFlow::Continue(()))
+        };
+        match self.source {
+            Source::All => self
+                .db
+                .backend
+                .borrow()
+                .for_each_model(&mut |model| collect(model.clone()))?,
+            Source::Ids(ids) => {
+                let backend = self.db.backend.borrow();
+                for id in &ids {
+                    if let Some(model) = backend.fetch_option_one(id)? {
+                        if collect(model)?.is_break() {
+                            break;
+                        }
+                    }
+                }
+            }
+            Source::Models(models) => {
+                for model in models {
+                    if collect(model)?.is_break() {
+                        break;
+                    }
+                }
+            }
+            Source::Failed(err) => return Err(err),
+        }
+
+        if let Some(compare) = &self.sort {
+            result.sort_by(|a, b| compare(a, b));
+        }
+        Ok(result
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    /// Returns the number of models that match the query.
+    pub fn count(self) -> Result<usize, TxError> {
+        Ok(self.fetch()?.len())
+    }
+
+    /// Returns the sum of the values extracted from the models that match the query.
+    pub fn sum<T: Sum>(self, extract: impl Fn(&BackendModel<Data, B>) -> T) -> Result<T, TxError> {
+        Ok(self.fetch()?.iter().map(extract).sum())
+    }
+
+    /// Returns the minimum of the values extracted from the models that match the query.
+    pub fn min<T: Ord>(
+        self,
+        extract: impl Fn(&BackendModel<Data, B>) -> T,
+    ) -> Result<Option<T>, TxError> {
+        Ok(self.fetch()?.iter().map(extract).min())
+    }
+
+    /// Returns the maximum of the values extracted from the models that match the query.
+    pub fn max<T: Ord>(
+        self,
+        extract: impl Fn(&BackendModel<Data, B>) -> T,
+    ) -> Result<Option<T>, TxError> {
+        Ok(self.fetch()?.iter().map(extract).max())
+    }
+}
+
+impl<Data: 'static, B: Backend<Data>> Query<'_, Data, B>
+where
+    B::IdType: Eq + Hash + 'static,
+{
+    /// Reads only the models whose key in the index is equal to the specified one.
+    pub fn index_eq<K: Ord + 'static>(self, name: &str, key: &K) -> Self {
+        self.index_range::<K>(name, (Bound::Included(key), Bound::Included(key)))
+    }
+
+    /// Reads only the models whose key in the index is in the range, ordered by key.
+    pub fn index_range<K: Ord + 'static>(mut self, name: &str, range: impl RangeBounds<K>) -> Self {
+        self.source = match self.db.indexes.lookup(name, range) {
+            Ok(ids) => Source::Ids(ids),
+            Err(err) => Source::Failed(err),
+        };
+        self
+    }
+}
+
+impl<Data, B: Backend<Data>> IcTx<Data, B> {
+    /// Starts a query on all the models.
+    pub fn query(&self) -> Query<'_, Data, B> {
+        Query::new(self, Source::All)
+    }
+}
+
+impl<Data, B: OrderedBackend<Data>> IcTx<Data, B>
+where
+    B::IdType: Ord,
+{
+    /// Starts a query on the models with an id in the range, ordered by id.
+    pub fn query_range(&self, range: impl RangeBounds<B::IdType>) -> Query<'_, Data, B> {
+        let models = self
+            .backend
+            .borrow()
+            .fetch_range((range.start_bound(), range.end_bound()));
+        match models {
+            Ok(models) => Query::new(self, Source::Models(models)),
+            Err(err) => Query::new(self, Source::Failed(err)),
+        }
+    }
+
+    /// Starts a query on the models with a composite id that starts with the prefix, ordered by id.
+    pub fn query_prefix<P>(&self, prefix: &P) -> Query<'_, Data, B>
+    where
+        B::IdType: PrefixKey<P>,
+    {
+        match self.backend.borrow().fetch_prefix(prefix) {
+            Ok(models) => Query::new(self, Source::Models(models)),
+            Err(err) => Query::new(self, Source::Failed(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use ic_principal::Principal;
+
+    use crate::{
+        backend::{btreemap::BTreeMapBackend, hashmap::HashmapBackend},
+        model::{Model, NewModel},
+        test_utils::new_db,
+        Cell, Ref, RefCell,
+    };
+
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct User {
+        username: String,
+        tokens: u32,
+    }
+
+    fn user(username: &str, tokens: u32) -> User {
+        User {
+            username: username.to_owned(),
+            tokens,
+        }
+    }
+
+    fn populated_db() -> IcTx<User, BTreeMapBackend<u32, User>> {
+        let db = new_db()
+            .with_index("username", |user: &User| user.username.clone())
+            .with_index("tokens", |user: &User| user.tokens);
+        let mut tx = db.tx();
+        for (id, username, tokens) in [
+            (1, "alice", 50),
+            (2, "bob", 10),
+            (3, "carol", 30),
+            (4, "dave", 20),
+            (5, "eve", 40),
+        ] {
+            tx.save(NewModel::new(id, user(username, tokens))).unwrap();
+        }
+        tx.commit();
+        db
+    }
+
+    fn ids(models: Vec<Model<u32, User>>) -> Vec<u32> {
+        models.into_iter().map(|model| model.id).collect()
+    }
+
+    #[test]
+    fn query_should_filter_sort_and_paginate() {
+        // Arrange
+        let db = populated_db();
+
+        // Act
+        let result = db
+            .query()
+            .filter(|model| model.data.tokens >= 20)
+            .sort_by(|a, b| b.data.tokens.cmp(&a.data.tokens))
+            .offset(1)
+            .limit(2)
+            .fetch()
+            .unwrap();
+
+        // Assert
+        assert_eq!(vec![5, 3], ids(result));
+    }
+
+    #[test]
+    fn query_without_sort_should_stop_once_the_limit_is_reached() {
+        // Arrange
+        let db = populated_db();
+        let read = Cell::new(0);
+
+        // Act
+        let result = db
+            .query()
+            .filter(|_| {
+                read.set(read.get() + 1);
+                true
+            })
+            .offset(1)
+            .limit(2)
+            .fetch()
+            .unwrap();
+
+        // Assert
+        assert_eq!(2, result.len());
+        assert_eq!(3, read.get());
+    }
+
+    #[test]
+    fn query_should_compute_aggregates() {
+        // Arrange
+        let db = populated_db();
+
+        // Act
+        let count = db.query().filter(|model| model.id > 2).count().unwrap();
+        let sum = db.query().sum(|model| model.data.tokens).unwrap();
+        let min = db.query().min(|model| model.data.tokens).unwrap();
+        let max = db
+            .query()
+            .filter(|model| model.data.tokens < 50)
+            .max(|model| model.data.username.clone())
+            .unwrap();
+        let empty_min = db
+            .query()
+            .filter(|_| false)
+            .min(|model| model.data.tokens)
+            .unwrap();
+
+        // Assert
+        assert_eq!(3, count);
+        assert_eq!(150, sum);
+        assert_eq!(Some(10), min);
+        assert_eq!(Some("eve".to_owned()), max);
+        assert_eq!(None, empty_min);
+    }
+
+    #[test]
+    fn query_should_use_the_indexes() {
+        // Arrange
+        let db = populated_db();
+        let mut tx = db.tx();
+        let mut model = db.fetch_one(&2).unwrap();
+        model.data.tokens = 35;
+        tx.update(model).unwrap();
+        tx.delete(db.fetch_one(&5).unwrap()).unwrap();
+        tx.commit();
+
+        // Act
+        let by_username = db
+            .query()
+            .index_eq("username", &"carol".to_owned())
+            .fetch()
+            .unwrap();
+        let by_tokens = db
+            .query()
+            .index_range("tokens", 20u32..=40)
+            .fetch()
+            .unwrap();
+        let missing_index = db.query().index_eq("missing", &1).fetch();
+        let wrong_key = db.query().index_eq("tokens", &"1").fetch();
+
+        // Assert
+        assert_eq!(vec![3], ids(by_username));
+        assert_eq!(vec![4, 3, 2], ids(by_tokens));
+        assert!(matches!(missing_index, Err(TxError::QueryError { .. })));
+        assert!(matches!(wrong_key, Err(TxError::QueryError { .. })));
+    }
+
+    #[test]
+    fn query_should_use_the_id_range() {
+        // Arrange
+        let db = populated_db();
+
+        // Act
+        let result = db
+            .query_range(2..5)
+            .filter(|model| model.data.tokens != 30)
+            .fetch()
+            .unwrap();
+
+        // Assert
+        assert_eq!(vec![2, 4], ids(result));
+    }
+
+    #[test]
+    fn query_should_return_nothing_if_the_range_selects_no_key() {
+        // Arrange
+        let db = populated_db();
+
+        // Act
+        let reversed_ids = db
+            .query_range((Bound::Included(4), Bound::Included(2)))
+            .fetch()
+            .unwrap();
+        let equal_excluded_ids = db
+            .query_range((Bound::Excluded(3), Bound::Excluded(3)))
+            .fetch()
+            .unwrap();
+        let reversed_keys = db
+            .query()
+            .index_range("tokens", (Bound::Included(40u32), Bound::Included(20)))
+            .fetch()
+            .unwrap();
+        let equal_excluded_keys = db
+            .query()
+            .index_range("tokens", (Bound::Excluded(30u32), Bound::Excluded(30)))
+            .fetch()
+            .unwrap();
+
+        // Assert
+        assert!(reversed_ids.is_empty());
+        assert!(equal_excluded_ids.is_empty());
+        assert!(reversed_keys.is_empty());
+        assert!(equal_excluded_keys.is_empty());
+    }
+
+    #[test]
+    fn query_should_use_the_prefix_of_the_composite_ids() {
+        // Arrange
+        let owner = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        let db = IcTx::new(Ref::new(RefCell::new(BTreeMapBackend::<
+            (Principal, u64),
+            User,
+        >::new())));
+        let mut tx = db.tx();
+        tx.save(NewModel::new((owner, 2), user("alice", 10)))
+            .unwrap();
+        tx.save(NewModel::new((other, 1), user("bob", 20))).unwrap();
+        tx.save(NewModel::new((owner, 1), user("carl", 30)))
+            .unwrap();
+        tx.commit();
+
+        // Act
+        let result = db
+            .query_prefix(&owner)
+            .filter(|model| model.data.tokens > 5)
+            .fetch()
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            vec![(owner, 1), (owner, 2)],
+            result.into_iter().map(|model| model.id).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn queried_models_should_be_updatable() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<u32, User>::new())));
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, user("alice", 10))).unwrap();
+        tx.commit();
+
+        // Act
+        let mut tx = db.tx();
+        for mut model in db.query().fetch().unwrap() {
+            model.data.tokens += 1;
+            tx.update(model).unwrap();
+        }
+        let result = tx.try_commit();
+
+        // Assert
+        assert!(result.is_ok());
+        assert_eq!(11, db.fetch_one(&1).unwrap().data.tokens);
+        assert_eq!(1, db.fetch_one(&1).unwrap().version());
+    }
+}
@@ -0,0 +1,787 @@
+use std::{any::Any, collections::HashSet};
+
+use crate::{
+    backend::{Backend, BackendModel},
+    db::IcTx,
+    error::TxError,
+    key::Key,
+    migration::migrate,
+    model::{Model, NewModel},
+    shared::shared,
+    tx::{Action, Tx, TxAction},
+    Ref, RefCell, Shareable,
+};
+
+/// Checks that the id referenced by the data of a model exists after the commit.
+/// It returns the reason of the failure if the referenced model does not exist.
+pub type Reference<Data> = Ref<shared!(Fn(&Data, &PendingIds<'_>) -> Result<(), String>)>;
+
+/// The ids saved and deleted by a transaction in its collection,
+/// so the references see the collection as it would be after the commit, e.g. for a self reference.
+pub struct PendingIds<'a> {
+    collection: usize,
+    // The transaction, whose types are known by the references of its collection
+    tx: &'a dyn Any,
+}
+
+impl<'a> PendingIds<'a> {
+    pub(crate) fn new<Data: 'static, B: Backend<Data> + 'static>(
+        backend: &Ref<RefCell<B>>,
+        tx: &'a Tx<Data, B>,
+    ) -> Self {
+        Self {
+            collection: collection_key(backend),
+            tx,
+        }
+    }
+
+    /// Returns the pending action of the transaction on the id, if the transaction changes the collection.
+    fn action<Data: 'static, B: Backend<Data> + 'static>(
+        &self,
+        backend: &Ref<RefCell<B>>,
+        id: &B::IdType,
+    ) -> Option<&'a TxAction<Data, B>> {
+        if self.collection != collection_key(backend) {
+            return None;
+        }
+        self.tx.downcast_ref::<Tx<Data, B>>()?.pending_action(id)
+    }
+
+    /// Returns whether the id exists after the commit, or `None` if the transaction does not change it.
+    fn exists<Data: 'static, B: Backend<Data> + 'static>(
+        &self,
+        backend: &Ref<RefCell<B>>,
+        id: &B::IdType,
+    ) -> Option<bool> {
+        self.action::<Data, B>(backend, id)
+            .map(|action| matches!(action, Action::Create { .. } | Action::Update { .. }))
+    }
+}
+
+/// Applies the delete policy of a reference to the models that reference the deleted ids.
+/// The pending ids are the ones of the committed transaction, that the references see as changed.
+/// The changes are added to the transaction of the referencing collection in the cascades.
+pub(crate) type InboundReference<IdType> =
+    Ref<shared!(Fn(&[IdType], &PendingIds<'_>, &mut Cascades) -> Result<(), TxError>)>;
+
+/// The changes that the deletes of a commit cascade to the referencing collections.
+///
+/// Each collection reached by the cascades has a single transaction, so a model reached through
+/// several references, or by the deletes of several collections, is changed once.
+/// All the transactions are checked by `prepare` before the commit writes anything.
+#[derive(Default)]
+pub(crate) struct Cascades {
+    collections: Vec<(usize, Box<shared!(CascadeCollection)>)>,
+}
+
+impl Cascades {
+    /// Applies the references to the ids deleted by the cascades until no more ids are deleted,
+    /// then checks the transactions of all the collections.
+    /// The pending ids are the ones of the committed transaction.
+    pub(crate) fn prepare(&mut self, pending: &PendingIds<'_>, now: u64) -> Result<(), TxError> {
+        while let Some(cascade) = self
+            .collections
+            .iter_mut()
+            .find_map(|(_, collection)| collection.next_cascade())
+        {
+            cascade(pending, self)?;
+        }
+        for (_, collection) in &self.collections {
+            collection.check(now)?;
+        }
+        Ok(())
+    }
+
+    /// Locks the ids changed in the collections with a lock manager until the cascades are written or dropped.
+    pub(crate) fn hold(&mut self) -> Result<(), TxError> {
+        for (_, collection) in &mut self.collections {
+            collection.hold()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the changes of all the collections.
+    pub(crate) fn write(&mut self, now: u64) -> Result<(), TxError> {
+        for (_, collection) in &mut self.collections {
+            collection.write(now)?;
+        }
+        Ok(())
+    }
+
+    fn tx<Data: 'static, B: Backend<Data> + 'static>(
+        &mut self,
+        db: &IcTx<Data, B>,
+    ) -> &mut CascadeTx<Data, B>
+    where
+        Tx<Data, B>: Shareable,
+        B::IdType: Shareable,
+    {
+        let key = collection_key(&db.backend);
+        let position = match self.collections.iter().position(|(other, _)| *other == key) {
+            Some(position) => position,
+            None => {
+                self.collections.push((
+                    key,
+                    Box::new(CascadeTx {
+                        tx: db.tx(),
+                        referenced_by: db.referenced_by.clone(),
+                        deleted_ids: vec![],
+                    }),
+                ));
+                self.collections.len() - 1
+            }
+        };
+        self.collections[position]
+            .1
+            .as_any_mut()
+            .downcast_mut()
+            .expect("The collections with the same backend have the same types")
+    }
+}
+
+// Identifies a collection by its backend, shared by all the clones of the database
+fn collection_key<B>(backend: &Ref<RefCell<B>>) -> usize {
+    Ref::as_ptr(backend) as usize
+}
+
+type Cascade = Box<dyn FnOnce(&PendingIds<'_>, &mut Cascades) -> Result<(), TxError>>;
+
+trait CascadeCollection {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    /// Returns the cascade of the ids deleted since the last call, if any.
+    fn next_cascade(&mut self) -> Option<Cascade>;
+
+    fn check(&self, now: u64) -> Result<(), TxError>;
+
+    fn hold(&mut self) -> Result<(), TxError>;
+
+    fn write(&mut self, now: u64) -> Result<(), TxError>;
+}
+
+/// The transaction of a collection reached by the cascades.
+struct CascadeTx<Data, B: Backend<Data>> {
+    tx: Tx<Data, B>,
+    referenced_by: Ref<RefCell<Vec<InboundReference<B::IdType>>>>,
+    // The ids deleted by the transaction whose references are not applied yet
+    deleted_ids: Vec<B::IdType>,
+}
+
+impl<Data, B: Backend<Data>> CascadeTx<Data, B> {
+    fn delete(&mut self, model: BackendModel<Data, B>) -> Result<(), TxError> {
+        self.deleted_ids.push(model.id.clone());
+        self.tx.delete(model)
+    }
+}
+
+impl<Data: 'static, B: Backend<Data> + 'static> CascadeCollection for CascadeTx<Data, B> {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn next_cascade(&mut self) -> Option<Cascade> {
+        if self.deleted_ids.is_empty() {
+            return None;
+        }
+        let deleted_ids = std::mem::take(&mut self.deleted_ids);
+        let referenced_by = self.referenced_by.clone();
+        Some(Box::new(move |pending, cascades| {
+            let references = referenced_by.borrow().clone();
+            for reference in references {
+                reference(&deleted_ids, pending, cascades)?;
+            }
+            Ok(())
+        }))
+    }
+
+    fn check(&self, now: u64) -> Result<(), TxError> {
+        self.tx.check(now)
+    }
+
+    fn hold(&mut self) -> Result<(), TxError> {
+        self.tx.hold_pending()
+    }
+
+    fn write(&mut self, now: u64) -> Result<(), TxError> {
+        self.tx.write_checked(now)
+    }
+}
+
+/// Defines what happens to the models that reference a deleted model.
+pub enum OnDelete<Data> {
+    /// The delete fails with a `TxError::ReferenceError`.
+    Restrict,
+    /// The referencing models are deleted too.
+    Cascade,
+    /// The referencing models are updated with the function, that must remove the reference.
+    SetNull(Ref<shared!(Fn(&mut Data))>),
+}
+
+impl<Data> OnDelete<Data> {
+    pub fn set_null(nullify: impl Fn(&mut Data) + Shareable + 'static) -> Self {
+        OnDelete::SetNull(Ref::new(nullify))
+    }
+}
+
+impl<Data: 'static, B: Backend<Data> + 'static> IcTx<Data, B> {
+    /// Declares that the models of this collection reference the models of the parent collection
+    /// through the id returned by `key`, if any.
+    ///
+    /// The commits fail with a `TxError::ReferenceError` if a saved or updated model references an id
+    /// that does not exist in the parent collection after the commit, e.g. deleted by the same transaction. When models of the parent collection are deleted,
+    /// the `on_delete` policy is applied to the referencing models in the same commit.
+    /// A model reached through several references is changed once, and a cascade wins over a set null.
+    /// The referencing models are found with a full scan of this collection.
+    ///
+    /// The reference uses the settings this database has when the reference is declared,
+    /// so it should be declared after the other settings.
+    pub fn with_reference<ParentData: 'static, ParentB: Backend<ParentData> + Shareable + 'static>(
+        mut self,
+        name: &str,
+        parent: &IcTx<ParentData, ParentB>,
+        key: impl Fn(&Data) -> Option<ParentB::IdType> + Shareable + 'static,
+        on_delete: OnDelete<Data>,
+    ) -> Self
+    where
+        Self: Shareable,
+        Tx<Data, B>: Shareable,
+        B::IdType: Shareable,
+    {
+        let key = Ref::new(key);
+
+        let child = self.clone();
+        let reference_name = name.to_owned();
+        let key_for_delete = key.clone();
+        let inbound: InboundReference<_> = Ref::new(move |deleted_ids, pending, cascades| {
+            let deleted_ids: HashSet<_> = deleted_ids.iter().collect();
+            let models = child.backend.borrow().fetch_all()?;
+            for model in models {
+                let mut model = migrate(&child.migrations, model)?;
+                // The committed transaction changes the model too, e.g. through a self reference
+                let data = match pending.action::<Data, B>(&child.backend, &model.id) {
+                    // The model is deleted with its parent
+                    Some(Action::Delete { .. } | Action::DeleteOption { .. }) => continue,
+                    // The model may reference another parent after the commit
+                    Some(
+                        Action::Create {
+                            model: NewModel { data, .. },
+                        }
+                        | Action::Update {
+                            model: Model { data, .. },
+                        },
+                    ) => data,
+                    None => &model.data,
+                };
+                let Some(parent_id) = key_for_delete(data) else {
+                    continue;
+                };
+                if !deleted_ids.contains(&parent_id) {
+                    continue;
+                }
+                if let OnDelete::Restrict = on_delete {
+                    return Err(TxError::ReferenceError {
+                        id: parent_id.to_key_string(),
+                        message: format!(
+                            "It is referenced by the model with id [{}] through [{reference_name}].",
+                            model.id.display()
+                        ),
+                    });
+                }
+                let cascade = cascades.tx(&child);
+                // The model is already deleted through another reference
+                if let Some(Action::Delete { .. } | Action::DeleteOption { .. }) =
+                    cascade.tx.pending_action(&model.id)
+                {
+                    continue;
+                }
+                match &on_delete {
+                    // The delete replaces the update of another reference, if any
+                    OnDelete::Cascade => cascade.delete(model)?,
+                    OnDelete::SetNull(nullify) => match cascade.tx.pending_update_mut(&model.id) {
+                        // Another reference already updates the model, so both are nullified by the same update
+                        Some(pending) => nullify(&mut pending.data),
+                        None => {
+                            nullify(&mut model.data);
+                            cascade.tx.update(model)?
+                        }
+                    },
+                    OnDelete::Restrict => (),
+                }
+            }
+            Ok(())
+        });
+        parent.referenced_by.borrow_mut().push(inbound);
+        parent.commit_lock.add_referencing(self.commit_lock.clone());
+
+        let parent_backend = parent.backend.clone();
+        let reference_name = name.to_owned();
+        self.references.push((
+            name.to_owned(),
+            Ref::new(move |data, pending: &PendingIds| {
+                let Some(parent_id) = key(data) else {
+                    return Ok(());
+                };
+                let exists = match pending.exists::<ParentData, ParentB>(&parent_backend, &parent_id) {
+                    Some(exists) => Ok(exists),
+                    None => parent_backend
+                        .borrow()
+                        .fetch_option_version(&parent_id)
+                        .map(|version| version.is_some()),
+                };
+                match exists {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err(format!(
+                        "The model with id [{}] referenced through [{reference_name}] does not exist.",
+                        parent_id.display()
+                    )),
+                    Err(err) => Err(err.to_string()),
+                }
+            }),
+        ));
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::time::Duration;
+
+    use crate::{backend::hashmap::HashmapBackend, model::NewModel, test_utils::new_db};
+
+    use super::*;
+
+    type Users = IcTx<String, HashmapBackend<u32, String>>;
+    type Orders = IcTx<Order, HashmapBackend<u32, Order>>;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Order {
+        user_id: Option<u32>,
+        amount: u32,
+    }
+
+    fn order(user_id: u32, amount: u32) -> Order {
+        Order {
+            user_id: Some(user_id),
+            amount,
+        }
+    }
+
+    fn new_dbs(on_delete: OnDelete<Order>) -> (Users, Orders) {
+        let users = new_db();
+        let orders = new_db().with_reference(
+            "order_user",
+            &users,
+            |order: &Order| order.user_id,
+            on_delete,
+        );
+
+        let mut tx = users.tx();
+        tx.save(NewModel::new(1, "alice".to_owned())).unwrap();
+        tx.save(NewModel::new(2, "bob".to_owned())).unwrap();
+        tx.commit();
+
+        let mut tx = orders.tx();
+        tx.save(NewModel::new(10, order(1, 100))).unwrap();
+        tx.save(NewModel::new(11, order(1, 200))).unwrap();
+        tx.save(NewModel::new(20, order(2, 300))).unwrap();
+        tx.commit();
+
+        (users, orders)
+    }
+
+    fn delete_user(users: &Users, id: u32) -> Result<(), TxError> {
+        let mut tx = users.tx();
+        tx.delete(users.fetch_one(&id)?)?;
+        tx.try_commit()
+    }
+
+    #[test]
+    fn commit_should_fail_if_referenced_model_does_not_exist() {
+        // Arrange
+        let (_users, orders) = new_dbs(OnDelete::Restrict);
+
+        // Act
+        let mut tx = orders.tx();
+        tx.save(NewModel::new(30, order(3, 100))).unwrap();
+        let missing_result = tx.try_commit();
+
+        let mut tx = orders.tx();
+        tx.save(NewModel::new(
+            31,
+            Order {
+                user_id: None,
+                amount: 100,
+            },
+        ))
+        .unwrap();
+        let no_reference_result = tx.try_commit();
+
+        // Assert
+        assert!(matches!(
+            missing_result,
+            Err(TxError::ReferenceError { id, .. }) if id == "30"
+        ));
+        assert!(no_reference_result.is_ok());
+        assert!(orders.fetch_option_one(&30).unwrap().is_none());
+    }
+
+    #[test]
+    fn delete_should_fail_if_restricted() {
+        // Arrange
+        let (users, orders) = new_dbs(OnDelete::Restrict);
+
+        // Act
+        let result = delete_user(&users, 1);
+
+        // Assert
+        assert!(matches!(
+            result,
+            Err(TxError::ReferenceError { id, .. }) if id == "1"
+        ));
+        assert!(users.fetch_option_one(&1).unwrap().is_some());
+        assert!(orders.fetch_option_one(&10).unwrap().is_some());
+    }
+
+    #[test]
+    fn delete_should_cascade() {
+        // Arrange
+        let (users, orders) = new_dbs(OnDelete::Cascade);
+
+        // Act
+        delete_user(&users, 1).unwrap();
+
+        // Assert
+        assert!(users.fetch_option_one(&1).unwrap().is_none());
+        assert!(orders.fetch_option_one(&10).unwrap().is_none());
+        assert!(orders.fetch_option_one(&11).unwrap().is_none());
+        assert!(orders.fetch_option_one(&20).unwrap().is_some());
+    }
+
+    #[test]
+    fn delete_should_set_null() {
+        // Arrange
+        let (users, orders) = new_dbs(OnDelete::set_null(|order: &mut Order| order.user_id = None));
+
+        // Act
+        delete_user(&users, 1).unwrap();
+
+        // Assert
+        let order = orders.fetch_one(&10).unwrap();
+        assert!(users.fetch_option_one(&1).unwrap().is_none());
+        assert_eq!(None, order.data.user_id);
+        assert_eq!(1, order.version());
+        assert_eq!(Some(2), orders.fetch_one(&20).unwrap().data.user_id);
+    }
+
+    #[test]
+    fn delete_should_fail_if_cascade_fails() {
+        // Arrange
+        let (users, orders) = new_dbs(OnDelete::Restrict);
+        let users: Users = IcTx::new(users.backend.clone());
+        let orders = IcTx::new(orders.backend.clone())
+            .with_lock_manager()
+            .with_reference(
+                "order_user_cascade",
+                &users,
+                |order: &Order| order.user_id,
+                OnDelete::Cascade,
+            );
+        let mut locking_tx = orders.tx();
+        locking_tx.lock(&11, Duration::from_secs(60)).unwrap();
+
+        // Act
+        let result = delete_user(&users, 2);
+        let locked_result = delete_user(&users, 1);
+
+        // Assert
+        assert!(result.is_ok());
+        assert!(orders.fetch_option_one(&20).unwrap().is_none());
+        assert!(matches!(locked_result, Err(TxError::LockError { .. })));
+        assert!(users.fetch_option_one(&1).unwrap().is_some());
+        assert!(orders.fetch_option_one(&10).unwrap().is_some());
+        assert!(orders.fetch_option_one(&11).unwrap().is_some());
+    }
+
+    #[test]
+    fn delete_should_cascade_through_several_collections() {
+        // Arrange
+        let (users, orders) = new_dbs(OnDelete::Cascade);
+        let items: IcTx<u32, HashmapBackend<u32, u32>> = new_db().with_reference(
+            "item_order",
+            &orders,
+            |order_id: &u32| Some(*order_id),
+            OnDelete::Cascade,
+        );
+        let mut tx = items.tx();
+        tx.save(NewModel::new(100, 10)).unwrap();
+        tx.save(NewModel::new(200, 20)).unwrap();
+        tx.commit();
+
+        // Act
+        delete_user(&users, 1).unwrap();
+
+        // Assert
+        assert!(orders.fetch_option_one(&10).unwrap().is_none());
+        assert!(items.fetch_option_one(&100).unwrap().is_none());
+        assert!(items.fetch_option_one(&200).unwrap().is_some());
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Transfer {
+        sender: Option<u32>,
+        receiver: Option<u32>,
+    }
+
+    fn new_transfers(
+        users: &Users,
+        on_delete_receiver: OnDelete<Transfer>,
+    ) -> IcTx<Transfer, HashmapBackend<u32, Transfer>> {
+        let transfers = new_db()
+            .with_reference(
+                "transfer_sender",
+                users,
+                |transfer: &Transfer| transfer.sender,
+                OnDelete::Cascade,
+            )
+            .with_reference(
+                "transfer_receiver",
+                users,
+                |transfer: &Transfer| transfer.receiver,
+                on_delete_receiver,
+            );
+        let mut tx = transfers.tx();
+        for (id, sender, receiver) in [(10, 1, 2), (11, 2, 1), (12, 2, 2)] {
+            tx.save(NewModel::new(
+                id,
+                Transfer {
+                    sender: Some(sender),
+                    receiver: Some(receiver),
+                },
+            ))
+            .unwrap();
+        }
+        tx.commit();
+        transfers
+    }
+
+    #[test]
+    fn delete_should_cascade_once_to_a_model_reached_by_two_references() {
+        // Arrange
+        let (users, _orders) = new_dbs(OnDelete::Restrict);
+        let users: Users = IcTx::new(users.backend.clone());
+        let transfers = new_transfers(&users, OnDelete::Cascade);
+
+        // Act
+        let mut tx = users.tx();
+        tx.delete(users.fetch_one(&1).unwrap()).unwrap();
+        tx.delete(users.fetch_one(&2).unwrap()).unwrap();
+        let result = tx.try_commit();
+
+        // Assert
+        assert!(result.is_ok());
+        assert!(users.fetch_option_one(&1).unwrap().is_none());
+        assert!(users.fetch_option_one(&2).unwrap().is_none());
+        for id in [10, 11, 12] {
+            assert!(transfers.fetch_option_one(&id).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn delete_should_prefer_the_cascade_to_set_null_on_the_same_model() {
+        // Arrange
+        let (users, _orders) = new_dbs(OnDelete::Restrict);
+        let users: Users = IcTx::new(users.backend.clone());
+        let transfers = new_transfers(
+            &users,
+            OnDelete::set_null(|transfer: &mut Transfer| transfer.receiver = None),
+        );
+
+        // Act
+        let result = delete_user(&users, 2);
+
+        // Assert
+        assert!(result.is_ok());
+        assert_eq!(
+            Transfer {
+                sender: Some(1),
+                receiver: None
+            },
+            transfers.fetch_one(&10).unwrap().data
+        );
+        assert!(transfers.fetch_option_one(&11).unwrap().is_none());
+        assert!(transfers.fetch_option_one(&12).unwrap().is_none());
+    }
+
+    #[test]
+    fn delete_should_cascade_through_a_self_reference() {
+        // Arrange
+        let employees: IcTx<Option<u32>, HashmapBackend<u32, Option<u32>>> = new_db();
+        let employees = employees.clone().with_reference(
+            "employee_manager",
+            &employees,
+            |manager: &Option<u32>| *manager,
+            OnDelete::Cascade,
+        );
+        // The referenced models must be stored before the models that reference them
+        for (id, manager) in [(1, None), (2, Some(1)), (3, Some(2)), (4, None)] {
+            let mut tx = employees.tx();
+            tx.save(NewModel::new(id, manager)).unwrap();
+            tx.commit();
+        }
+
+        // Act
+        let mut tx = employees.tx();
+        tx.delete(employees.fetch_one(&1).unwrap()).unwrap();
+        tx.delete(employees.fetch_one(&2).unwrap()).unwrap();
+        let result = tx.try_commit();
+
+        // Assert
+        assert!(result.is_ok());
+        for id in [1, 2, 3] {
+            assert!(employees.fetch_option_one(&id).unwrap().is_none());
+        }
+        assert!(employees.fetch_option_one(&4).unwrap().is_some());
+    }
+
+    #[test]
+    fn delete_should_not_be_restricted_by_the_models_deleted_in_the_same_commit() {
+        // Arrange
+        let employees: IcTx<Option<u32>, HashmapBackend<u32, Option<u32>>> = new_db();
+        let employees = employees.clone().with_reference(
+            "employee_manager",
+            &employees,
+            |manager: &Option<u32>| *manager,
+            OnDelete::Restrict,
+        );
+        for (id, manager) in [(1, None), (2, Some(1)), (3, Some(2))] {
+            let mut tx = employees.tx();
+            tx.save(NewModel::new(id, manager)).unwrap();
+            tx.commit();
+        }
+
+        // Act
+        let mut tx = employees.tx();
+        tx.delete(employees.fetch_one(&1).unwrap()).unwrap();
+        tx.delete(employees.fetch_one(&2).unwrap()).unwrap();
+        let restricted_result = tx.try_commit();
+
+        let mut tx = employees.tx();
+        for id in [1, 2, 3] {
+            tx.delete(employees.fetch_one(&id).unwrap()).unwrap();
+        }
+        let result = tx.try_commit();
+
+        // Assert
+        assert!(matches!(
+            restricted_result,
+            Err(TxError::ReferenceError { id, .. }) if id == "2"
+        ));
+        assert!(result.is_ok());
+        for id in [1, 2, 3] {
+            assert!(employees.fetch_option_one(&id).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn commit_should_check_the_references_against_the_pending_changes() {
+        // Arrange
+        let employees: IcTx<Option<u32>, HashmapBackend<u32, Option<u32>>> = new_db();
+        let employees = employees.clone().with_reference(
+            "employee_manager",
+            &employees,
+            |manager: &Option<u32>| *manager,
+            OnDelete::Cascade,
+        );
+        let mut tx = employees.tx();
+        tx.save(NewModel::new(1, None)).unwrap();
+        tx.commit();
+
+        // Act
+        let mut tx = employees.tx();
+        tx.delete(employees.fetch_one(&1).unwrap()).unwrap();
+        tx.save(NewModel::new(2, Some(1))).unwrap();
+        let deleted_parent_result = tx.try_commit();
+
+        let mut tx = employees.tx();
+        tx.save(NewModel::new(3, None)).unwrap();
+        tx.save(NewModel::new(4, Some(3))).unwrap();
+        let saved_parent_result = tx.try_commit();
+
+        // Assert
+        assert!(matches!(
+            deleted_parent_result,
+            Err(TxError::ReferenceError { id, .. }) if id == "2"
+        ));
+        assert!(employees.fetch_option_one(&1).unwrap().is_some());
+        assert!(employees.fetch_option_one(&2).unwrap().is_none());
+        assert!(saved_parent_result.is_ok());
+        assert_eq!(Some(3), employees.fetch_one(&4).unwrap().data);
+    }
+
+    #[test]
+    fn delete_should_not_be_restricted_by_the_models_pointed_to_another_parent_in_the_same_commit()
+    {
+        // Arrange
+        let employees: IcTx<Option<u32>, HashmapBackend<u32, Option<u32>>> = new_db();
+        let employees = employees.clone().with_reference(
+            "employee_manager",
+            &employees,
+            |manager: &Option<u32>| *manager,
+            OnDelete::Restrict,
+        );
+        for (id, manager) in [(1, None), (2, Some(1)), (3, None)] {
+            let mut tx = employees.tx();
+            tx.save(NewModel::new(id, manager)).unwrap();
+            tx.commit();
+        }
+
+        // Act
+        let mut tx = employees.tx();
+        let mut model = employees.fetch_one(&2).unwrap();
+        model.data = Some(3);
+        tx.update(model).unwrap();
+        tx.delete(employees.fetch_one(&1).unwrap()).unwrap();
+        let result = tx.try_commit();
+
+        // Assert
+        assert!(result.is_ok());
+        assert!(employees.fetch_option_one(&1).unwrap().is_none());
+        assert_eq!(Some(3), employees.fetch_one(&2).unwrap().data);
+    }
+
+    #[test]
+    fn commit_should_not_confuse_the_ids_formatted_the_same_way() {
+        // Arrange
+        type Id = (String, String);
+        let id = |first: &str, second: &str| (first.to_owned(), second.to_owned());
+        let employees: IcTx<Option<Id>, HashmapBackend<Id, Option<Id>>> = new_db();
+        let employees = employees.clone().with_reference(
+            "employee_manager",
+            &employees,
+            |manager: &Option<Id>| manager.clone(),
+            OnDelete::Cascade,
+        );
+        let mut tx = employees.tx();
+        tx.save(NewModel::new(id("a", "b, c"), None)).unwrap();
+        tx.commit();
+
+        // Act
+        // Both the deleted parent and the saved model are formatted as [(a, b, c)]
+        let mut tx = employees.tx();
+        tx.delete(employees.fetch_one(&id("a", "b, c")).unwrap())
+            .unwrap();
+        tx.save(NewModel::new(id("a, b", "c"), None)).unwrap();
+        tx.save(NewModel::new(id("d", "e"), Some(id("a", "b, c"))))
+            .unwrap();
+        let result = tx.try_commit();
+
+        // Assert
+        assert!(matches!(result, Err(TxError::ReferenceError { .. })));
+        assert!(employees
+            .fetch_option_one(&id("a", "b, c"))
+            .unwrap()
+            .is_some());
+        assert!(employees.fetch_option_one(&id("d", "e")).unwrap().is_none());
+    }
+}
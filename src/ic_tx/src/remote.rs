@@ -0,0 +1,395 @@
+use std::{collections::HashMap, hash::Hash, marker::PhantomData};
+
+use crate::{
+    backend::Backend,
+    db::IcTx,
+    error::TxError,
+    key::Key,
+    model::{Model, NewModel, Version},
+    tx::{push_action, Action, COMMIT_PANIC_MESSAGE},
+    Ref,
+};
+
+/// The model type stored by an asynchronous backend.
+pub type AsyncBackendModel<Data, B> =
+    Model<<B as AsyncBackend<Data>>::IdType, Data, <B as AsyncBackend<Data>>::VersionType>;
+
+/// The action type sent to an asynchronous backend.
+pub type AsyncBackendAction<Data, B> =
+    Action<<B as AsyncBackend<Data>>::IdType, Data, <B as AsyncBackend<Data>>::VersionType>;
+
+/// The asynchronous counterpart of `Backend`, for models stored outside of the canister.
+/// The backend receives all the actions of a transaction at once and must validate and apply them atomically.
+// The futures of the IC are not Send, so the trait does not require it
+#[allow(async_fn_in_trait)]
+pub trait AsyncBackend<Data> {
+    type IdType: Key + Clone + Eq + Hash;
+    type VersionType: Version;
+
+    /// Fetches a model.
+    /// Returns an error if no model is found with the specified id.
+    async fn fetch_one(&self, id: &Self::IdType) -> Result<AsyncBackendModel<Data, Self>, TxError> {
+        self.fetch_option_one(id)
+            .await?
+            .ok_or_else(|| TxError::FetchNotFoundError {
+                id: id.to_key_string(),
+            })
+    }
+
+    /// Fetches a model.
+    async fn fetch_option_one(
+        &self,
+        id: &Self::IdType,
+    ) -> Result<Option<AsyncBackendModel<Data, Self>>, TxError>;
+
+    /// Validates and applies the actions of a transaction atomically.
+    async fn commit(&self, actions: Vec<AsyncBackendAction<Data, Self>>) -> Result<(), TxError>;
+}
+
+impl<Data, B: Backend<Data>> IcTx<Data, B> {
+    /// Validates and applies the actions in a single transaction.
+    /// This is what a storage canister executes when a remote transaction commits.
+    /// The actions come from another canister, so the caller must be authorized before calling it;
+    /// the metadata sent with the updated models is ignored.
+    pub fn commit_actions(
+        &self,
+        actions: Vec<Action<B::IdType, Data, B::VersionType>>,
+    ) -> Result<(), TxError>
+    where
+        Data: 'static,
+        B: 'static,
+    {
+        let mut tx = self.tx();
+        for action in actions {
+            tx.push(action)?;
+        }
+        tx.try_commit()
+    }
+}
+
+/// Exposes a local database as an asynchronous backend.
+impl<Data: 'static, B: Backend<Data> + 'static> AsyncBackend<Data> for IcTx<Data, B> {
+    type IdType = B::IdType;
+    type VersionType = B::VersionType;
+
+    async fn fetch_option_one(
+        &self,
+        id: &Self::IdType,
+    ) -> Result<Option<AsyncBackendModel<Data, Self>>, TxError> {
+        IcTx::fetch_option_one(self, id)
+    }
+
+    async fn commit(&self, actions: Vec<AsyncBackendAction<Data, Self>>) -> Result<(), TxError> {
+        self.commit_actions(actions)
+    }
+}
+
+/// A database whose models are stored by an asynchronous backend, e.g. a storage canister.
+pub struct AsyncIcTx<Data, B: AsyncBackend<Data>> {
+    backend: Ref<B>,
+    phantom_data: PhantomData<Data>,
+}
+
+impl<Data, B: AsyncBackend<Data>> Clone for AsyncIcTx<Data, B> {
+    fn clone(&self) -> Self {
+        Self {
+            backend: self.backend.clone(),
+            phantom_data: PhantomData,
+        }
+    }
+}
+
+impl<Data, B: AsyncBackend<Data>> AsyncIcTx<Data, B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend: Ref::new(backend),
+            phantom_data: PhantomData,
+        }
+    }
+
+    /// Starts a new atomic transaction
+    pub fn tx(&self) -> AsyncTx<Data, B> {
+        AsyncTx {
+            actions: vec![],
+            positions: HashMap::default(),
+            db: self.clone(),
+        }
+    }
+
+    /// Fetches a model from the database.
+    /// Returns an error if no model is found with the specified id.
+    pub async fn fetch_one(&self, id: &B::IdType) -> Result<AsyncBackendModel<Data, B>, TxError> {
+        self.backend.fetch_one(id).await
+    }
+
+    /// Fetches a model from the database.
+    pub async fn fetch_option_one(
+        &self,
+        id: &B::IdType,
+    ) -> Result<Option<AsyncBackendModel<Data, B>>, TxError> {
+        self.backend.fetch_option_one(id).await
+    }
+}
+
+/// A transaction on an asynchronous backend.
+/// The actions are sent to the backend by the commit, that validates and applies them atomically.
+pub struct AsyncTx<Data, B: AsyncBackend<Data>> {
+    // A slot is emptied when its actions cancel each other out
+    actions: Vec<Option<AsyncBackendAction<Data, B>>>,
+    positions: HashMap<B::IdType, usize>,
+    db: AsyncIcTx<Data, B>,
+}
+
+impl<Data, B: AsyncBackend<Data>> AsyncTx<Data, B> {
+    /// Fetches a model from the database.
+    /// Returns an error if no model is found with the specified id.
+    pub async fn fetch_one(&self, id: &B::IdType) -> Result<AsyncBackendModel<Data, B>, TxError> {
+        self.db.fetch_one(id).await
+    }
+
+    /// Fetches a model from the database.
+    pub async fn fetch_option_one(
+        &self,
+        id: &B::IdType,
+    ) -> Result<Option<AsyncBackendModel<Data, B>>, TxError> {
+        self.db.fetch_option_one(id).await
+    }
+
+    /// Updates a model of the database.
+    /// The transaction will fail if the model does not exist or if the model version does not match.
+    pub fn update(&mut self, model: AsyncBackendModel<Data, B>) -> Result<(), TxError> {
+        self.push(Action::Update { model })
+    }
+
+    /// Deletes a model from the database.
+    /// The transaction will fail if the model does not exist or if the model version does not match.
+    pub fn delete(&mut self, model: AsyncBackendModel<Data, B>) -> Result<(), TxError> {
+        self.push(Action::Delete {
+            id: model.id,
+            version: model.version,
+        })
+    }
+
+    /// Deletes a model from the database.
+    /// The transaction will fail if the model version does not match but it will succeed if the model does not exist.
+    pub fn delete_option(&mut self, model: AsyncBackendModel<Data, B>) -> Result<(), TxError> {
+        self.push(Action::DeleteOption {
+            id: model.id,
+            version: model.version,
+        })
+    }
+
+    /// Creates a new model in the database.
+    /// The transaction will fail if a model with the same ID already exists.
+    pub fn save(&mut self, model: NewModel<B::IdType, Data>) -> Result<(), TxError> {
+        self.push(Action::Create { model })
+    }
+
+    fn push(&mut self, action: AsyncBackendAction<Data, B>) -> Result<(), TxError> {
+        // The stored models cannot be read synchronously, so a saved model cannot be deleted
+        push_action(&mut self.actions, &mut self.positions, action, |_| Ok(None))
+    }
+
+    /// Commits the transaction. Panics if any error
+    pub async fn commit(self) {
+        self.try_commit().await.expect(COMMIT_PANIC_MESSAGE)
+    }
+
+    /// Commits the transaction. Returns an error and persists nothing if any check fails.
+    pub async fn try_commit(self) -> Result<(), TxError> {
+        let actions: Vec<_> = self.actions.into_iter().flatten().collect();
+        if actions.is_empty() {
+            return Ok(());
+        }
+        self.db.backend.commit(actions).await
+    }
+
+    pub fn rollback(self) {}
+}
+
+#[cfg(feature = "candid")]
+pub use canister::*;
+
+#[cfg(feature = "candid")]
+mod canister {
+
+    use candid::{CandidType, Principal};
+    use serde::de::DeserializeOwned;
+
+    use super::*;
+    use crate::model::VersionType;
+
+    /// The method of the storage canister that fetches a model.
+    /// It takes the id and returns a `Result<Option<Model>, TxError>`.
+    pub const FETCH_OPTION_ONE_METHOD: &str = "fetch_option_one";
+    /// The method of the storage canister that commits the actions of a transaction
+    /// with `IcTx::commit_actions`. It takes a `Vec<Action>` and returns a `Result<(), TxError>`.
+    pub const COMMIT_METHOD: &str = "commit";
+
+    type FetchResult<IdType, Data, V> = Result<Option<Model<IdType, Data, V>>, TxError>;
+
+    /// An asynchronous backend that stores the models in a storage canister.
+    pub struct RemoteBackend<IdType, Data, V = VersionType> {
+        canister: Principal,
+        phantom_data: PhantomData<(IdType, Data, V)>,
+    }
+
+    impl<IdType, Data, V> RemoteBackend<IdType, Data, V> {
+        pub fn new(canister: Principal) -> Self {
+            Self {
+                canister,
+                phantom_data: PhantomData,
+            }
+        }
+
+        pub fn canister(&self) -> Principal {
+            self.canister
+        }
+    }
+
+    impl<IdType, Data, V> AsyncBackend<Data> for RemoteBackend<IdType, Data, V>
+    where
+        IdType: Key + Clone + Eq + Hash + CandidType + DeserializeOwned,
+        Data: CandidType + DeserializeOwned,
+        V: Version + CandidType + DeserializeOwned,
+    {
+        type IdType = IdType;
+        type VersionType = V;
+
+        async fn fetch_option_one(
+            &self,
+            id: &Self::IdType,
+        ) -> Result<Option<AsyncBackendModel<Data, Self>>, TxError> {
+            let (result,): (FetchResult<IdType, Data, V>,) =
+                ic_cdk::call(self.canister, FETCH_OPTION_ONE_METHOD, (id,))
+                    .await
+                    .map_err(|(code, message)| TxError::RemoteError {
+                        message: format!("The fetch failed with code [{code:?}]. {message}"),
+                    })?;
+            result
+        }
+
+        async fn commit(
+            &self,
+            actions: Vec<AsyncBackendAction<Data, Self>>,
+        ) -> Result<(), TxError> {
+            let (result,): (Result<(), TxError>,) =
+                ic_cdk::call(self.canister, COMMIT_METHOD, (actions,))
+                    .await
+                    .map_err(|(code, message)| TxError::RemoteError {
+                        message: format!("The commit failed with code [{code:?}]. {message}"),
+                    })?;
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use crate::{backend::hashmap::HashmapBackend, test_utils::new_db};
+
+    use super::*;
+
+    /// Runs a future that never waits, as the ones of a local backend.
+    fn run<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => unreachable!("The future should be ready"),
+        }
+    }
+
+    type Storage = IcTx<i32, HashmapBackend<i32, i32>>;
+
+    fn new_dbs() -> (Storage, AsyncIcTx<i32, Storage>) {
+        let storage = new_db();
+        (storage.clone(), AsyncIcTx::new(storage))
+    }
+
+    #[test]
+    fn async_tx_should_commit_through_the_backend() {
+        // Arrange
+        let (storage, db) = new_dbs();
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, 100)).unwrap();
+        tx.save(NewModel::new(2, 200)).unwrap();
+        run(tx.commit());
+
+        let mut tx = db.tx();
+        let mut model = run(tx.fetch_one(&1)).unwrap();
+        model.data = 111;
+        tx.update(model).unwrap();
+        tx.delete(run(tx.fetch_one(&2)).unwrap()).unwrap();
+        let result = run(tx.try_commit());
+
+        // Assert
+        assert!(result.is_ok());
+        assert_eq!(111, storage.fetch_one(&1).unwrap().data);
+        assert_eq!(1, run(db.fetch_one(&1)).unwrap().version());
+        assert!(run(db.fetch_option_one(&2)).unwrap().is_none());
+    }
+
+    #[test]
+    fn async_tx_should_fail_atomically() {
+        // Arrange
+        let (storage, db) = new_dbs();
+        let mut tx = storage.tx();
+        tx.save(NewModel::new(1, 100)).unwrap();
+        tx.commit();
+        let stale_model = storage.fetch_one(&1).unwrap();
+        let mut tx = storage.tx();
+        tx.update(storage.fetch_one(&1).unwrap()).unwrap();
+        tx.commit();
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel::new(2, 200)).unwrap();
+        tx.update(stale_model).unwrap();
+        let result = run(tx.try_commit());
+
+        // Assert
+        assert_eq!(
+            Err(TxError::UpdateOptimisticLockError {
+                id: "1".to_owned(),
+                expected: 0,
+                found: 1
+            }),
+            result
+        );
+        assert!(storage.fetch_option_one(&2).unwrap().is_none());
+    }
+
+    #[test]
+    fn async_tx_should_coalesce_the_actions() {
+        // Arrange
+        let (storage, db) = new_dbs();
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, 100)).unwrap();
+        tx.update(Model::from((1, 111))).unwrap();
+        let conflict = tx.save(NewModel::new(1, 100));
+        tx.save(NewModel::new(2, 200)).unwrap();
+        let delete_saved = tx.delete(Model::from((2, 200)));
+        run(tx.commit());
+
+        // Assert
+        assert!(matches!(
+            conflict,
+            Err(TxError::ConflictingActionsError { .. })
+        ));
+        assert!(matches!(
+            delete_saved,
+            Err(TxError::ConflictingActionsError { .. })
+        ));
+        assert_eq!(111, storage.fetch_one(&1).unwrap().data);
+    }
+}
@@ -0,0 +1,536 @@
+use std::{collections::HashMap, future::Future, time::Duration};
+
+use crate::{
+    backend::Backend,
+    db::IcTx,
+    error::TxError,
+    model::{Model, NewModel},
+    shared::shared,
+    two_phase::BoxFuture,
+    Cell, Ref, Shareable,
+};
+
+/// Identifies a running saga in the store.
+pub type SagaId = String;
+
+type StepFn<Ctx> = Ref<shared!(Fn(Ctx) -> BoxFuture<'static, Result<Ctx, TxError>>)>;
+
+/// The progress of a saga.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "candid", derive(candid::CandidType))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SagaStatus {
+    /// The steps are being executed
+    Running,
+    /// A step failed and the compensations of the previous steps are being executed
+    Compensating,
+    /// All the steps succeeded
+    Completed,
+    /// A step failed and all the previous steps were compensated
+    Compensated,
+}
+
+/// The persisted state of a saga.
+/// It is saved in the store after every step, so the saga can be resumed after a trap or an upgrade.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "candid", derive(candid::CandidType))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SagaRecord<Ctx> {
+    /// The name of the saga definition
+    pub name: String,
+    pub status: SagaStatus,
+    /// The number of steps completed and not yet compensated
+    pub step: u32,
+    /// The data passed from one step to the next
+    pub context: Ctx,
+    /// The error of the step that failed
+    pub error: Option<TxError>,
+    /// The time the saga completed or was compensated, see `SagaOrchestrator::purge_finished`
+    pub finished_at: Option<u64>,
+}
+
+struct Step<Ctx> {
+    name: String,
+    action: StepFn<Ctx>,
+    compensation: StepFn<Ctx>,
+}
+
+/// The definition of a saga: a sequence of steps, each with a compensation that undoes it.
+///
+/// Steps and compensations can be executed again when a saga is resumed after a trap,
+/// so they must be idempotent.
+pub struct Saga<Ctx> {
+    name: String,
+    steps: Vec<Step<Ctx>>,
+}
+
+impl<Ctx: 'static> Saga<Ctx> {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            steps: vec![],
+        }
+    }
+
+    /// Adds a step that commits a local transaction.
+    pub fn with_local_step(
+        self,
+        name: &str,
+        action: impl Fn(&mut Ctx) -> Result<(), TxError> + Shareable + 'static,
+        compensation: impl Fn(&mut Ctx) -> Result<(), TxError> + Shareable + 'static,
+    ) -> Self {
+        self.with_remote_step(name, local(action), local(compensation))
+    }
+
+    /// Adds a step that calls another canister.
+    pub fn with_remote_step<A, C>(
+        mut self,
+        name: &str,
+        action: impl Fn(Ctx) -> A + Shareable + 'static,
+        compensation: impl Fn(Ctx) -> C + Shareable + 'static,
+    ) -> Self
+    where
+        A: Future<Output = Result<Ctx, TxError>> + 'static,
+        C: Future<Output = Result<Ctx, TxError>> + 'static,
+    {
+        self.steps.push(Step {
+            name: name.to_owned(),
+            action: Ref::new(move |context| Box::pin(action(context))),
+            compensation: Ref::new(move |context| Box::pin(compensation(context))),
+        });
+        self
+    }
+}
+
+fn local<Ctx: 'static>(
+    f: impl Fn(&mut Ctx) -> Result<(), TxError> + Shareable + 'static,
+) -> impl Fn(Ctx) -> BoxFuture<'static, Result<Ctx, TxError>> + Shareable {
+    move |mut context| {
+        let result = f(&mut context).map(|_| context);
+        Box::pin(async move { result })
+    }
+}
+
+/// Executes the sagas and persists their state in a store.
+pub struct SagaOrchestrator<Ctx, B: Backend<SagaRecord<Ctx>, IdType = SagaId>> {
+    store: IcTx<SagaRecord<Ctx>, B>,
+    sagas: HashMap<String, Ref<Saga<Ctx>>>,
+    next_id: Ref<Cell<u64>>,
+}
+
+impl<Ctx, B: Backend<SagaRecord<Ctx>, IdType = SagaId>> Clone for SagaOrchestrator<Ctx, B> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            sagas: self.sagas.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+impl<Ctx: Clone + 'static, B: Backend<SagaRecord<Ctx>, IdType = SagaId> + 'static>
+    SagaOrchestrator<Ctx, B>
+{
+    pub fn new(store: IcTx<SagaRecord<Ctx>, B>) -> Self {
+        Self {
+            store,
+            sagas: HashMap::default(),
+            next_id: Default::default(),
+        }
+    }
+
+    /// Registers a saga definition.
+    pub fn with_saga(mut self, saga: Saga<Ctx>) -> Self {
+        self.sagas.insert(saga.name.clone(), Ref::new(saga));
+        self
+    }
+
+    /// Returns the store of the saga records.
+    pub fn store(&self) -> &IcTx<SagaRecord<Ctx>, B> {
+        &self.store
+    }
+
+    /// Creates and runs a saga.
+    /// Returns the context produced by the last step, or the error of the failed step once it is compensated.
+    pub async fn start(&self, name: &str, context: Ctx) -> Result<Ctx, TxError> {
+        let id = self.create(name, context)?;
+        self.run(&id).await
+    }
+
+    /// Persists a new saga without running it.
+    pub fn create(&self, name: &str, context: Ctx) -> Result<SagaId, TxError> {
+        self.saga(name, name)?;
+        let next_id = self.next_id.get() + 1;
+        self.next_id.set(next_id);
+        // The time keeps the ids unique when the counter restarts after an upgrade
+        let id = format!("{name}-{}-{next_id}", (self.store.clock)());
+
+        let mut tx = self.store.tx();
+        tx.save(NewModel::new(
+            id.clone(),
+            SagaRecord {
+                name: name.to_owned(),
+                status: SagaStatus::Running,
+                step: 0,
+                context,
+                error: None,
+                finished_at: None,
+            },
+        ))?;
+        tx.try_commit()?;
+        Ok(id)
+    }
+
+    /// Runs the saga from its persisted state until it completes or it is compensated.
+    /// Returns an error, leaving the saga to be resumed later, if a compensation fails.
+    pub async fn run(&self, id: &str) -> Result<Ctx, TxError> {
+        let id = id.to_owned();
+        loop {
+            let mut record = self.store.fetch_one(&id)?;
+            let saga = self.saga(&id, &record.data.name)?;
+            let step = record.data.step as usize;
+            match record.data.status {
+                SagaStatus::Completed => return Ok(record.data.context),
+                SagaStatus::Compensated => {
+                    return Err(record
+                        .data
+                        .error
+                        .unwrap_or_else(|| saga_error(&id, "It was compensated.".to_owned())))
+                }
+                SagaStatus::Running if step >= saga.steps.len() => {
+                    record.data.status = SagaStatus::Completed;
+                    record.data.finished_at = Some((self.store.clock)());
+                }
+                SagaStatus::Running => {
+                    match (saga.steps[step].action)(record.data.context.clone()).await {
+                        Ok(context) => {
+                            record.data.context = context;
+                            record.data.step += 1;
+                        }
+                        Err(err) => {
+                            record.data.status = SagaStatus::Compensating;
+                            record.data.error = Some(err);
+                        }
+                    }
+                }
+                SagaStatus::Compensating if step == 0 => {
+                    record.data.status = SagaStatus::Compensated;
+                    record.data.finished_at = Some((self.store.clock)());
+                }
+                SagaStatus::Compensating => {
+                    let compensated = &saga.steps[step - 1];
+                    let context = (compensated.compensation)(record.data.context.clone())
+                        .await
+                        .map_err(|err| {
+                            saga_error(
+                                &id,
+                                format!(
+                                    "The compensation of the step [{}] failed. {err}",
+                                    compensated.name
+                                ),
+                            )
+                        })?;
+                    record.data.context = context;
+                    record.data.step -= 1;
+                }
+            }
+            // The version check stops a saga that was advanced concurrently by another call
+            self.save(record)?;
+        }
+    }
+
+    /// Resumes the sagas that are running or compensating, e.g. after a trap or an upgrade.
+    /// Returns the first error of the sagas that could not be completed or compensated.
+    pub async fn recover(&self) -> Result<(), TxError> {
+        let ids: Vec<_> = self
+            .store
+            .query()
+            .filter(|record| {
+                matches!(
+                    record.data.status,
+                    SagaStatus::Running | SagaStatus::Compensating
+                )
+            })
+            .fetch()?
+            .into_iter()
+            .map(|record| record.id)
+            .collect();
+
+        let mut result = Ok(());
+        for id in ids {
+            let outcome = self.run(&id).await;
+            let finished = matches!(
+                self.store.fetch_one(&id).map(|record| record.data.status),
+                Ok(SagaStatus::Completed | SagaStatus::Compensated)
+            );
+            if let (false, Err(err), Ok(())) = (finished, outcome, &result) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    /// Deletes the records of the sagas that completed or were compensated at least `older_than` ago,
+    /// and returns their number. The records of the running and compensating sagas are kept.
+    /// The finished records are kept until they are purged, so their outcome can still be read.
+    pub fn purge_finished(&self, older_than: Duration) -> Result<usize, TxError> {
+        let now = (self.store.clock)();
+        let finished_before =
+            now.saturating_sub(older_than.as_nanos().try_into().unwrap_or(u64::MAX));
+        let finished = self
+            .store
+            .query()
+            .filter(|record| matches!(record.data.finished_at, Some(at) if at <= finished_before))
+            .fetch()?;
+        let purged = finished.len();
+        let mut tx = self.store.tx();
+        for record in finished {
+            tx.delete(record)?;
+        }
+        tx.try_commit()?;
+        Ok(purged)
+    }
+
+    fn saga(&self, id: &str, name: &str) -> Result<Ref<Saga<Ctx>>, TxError> {
+        self.sagas
+            .get(name)
+            .cloned()
+            .ok_or_else(|| saga_error(id, format!("The saga [{name}] is not registered.")))
+    }
+
+    fn save(&self, record: Model<SagaId, SagaRecord<Ctx>, B::VersionType>) -> Result<(), TxError> {
+        let mut tx = self.store.tx();
+        tx.update(record)?;
+        tx.try_commit()
+    }
+}
+
+fn saga_error(id: &str, message: String) -> TxError {
+    TxError::SagaError {
+        id: id.to_owned(),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::task::{Context, Poll, Waker};
+
+    use crate::{backend::hashmap::HashmapBackend, Cell, Ref, RefCell};
+
+    use super::*;
+
+    /// Runs a future that never waits, as the ones of these steps.
+    fn run<F: Future>(future: F) -> F::Output {
+        match std::pin::pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => unreachable!("The future should be ready"),
+        }
+    }
+
+    type Log = Ref<RefCell<Vec<String>>>;
+
+    fn new_orchestrator(
+        fail_at: usize,
+        log: &Log,
+    ) -> SagaOrchestrator<u32, HashmapBackend<SagaId, SagaRecord<u32>>> {
+        let mut saga = Saga::new("transfer");
+        for index in 0..3 {
+            let (action_log, compensation_log) = (log.clone(), log.clone());
+            saga = saga.with_remote_step(
+                &format!("step_{index}"),
+                move |context: u32| {
+                    let result = match index == fail_at {
+                        true => Err(TxError::RemoteError {
+                            message: format!("step_{index} failed"),
+                        }),
+                        false => {
+                            action_log.borrow_mut().push(format!("do_{index}"));
+                            Ok(context + 1)
+                        }
+                    };
+                    async move { result }
+                },
+                move |context: u32| {
+                    compensation_log.borrow_mut().push(format!("undo_{index}"));
+                    async move { Ok(context - 1) }
+                },
+            );
+        }
+        SagaOrchestrator::new(IcTx::new(Ref::new(RefCell::new(HashmapBackend::new()))))
+            .with_saga(saga)
+    }
+
+    #[test]
+    fn saga_should_run_all_the_steps() {
+        // Arrange
+        let log = Log::default();
+        let orchestrator = new_orchestrator(usize::MAX, &log);
+
+        // Act
+        let result = run(orchestrator.start("transfer", 10));
+
+        // Assert
+        assert_eq!(Ok(13), result);
+        assert_eq!(vec!["do_0", "do_1", "do_2"], *log.borrow());
+        let records = orchestrator.store().query().fetch().unwrap();
+        assert_eq!(1, records.len());
+        assert_eq!(SagaStatus::Completed, records[0].data.status);
+    }
+
+    #[test]
+    fn failed_saga_should_compensate_the_previous_steps_in_reverse() {
+        // Arrange
+        let log = Log::default();
+        let orchestrator = new_orchestrator(2, &log);
+
+        // Act
+        let result = run(orchestrator.start("transfer", 10));
+
+        // Assert
+        assert_eq!(
+            Err(TxError::RemoteError {
+                message: "step_2 failed".to_owned()
+            }),
+            result
+        );
+        assert_eq!(vec!["do_0", "do_1", "undo_1", "undo_0"], *log.borrow());
+        let record = &orchestrator.store().query().fetch().unwrap()[0];
+        assert_eq!(SagaStatus::Compensated, record.data.status);
+        assert_eq!(0, record.data.step);
+        assert_eq!(10, record.data.context);
+    }
+
+    #[test]
+    fn recover_should_resume_the_persisted_sagas() {
+        // Arrange
+        let log = Log::default();
+        let orchestrator = new_orchestrator(usize::MAX, &log);
+        // The saga is created but the call traps before running it
+        let id = orchestrator.create("transfer", 10).unwrap();
+        let completed_id = orchestrator.create("transfer", 20).unwrap();
+        run(orchestrator.run(&completed_id)).unwrap();
+        log.borrow_mut().clear();
+
+        // Act
+        let result = run(orchestrator.recover());
+
+        // Assert
+        assert!(result.is_ok());
+        assert_eq!(vec!["do_0", "do_1", "do_2"], *log.borrow());
+        let record = orchestrator.store().fetch_one(&id).unwrap();
+        assert_eq!(SagaStatus::Completed, record.data.status);
+        assert_eq!(13, record.data.context);
+    }
+
+    #[test]
+    fn purge_finished_should_delete_only_the_old_finished_sagas() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let clock = now.clone();
+        let saga = Saga::new("check").with_local_step(
+            "check",
+            |amount: &mut u32| match *amount > 50 {
+                true => Err(TxError::ValidationError {
+                    id: "1".to_owned(),
+                    reason: "Too many tokens".to_owned(),
+                }),
+                false => Ok(()),
+            },
+            |_| Ok(()),
+        );
+        let store = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<
+            SagaId,
+            SagaRecord<u32>,
+        >::new())))
+        .with_clock(move || clock.get());
+        let orchestrator = SagaOrchestrator::new(store).with_saga(saga);
+        let completed = orchestrator.create("check", 10).unwrap();
+        run(orchestrator.run(&completed)).unwrap();
+        let compensated = orchestrator.create("check", 60).unwrap();
+        run(orchestrator.run(&compensated)).unwrap_err();
+        now.set(100);
+        let recent = orchestrator.create("check", 20).unwrap();
+        run(orchestrator.run(&recent)).unwrap();
+        let running = orchestrator.create("check", 30).unwrap();
+        now.set(150);
+
+        // Act
+        let purged = orchestrator
+            .purge_finished(Duration::from_nanos(100))
+            .unwrap();
+
+        // Assert
+        assert_eq!(2, purged);
+        let store = orchestrator.store();
+        assert!(store.fetch_option_one(&completed).unwrap().is_none());
+        assert!(store.fetch_option_one(&compensated).unwrap().is_none());
+        assert_eq!(
+            Some(100),
+            store.fetch_one(&recent).unwrap().data.finished_at
+        );
+        assert_eq!(None, store.fetch_one(&running).unwrap().data.finished_at);
+    }
+
+    #[test]
+    fn local_steps_should_commit_local_transactions() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<u32, i32>::new())));
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, 100)).unwrap();
+        tx.commit();
+
+        let (withdraw_db, refund_db) = (db.clone(), db.clone());
+        let saga = Saga::new("withdraw")
+            .with_local_step(
+                "withdraw",
+                move |amount: &mut i32| {
+                    let mut tx = withdraw_db.tx();
+                    let mut model = tx.fetch_one(&1)?;
+                    model.data -= *amount;
+                    tx.update(model)?;
+                    tx.try_commit()
+                },
+                move |amount: &mut i32| {
+                    let mut tx = refund_db.tx();
+                    let mut model = tx.fetch_one(&1)?;
+                    model.data += *amount;
+                    tx.update(model)?;
+                    tx.try_commit()
+                },
+            )
+            .with_local_step(
+                "check",
+                |amount: &mut i32| match *amount > 50 {
+                    true => Err(TxError::ValidationError {
+                        id: "1".to_owned(),
+                        reason: "Too many tokens".to_owned(),
+                    }),
+                    false => Ok(()),
+                },
+                |_| Ok(()),
+            );
+        let orchestrator =
+            SagaOrchestrator::new(IcTx::new(Ref::new(RefCell::new(HashmapBackend::<
+                SagaId,
+                SagaRecord<i32>,
+            >::new()))))
+            .with_saga(saga);
+
+        // Act
+        let small_withdraw = run(orchestrator.start("withdraw", 30));
+        let large_withdraw = run(orchestrator.start("withdraw", 60));
+        let unknown_saga = run(orchestrator.start("unknown", 10));
+
+        // Assert
+        assert_eq!(Ok(30), small_withdraw);
+        assert!(matches!(
+            large_withdraw,
+            Err(TxError::ValidationError { .. })
+        ));
+        assert!(matches!(unknown_saga, Err(TxError::SagaError { .. })));
+        assert_eq!(70, db.fetch_one(&1).unwrap().data);
+        assert_eq!(3, db.fetch_one(&1).unwrap().version());
+    }
+}
@@ -0,0 +1,426 @@
+//! The shared ownership and the interior mutability of the database state.
+//! By default they are `Rc` and `RefCell`, as canisters are single threaded.
+//! The `sync` feature switches them to `Arc`, `RwLock` and `Mutex`, so `IcTx` and `Tx` are `Send + Sync`.
+
+#[cfg(not(feature = "sync"))]
+pub type Ref<T> = std::rc::Rc<T>;
+#[cfg(feature = "sync")]
+pub type Ref<T> = std::sync::Arc<T>;
+
+#[cfg(feature = "sync")]
+pub use lock::{Cell, RefCell};
+#[cfg(not(feature = "sync"))]
+pub use std::cell::{Cell, RefCell};
+
+/// The bound of the functions and of the state stored in the database.
+/// It is `Send + Sync` with the `sync` feature and it is implemented by every type otherwise.
+#[cfg(not(feature = "sync"))]
+pub trait Shareable {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> Shareable for T {}
+
+/// The bound of the functions and of the state stored in the database.
+/// It is `Send + Sync` with the `sync` feature and it is implemented by every type otherwise.
+#[cfg(feature = "sync")]
+pub trait Shareable: Send + Sync {}
+#[cfg(feature = "sync")]
+impl<T: ?Sized + Send + Sync> Shareable for T {}
+
+/// The trait object of a function stored in the database, `Send + Sync` with the `sync` feature.
+#[cfg(not(feature = "sync"))]
+macro_rules! shared {
+    ($($bound:tt)*) => { dyn $($bound)* };
+}
+#[cfg(feature = "sync")]
+macro_rules! shared {
+    ($($bound:tt)*) => { dyn $($bound)* + Send + Sync };
+}
+pub(crate) use shared;
+
+/// The lock that serializes the commits of a database, shared by all its clones.
+/// Canisters execute one message at a time, so it is a no-op without the `sync` feature.
+#[cfg(not(feature = "sync"))]
+#[derive(Default)]
+pub(crate) struct CommitLock;
+
+#[cfg(not(feature = "sync"))]
+impl CommitLock {
+    pub(crate) fn add_referencing(&self, _lock: Ref<CommitLock>) {}
+}
+
+/// Serializes the commits, so the validation and the writes of a commit are atomic.
+#[cfg(not(feature = "sync"))]
+pub(crate) fn commit_guard(_lock: &Ref<CommitLock>) -> Result<CommitGuard, TxError> {
+    Ok(CommitGuard)
+}
+
+#[cfg(not(feature = "sync"))]
+pub(crate) struct CommitGuard;
+
+#[cfg(not(feature = "sync"))]
+use crate::error::TxError;
+
+#[cfg(feature = "sync")]
+pub(crate) use commit::*;
+
+#[cfg(feature = "sync")]
+mod commit {
+    use std::{
+        collections::HashSet,
+        sync::{Condvar, Mutex, MutexGuard, PoisonError},
+        thread::{self, ThreadId},
+    };
+
+    use super::{Ref, RefCell};
+    use crate::error::TxError;
+
+    /// The lock that serializes the commits of a database, shared by all its clones.
+    /// It knows the locks of the databases that reference this one,
+    /// as a commit can cascade its deletes to them.
+    /// It is not reentrant: a commit started while the same thread commits to the database,
+    /// e.g. from a validator or a view, fails instead of waiting for itself forever.
+    #[derive(Default)]
+    pub(crate) struct CommitLock {
+        // The thread committing to the database, if any
+        owner: Mutex<Option<ThreadId>>,
+        unlocked: Condvar,
+        referencing: RefCell<Vec<Ref<CommitLock>>>,
+    }
+
+    impl CommitLock {
+        pub(crate) fn add_referencing(&self, lock: Ref<CommitLock>) {
+            self.referencing.borrow_mut().push(lock);
+        }
+
+        fn lock(&self) -> Result<(), TxError> {
+            let current = thread::current().id();
+            let mut owner = self.owner();
+            while let Some(owner_id) = *owner {
+                if owner_id == current {
+                    return Err(TxError::CommitError {
+                        message: "The thread is already committing to the database.".to_owned(),
+                    });
+                }
+                owner = self
+                    .unlocked
+                    .wait(owner)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            *owner = Some(current);
+            Ok(())
+        }
+
+        fn unlock(&self) {
+            *self.owner() = None;
+            self.unlocked.notify_one();
+        }
+
+        fn owner(&self) -> MutexGuard<'_, Option<ThreadId>> {
+            self.owner.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    /// Serializes the commits, so the validation and the writes of a commit are atomic.
+    /// It locks the database and the databases its deletes can cascade to, in the order of their
+    /// addresses, so the commits of unrelated databases run in parallel and never deadlock.
+    pub(crate) fn commit_guard(lock: &Ref<CommitLock>) -> Result<CommitGuard, TxError> {
+        let mut visited = HashSet::new();
+        let mut pending = vec![lock.clone()];
+        let mut locks = vec![];
+        while let Some(lock) = pending.pop() {
+            if visited.insert(Ref::as_ptr(&lock)) {
+                pending.extend(lock.referencing.borrow().iter().cloned());
+                locks.push(lock);
+            }
+        }
+        locks.sort_by_key(Ref::as_ptr);
+        // The guard unlocks the locks already taken if one of them fails
+        let mut guard = CommitGuard {
+            locks: Vec::with_capacity(locks.len()),
+        };
+        for lock in locks {
+            lock.lock()?;
+            guard.locks.push(lock);
+        }
+        Ok(guard)
+    }
+
+    pub(crate) struct CommitGuard {
+        locks: Vec<Ref<CommitLock>>,
+    }
+
+    impl Drop for CommitGuard {
+        fn drop(&mut self) {
+            for lock in &self.locks {
+                lock.unlock();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sync")]
+mod lock {
+    use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+    /// A `Mutex` with the interface of a `Cell`.
+    #[derive(Debug, Default)]
+    pub struct Cell<T>(Mutex<T>);
+
+    impl<T: Copy> Cell<T> {
+        pub const fn new(value: T) -> Self {
+            Self(Mutex::new(value))
+        }
+
+        pub fn get(&self) -> T {
+            *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn set(&self, value: T) {
+            *self.0.lock().unwrap_or_else(PoisonError::into_inner) = value;
+        }
+    }
+
+    /// A `RwLock` with the interface of a `RefCell`.
+    /// A panic while the lock is held does not poison it, as it happens to a `RefCell`.
+    #[derive(Debug, Default)]
+    pub struct RefCell<T>(RwLock<T>);
+
+    impl<T> RefCell<T> {
+        pub const fn new(value: T) -> Self {
+            Self(RwLock::new(value))
+        }
+
+        /// Locks the value for reading, blocking while another thread writes it.
+        pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+            self.0.read().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        /// Locks the value for writing, blocking while other threads read or write it.
+        pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+            self.0.write().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn into_inner(self) -> T {
+            self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sync"))]
+mod test {
+
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use crate::{
+        backend::hashmap::HashmapBackend,
+        db::IcTx,
+        error::TxError,
+        model::NewModel,
+        reference::OnDelete,
+        saga::{SagaId, SagaOrchestrator, SagaRecord},
+        two_phase::{Coordinator, Participant},
+        tx::Tx,
+    };
+
+    use super::*;
+
+    type Db = IcTx<u32, HashmapBackend<u32, u32>>;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn db_and_tx_should_be_send_and_sync() {
+        assert_send_sync::<Db>();
+        assert_send_sync::<Tx<u32, HashmapBackend<u32, u32>>>();
+    }
+
+    #[test]
+    fn distributed_txs_and_sagas_should_be_send_and_sync() {
+        assert_send_sync::<Coordinator>();
+        assert_send_sync::<Participant<u32, HashmapBackend<u32, u32>>>();
+        assert_send_sync::<SagaOrchestrator<u32, HashmapBackend<SagaId, SagaRecord<u32>>>>();
+    }
+
+    #[test]
+    fn concurrent_commits_should_not_lose_updates() {
+        // Arrange
+        let db: Db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::new()))).with_view(
+            "total",
+            0u32,
+            |total, data| *total += data,
+            |total, data| *total -= data,
+        );
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, 0)).unwrap();
+        tx.commit();
+
+        // Act
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..8)
+                .map(|_| {
+                    let db = db.clone();
+                    scope.spawn(move || {
+                        for _ in 0..100 {
+                            loop {
+                                let mut tx = db.tx();
+                                let mut model = tx.fetch_one(&1).unwrap();
+                                model.data += 1;
+                                tx.update(model).unwrap();
+                                match tx.try_commit() {
+                                    Ok(()) => break,
+                                    Err(TxError::UpdateOptimisticLockError { .. }) => continue,
+                                    Err(err) => panic!("unexpected error: {err}"),
+                                }
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+        });
+
+        // Assert
+        let model = db.fetch_one(&1).unwrap();
+        assert_eq!(800, model.data);
+        assert_eq!(800, model.version);
+        assert_eq!(Some(800), db.view::<u32>("total"));
+    }
+
+    #[test]
+    fn commits_of_unrelated_databases_should_not_wait_for_each_other() {
+        // Arrange
+        let first: Db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::new())));
+        let second: Db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::new())));
+        let _guard = commit_guard(&first.commit_lock).unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        // Act
+        thread::spawn(move || {
+            let mut tx = second.tx();
+            tx.save(NewModel::new(1, 1)).unwrap();
+            sender.send(tx.try_commit()).unwrap();
+        });
+
+        // Assert
+        let result = receiver.recv_timeout(Duration::from_secs(10));
+        assert!(matches!(result, Ok(Ok(()))));
+    }
+
+    #[test]
+    fn nested_commit_should_fail_instead_of_deadlocking() {
+        // Arrange
+        let db: Db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::new())));
+        let guard = commit_guard(&db.commit_lock).unwrap();
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, 1)).unwrap();
+        let nested = tx.try_commit();
+        drop(guard);
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, 1)).unwrap();
+        let after_unlock = tx.try_commit();
+
+        // Assert
+        assert!(matches!(nested, Err(TxError::CommitError { .. })));
+        assert!(after_unlock.is_ok());
+        assert_eq!(1, db.metrics().unwrap().failed_commits);
+    }
+
+    #[test]
+    fn commit_should_wait_for_the_databases_it_can_cascade_to() {
+        // Arrange
+        let parents: Db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::new())));
+        let children: Db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::new()))).with_reference(
+            "child_parent",
+            &parents,
+            |parent_id: &u32| Some(*parent_id),
+            OnDelete::Cascade,
+        );
+        let mut tx = parents.tx();
+        tx.save(NewModel::new(1, 1)).unwrap();
+        tx.commit();
+        let mut tx = children.tx();
+        tx.save(NewModel::new(10, 1)).unwrap();
+        tx.commit();
+        let guard = commit_guard(&children.commit_lock).unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        // Act
+        let parents_in_thread = parents.clone();
+        thread::spawn(move || {
+            let mut tx = parents_in_thread.tx();
+            tx.delete(parents_in_thread.fetch_one(&1).unwrap()).unwrap();
+            sender.send(tx.try_commit()).unwrap();
+        });
+        let while_locked = receiver.recv_timeout(Duration::from_millis(100));
+        drop(guard);
+        let after_unlock = receiver.recv_timeout(Duration::from_secs(10));
+
+        // Assert
+        assert!(while_locked.is_err());
+        assert!(matches!(after_unlock, Ok(Ok(()))));
+        assert!(children.fetch_option_one(&10).unwrap().is_none());
+    }
+
+    #[test]
+    fn self_reference_should_not_deadlock_with_a_waiting_writer() {
+        // Arrange
+        type Tree = IcTx<Option<u32>, HashmapBackend<u32, Option<u32>>>;
+        let tree: Tree = IcTx::new(Ref::new(RefCell::new(HashmapBackend::new())));
+        let tree = tree.clone().with_reference(
+            "node_parent",
+            &tree,
+            |parent_id: &Option<u32>| *parent_id,
+            OnDelete::Cascade,
+        );
+        let done = Cell::new(false);
+
+        // Act
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.get() {
+                    drop(tree.backend.borrow_mut());
+                }
+            });
+            for _ in 0..20_000 {
+                for (id, parent_id) in [(1, None), (2, Some(1)), (3, Some(2))] {
+                    let mut tx = tree.tx();
+                    tx.save(NewModel::new(id, parent_id)).unwrap();
+                    tx.commit();
+                }
+                let mut tx = tree.tx();
+                tx.delete(tree.fetch_one(&1).unwrap()).unwrap();
+                tx.commit();
+            }
+            done.set(true);
+        });
+
+        // Assert
+        for id in 1..=3 {
+            assert!(tree.fetch_option_one(&id).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn ref_cell_should_not_be_poisoned_by_a_panic() {
+        // Arrange
+        let cell = Ref::new(RefCell::new(1));
+        let cell_in_thread = cell.clone();
+
+        // Act
+        let result = thread::spawn(move || {
+            let _value = cell_in_thread.borrow_mut();
+            panic!("panic while the lock is held");
+        })
+        .join();
+
+        // Assert
+        assert!(result.is_err());
+        assert_eq!(1, *cell.borrow());
+        *cell.borrow_mut() = 2;
+        assert_eq!(2, *cell.borrow());
+    }
+}
@@ -0,0 +1,761 @@
+use std::{
+    collections::HashSet,
+    hash::Hash,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    ops::ControlFlow,
+};
+
+use candid::{de::IDLDeserialize, ser::IDLBuilder, CandidType, Deserialize};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    backend::{Backend, BackendModel},
+    db::IcTx,
+    error::TxError,
+    idempotency::Receipt,
+    key::Key,
+    metadata::Metadata,
+    migration::SchemaVersion,
+    model::Model,
+};
+
+const MAGIC: &[u8; 8] = b"ICTXSNAP";
+const FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2 + 8 + 4;
+
+/// The number of models decoded before they are added to the backend, when a snapshot is restored.
+const RESTORE_BATCH_LEN: usize = 1024;
+
+/// A chunk size that fits comfortably in the reply of a canister query.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// A binary copy of the whole content of a database.
+///
+/// The format is: an 8 bytes magic string, the format version (u16), the payload length (u64),
+/// the CRC32 checksum of the payload (u32), all little endian, followed by the payload.
+/// The payload is a sequence of candid messages, each one prefixed by its length (u32):
+/// the number of models, then one message per model with its fields and its data as two separate values,
+/// so the data can be decoded with the type of its schema version, see `IcTx::with_legacy_data`,
+/// and last the tombstones and the idempotency keys.
+/// The snapshot can be split into chunks to be streamed over several calls and rebuilt with `push_chunk`.
+/// A canister that cannot keep the snapshot between its calls, e.g. in query calls,
+/// returns each chunk with `IcTx::export_snapshot_chunk` instead.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    bytes: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self { bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns the number of chunks of the specified size.
+    pub fn chunk_count(&self, chunk_size: usize) -> usize {
+        self.bytes.len().div_ceil(chunk_size)
+    }
+
+    /// Returns the chunk at the specified index, or `None` if the index is out of range.
+    pub fn chunk(&self, index: usize, chunk_size: usize) -> Option<&[u8]> {
+        self.bytes.chunks(chunk_size).nth(index)
+    }
+
+    /// Appends a chunk to the snapshot being rebuilt.
+    pub fn push_chunk(&mut self, chunk: &[u8]) {
+        self.bytes.extend_from_slice(chunk);
+    }
+}
+
+/// A writer that can go back to fill in a length once the data after it is written.
+pub(crate) trait WriteSeek: Write + Seek {}
+
+impl<T: Write + Seek> WriteSeek for T {}
+
+/// Writes a snapshot to the writer: `for_each_model` passes each of the `count` models to the function
+/// that encodes it. The payload is streamed into the writer one message at a time, and the header is filled in
+/// afterwards, so neither the models nor the snapshot are copied into an intermediate buffer.
+pub(crate) fn write_encoded<IdType, Data, V>(
+    writer: &mut dyn WriteSeek,
+    count: usize,
+    for_each_model: impl FnOnce(
+        &mut dyn FnMut(&Model<IdType, Data, V>) -> Result<(), TxError>,
+    ) -> Result<(), TxError>,
+    tombstones: &[(IdType, V)],
+    idempotency_keys: &[(String, Receipt<IdType, V>, u64)],
+) -> Result<(), TxError>
+where
+    IdType: CandidType + Clone,
+    Data: CandidType,
+    V: CandidType + Copy,
+{
+    let start = writer.stream_position().map_err(write_error)?;
+    writer.write_all(&[0; HEADER_LEN]).map_err(write_error)?;
+
+    let mut payload = PayloadWriter {
+        writer: &mut *writer,
+        hasher: crc32fast::Hasher::new(),
+        len: 0,
+        message: vec![],
+    };
+    payload.write_message(IDLBuilder::new().arg(&(count as u64)))?;
+    let mut written = 0;
+    for_each_model(&mut |model| {
+        let fields = ModelFields {
+            id: model.id.clone(),
+            version: model.version,
+            schema_version: model.schema_version,
+            metadata: model.metadata.clone(),
+        };
+        written += 1;
+        payload.write_message(
+            IDLBuilder::new()
+                .arg(&fields)
+                .and_then(|builder| builder.arg(&model.data)),
+        )
+    })?;
+    if written != count {
+        return Err(TxError::SnapshotError {
+            message: format!(
+                "Cannot encode the snapshot. Expected [{count}] models, found [{written}]."
+            ),
+        });
+    }
+    payload.write_message(
+        IDLBuilder::new()
+            .arg(&tombstones)
+            .and_then(|builder| builder.arg(&idempotency_keys)),
+    )?;
+    let (len, checksum) = (payload.len, payload.hasher.finalize());
+
+    let end = writer.stream_position().map_err(write_error)?;
+    writer.seek(SeekFrom::Start(start)).map_err(write_error)?;
+    writer.write_all(MAGIC).map_err(write_error)?;
+    writer
+        .write_all(&FORMAT_VERSION.to_le_bytes())
+        .map_err(write_error)?;
+    writer.write_all(&len.to_le_bytes()).map_err(write_error)?;
+    writer
+        .write_all(&checksum.to_le_bytes())
+        .map_err(write_error)?;
+    writer.seek(SeekFrom::Start(end)).map_err(write_error)?;
+    Ok(())
+}
+
+/// Computes the length and the checksum of the payload while it is written.
+struct PayloadWriter<'a> {
+    writer: &'a mut dyn WriteSeek,
+    hasher: crc32fast::Hasher,
+    len: u64,
+    // Reused by all the messages, so it grows to the size of the largest one
+    message: Vec<u8>,
+}
+
+impl PayloadWriter<'_> {
+    fn write_message(
+        &mut self,
+        builder: candid::Result<&mut IDLBuilder>,
+    ) -> Result<(), TxError> {
+        self.message.clear();
+        builder
+            .and_then(|builder| builder.serialize(&mut self.message))
+            .map_err(|err| TxError::SnapshotError {
+                message: format!("Cannot encode the snapshot. {err}"),
+            })?;
+        let len = u32::try_from(self.message.len()).map_err(|_| TxError::SnapshotError {
+            message: "Cannot encode the snapshot. A model is larger than 4 GiB.".to_owned(),
+        })?;
+        let message = std::mem::take(&mut self.message);
+        let result = self
+            .write_all(&len.to_le_bytes())
+            .and_then(|()| self.write_all(&message))
+            .map_err(write_error);
+        self.message = message;
+        result
+    }
+}
+
+impl Write for PayloadWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A writer that keeps only the bytes written in a window of the stream, e.g. a chunk of a snapshot,
+/// and discards the others.
+struct ChunkWriter {
+    start: u64,
+    chunk: Vec<u8>,
+    chunk_size: usize,
+    position: u64,
+    len: u64,
+}
+
+impl ChunkWriter {
+    fn new(index: usize, chunk_size: usize) -> Self {
+        Self {
+            start: index as u64 * chunk_size as u64,
+            chunk: vec![],
+            chunk_size,
+            position: 0,
+            len: 0,
+        }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let end = self.position + buf.len() as u64;
+        let chunk_end = self.start + self.chunk_size as u64;
+        let (from, to) = (self.position.max(self.start), end.min(chunk_end));
+        if from < to {
+            let offset = (from - self.start) as usize;
+            let bytes = &buf[(from - self.position) as usize..(to - self.position) as usize];
+            if self.chunk.len() < offset + bytes.len() {
+                self.chunk.resize(offset + bytes.len(), 0);
+            }
+            self.chunk[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        self.position = end;
+        self.len = self.len.max(end);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ChunkWriter {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek position")
+        })?;
+        Ok(self.position)
+    }
+}
+
+fn write_error(err: std::io::Error) -> TxError {
+    TxError::SnapshotError {
+        message: format!("Cannot write the snapshot. {err}"),
+    }
+}
+
+/// Reads the payload of a snapshot one message at a time, checking its length and its checksum.
+struct PayloadReader<'a> {
+    reader: &'a mut dyn Read,
+    hasher: crc32fast::Hasher,
+    remaining: u64,
+    // Reused by all the messages, so it grows to the size of the largest one
+    message: Vec<u8>,
+}
+
+impl PayloadReader<'_> {
+    fn read_message(&mut self) -> Result<IDLDeserialize<'_>, TxError> {
+        let mut len = [0; 4];
+        self.read_exact(&mut len).map_err(read_error)?;
+        let len = u32::from_le_bytes(len) as u64;
+        if len > self.remaining {
+            return Err(snapshot_error("The snapshot is corrupted."));
+        }
+        self.message.resize(len as usize, 0);
+        let mut message = std::mem::take(&mut self.message);
+        let result = self.read_exact(&mut message).map_err(read_error);
+        self.message = message;
+        result?;
+        IDLDeserialize::new(&self.message).map_err(decode_error)
+    }
+
+    /// Checks that the whole payload was read and that its checksum matches.
+    fn finish(self, checksum: u32) -> Result<(), TxError> {
+        if self.remaining > 0 {
+            return Err(snapshot_error("The snapshot is corrupted."));
+        }
+        if checksum != self.hasher.finalize() {
+            return Err(snapshot_error("The snapshot checksum does not match."));
+        }
+        Ok(())
+    }
+}
+
+impl Read for PayloadReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let max = buf.len().min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let read = self.reader.read(&mut buf[..max])?;
+        self.hasher.update(&buf[..read]);
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+fn snapshot_error(message: &str) -> TxError {
+    TxError::SnapshotError {
+        message: message.to_owned(),
+    }
+}
+
+fn read_error(err: std::io::Error) -> TxError {
+    match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => snapshot_error("The snapshot is incomplete."),
+        _ => TxError::SnapshotError {
+            message: format!("Cannot read the snapshot. {err}"),
+        },
+    }
+}
+
+fn decode_error(err: candid::Error) -> TxError {
+    TxError::SnapshotError {
+        message: format!("Cannot decode the snapshot. {err}"),
+    }
+}
+
+/// The fields of a model stored in a snapshot, without its data.
+#[derive(CandidType, Deserialize)]
+struct ModelFields<IdType, V> {
+    id: IdType,
+    version: V,
+    schema_version: SchemaVersion,
+    metadata: Option<Metadata>,
+}
+
+/// The tombstones and the idempotency keys of a snapshot, that follow its models.
+pub(crate) struct SnapshotTail<IdType, V> {
+    tombstones: Vec<(IdType, V)>,
+    idempotency_keys: Vec<(String, Receipt<IdType, V>, u64)>,
+}
+
+impl<Data, B: Backend<Data>> IcTx<Data, B>
+where
+    Data: CandidType + DeserializeOwned,
+    B::IdType: CandidType + DeserializeOwned,
+    B::VersionType: CandidType + DeserializeOwned,
+{
+    /// Exports all the models, with their versions, the tombstones and the idempotency keys into a snapshot.
+    pub fn export_snapshot(&self) -> Result<Snapshot, TxError> {
+        let mut cursor = Cursor::new(vec![]);
+        self.write_snapshot(&mut cursor)?;
+        Ok(Snapshot::from_bytes(cursor.into_inner()))
+    }
+
+    /// Exports only the chunk at the specified index of the snapshot that `export_snapshot` would return,
+    /// or `None` if the index is out of range.
+    /// The whole snapshot is encoded again but only the chunk is kept, so a canister can stream its snapshot
+    /// over several query calls without keeping it on the heap. The chunks are checked together by `import_snapshot`,
+    /// which fails if the database changed between the calls.
+    pub fn export_snapshot_chunk(
+        &self,
+        index: usize,
+        chunk_size: usize,
+    ) -> Result<Option<Vec<u8>>, TxError> {
+        let mut writer = ChunkWriter::new(index, chunk_size);
+        self.write_snapshot(&mut writer)?;
+        Ok((!writer.chunk.is_empty()).then_some(writer.chunk))
+    }
+
+    /// Writes the snapshot of the database to the writer, see `export_snapshot`.
+    /// Each model is encoded into the writer as the backend is visited, without cloning them all first.
+    pub(crate) fn write_snapshot(&self, writer: &mut dyn WriteSeek) -> Result<(), TxError> {
+        let backend = self.backend.borrow();
+        write_encoded(
+            writer,
+            backend.count()?,
+            |write_model| {
+                backend.for_each_model(&mut |model| {
+                    write_model(model).map(|()| ControlFlow::Continue(()))
+                })
+            },
+            &backend.fetch_all_tombstones()?,
+            &self.idempotency_keys.borrow().all(),
+        )
+    }
+
+    /// Replaces the whole content of the database with the one of the snapshot.
+    /// The backend is changed only after the whole snapshot has been validated.
+    /// The data stored with an older schema version is decoded with the type registered
+    /// with `with_legacy_data`, if any, and migrated when it is fetched.
+    pub fn import_snapshot(&self, snapshot: &Snapshot) -> Result<(), TxError> {
+        let mut reader = snapshot.as_bytes();
+        let tail = self.check_snapshot(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(snapshot_error("The snapshot is corrupted."));
+        }
+        self.restore_snapshot(&mut snapshot.as_bytes(), tail)
+    }
+
+    /// Decodes a snapshot from the reader one model at a time and checks that each id appears once,
+    /// without changing the database nor keeping the decoded models.
+    /// Returns the tail of the snapshot, to be restored by `restore_snapshot` with the models.
+    pub(crate) fn check_snapshot(
+        &self,
+        reader: &mut dyn Read,
+    ) -> Result<SnapshotTail<B::IdType, B::VersionType>, TxError> {
+        let mut ids = HashSet::new();
+        let tail = self.read_encoded(reader, &mut |model| check_unique(&mut ids, model.id))?;
+        for (id, _) in &tail.tombstones {
+            check_unique(&mut ids, id.clone())?;
+        }
+        Ok(tail)
+    }
+
+    /// Replaces the whole content of the database with the snapshot read again from the reader,
+    /// once `check_snapshot` checked it and returned its tail.
+    /// The models are added to the backend in batches while they are decoded.
+    pub(crate) fn restore_snapshot(
+        &self,
+        reader: &mut dyn Read,
+        tail: SnapshotTail<B::IdType, B::VersionType>,
+    ) -> Result<(), TxError> {
+        self.backend.borrow_mut().restore(vec![], tail.tombstones)?;
+        let mut batch = Vec::with_capacity(RESTORE_BATCH_LEN);
+        self.read_encoded(reader, &mut |model| {
+            batch.push(model);
+            if batch.len() == RESTORE_BATCH_LEN {
+                let models = std::mem::replace(&mut batch, Vec::with_capacity(RESTORE_BATCH_LEN));
+                self.backend.borrow_mut().restore_models(models)?;
+            }
+            Ok(())
+        })?;
+        self.backend.borrow_mut().restore_models(batch)?;
+        self.idempotency_keys
+            .borrow_mut()
+            .restore(tail.idempotency_keys);
+        self.rebuild_indexes()?;
+        self.rebuild_views()
+    }
+
+    /// Decodes a snapshot from the reader and passes each model to `on_model` as soon as it is decoded.
+    fn read_encoded(
+        &self,
+        reader: &mut dyn Read,
+        on_model: &mut dyn FnMut(BackendModel<Data, B>) -> Result<(), TxError>,
+    ) -> Result<SnapshotTail<B::IdType, B::VersionType>, TxError> {
+        let mut header = [0; HEADER_LEN];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| snapshot_error("The data is not a snapshot."))?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(snapshot_error("The data is not a snapshot."));
+        }
+        let format_version = u16::from_le_bytes([header[8], header[9]]);
+        if format_version != FORMAT_VERSION {
+            return Err(TxError::SnapshotError {
+                message: format!(
+                    "Unsupported snapshot format version [{format_version}], expected [{FORMAT_VERSION}]."
+                ),
+            });
+        }
+        let payload_len = u64::from_le_bytes(header[10..18].try_into().expect("8 bytes"));
+        let checksum = u32::from_le_bytes(header[18..22].try_into().expect("4 bytes"));
+
+        let mut payload = PayloadReader {
+            reader,
+            hasher: crc32fast::Hasher::new(),
+            remaining: payload_len,
+            message: vec![],
+        };
+        let count: u64 = decode_message(payload.read_message()?, |message| message.get_value())?;
+        for _ in 0..count {
+            let model = decode_message(payload.read_message()?, |message| {
+                let fields: ModelFields<B::IdType, B::VersionType> = message.get_value()?;
+                let data = self.decode_data(fields.schema_version, message)?;
+                Ok(Model {
+                    id: fields.id,
+                    version: fields.version,
+                    schema_version: fields.schema_version,
+                    data,
+                    metadata: fields.metadata,
+                })
+            })?;
+            on_model(model)?;
+        }
+        let (tombstones, idempotency_keys) = decode_message(payload.read_message()?, |message| {
+            Ok((message.get_value()?, message.get_value()?))
+        })?;
+        payload.finish(checksum)?;
+        Ok(SnapshotTail {
+            tombstones,
+            idempotency_keys,
+        })
+    }
+}
+
+/// Fails if the id already appeared in the snapshot.
+fn check_unique<IdType: Key + Eq + Hash>(
+    ids: &mut HashSet<IdType>,
+    id: IdType,
+) -> Result<(), TxError> {
+    if ids.contains(&id) {
+        return Err(TxError::SnapshotError {
+            message: format!(
+                "The snapshot contains the id [{}] more than once.",
+                id.display()
+            ),
+        });
+    }
+    ids.insert(id);
+    Ok(())
+}
+
+/// Decodes all the values of a message.
+fn decode_message<T>(
+    mut message: IDLDeserialize<'_>,
+    decode: impl FnOnce(&mut IDLDeserialize<'_>) -> candid::Result<T>,
+) -> Result<T, TxError> {
+    let value = decode(&mut message).map_err(decode_error)?;
+    message.done().map_err(decode_error)?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::{backend::hashmap::HashmapBackend, model::NewModel, test_utils::new_db};
+
+    type Db = IcTx<String, HashmapBackend<u32, String>>;
+
+    fn populated_db() -> Db {
+        let db: Db = new_db();
+        let mut tx = db.tx();
+        for id in 0..100 {
+            tx.save(NewModel::new(id, format!("data_{id}"))).unwrap();
+        }
+        tx.commit();
+
+        let mut tx = db.tx();
+        tx.update(db.fetch_one(&1).unwrap()).unwrap();
+        tx.delete(db.fetch_one(&2).unwrap()).unwrap();
+        tx.commit();
+        db
+    }
+
+    #[test]
+    fn snapshot_should_restore_models_versions_and_tombstones() {
+        // Arrange
+        let db = populated_db();
+        let restored_db: Db = new_db();
+
+        // Act
+        let snapshot = db.export_snapshot().unwrap();
+        restored_db.import_snapshot(&snapshot).unwrap();
+
+        // Assert
+        for id in 0..100 {
+            assert_eq!(
+                db.fetch_option_one(&id).unwrap(),
+                restored_db.fetch_option_one(&id).unwrap()
+            );
+        }
+        assert_eq!(1, restored_db.fetch_one(&1).unwrap().version());
+
+        // The tombstone prevents the version of the deleted model from being reused
+        let mut tx = restored_db.tx();
+        tx.save(NewModel::new(2, "data_2".to_owned())).unwrap();
+        tx.commit();
+        assert_eq!(1, restored_db.fetch_one(&2).unwrap().version());
+    }
+
+    #[test]
+    fn import_should_restore_the_models_of_several_batches() {
+        // Arrange
+        let db: Db = new_db();
+        let count = 2 * RESTORE_BATCH_LEN as u32 + 1;
+        let mut tx = db.tx();
+        for id in 0..count {
+            tx.save(NewModel::new(id, format!("data_{id}"))).unwrap();
+        }
+        tx.commit();
+        let restored_db: Db = new_db();
+        let mut tx = restored_db.tx();
+        tx.save(NewModel::new(count, "stale".to_owned())).unwrap();
+        tx.commit();
+
+        // Act
+        let snapshot = db.export_snapshot().unwrap();
+        restored_db.import_snapshot(&snapshot).unwrap();
+
+        // Assert
+        assert_eq!(
+            count as usize,
+            restored_db.backend.borrow().count().unwrap()
+        );
+        assert_eq!(
+            db.fetch_option_one(&(count - 1)).unwrap(),
+            restored_db.fetch_option_one(&(count - 1)).unwrap()
+        );
+        assert!(restored_db.fetch_option_one(&count).unwrap().is_none());
+    }
+
+    #[test]
+    fn snapshot_should_be_rebuilt_from_chunks() {
+        // Arrange
+        let db = populated_db();
+        let restored_db: Db = new_db();
+        let snapshot = db.export_snapshot().unwrap();
+        let chunk_size = 100;
+
+        // Act
+        let mut rebuilt_snapshot = Snapshot::default();
+        for index in 0..snapshot.chunk_count(chunk_size) {
+            rebuilt_snapshot.push_chunk(snapshot.chunk(index, chunk_size).unwrap());
+        }
+        restored_db.import_snapshot(&rebuilt_snapshot).unwrap();
+
+        // Assert
+        assert!(snapshot.chunk_count(chunk_size) > 1);
+        assert!(snapshot
+            .chunk(snapshot.chunk_count(chunk_size), chunk_size)
+            .is_none());
+        assert_eq!(snapshot, rebuilt_snapshot);
+        assert_eq!(
+            db.fetch_option_one(&99).unwrap(),
+            restored_db.fetch_option_one(&99).unwrap()
+        );
+    }
+
+    #[test]
+    fn export_snapshot_chunk_should_return_the_chunks_of_the_snapshot() {
+        // Arrange
+        let db = populated_db();
+        let snapshot = db.export_snapshot().unwrap();
+        let chunk_size = 100;
+
+        // Act
+        let chunks = (0..=snapshot.chunk_count(chunk_size))
+            .map(|index| db.export_snapshot_chunk(index, chunk_size).unwrap())
+            .collect::<Vec<_>>();
+
+        // Assert
+        assert_eq!(snapshot.chunk_count(chunk_size) + 1, chunks.len());
+        for (index, chunk) in chunks.iter().enumerate() {
+            assert_eq!(snapshot.chunk(index, chunk_size), chunk.as_deref());
+        }
+    }
+
+    #[test]
+    fn import_should_fail_if_snapshot_is_corrupted() {
+        // Arrange
+        let snapshot = populated_db().export_snapshot().unwrap();
+        let restored_db: Db = new_db();
+        {
+            let mut tx = restored_db.tx();
+            tx.save(NewModel::new(1000, "data_1000".to_owned()))
+                .unwrap();
+            tx.commit();
+        }
+
+        let mut corrupted_bytes = snapshot.clone().into_bytes();
+        let last = corrupted_bytes.len() - 1;
+        corrupted_bytes[last] ^= 0xff;
+
+        let mut truncated_bytes = snapshot.clone().into_bytes();
+        truncated_bytes.pop();
+
+        // Act
+        let corrupted_result = restored_db.import_snapshot(&Snapshot::from_bytes(corrupted_bytes));
+        let truncated_result = restored_db.import_snapshot(&Snapshot::from_bytes(truncated_bytes));
+        let invalid_result = restored_db.import_snapshot(&Snapshot::from_bytes(vec![1, 2, 3]));
+
+        // Assert
+        assert!(matches!(
+            corrupted_result,
+            Err(TxError::SnapshotError { .. })
+        ));
+        assert!(matches!(
+            truncated_result,
+            Err(TxError::SnapshotError { .. })
+        ));
+        assert!(matches!(invalid_result, Err(TxError::SnapshotError { .. })));
+        assert!(restored_db.fetch_option_one(&1000).unwrap().is_some());
+        assert!(restored_db.fetch_option_one(&1).unwrap().is_none());
+    }
+
+    #[test]
+    fn import_should_rebuild_the_views() {
+        // Arrange
+        let snapshot = populated_db().export_snapshot().unwrap();
+        let restored_db: Db = new_db().with_view(
+            "count",
+            0u32,
+            |count, _: &String| *count += 1,
+            |count, _: &String| *count -= 1,
+        );
+
+        // Act
+        restored_db.import_snapshot(&snapshot).unwrap();
+
+        // Assert
+        assert_eq!(Some(99u32), restored_db.view("count"));
+    }
+
+    #[test]
+    fn import_should_restore_the_idempotency_keys() {
+        // Arrange
+        let db: Db = new_db();
+        let restored_db: Db = new_db();
+        let mut tx = db.tx().with_idempotency_key("create_1");
+        tx.save(NewModel::new(1, "data".to_owned())).unwrap();
+        let receipt = tx.try_commit_with_receipt().unwrap();
+
+        // Act
+        restored_db
+            .import_snapshot(&db.export_snapshot().unwrap())
+            .unwrap();
+        let mut tx = restored_db.tx().with_idempotency_key("create_1");
+        tx.save(NewModel::new(1, "data".to_owned())).unwrap();
+        let retry = tx.try_commit_with_receipt().unwrap();
+
+        // Assert
+        assert!(retry.replayed);
+        assert_eq!(receipt.changes, retry.changes);
+    }
+
+    #[test]
+    fn import_should_fail_if_ids_are_duplicated() {
+        // Arrange
+        let db: Db = new_db();
+        let mut cursor = Cursor::new(vec![]);
+        write_encoded::<u32, String, u32>(
+            &mut cursor,
+            1,
+            |write_model| write_model(&Model::from((1, "data".to_owned()))),
+            &[(1, 0)],
+            &[],
+        )
+        .unwrap();
+        let snapshot = Snapshot::from_bytes(cursor.into_inner());
+
+        // Act
+        let result = db.import_snapshot(&snapshot);
+
+        // Assert
+        assert!(matches!(result, Err(TxError::SnapshotError { .. })));
+    }
+}
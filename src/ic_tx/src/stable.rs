@@ -0,0 +1,663 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use candid::CandidType;
+pub use ic_cdk::api::stable::{CanisterStableMemory, StableMemory};
+use ic_cdk::api::stable::{StableReader, StableWriter};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    backend::Backend,
+    db::IcTx,
+    error::TxError,
+    snapshot::{Snapshot, WriteSeek},
+};
+
+const MAGIC: &[u8; 8] = b"ICTXSTBL";
+const LAYOUT_VERSION: u16 = 1;
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// A named snapshot of a database saved into stable memory.
+///
+/// The schema tag identifies the layout of the data and is checked when the section is restored,
+/// so a canister refuses to load data written by an incompatible version of its code.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StableSection {
+    pub name: String,
+    pub schema_tag: String,
+    pub snapshot: Snapshot,
+}
+
+impl StableSection {
+    /// Fails if the schema tag of the section differs from the expected one.
+    pub(crate) fn check_schema_tag(&self, schema_tag: &str) -> Result<(), TxError> {
+        check_schema_tag(&self.name, &self.schema_tag, schema_tag)
+    }
+}
+
+fn check_schema_tag(name: &str, section_schema_tag: &str, schema_tag: &str) -> Result<(), TxError> {
+    if section_schema_tag != schema_tag {
+        return Err(TxError::StableMemoryError {
+            message: format!(
+                "The section [{name}] has schema tag [{section_schema_tag}], expected [{schema_tag}]."
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Writes the sections into the stable memory from the specified offset, growing it when needed.
+/// Returns the offset where the sections end.
+///
+/// The sections occupy the stable memory from the offset to the returned end, whose position depends
+/// on the size of the data: other data must be kept before the offset, or after the end of the largest
+/// sections ever written. The bytes after the end are left unchanged.
+///
+/// The layout is: an 8 bytes magic string, the layout version (u16) and the number of sections (u32),
+/// followed by the name, the schema tag and the snapshot of each section, all prefixed by their length.
+pub fn write_sections<M: StableMemory>(
+    memory: M,
+    offset: u64,
+    sections: &[StableSection],
+) -> Result<u64, TxError> {
+    let mut writer = SectionWriter::new(memory, offset, sections.len() as u32)?;
+    for section in sections {
+        writer.write_section(section)?;
+    }
+    writer.finish()
+}
+
+/// Writes the sections one at a time into the stable memory from the specified offset,
+/// with the layout of `write_sections`.
+///
+/// A database is encoded straight into the stable memory by `IcTx::write_stable_section`,
+/// so saving it does not need a copy of its snapshot on the heap.
+pub struct SectionWriter<'a> {
+    writer: Box<dyn WriteSeek + 'a>,
+    remaining: u32,
+}
+
+impl<'a> SectionWriter<'a> {
+    /// Starts writing the specified number of sections at the offset.
+    pub fn new<M: StableMemory + 'a>(memory: M, offset: u64, count: u32) -> Result<Self, TxError> {
+        let mut writer = Self {
+            writer: Box::new(StableWriter::with_memory(memory, offset)),
+            remaining: count,
+        };
+        writer.write(MAGIC)?;
+        writer.write(&LAYOUT_VERSION.to_le_bytes())?;
+        writer.write(&count.to_le_bytes())?;
+        Ok(writer)
+    }
+
+    /// Writes a section already encoded.
+    pub fn write_section(&mut self, section: &StableSection) -> Result<(), TxError> {
+        self.write_section_with(&section.name, &section.schema_tag, |writer| {
+            writer
+                .write_all(section.snapshot.as_bytes())
+                .map_err(stable_memory_error)
+        })
+    }
+
+    /// Writes a section whose snapshot is written by the closure.
+    /// The length of the snapshot is filled in once the closure returns.
+    pub(crate) fn write_section_with(
+        &mut self,
+        name: &str,
+        schema_tag: &str,
+        write_snapshot: impl FnOnce(&mut dyn WriteSeek) -> Result<(), TxError>,
+    ) -> Result<(), TxError> {
+        if self.remaining == 0 {
+            return Err(TxError::StableMemoryError {
+                message: format!(
+                    "Cannot write the section [{name}], all the sections are written."
+                ),
+            });
+        }
+        self.remaining -= 1;
+        for text in [name, schema_tag] {
+            self.write(&(text.len() as u32).to_le_bytes())?;
+            self.write(text.as_bytes())?;
+        }
+        let len_position = self.position()?;
+        self.write(&0u64.to_le_bytes())?;
+        write_snapshot(&mut self.writer)?;
+        let end = self.position()?;
+        let len = end - len_position - std::mem::size_of::<u64>() as u64;
+        self.seek(len_position)?;
+        self.write(&len.to_le_bytes())?;
+        self.seek(end)
+    }
+
+    /// Checks that all the sections are written and returns the offset where they end.
+    pub fn finish(mut self) -> Result<u64, TxError> {
+        if self.remaining > 0 {
+            return Err(TxError::StableMemoryError {
+                message: format!("[{}] sections are not written.", self.remaining),
+            });
+        }
+        self.position()
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), TxError> {
+        self.writer.write_all(bytes).map_err(stable_memory_error)
+    }
+
+    fn position(&mut self) -> Result<u64, TxError> {
+        self.writer.stream_position().map_err(stable_memory_error)
+    }
+
+    fn seek(&mut self, position: u64) -> Result<(), TxError> {
+        self.writer
+            .seek(SeekFrom::Start(position))
+            .map(|_| ())
+            .map_err(stable_memory_error)
+    }
+}
+
+/// Reads the sections written by `write_sections` at the specified offset, see `SectionReader::new`.
+pub fn read_sections<M: StableMemory>(
+    memory: M,
+    offset: u64,
+) -> Result<Vec<StableSection>, TxError> {
+    let mut reader = SectionReader::new(memory, offset)?;
+    let mut sections = Vec::with_capacity(reader.remaining() as usize);
+    while reader.remaining() > 0 {
+        let section = reader.read_section_with(|header, snapshot| {
+            let mut bytes = Vec::with_capacity(header.len as usize);
+            snapshot
+                .read_to_end(&mut bytes)
+                .map_err(stable_memory_error)?;
+            Ok(StableSection {
+                name: header.name.clone(),
+                schema_tag: header.schema_tag.clone(),
+                snapshot: Snapshot::from_bytes(bytes),
+            })
+        })?;
+        sections.push(section);
+    }
+    Ok(sections)
+}
+
+/// The name, the schema tag and the snapshot length of a section read by a `SectionReader`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SectionHeader {
+    pub name: String,
+    pub schema_tag: String,
+    pub len: u64,
+}
+
+impl SectionHeader {
+    /// Fails if the schema tag of the section differs from the expected one.
+    pub(crate) fn check_schema_tag(&self, schema_tag: &str) -> Result<(), TxError> {
+        check_schema_tag(&self.name, &self.schema_tag, schema_tag)
+    }
+}
+
+/// Reads the sections written by `write_sections` one at a time.
+///
+/// The snapshot of each section is read straight from the stable memory while it is decoded,
+/// e.g. by `IcTx::restore_from_memory`, so restoring a database does not need a copy of its snapshot on the heap.
+pub struct SectionReader<M: StableMemory> {
+    reader: StableReader<M>,
+    remaining: u32,
+}
+
+impl<M: StableMemory> SectionReader<M> {
+    /// Starts reading the sections written at the offset.
+    /// There are no sections if nothing was written there, e.g. on the first install of a canister:
+    /// the stable memory ends before the offset, or it is still zeroed at the offset.
+    pub fn new(memory: M, offset: u64) -> Result<Self, TxError> {
+        let is_empty = memory.stable_size() * WASM_PAGE_SIZE < offset + MAGIC.len() as u64;
+        let mut reader = Self {
+            reader: StableReader::with_memory(memory, offset),
+            remaining: 0,
+        };
+        if is_empty {
+            return Ok(reader);
+        }
+
+        let mut magic = [0; MAGIC.len()];
+        read_exact(&mut reader.reader, &mut magic)?;
+        if magic == [0; MAGIC.len()] {
+            return Ok(reader);
+        }
+        if &magic != MAGIC {
+            return Err(TxError::StableMemoryError {
+                message: "The stable memory does not contain ic_tx data.".to_owned(),
+            });
+        }
+        let layout_version = u16::from_le_bytes(read_array(&mut reader.reader)?);
+        if layout_version != LAYOUT_VERSION {
+            return Err(TxError::StableMemoryError {
+                message: format!(
+                    "Unsupported stable memory layout version [{layout_version}], expected [{LAYOUT_VERSION}]."
+                ),
+            });
+        }
+        reader.remaining = u32::from_le_bytes(read_array(&mut reader.reader)?);
+        Ok(reader)
+    }
+
+    /// The number of sections not read yet.
+    pub fn remaining(&self) -> u32 {
+        self.remaining
+    }
+
+    /// Reads the next section: the closure receives its header and a reader of its snapshot,
+    /// that it must read to the end.
+    pub fn read_section_with<T>(
+        &mut self,
+        read_snapshot: impl FnOnce(&SectionHeader, &mut dyn Read) -> Result<T, TxError>,
+    ) -> Result<T, TxError> {
+        if self.remaining == 0 {
+            return Err(TxError::StableMemoryError {
+                message: "Cannot read a section, all the sections are read.".to_owned(),
+            });
+        }
+        self.remaining -= 1;
+        let header = SectionHeader {
+            name: read_string(&mut self.reader)?,
+            schema_tag: read_string(&mut self.reader)?,
+            len: u64::from_le_bytes(read_array(&mut self.reader)?),
+        };
+        let mut snapshot = (&mut self.reader).take(header.len);
+        let result = read_snapshot(&header, &mut snapshot)?;
+        if snapshot.limit() > 0 {
+            return Err(TxError::StableMemoryError {
+                message: format!("The section [{}] is corrupted.", header.name),
+            });
+        }
+        Ok(result)
+    }
+}
+
+fn read_exact<M: StableMemory>(
+    reader: &mut StableReader<M>,
+    buf: &mut [u8],
+) -> Result<(), TxError> {
+    reader.read_exact(buf).map_err(stable_memory_error)
+}
+
+fn read_array<M: StableMemory, const N: usize>(
+    reader: &mut StableReader<M>,
+) -> Result<[u8; N], TxError> {
+    let mut buf = [0; N];
+    read_exact(reader, &mut buf)?;
+    Ok(buf)
+}
+
+fn read_string<M: StableMemory>(reader: &mut StableReader<M>) -> Result<String, TxError> {
+    let len = u32::from_le_bytes(read_array(reader)?);
+    let mut buf = vec![0; len as usize];
+    read_exact(reader, &mut buf)?;
+    String::from_utf8(buf).map_err(stable_memory_error)
+}
+
+fn stable_memory_error(err: impl std::fmt::Display) -> TxError {
+    TxError::StableMemoryError {
+        message: format!("Cannot access the stable memory. {err}"),
+    }
+}
+
+impl<Data, B: Backend<Data>> IcTx<Data, B>
+where
+    Data: CandidType + DeserializeOwned,
+    B::IdType: CandidType + DeserializeOwned,
+    B::VersionType: CandidType + DeserializeOwned,
+{
+    /// Exports the database into a section to be written to the stable memory.
+    pub fn to_stable_section(
+        &self,
+        name: &str,
+        schema_tag: &str,
+    ) -> Result<StableSection, TxError> {
+        Ok(StableSection {
+            name: name.to_owned(),
+            schema_tag: schema_tag.to_owned(),
+            snapshot: self.export_snapshot()?,
+        })
+    }
+
+    /// Encodes the database straight into the next section of the writer.
+    pub fn write_stable_section(
+        &self,
+        writer: &mut SectionWriter<'_>,
+        name: &str,
+        schema_tag: &str,
+    ) -> Result<(), TxError> {
+        writer.write_section_with(name, schema_tag, |writer| self.write_snapshot(writer))
+    }
+
+    /// Replaces the content of the database with the one of the section.
+    /// Fails without changing the database if the schema tag of the section differs from the expected one.
+    pub fn restore_from_stable_section(
+        &self,
+        section: &StableSection,
+        schema_tag: &str,
+    ) -> Result<(), TxError> {
+        section.check_schema_tag(schema_tag)?;
+        self.import_snapshot(&section.snapshot)
+    }
+
+    /// Saves the database to the stable memory of the canister, from its start.
+    /// It is meant to be called from the `pre_upgrade` hook, if the canister keeps nothing else in the stable memory.
+    pub fn save_to_stable_memory(&self, schema_tag: &str) -> Result<(), TxError> {
+        self.save_to_memory(CanisterStableMemory::default(), 0, schema_tag)
+            .map(|_| ())
+    }
+
+    /// Restores the database saved by `save_to_stable_memory`.
+    /// It is meant to be called from the `post_upgrade` hook.
+    pub fn restore_from_stable_memory(&self, schema_tag: &str) -> Result<(), TxError> {
+        self.restore_from_memory(CanisterStableMemory::default(), 0, schema_tag)
+    }
+
+    /// Saves the database to the specified memory from the offset, and returns the offset where it ends.
+    /// See `write_sections` for the region it occupies.
+    pub fn save_to_memory<M: StableMemory>(
+        &self,
+        memory: M,
+        offset: u64,
+        schema_tag: &str,
+    ) -> Result<u64, TxError> {
+        let mut writer = SectionWriter::new(memory, offset, 1)?;
+        self.write_stable_section(&mut writer, "", schema_tag)?;
+        writer.finish()
+    }
+
+    /// Restores the database saved to the specified memory at the offset.
+    /// The database is left unchanged if nothing was saved there.
+    /// The snapshot is decoded straight from the memory and checked first, then read again to restore the database
+    /// in batches, so the database is changed only once the snapshot is checked.
+    pub fn restore_from_memory<M: StableMemory + Clone>(
+        &self,
+        memory: M,
+        offset: u64,
+        schema_tag: &str,
+    ) -> Result<(), TxError> {
+        let mut reader = SectionReader::new(memory.clone(), offset)?;
+        match reader.remaining() {
+            0 => Ok(()),
+            1 => {
+                let tail = reader.read_section_with(|header, snapshot| {
+                    header.check_schema_tag(schema_tag)?;
+                    self.check_snapshot(snapshot)
+                })?;
+                SectionReader::new(memory, offset)?
+                    .read_section_with(|_, snapshot| self.restore_snapshot(snapshot, tail))
+            }
+            count => Err(TxError::StableMemoryError {
+                message: format!(
+                    "Expected a single section in the stable memory, found [{count}]."
+                ),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+
+    use ic_cdk::api::stable::StableMemoryError;
+
+    use super::*;
+    use crate::{
+        backend::hashmap::HashmapBackend, model::NewModel, snapshot::DEFAULT_CHUNK_SIZE,
+        test_utils::new_db, Ref, RefCell,
+    };
+
+    const PAGE_SIZE: usize = 64 * 1024;
+
+    #[derive(Clone, Default)]
+    pub(crate) struct VecMemory(Ref<RefCell<Vec<u8>>>);
+
+    impl StableMemory for VecMemory {
+        fn stable_size(&self) -> u64 {
+            (self.0.borrow().len() / PAGE_SIZE) as u64
+        }
+
+        fn stable_grow(&self, new_pages: u64) -> Result<u64, StableMemoryError> {
+            let previous = self.stable_size();
+            let new_len = self.0.borrow().len() + new_pages as usize * PAGE_SIZE;
+            self.0.borrow_mut().resize(new_len, 0);
+            Ok(previous)
+        }
+
+        fn stable_write(&self, offset: u64, buf: &[u8]) {
+            let offset = offset as usize;
+            self.0.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf);
+        }
+
+        fn stable_read(&self, offset: u64, buf: &mut [u8]) {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.0.borrow()[offset..offset + buf.len()]);
+        }
+    }
+
+    type Db = IcTx<String, HashmapBackend<u32, String>>;
+
+    #[test]
+    fn db_should_survive_a_round_trip_to_stable_memory() {
+        // Arrange
+        let db: Db = new_db();
+        let restored_db: Db = new_db();
+        let memory = VecMemory::default();
+        let mut tx = db.tx();
+        for id in 0..1000 {
+            tx.save(NewModel::new(id, "x".repeat(2000))).unwrap();
+        }
+        tx.commit();
+
+        // Act
+        db.save_to_memory(memory.clone(), 0, "v1").unwrap();
+        restored_db
+            .restore_from_memory(memory.clone(), 0, "v1")
+            .unwrap();
+
+        // Assert
+        assert!(memory.0.borrow().len() > DEFAULT_CHUNK_SIZE);
+        for id in 0..1000 {
+            assert_eq!(
+                db.fetch_option_one(&id).unwrap(),
+                restored_db.fetch_option_one(&id).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn streamed_sections_should_match_the_encoded_ones() {
+        // Arrange
+        let db: Db = new_db();
+        let streamed = VecMemory::default();
+        let encoded = VecMemory::default();
+        let mut tx = db.tx();
+        for id in 0..10 {
+            tx.save(NewModel::new(id, format!("data_{id}"))).unwrap();
+        }
+        tx.commit();
+
+        // Act
+        let mut writer = SectionWriter::new(streamed.clone(), 0, 2).unwrap();
+        db.write_stable_section(&mut writer, "first", "v1").unwrap();
+        db.write_stable_section(&mut writer, "second", "v1")
+            .unwrap();
+        writer.finish().unwrap();
+        write_sections(
+            encoded.clone(),
+            0,
+            &[
+                db.to_stable_section("first", "v1").unwrap(),
+                db.to_stable_section("second", "v1").unwrap(),
+            ],
+        )
+        .unwrap();
+
+        // Assert
+        assert_eq!(*encoded.0.borrow(), *streamed.0.borrow());
+        assert_eq!(2, read_sections(streamed, 0).unwrap().len());
+    }
+
+    #[test]
+    fn section_writer_should_fail_if_sections_are_missing() {
+        // Arrange
+        let db: Db = new_db();
+        let mut writer = SectionWriter::new(VecMemory::default(), 0, 2).unwrap();
+        db.write_stable_section(&mut writer, "first", "v1").unwrap();
+
+        // Act
+        let result = writer.finish();
+
+        // Assert
+        assert!(matches!(result, Err(TxError::StableMemoryError { .. })));
+    }
+
+    #[test]
+    fn restore_should_fail_if_schema_tag_differs() {
+        // Arrange
+        let db: Db = new_db();
+        let memory = VecMemory::default();
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, "data".to_owned())).unwrap();
+        tx.commit();
+        db.save_to_memory(memory.clone(), 0, "v1").unwrap();
+        let restored_db: Db = new_db();
+
+        // Act
+        let result = restored_db.restore_from_memory(memory, 0, "v2");
+
+        // Assert
+        assert!(matches!(result, Err(TxError::StableMemoryError { .. })));
+        assert!(restored_db.fetch_option_one(&1).unwrap().is_none());
+    }
+
+    #[test]
+    fn restore_should_do_nothing_if_memory_is_empty() {
+        // Arrange
+        let db: Db = new_db();
+
+        // Act
+        let result = db.restore_from_memory(VecMemory::default(), 0, "v1");
+
+        // Assert
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn read_should_fail_if_memory_contains_other_data() {
+        // Arrange
+        let memory = VecMemory::default();
+        memory.stable_grow(1).unwrap();
+        memory.stable_write(0, b"something else");
+
+        // Act
+        let result = read_sections(memory, 0);
+
+        // Assert
+        assert!(matches!(result, Err(TxError::StableMemoryError { .. })));
+    }
+
+    #[test]
+    fn sections_should_be_written_and_read_in_order() {
+        // Arrange
+        let memory = VecMemory::default();
+        let sections = vec![
+            StableSection {
+                name: "users".to_owned(),
+                schema_tag: "v1".to_owned(),
+                snapshot: Snapshot::from_bytes(vec![1, 2, 3]),
+            },
+            StableSection {
+                name: "orders".to_owned(),
+                schema_tag: "v2".to_owned(),
+                snapshot: Snapshot::default(),
+            },
+        ];
+
+        // Act
+        write_sections(memory.clone(), 0, &sections).unwrap();
+        let result = read_sections(memory, 0).unwrap();
+
+        // Assert
+        assert_eq!(sections, result);
+    }
+
+    #[test]
+    fn sections_should_be_written_only_in_their_region() {
+        // Arrange
+        let db: Db = new_db();
+        let restored_db: Db = new_db();
+        let memory = VecMemory::default();
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, "data".to_owned())).unwrap();
+        tx.commit();
+        let offset = PAGE_SIZE as u64 + 10;
+        memory.stable_grow(3).unwrap();
+        memory.stable_write(0, &[7; PAGE_SIZE + 10]);
+        memory.stable_write(2 * PAGE_SIZE as u64, &[9; PAGE_SIZE]);
+
+        // Act
+        let end = db.save_to_memory(memory.clone(), offset, "v1").unwrap();
+        let at_start = read_sections(memory.clone(), 0);
+        restored_db
+            .restore_from_memory(memory.clone(), offset, "v1")
+            .unwrap();
+
+        // Assert
+        let bytes = memory.0.borrow().clone();
+        assert!(bytes[..offset as usize].iter().all(|byte| *byte == 7));
+        assert_eq!(
+            MAGIC,
+            &bytes[offset as usize..offset as usize + MAGIC.len()]
+        );
+        assert!(end < 2 * PAGE_SIZE as u64);
+        assert!(bytes[2 * PAGE_SIZE..].iter().all(|byte| *byte == 9));
+        assert!(matches!(at_start, Err(TxError::StableMemoryError { .. })));
+        assert_eq!("data", restored_db.fetch_one(&1).unwrap().data);
+    }
+
+    #[test]
+    fn read_should_return_no_sections_if_nothing_was_written_at_the_offset() {
+        // Arrange
+        let memory = VecMemory::default();
+        memory.stable_grow(1).unwrap();
+        memory.stable_write(0, b"other data");
+
+        // Act
+        let zeroed = read_sections(memory.clone(), 100).unwrap();
+        let beyond_the_end = read_sections(memory, 2 * PAGE_SIZE as u64).unwrap();
+
+        // Assert
+        assert!(zeroed.is_empty());
+        assert!(beyond_the_end.is_empty());
+    }
+
+    #[test]
+    fn restore_should_fail_if_a_section_has_bytes_after_the_snapshot() {
+        // Arrange
+        let db: Db = new_db();
+        let memory = VecMemory::default();
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, "data".to_owned())).unwrap();
+        tx.commit();
+        let mut bytes = db.export_snapshot().unwrap().into_bytes();
+        bytes.push(0);
+        write_sections(
+            memory.clone(),
+            0,
+            &[StableSection {
+                name: "".to_owned(),
+                schema_tag: "v1".to_owned(),
+                snapshot: Snapshot::from_bytes(bytes),
+            }],
+        )
+        .unwrap();
+        let restored_db: Db = new_db();
+
+        // Act
+        let result = restored_db.restore_from_memory(memory, 0, "v1");
+
+        // Assert
+        assert!(matches!(result, Err(TxError::StableMemoryError { .. })));
+        assert!(restored_db.fetch_option_one(&1).unwrap().is_none());
+    }
+}
@@ -0,0 +1,8 @@
+//! The helpers shared by the tests of the modules.
+
+use crate::{backend::Backend, db::IcTx, Ref, RefCell};
+
+/// Returns an empty database over a new backend, that each test configures with the `with_*` methods.
+pub(crate) fn new_db<Data, B: Backend<Data> + Default>() -> IcTx<Data, B> {
+    IcTx::new(Ref::new(RefCell::new(B::default())))
+}
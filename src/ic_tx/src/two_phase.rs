@@ -0,0 +1,1361 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    time::Duration,
+};
+
+use crate::{
+    backend::Backend,
+    clock::{default_clock, Clock},
+    db::IcTx,
+    error::TxError,
+    model::NewModel,
+    shared::shared,
+    tx::{Action, PreparedTx, Tx, TxAction, COMMIT_PANIC_MESSAGE},
+    Cell, Ref, RefCell, Shareable,
+};
+
+/// Identifies a distributed transaction across all its participants.
+pub type TxId = String;
+
+/// A boxed future, used by the branches so they can be stored as trait objects.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// How long the coordinator waits for the prepare of the participants if no timeout is specified.
+pub const DEFAULT_PREPARE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The number of resolved transactions remembered by a participant to answer repeated commits and aborts.
+const RESOLVED_CAPACITY: usize = 1024;
+
+/// The outcome of a distributed transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "candid", derive(candid::CandidType))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Decision {
+    Commit,
+    Abort,
+}
+
+/// The changes of a distributed transaction that a single participant has to apply.
+/// All the methods must be idempotent, as the coordinator repeats them when recovering.
+pub trait Branch {
+    /// Validates the changes and holds them, with the locks on their ids, until the commit or the abort.
+    /// After the timeout the coordinator has decided, so the participant can ask for the decision.
+    fn prepare<'a>(
+        &'a self,
+        tx_id: &'a str,
+        timeout: Duration,
+    ) -> BoxFuture<'a, Result<(), TxError>>;
+
+    /// Applies the prepared changes.
+    fn commit<'a>(&'a self, tx_id: &'a str) -> BoxFuture<'a, Result<(), TxError>>;
+
+    /// Discards the prepared changes.
+    fn abort<'a>(&'a self, tx_id: &'a str) -> BoxFuture<'a, Result<(), TxError>>;
+}
+
+struct Prepared<Data, B: Backend<Data>> {
+    tx: PreparedTx<Data, B>,
+    expires_at: u64,
+}
+
+/// The persisted state of a transaction prepared by a participant, see `Participant::with_log`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "candid", derive(candid::CandidType))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParticipantRecord<IdType, Data, V> {
+    /// The prepared actions, removed once the transaction is resolved
+    pub actions: Vec<Action<IdType, Data, V>>,
+    /// The time after which the decision can be asked to the coordinator
+    pub expires_at: u64,
+    /// The decision, once the transaction is resolved
+    pub decision: Option<Decision>,
+}
+
+type LogRecord<Data, B> =
+    ParticipantRecord<<B as Backend<Data>>::IdType, Data, <B as Backend<Data>>::VersionType>;
+
+type LoggedTx<Data, B> = (TxId, LogRecord<Data, B>);
+
+/// The store of the participant records, whatever the type of its backend.
+trait ParticipantLog<Data, B: Backend<Data>> {
+    fn save(&self, tx_id: &str, record: LogRecord<Data, B>) -> Result<(), TxError>;
+
+    fn remove(&self, tx_id: &str) -> Result<(), TxError>;
+
+    fn all(&self) -> Result<Vec<LoggedTx<Data, B>>, TxError>;
+}
+
+impl<Data, B, LB> ParticipantLog<Data, B> for IcTx<LogRecord<Data, B>, LB>
+where
+    Data: Clone + 'static,
+    B: Backend<Data>,
+    B::IdType: 'static,
+    LB: Backend<LogRecord<Data, B>, IdType = TxId> + 'static,
+{
+    fn save(&self, tx_id: &str, record: LogRecord<Data, B>) -> Result<(), TxError> {
+        let tx_id = tx_id.to_owned();
+        let mut tx = self.tx();
+        match tx.fetch_option_one(&tx_id)? {
+            Some(mut model) => {
+                model.data = record;
+                tx.update(model)?;
+            }
+            None => tx.save(NewModel::new(tx_id, record))?,
+        }
+        tx.try_commit()
+    }
+
+    fn remove(&self, tx_id: &str) -> Result<(), TxError> {
+        let mut tx = self.tx();
+        if let Some(model) = tx.fetch_option_one(&tx_id.to_owned())? {
+            tx.delete(model)?;
+        }
+        tx.try_commit()
+    }
+
+    fn all(&self) -> Result<Vec<LoggedTx<Data, B>>, TxError> {
+        Ok(self
+            .query()
+            .fetch()?
+            .into_iter()
+            .map(|model| (model.id, model.data))
+            .collect())
+    }
+}
+
+/// The participant side of the two-phase commit protocol for a database.
+///
+/// A prepared transaction holds the locks on the ids it changes until it is committed or aborted.
+/// The prepare runs all the checks of a commit, e.g. the validators, the global checks and the references,
+/// and computes the cascades of the deletes, so the commit only writes the checked changes.
+/// The ids changed by the cascades are locked too, in the referencing collections with a lock manager.
+/// Once prepared, the participant never aborts it on its own and its locks do not expire,
+/// as the coordinator might have decided to commit: if the decision does not arrive before the timeout
+/// sent by the coordinator with the prepare, e.g. because the coordinator trapped,
+/// the participant asks the coordinator for it with `recover_with` (cooperative termination).
+/// If the coordinator is lost for good, an administrator can resolve the timed out transactions with `resolve_manually`.
+/// The database must have the lock manager enabled.
+///
+/// The prepared and the resolved transactions are kept on the heap, so they are lost by an upgrade,
+/// unless they are recorded in a log with `with_log`, e.g. a collection saved to the stable memory:
+/// `restore` prepares them again after the upgrade. Without a log, a canister should be upgraded only
+/// when `in_doubt` is empty, and a coordinator that repeats the commit of a transaction resolved before
+/// the upgrade gets an error.
+pub struct Participant<Data, B: Backend<Data>> {
+    db: IcTx<Data, B>,
+    prepared: Ref<RefCell<HashMap<TxId, Prepared<Data, B>>>>,
+    resolved: Ref<RefCell<VecDeque<(TxId, Decision)>>>,
+    log: Option<Ref<shared!(ParticipantLog<Data, B>)>>,
+}
+
+impl<Data, B: Backend<Data>> Clone for Participant<Data, B> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            prepared: self.prepared.clone(),
+            resolved: self.resolved.clone(),
+            log: self.log.clone(),
+        }
+    }
+}
+
+impl<Data: Clone + 'static, B: Backend<Data> + 'static> Participant<Data, B> {
+    pub fn new(db: IcTx<Data, B>) -> Self {
+        Self {
+            db,
+            prepared: Default::default(),
+            resolved: Default::default(),
+            log: None,
+        }
+    }
+
+    /// Records the prepared and the resolved transactions in the store, so they survive an upgrade
+    /// if the store is saved to the stable memory, see `restore`.
+    pub fn with_log<LB>(
+        mut self,
+        store: IcTx<ParticipantRecord<B::IdType, Data, B::VersionType>, LB>,
+    ) -> Self
+    where
+        LB: Backend<ParticipantRecord<B::IdType, Data, B::VersionType>, IdType = TxId>,
+        IcTx<ParticipantRecord<B::IdType, Data, B::VersionType>, LB>: Shareable + 'static,
+    {
+        self.log = Some(Ref::new(store));
+        self
+    }
+
+    /// Prepares again the transactions recorded in the log, e.g. after an upgrade,
+    /// and remembers the resolved ones, so the coordinator can repeat its decisions.
+    /// The prepared transactions are checked again, so the database must be restored first.
+    pub fn restore(&self) -> Result<(), TxError> {
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+        let mut records = log.all()?;
+        records.sort_by_key(|(_, record)| record.expires_at);
+        let now = (self.db.clock)();
+        for (tx_id, record) in records {
+            match record.decision {
+                Some(decision) => self.remember(&tx_id, decision)?,
+                None if self.prepared.borrow().contains_key(&tx_id) => (),
+                None => {
+                    let tx = prepare_tx(self.db.tx(), record.actions, now)?;
+                    self.prepared.borrow_mut().insert(
+                        tx_id,
+                        Prepared {
+                            tx,
+                            expires_at: record.expires_at,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the database of the participant.
+    pub fn db(&self) -> &IcTx<Data, B> {
+        &self.db
+    }
+
+    /// Returns a branch that applies the changes of the transaction through this participant.
+    pub fn branch(&self, tx: Tx<Data, B>) -> LocalBranch<Data, B> {
+        LocalBranch {
+            participant: self.clone(),
+            actions: tx.into_actions(),
+        }
+    }
+
+    /// Validates the actions and locks their ids until the commit or the abort.
+    /// The timeout is the time after which the decision can be asked to the coordinator, see `timed_out`.
+    /// Preparing an already prepared transaction does nothing.
+    pub fn prepare(
+        &self,
+        tx_id: &str,
+        actions: Vec<TxAction<Data, B>>,
+        timeout: Duration,
+    ) -> Result<(), TxError> {
+        if self.prepared.borrow().contains_key(tx_id) {
+            return Ok(());
+        }
+        if let Some(decision) = self.resolution(tx_id) {
+            return Err(two_phase_error(
+                tx_id,
+                format!("It is already resolved with decision [{decision:?}]."),
+            ));
+        }
+
+        let now = (self.db.clock)();
+        let logged_actions = self.log.as_ref().map(|_| actions.clone());
+        let tx = prepare_tx(self.db.tx(), actions, now)?;
+        let expires_at = now.saturating_add(timeout.as_nanos().try_into().unwrap_or(u64::MAX));
+        if let (Some(log), Some(actions)) = (&self.log, logged_actions) {
+            // The prepared transaction is dropped if it cannot be recorded, which releases its locks
+            log.save(
+                tx_id,
+                ParticipantRecord {
+                    actions,
+                    expires_at,
+                    decision: None,
+                },
+            )?;
+        }
+        self.prepared
+            .borrow_mut()
+            .insert(tx_id.to_owned(), Prepared { tx, expires_at });
+        Ok(())
+    }
+
+    /// Applies a prepared transaction.
+    /// The changes and their cascades were checked by the prepare and their ids are locked since then,
+    /// so they are written without checking them again, as the other participants commit theirs.
+    /// The coordinator decided to commit, so the transaction can no longer be aborted:
+    /// a write that fails anyway panics, which on the IC traps, reverts the partial writes
+    /// and keeps the transaction prepared, so the commit can be repeated.
+    /// Committing an already committed transaction does nothing.
+    pub fn commit(&self, tx_id: &str) -> Result<(), TxError> {
+        let prepared = self.prepared.borrow_mut().remove(tx_id);
+        match prepared {
+            Some(prepared) => {
+                prepared
+                    .tx
+                    .commit((self.db.clock)())
+                    .and_then(|()| self.resolve_as(tx_id, Decision::Commit, prepared.expires_at))
+                    .expect(COMMIT_PANIC_MESSAGE);
+                Ok(())
+            }
+            None => match self.resolution(tx_id) {
+                Some(Decision::Commit) => Ok(()),
+                Some(Decision::Abort) => Err(two_phase_error(tx_id, "It was aborted.".to_owned())),
+                None => Err(two_phase_error(tx_id, "It is not prepared.".to_owned())),
+            },
+        }
+    }
+
+    /// Discards a prepared transaction and releases its locks.
+    /// Aborting an unknown or already aborted transaction does nothing.
+    pub fn abort(&self, tx_id: &str) -> Result<(), TxError> {
+        let prepared = self.prepared.borrow_mut().remove(tx_id);
+        match prepared {
+            Some(prepared) => {
+                prepared.tx.rollback();
+                self.resolve_as(tx_id, Decision::Abort, prepared.expires_at)
+            }
+            None => match self.resolution(tx_id) {
+                Some(Decision::Commit) => Err(two_phase_error(
+                    tx_id,
+                    "It is already committed.".to_owned(),
+                )),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Applies the decision of the coordinator to a prepared transaction.
+    pub fn resolve(&self, tx_id: &str, decision: Decision) -> Result<(), TxError> {
+        match decision {
+            Decision::Commit => self.commit(tx_id),
+            Decision::Abort => self.abort(tx_id),
+        }
+    }
+
+    /// Returns the prepared transactions that are waiting for the decision of the coordinator.
+    pub fn in_doubt(&self) -> Vec<TxId> {
+        let mut tx_ids: Vec<_> = self.prepared.borrow().keys().cloned().collect();
+        tx_ids.sort();
+        tx_ids
+    }
+
+    /// Returns the prepared transactions whose timeout is elapsed without a decision.
+    /// The coordinator has decided them by now, so they should be resolved with `recover_with`.
+    pub fn timed_out(&self) -> Vec<TxId> {
+        let now = (self.db.clock)();
+        let mut tx_ids: Vec<_> = self
+            .prepared
+            .borrow()
+            .iter()
+            .filter(|(_, prepared)| prepared.expires_at <= now)
+            .map(|(tx_id, _)| tx_id.clone())
+            .collect();
+        tx_ids.sort();
+        tx_ids
+    }
+
+    /// Resolves the in-doubt transactions with the decisions of the coordinator,
+    /// e.g. `Coordinator::decision` when the coordinator lives in the same canister.
+    /// The transactions still being prepared by the coordinator are left in doubt.
+    pub fn recover_with(&self, decision: impl Fn(&str) -> Option<Decision>) -> Result<(), TxError> {
+        for tx_id in self.in_doubt() {
+            if let Some(decision) = decision(&tx_id) {
+                self.resolve(&tx_id, decision)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves a timed out transaction without the coordinator, e.g. when it was deleted or never replies.
+    /// It is meant to be exposed only to the administrators of the canister, as the decision might differ
+    /// from the one of the coordinator: the outcome should be reconciled with the other participants by hand.
+    /// Returns an error if the transaction is not timed out, as the coordinator might still decide it.
+    pub fn resolve_manually(&self, tx_id: &str, decision: Decision) -> Result<(), TxError> {
+        if !self.timed_out().iter().any(|timed_out| timed_out == tx_id) {
+            return Err(two_phase_error(
+                tx_id,
+                "It is not a prepared transaction whose timeout is elapsed.".to_owned(),
+            ));
+        }
+        self.resolve(tx_id, decision)
+    }
+
+    fn resolution(&self, tx_id: &str) -> Option<Decision> {
+        self.resolved
+            .borrow()
+            .iter()
+            .find(|(resolved_id, _)| resolved_id == tx_id)
+            .map(|(_, decision)| *decision)
+    }
+
+    fn resolve_as(&self, tx_id: &str, decision: Decision, expires_at: u64) -> Result<(), TxError> {
+        if let Some(log) = &self.log {
+            log.save(
+                tx_id,
+                ParticipantRecord {
+                    actions: vec![],
+                    expires_at,
+                    decision: Some(decision),
+                },
+            )?;
+        }
+        self.remember(tx_id, decision)
+    }
+
+    fn remember(&self, tx_id: &str, decision: Decision) -> Result<(), TxError> {
+        let mut resolved = self.resolved.borrow_mut();
+        if resolved.len() == RESOLVED_CAPACITY {
+            if let (Some((forgotten, _)), Some(log)) = (resolved.pop_front(), &self.log) {
+                log.remove(&forgotten)?;
+            }
+        }
+        resolved.push_back((tx_id.to_owned(), decision));
+        Ok(())
+    }
+}
+
+/// Runs all the checks of the commit, so the commit can only write the changes.
+/// The locks are held until the decision, and the failed prepare releases them.
+fn prepare_tx<Data: 'static, B: Backend<Data> + 'static>(
+    mut tx: Tx<Data, B>,
+    actions: Vec<TxAction<Data, B>>,
+    now: u64,
+) -> Result<PreparedTx<Data, B>, TxError> {
+    for action in actions {
+        tx.lock(action.id(), Duration::MAX)?;
+        tx.push(action)?;
+    }
+    tx.prepare_commit(now)
+}
+
+/// A branch applied by a participant living in the same canister as the coordinator.
+pub struct LocalBranch<Data, B: Backend<Data>> {
+    participant: Participant<Data, B>,
+    actions: Vec<TxAction<Data, B>>,
+}
+
+impl<Data: Clone + 'static, B: Backend<Data> + 'static> Branch for LocalBranch<Data, B> {
+    fn prepare<'a>(
+        &'a self,
+        tx_id: &'a str,
+        timeout: Duration,
+    ) -> BoxFuture<'a, Result<(), TxError>> {
+        let result = self
+            .participant
+            .prepare(tx_id, self.actions.clone(), timeout);
+        Box::pin(async move { result })
+    }
+
+    fn commit<'a>(&'a self, tx_id: &'a str) -> BoxFuture<'a, Result<(), TxError>> {
+        let result = self.participant.commit(tx_id);
+        Box::pin(async move { result })
+    }
+
+    fn abort<'a>(&'a self, tx_id: &'a str) -> BoxFuture<'a, Result<(), TxError>> {
+        let result = self.participant.abort(tx_id);
+        Box::pin(async move { result })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Preparing,
+    Committed,
+}
+
+struct LogEntry {
+    phase: Phase,
+    // After this time a transaction still being prepared can only be aborted
+    expires_at: u64,
+    // The branches that have not yet acknowledged the decision
+    pending: Vec<Ref<shared!(Branch)>>,
+}
+
+/// The coordinator side of the two-phase commit protocol.
+///
+/// It logs every transaction before sending the prepare and records the commit decision
+/// before broadcasting it, so `recover` can complete the transactions interrupted by a trap.
+/// A transaction not decided within the timeout is aborted, and unknown transactions are presumed aborted.
+///
+/// The log is kept on the heap, so it is lost by an upgrade, and with it the commit decisions
+/// not yet acknowledged by all the participants: a canister should be upgraded only when `in_doubt` is empty,
+/// e.g. trapping in `pre_upgrade` otherwise, which makes the upgrade fail and keeps the log.
+#[derive(Clone)]
+pub struct Coordinator {
+    name: String,
+    next_id: Ref<Cell<u64>>,
+    log: Ref<RefCell<BTreeMap<TxId, LogEntry>>>,
+    clock: Clock,
+    timeout: Duration,
+}
+
+impl Coordinator {
+    /// Creates a coordinator. The name is the prefix of the ids of its transactions,
+    /// e.g. the principal of the canister, and must be unique among the coordinators of the participants.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            next_id: Default::default(),
+            log: Default::default(),
+            clock: default_clock(),
+            timeout: DEFAULT_PREPARE_TIMEOUT,
+        }
+    }
+
+    /// Replaces the clock used to generate the transaction ids.
+    pub fn with_clock(mut self, clock: impl Fn() -> u64 + Shareable + 'static) -> Self {
+        self.clock = Ref::new(clock);
+        self
+    }
+
+    /// Sets how long the coordinator waits for the prepare of the participants before aborting.
+    /// It is also the time after which the participants can ask for the decision.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Starts a new distributed transaction
+    pub fn tx(&self) -> DistributedTx {
+        let id = self.next_id.get() + 1;
+        self.next_id.set(id);
+        DistributedTx {
+            id: format!("{}-{}-{id}", self.name, (self.clock)()),
+            coordinator: self.clone(),
+            branches: vec![],
+            prepared: false,
+        }
+    }
+
+    /// Returns the decision on the transaction, to let an in-doubt participant resolve it.
+    /// Returns `None` while the transaction is being prepared within its timeout.
+    pub fn decision(&self, tx_id: &str) -> Option<Decision> {
+        let now = (self.clock)();
+        match self.log.borrow().get(tx_id) {
+            Some(entry) if entry.phase == Phase::Committed => Some(Decision::Commit),
+            Some(entry) if entry.expires_at > now => None,
+            // A transaction not committed within the timeout is never committed
+            _ => Some(Decision::Abort),
+        }
+    }
+
+    /// Returns the transactions whose decision is not yet acknowledged by all the participants.
+    pub fn in_doubt(&self) -> Vec<TxId> {
+        self.log.borrow().keys().cloned().collect()
+    }
+
+    /// Completes the transactions interrupted by a trap or by a failure of the participants.
+    /// The committed ones are committed again on the pending participants,
+    /// the ones not committed within the timeout are aborted.
+    /// The transactions still being prepared within the timeout are left alone, as their commit might be waiting
+    /// for the replies of the participants.
+    /// It is meant to be called periodically, e.g. from a timer.
+    pub async fn recover(&self) -> Result<(), TxError> {
+        let mut result = Ok(());
+        for tx_id in self.in_doubt() {
+            let now = (self.clock)();
+            let (phase, expires_at) = match self.log.borrow().get(&tx_id) {
+                Some(entry) => (entry.phase, entry.expires_at),
+                None => continue,
+            };
+            let outcome = match phase {
+                Phase::Preparing if expires_at > now => continue,
+                Phase::Preparing => self.abort(&tx_id).await,
+                Phase::Committed => self.broadcast_commit(&tx_id).await,
+            };
+            if outcome.is_err() && result.is_ok() {
+                result = outcome;
+            }
+        }
+        result
+    }
+
+    async fn abort(&self, tx_id: &str) -> Result<(), TxError> {
+        let branches = self.pending(tx_id);
+        let mut result = Ok(());
+        for branch in branches {
+            if let Err(err) = branch.abort(tx_id).await {
+                result = Err(err);
+            }
+        }
+        // The participants that did not receive the abort ask for the decision after the timeout,
+        // and an unknown transaction is presumed aborted, so they release the locks then
+        self.log.borrow_mut().remove(tx_id);
+        result
+    }
+
+    async fn broadcast_commit(&self, tx_id: &str) -> Result<(), TxError> {
+        for branch in self.pending(tx_id) {
+            if branch.commit(tx_id).await.is_ok() {
+                if let Some(entry) = self.log.borrow_mut().get_mut(tx_id) {
+                    entry
+                        .pending
+                        .retain(|pending| !Ref::ptr_eq(pending, &branch));
+                }
+            }
+        }
+
+        let mut log = self.log.borrow_mut();
+        let pending = log.get(tx_id).map_or(0, |entry| entry.pending.len());
+        if pending == 0 {
+            log.remove(tx_id);
+            Ok(())
+        } else {
+            Err(two_phase_error(
+                tx_id,
+                format!("It is committed but [{pending}] participants did not acknowledge it yet. The commit is sent to them again by `recover`, and they can ask for the decision."),
+            ))
+        }
+    }
+
+    fn pending(&self, tx_id: &str) -> Vec<Ref<shared!(Branch)>> {
+        self.log
+            .borrow()
+            .get(tx_id)
+            .map(|entry| entry.pending.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// A transaction whose changes are applied atomically by several participants.
+pub struct DistributedTx {
+    id: TxId,
+    coordinator: Coordinator,
+    branches: Vec<Ref<shared!(Branch)>>,
+    prepared: bool,
+}
+
+impl DistributedTx {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Adds the changes of a participant to the transaction.
+    pub fn enlist(&mut self, branch: impl Branch + Shareable + 'static) {
+        self.branches.push(Ref::new(branch));
+    }
+
+    /// Step 1: sends the prepare to the participants, in the order they were enlisted.
+    /// If any of them fails, the transaction is aborted on all of them.
+    pub async fn prepare(&mut self) -> Result<(), TxError> {
+        if self.prepared {
+            return Ok(());
+        }
+        self.prepared = true;
+
+        // The transaction is logged before any participant holds it, so the recovery can find it
+        let timeout = self
+            .coordinator
+            .timeout
+            .as_nanos()
+            .try_into()
+            .unwrap_or(u64::MAX);
+        self.coordinator.log.borrow_mut().insert(
+            self.id.clone(),
+            LogEntry {
+                phase: Phase::Preparing,
+                expires_at: (self.coordinator.clock)().saturating_add(timeout),
+                pending: self.branches.clone(),
+            },
+        );
+        for branch in &self.branches {
+            if let Err(err) = branch.prepare(&self.id, self.coordinator.timeout).await {
+                // The abort errors are ignored, the prepared participants ask for the decision after the timeout
+                let _ = self.coordinator.abort(&self.id).await;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Commits the transaction. Panics if any error
+    pub async fn commit(self) {
+        self.try_commit().await.expect(COMMIT_PANIC_MESSAGE)
+    }
+
+    /// Step 2: records the commit decision and broadcasts it to the participants.
+    /// Returns an error and persists nothing if the prepare fails or ends after the timeout,
+    /// as the participants might have been told that the transaction is aborted.
+    /// If some participants do not acknowledge the commit, an error is returned
+    /// but the transaction is committed, and the recovery completes it.
+    pub async fn try_commit(mut self) -> Result<(), TxError> {
+        self.prepare().await?;
+        let now = (self.coordinator.clock)();
+        let decided = match self.coordinator.log.borrow_mut().get_mut(&self.id) {
+            Some(entry) if entry.expires_at > now => {
+                entry.phase = Phase::Committed;
+                true
+            }
+            _ => false,
+        };
+        if !decided {
+            let _ = self.coordinator.abort(&self.id).await;
+            return Err(two_phase_error(
+                &self.id,
+                "It is aborted because the prepare did not complete within the timeout.".to_owned(),
+            ));
+        }
+        self.coordinator.broadcast_commit(&self.id).await
+    }
+
+    /// Aborts the transaction on the participants that prepared it.
+    pub async fn rollback(self) -> Result<(), TxError> {
+        self.coordinator.abort(&self.id).await
+    }
+}
+
+fn two_phase_error(tx_id: &str, message: String) -> TxError {
+    TxError::TwoPhaseCommitError {
+        tx_id: tx_id.to_owned(),
+        message,
+    }
+}
+
+#[cfg(feature = "candid")]
+pub use canister::*;
+
+#[cfg(feature = "candid")]
+mod canister {
+
+    use std::hash::Hash;
+
+    use candid::{CandidType, Principal};
+    use serde::de::DeserializeOwned;
+
+    use super::*;
+    use crate::{key::Key, model::Version, tx::Action};
+
+    /// The method of a participant canister that prepares a transaction.
+    /// It takes the transaction id, the `Vec<Action>` and the timeout in nanoseconds
+    /// and returns a `Result<(), TxError>`.
+    pub const PREPARE_METHOD: &str = "prepare";
+    /// The method of a participant canister that commits a prepared transaction.
+    /// It takes the transaction id and returns a `Result<(), TxError>`.
+    pub const COMMIT_PREPARED_METHOD: &str = "commit_prepared";
+    /// The method of a participant canister that aborts a prepared transaction.
+    /// It takes the transaction id and returns a `Result<(), TxError>`.
+    pub const ABORT_PREPARED_METHOD: &str = "abort_prepared";
+    /// The method of a coordinator canister that returns the decision on a transaction.
+    /// It takes the transaction id and returns an `Option<Decision>`.
+    pub const DECISION_METHOD: &str = "decision";
+
+    /// A branch applied by a participant canister.
+    pub struct RemoteBranch<IdType, Data, V> {
+        canister: Principal,
+        actions: Vec<Action<IdType, Data, V>>,
+    }
+
+    impl<IdType, Data, V> RemoteBranch<IdType, Data, V> {
+        pub fn new(canister: Principal, actions: Vec<Action<IdType, Data, V>>) -> Self {
+            Self { canister, actions }
+        }
+    }
+
+    impl<IdType, Data, V> RemoteBranch<IdType, Data, V>
+    where
+        IdType: CandidType,
+        Data: CandidType,
+        V: CandidType,
+    {
+        async fn call(
+            &self,
+            method: &str,
+            args: impl candid::utils::ArgumentEncoder,
+        ) -> Result<(), TxError> {
+            let (result,): (Result<(), TxError>,) = ic_cdk::call(self.canister, method, args)
+                .await
+                .map_err(|(code, message)| TxError::RemoteError {
+                    message: format!(
+                        "The call to [{method}] of [{}] failed with code [{code:?}]. {message}",
+                        self.canister
+                    ),
+                })?;
+            result
+        }
+    }
+
+    impl<IdType, Data, V> Branch for RemoteBranch<IdType, Data, V>
+    where
+        IdType: Key + Clone + Eq + Hash + CandidType + DeserializeOwned,
+        Data: CandidType + DeserializeOwned,
+        V: Version + CandidType + DeserializeOwned,
+    {
+        fn prepare<'a>(
+            &'a self,
+            tx_id: &'a str,
+            timeout: Duration,
+        ) -> BoxFuture<'a, Result<(), TxError>> {
+            let timeout = timeout.as_nanos().try_into().unwrap_or(u64::MAX);
+            Box::pin(self.call(PREPARE_METHOD, (tx_id, &self.actions, timeout)))
+        }
+
+        fn commit<'a>(&'a self, tx_id: &'a str) -> BoxFuture<'a, Result<(), TxError>> {
+            Box::pin(self.call(COMMIT_PREPARED_METHOD, (tx_id,)))
+        }
+
+        fn abort<'a>(&'a self, tx_id: &'a str) -> BoxFuture<'a, Result<(), TxError>> {
+            Box::pin(self.call(ABORT_PREPARED_METHOD, (tx_id,)))
+        }
+    }
+
+    /// Asks the coordinator canister for the decision on a transaction.
+    pub async fn fetch_decision(
+        coordinator: Principal,
+        tx_id: &str,
+    ) -> Result<Option<Decision>, TxError> {
+        let (decision,): (Option<Decision>,) = ic_cdk::call(coordinator, DECISION_METHOD, (tx_id,))
+            .await
+            .map_err(|(code, message)| TxError::RemoteError {
+                message: format!("The decision fetch failed with code [{code:?}]. {message}"),
+            })?;
+        Ok(decision)
+    }
+
+    impl<Data: Clone + 'static, B: Backend<Data> + 'static> Participant<Data, B> {
+        /// Resolves the in-doubt transactions asking the coordinator canister for their decision,
+        /// as `recover_with` does for a local coordinator.
+        /// The transactions still being prepared by the coordinator are left in doubt.
+        pub async fn recover(&self, coordinator: Principal) -> Result<(), TxError> {
+            for tx_id in self.in_doubt() {
+                if let Some(decision) = fetch_decision(coordinator, &tx_id).await? {
+                    self.resolve(&tx_id, decision)?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::task::{Context, Poll, Waker};
+
+    use crate::{
+        backend::hashmap::HashmapBackend, model::NewModel, test_utils::new_db, Cell, Ref, RefCell,
+    };
+
+    use super::*;
+
+    /// Runs a future that never waits, as the ones of the local branches.
+    fn run<F: Future>(future: F) -> F::Output {
+        match std::pin::pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => unreachable!("The future should be ready"),
+        }
+    }
+
+    type Db = IcTx<i32, HashmapBackend<i32, i32>>;
+
+    fn db_with_clock(now: &Ref<Cell<u64>>) -> Db {
+        let clock = now.clone();
+        new_db().with_lock_manager().with_clock(move || clock.get())
+    }
+
+    fn save(db: &Db, id: i32, data: i32) {
+        let mut tx = db.tx();
+        tx.save(NewModel::new(id, data)).unwrap();
+        tx.commit();
+    }
+
+    /// A branch whose commit fails while the flag is set, as a participant that traps.
+    struct FailingBranch<Br> {
+        branch: Br,
+        fail_commit: Ref<Cell<bool>>,
+    }
+
+    impl<Br: Branch> Branch for FailingBranch<Br> {
+        fn prepare<'a>(
+            &'a self,
+            tx_id: &'a str,
+            timeout: Duration,
+        ) -> BoxFuture<'a, Result<(), TxError>> {
+            self.branch.prepare(tx_id, timeout)
+        }
+
+        fn commit<'a>(&'a self, tx_id: &'a str) -> BoxFuture<'a, Result<(), TxError>> {
+            if self.fail_commit.get() {
+                return Box::pin(async move {
+                    Err(TxError::RemoteError {
+                        message: "trapped".to_owned(),
+                    })
+                });
+            }
+            self.branch.commit(tx_id)
+        }
+
+        fn abort<'a>(&'a self, tx_id: &'a str) -> BoxFuture<'a, Result<(), TxError>> {
+            self.branch.abort(tx_id)
+        }
+    }
+
+    #[test]
+    fn commit_should_apply_the_changes_of_all_participants() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let (db_a, db_b) = (db_with_clock(&now), db_with_clock(&now));
+        let (participant_a, participant_b) = (
+            Participant::new(db_a.clone()),
+            Participant::new(db_b.clone()),
+        );
+        save(&db_a, 1, 100);
+        let coordinator = Coordinator::new("coordinator");
+
+        // Act
+        let mut tx = coordinator.tx();
+        let mut tx_a = db_a.tx();
+        let mut model = tx_a.fetch_one(&1).unwrap();
+        model.data -= 30;
+        tx_a.update(model).unwrap();
+        tx.enlist(participant_a.branch(tx_a));
+        let mut tx_b = db_b.tx();
+        tx_b.save(NewModel::new(1, 30)).unwrap();
+        tx.enlist(participant_b.branch(tx_b));
+        let result = run(tx.try_commit());
+
+        // Assert
+        assert!(result.is_ok());
+        assert_eq!(70, db_a.fetch_one(&1).unwrap().data);
+        assert_eq!(30, db_b.fetch_one(&1).unwrap().data);
+        assert!(!db_a.is_locked(&1));
+        assert!(!db_b.is_locked(&1));
+        assert!(coordinator.in_doubt().is_empty());
+    }
+
+    #[test]
+    fn commit_should_not_check_again_the_prepared_changes() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let db = db_with_clock(&now).with_global_check("total", |reader| {
+            let total: i32 = reader
+                .fetch_all()
+                .map_err(|err| err.to_string())?
+                .iter()
+                .map(|model| model.data)
+                .sum();
+            match total <= 100 {
+                true => Ok(()),
+                false => Err(format!("The total is [{total}].")),
+            }
+        });
+        let participant = Participant::new(db.clone());
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, 60)).unwrap();
+        participant
+            .prepare("tx", tx.into_actions(), Duration::from_secs(1))
+            .unwrap();
+        // The total would now be 110, but the changes were checked by the prepare
+        save(&db, 2, 50);
+
+        // Act
+        let result = participant.commit("tx");
+
+        // Assert
+        assert!(result.is_ok());
+        assert_eq!(60, db.fetch_one(&1).unwrap().data);
+        assert!(!db.is_locked(&1));
+        assert_eq!(Some(Decision::Commit), participant.resolution("tx"));
+    }
+
+    #[test]
+    fn prepare_failure_should_abort_all_participants() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let (db_a, db_b) = (db_with_clock(&now), db_with_clock(&now));
+        let (participant_a, participant_b) = (
+            Participant::new(db_a.clone()),
+            Participant::new(db_b.clone()),
+        );
+        save(&db_b, 1, 10);
+        let coordinator = Coordinator::new("coordinator");
+
+        // Act
+        let mut tx = coordinator.tx();
+        let tx_id = tx.id().to_owned();
+        let mut tx_a = db_a.tx();
+        tx_a.save(NewModel::new(1, 100)).unwrap();
+        tx.enlist(participant_a.branch(tx_a));
+        let mut tx_b = db_b.tx();
+        tx_b.save(NewModel::new(1, 30)).unwrap();
+        tx.enlist(participant_b.branch(tx_b));
+        let result = run(tx.try_commit());
+
+        // Assert
+        assert!(matches!(result, Err(TxError::SaveError { .. })));
+        assert!(db_a.fetch_option_one(&1).unwrap().is_none());
+        assert!(!db_a.is_locked(&1));
+        assert!(participant_a.in_doubt().is_empty());
+        assert_eq!(Some(Decision::Abort), coordinator.decision(&tx_id));
+    }
+
+    #[test]
+    fn prepared_tx_should_lock_the_ids_until_the_decision() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let db = db_with_clock(&now);
+        let participant = Participant::new(db.clone());
+        save(&db, 1, 100);
+        let mut tx = db.tx();
+        tx.update(db.fetch_one(&1).unwrap()).unwrap();
+        let actions = tx.into_actions();
+
+        // Act
+        participant
+            .prepare("tx_1", actions, Duration::from_nanos(100))
+            .unwrap();
+        let timed_out_before_timeout = participant.timed_out();
+        let mut other_tx = db.tx();
+        other_tx.update(db.fetch_one(&1).unwrap()).unwrap();
+        let locked_result = other_tx.try_commit();
+
+        now.set(100);
+        let locked_after_timeout = db.is_locked(&1);
+        let in_doubt_after_timeout = participant.in_doubt();
+        let timed_out_after_timeout = participant.timed_out();
+        let commit_after_timeout = participant.commit("tx_1");
+
+        // Assert
+        assert!(timed_out_before_timeout.is_empty());
+        assert!(matches!(locked_result, Err(TxError::LockError { .. })));
+        assert!(locked_after_timeout);
+        assert_eq!(vec!["tx_1".to_owned()], in_doubt_after_timeout);
+        assert_eq!(vec!["tx_1".to_owned()], timed_out_after_timeout);
+        assert!(commit_after_timeout.is_ok());
+        assert_eq!(1, db.fetch_one(&1).unwrap().version());
+        assert!(!db.is_locked(&1));
+    }
+
+    #[test]
+    fn commit_after_the_timeout_should_apply_the_prepared_changes() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let db = db_with_clock(&now);
+        let participant = Participant::new(db.clone());
+        save(&db, 1, 100);
+        let mut tx = db.tx();
+        let mut model = db.fetch_one(&1).unwrap();
+        model.data = 70;
+        tx.update(model).unwrap();
+        participant
+            .prepare("tx_1", tx.into_actions(), Duration::from_nanos(100))
+            .unwrap();
+
+        // The timeout is elapsed, but the ids are still locked, so another transaction cannot change the model
+        now.set(1_000);
+        let mut other_tx = db.tx();
+        let mut model = db.fetch_one(&1).unwrap();
+        model.data = 50;
+        other_tx.update(model).unwrap();
+        let other_result = other_tx.try_commit();
+
+        // Act
+        let result = participant.commit("tx_1");
+        let repeated_result = participant.commit("tx_1");
+
+        // Assert
+        assert!(matches!(other_result, Err(TxError::LockError { .. })));
+        assert!(result.is_ok());
+        assert!(repeated_result.is_ok());
+        assert_eq!(70, db.fetch_one(&1).unwrap().data);
+        assert_eq!(Some(Decision::Commit), participant.resolution("tx_1"));
+        assert!(participant.in_doubt().is_empty());
+        assert!(!db.is_locked(&1));
+    }
+
+    #[test]
+    fn restore_should_prepare_again_the_logged_txs() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let backend = Ref::new(RefCell::new(HashmapBackend::new()));
+        let log: IcTx<ParticipantRecord<i32, i32, u32>, HashmapBackend<TxId, _>> =
+            IcTx::new(Ref::new(RefCell::new(HashmapBackend::new())));
+        let db = IcTx::new(backend.clone()).with_lock_manager();
+        let participant = Participant::new(db.clone()).with_log(log.clone());
+        for (tx_id, id) in [("tx_1", 1), ("tx_2", 2)] {
+            let mut tx = db.tx();
+            tx.save(NewModel::new(id, 100)).unwrap();
+            participant
+                .prepare(tx_id, tx.into_actions(), Duration::from_nanos(100))
+                .unwrap();
+        }
+        participant.commit("tx_2").unwrap();
+
+        // The upgrade keeps the backend and the log, but loses the heap state of the participant and the locks
+        let clock = now.clone();
+        let upgraded_db = IcTx::new(backend)
+            .with_lock_manager()
+            .with_clock(move || clock.get());
+        let upgraded_participant = Participant::new(upgraded_db.clone()).with_log(log);
+
+        // Act
+        upgraded_participant.restore().unwrap();
+
+        // Assert
+        assert_eq!(vec!["tx_1".to_owned()], upgraded_participant.in_doubt());
+        assert_eq!(
+            Some(Decision::Commit),
+            upgraded_participant.resolution("tx_2")
+        );
+        assert!(upgraded_db.is_locked(&1));
+        assert!(upgraded_participant.commit("tx_2").is_ok());
+        assert!(upgraded_participant.commit("tx_1").is_ok());
+        assert_eq!(100, upgraded_db.fetch_one(&1).unwrap().data);
+        assert!(!upgraded_db.is_locked(&1));
+    }
+
+    #[test]
+    fn resolve_manually_should_resolve_only_the_timed_out_txs() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let db = db_with_clock(&now);
+        let participant = Participant::new(db.clone());
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, 100)).unwrap();
+        participant
+            .prepare("tx_1", tx.into_actions(), Duration::from_nanos(100))
+            .unwrap();
+
+        // Act
+        let resolve_before_timeout = participant.resolve_manually("tx_1", Decision::Abort);
+        // The coordinator never replies
+        now.set(100);
+        let resolve_after_timeout = participant.resolve_manually("tx_1", Decision::Abort);
+        let resolve_unknown = participant.resolve_manually("tx_2", Decision::Abort);
+
+        // Assert
+        assert!(matches!(
+            resolve_before_timeout,
+            Err(TxError::TwoPhaseCommitError { .. })
+        ));
+        assert!(resolve_after_timeout.is_ok());
+        assert!(matches!(
+            resolve_unknown,
+            Err(TxError::TwoPhaseCommitError { .. })
+        ));
+        assert!(participant.in_doubt().is_empty());
+        assert!(db.fetch_option_one(&1).unwrap().is_none());
+        assert!(!db.is_locked(&1));
+    }
+
+    #[test]
+    fn participant_should_commit_after_the_timeout_if_the_coordinator_decided_to_commit() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let clock = now.clone();
+        let (db_a, db_b) = (db_with_clock(&now), db_with_clock(&now));
+        let (participant_a, participant_b) = (
+            Participant::new(db_a.clone()),
+            Participant::new(db_b.clone()),
+        );
+        let coordinator = Coordinator::new("coordinator")
+            .with_clock(move || clock.get())
+            .with_timeout(Duration::from_nanos(100));
+        let fail_commit = Ref::new(Cell::new(true));
+
+        let mut tx = coordinator.tx();
+        let tx_id = tx.id().to_owned();
+        let mut tx_a = db_a.tx();
+        tx_a.save(NewModel::new(1, 100)).unwrap();
+        tx.enlist(participant_a.branch(tx_a));
+        let mut tx_b = db_b.tx();
+        tx_b.save(NewModel::new(1, 30)).unwrap();
+        tx.enlist(FailingBranch {
+            branch: participant_b.branch(tx_b),
+            fail_commit: fail_commit.clone(),
+        });
+        let commit_result = run(tx.try_commit());
+
+        // Act
+        now.set(1_000);
+        let timed_out = participant_b.timed_out();
+        let recover_result = participant_b.recover_with(|tx_id| coordinator.decision(tx_id));
+        // The participant already applied the commit, so the one repeated by the coordinator does nothing
+        fail_commit.set(false);
+        let coordinator_recover_result = run(coordinator.recover());
+
+        // Assert
+        assert!(matches!(
+            commit_result,
+            Err(TxError::TwoPhaseCommitError { .. })
+        ));
+        assert_eq!(vec![tx_id.clone()], timed_out);
+        assert!(recover_result.is_ok());
+        assert!(coordinator_recover_result.is_ok());
+        assert_eq!(100, db_a.fetch_one(&1).unwrap().data);
+        assert_eq!(30, db_b.fetch_one(&1).unwrap().data);
+        assert!(!db_b.is_locked(&1));
+        assert!(participant_b.in_doubt().is_empty());
+        assert!(coordinator.in_doubt().is_empty());
+    }
+
+    #[test]
+    fn coordinator_should_abort_the_txs_not_prepared_within_the_timeout() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let clock = now.clone();
+        let db = db_with_clock(&now);
+        let participant = Participant::new(db.clone());
+        let coordinator = Coordinator::new("coordinator")
+            .with_clock(move || clock.get())
+            .with_timeout(Duration::from_nanos(100));
+
+        let mut tx = coordinator.tx();
+        let tx_id = tx.id().to_owned();
+        let mut tx_a = db.tx();
+        tx_a.save(NewModel::new(1, 100)).unwrap();
+        tx.enlist(participant.branch(tx_a));
+        run(tx.prepare()).unwrap();
+        let decision_within_timeout = coordinator.decision(&tx_id);
+
+        // Act
+        now.set(100);
+        let decision_after_timeout = coordinator.decision(&tx_id);
+        let commit_result = run(tx.try_commit());
+
+        // Assert
+        assert_eq!(None, decision_within_timeout);
+        assert_eq!(Some(Decision::Abort), decision_after_timeout);
+        assert!(matches!(
+            commit_result,
+            Err(TxError::TwoPhaseCommitError { .. })
+        ));
+        assert!(participant.in_doubt().is_empty());
+        assert!(db.fetch_option_one(&1).unwrap().is_none());
+        assert!(!db.is_locked(&1));
+    }
+
+    #[test]
+    fn participant_operations_should_be_idempotent() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let db = db_with_clock(&now);
+        let participant = Participant::new(db.clone());
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, 100)).unwrap();
+        let actions = tx.into_actions();
+
+        // Act
+        let prepare_1 = participant.prepare("tx_1", actions.clone(), DEFAULT_PREPARE_TIMEOUT);
+        let prepare_2 = participant.prepare("tx_1", actions.clone(), DEFAULT_PREPARE_TIMEOUT);
+        let commit_1 = participant.commit("tx_1");
+        let commit_2 = participant.commit("tx_1");
+        let abort = participant.abort("tx_1");
+        let prepare_3 = participant.prepare("tx_1", actions, DEFAULT_PREPARE_TIMEOUT);
+        let unknown_abort = participant.abort("tx_2");
+
+        // Assert
+        assert!(prepare_1.is_ok());
+        assert!(prepare_2.is_ok());
+        assert!(commit_1.is_ok());
+        assert!(commit_2.is_ok());
+        assert!(matches!(abort, Err(TxError::TwoPhaseCommitError { .. })));
+        assert!(matches!(
+            prepare_3,
+            Err(TxError::TwoPhaseCommitError { .. })
+        ));
+        assert!(unknown_abort.is_ok());
+        assert_eq!(100, db.fetch_one(&1).unwrap().data);
+    }
+
+    #[test]
+    fn recover_should_commit_on_the_participants_that_failed() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let (db_a, db_b) = (db_with_clock(&now), db_with_clock(&now));
+        let (participant_a, participant_b) = (
+            Participant::new(db_a.clone()),
+            Participant::new(db_b.clone()),
+        );
+        let coordinator = Coordinator::new("coordinator");
+        let fail_commit = Ref::new(Cell::new(true));
+
+        let mut tx = coordinator.tx();
+        let tx_id = tx.id().to_owned();
+        let mut tx_a = db_a.tx();
+        tx_a.save(NewModel::new(1, 100)).unwrap();
+        tx.enlist(participant_a.branch(tx_a));
+        let mut tx_b = db_b.tx();
+        tx_b.save(NewModel::new(1, 30)).unwrap();
+        tx.enlist(FailingBranch {
+            branch: participant_b.branch(tx_b),
+            fail_commit: fail_commit.clone(),
+        });
+
+        // Act
+        let commit_result = run(tx.try_commit());
+        let decision = coordinator.decision(&tx_id);
+        let in_doubt = participant_b.in_doubt();
+        fail_commit.set(false);
+        let recover_result = run(coordinator.recover());
+
+        // Assert
+        assert!(matches!(
+            commit_result,
+            Err(TxError::TwoPhaseCommitError { .. })
+        ));
+        assert_eq!(Some(Decision::Commit), decision);
+        assert_eq!(vec![tx_id], in_doubt);
+        assert!(recover_result.is_ok());
+        assert_eq!(100, db_a.fetch_one(&1).unwrap().data);
+        assert_eq!(30, db_b.fetch_one(&1).unwrap().data);
+        assert!(coordinator.in_doubt().is_empty());
+        assert!(participant_b.in_doubt().is_empty());
+    }
+
+    #[test]
+    fn recover_should_abort_the_txs_interrupted_during_the_prepare() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let clock = now.clone();
+        let db = db_with_clock(&now);
+        let participant = Participant::new(db.clone());
+        let coordinator = Coordinator::new("coordinator")
+            .with_clock(move || clock.get())
+            .with_timeout(Duration::from_nanos(100));
+
+        // The coordinator traps after the prepare, so the transaction is never committed
+        let mut tx = coordinator.tx();
+        let tx_id = tx.id().to_owned();
+        let mut tx_a = db.tx();
+        tx_a.save(NewModel::new(1, 100)).unwrap();
+        tx.enlist(participant.branch(tx_a));
+        run(tx.prepare()).unwrap();
+        drop(tx);
+
+        // Act
+        let decision = coordinator.decision(&tx_id);
+        let locked = db.is_locked(&1);
+        now.set(100);
+        let recover_result = run(coordinator.recover());
+
+        // Assert
+        assert_eq!(None, decision);
+        assert!(locked);
+        assert!(recover_result.is_ok());
+        assert_eq!(Some(Decision::Abort), coordinator.decision(&tx_id));
+        assert!(participant.in_doubt().is_empty());
+        assert!(db.fetch_option_one(&1).unwrap().is_none());
+        assert!(!db.is_locked(&1));
+    }
+
+    #[test]
+    fn recover_should_not_abort_the_txs_being_prepared_within_the_timeout() {
+        // Arrange
+        let now = Ref::new(Cell::new(0));
+        let clock = now.clone();
+        let db = db_with_clock(&now);
+        let participant = Participant::new(db.clone());
+        let coordinator = Coordinator::new("coordinator")
+            .with_clock(move || clock.get())
+            .with_timeout(Duration::from_nanos(100));
+
+        // The commit is still waiting for the replies of the participants
+        let mut tx = coordinator.tx();
+        let tx_id = tx.id().to_owned();
+        let mut tx_a = db.tx();
+        tx_a.save(NewModel::new(1, 100)).unwrap();
+        tx.enlist(participant.branch(tx_a));
+        run(tx.prepare()).unwrap();
+
+        // Act
+        now.set(50);
+        let recover_result = run(coordinator.recover());
+        let in_doubt = coordinator.in_doubt();
+        let commit_result = run(tx.try_commit());
+
+        // Assert
+        assert!(recover_result.is_ok());
+        assert_eq!(vec![tx_id.clone()], in_doubt);
+        assert!(commit_result.is_ok());
+        assert_eq!(100, db.fetch_one(&1).unwrap().data);
+        assert!(coordinator.in_doubt().is_empty());
+    }
+}
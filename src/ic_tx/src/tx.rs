@@ -1,46 +1,228 @@
-use std::{cell::RefCell, marker::PhantomData, vec};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    time::Duration,
+    vec,
+};
 
 use crate::{
-    backend::Backend,
-    error::TxError,
-    model::{Model, NewModel, VersionType},
-    Ref,
+    backend::{Backend, BackendModel},
+    db::IcTx,
+    error::{Conflict, TxError},
+    idempotency::Receipt,
+    key::Key,
+    lock::LockOwner,
+    metadata::Metadata,
+    migration::migrate,
+    model::{Model, NewModel, Version},
+    reference::{Cascades, PendingIds},
+    shared::commit_guard,
 };
 
-enum Action<IdType, Data> {
+/// A change to a model recorded by a transaction and applied by the commit.
+/// Under the `candid` feature it is also the format used to send the changes to a storage canister.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "candid", derive(candid::CandidType))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Action<IdType, Data, V> {
     Create { model: NewModel<IdType, Data> },
     //    Read {
     //        id: IdType,
-    //        version: VersionType
+    //        version: V
     //    },
-    Update { model: Model<IdType, Data> },
-    Delete { id: IdType, version: VersionType },
-    DeleteOption { id: IdType, version: VersionType },
+    Update { model: Model<IdType, Data, V> },
+    Delete { id: IdType, version: V },
+    DeleteOption { id: IdType, version: V },
+}
+
+impl<IdType, Data, V> Action<IdType, Data, V> {
+    pub fn id(&self) -> &IdType {
+        match self {
+            Action::Create { model } => &model.id,
+            Action::Update { model } => &model.id,
+            Action::Delete { id, .. } => id,
+            Action::DeleteOption { id, .. } => id,
+        }
+    }
 }
 
-const COMMIT_PANIC_MESSAGE: &str = "Cannot commit the transaction";
+impl<IdType: Key, Data, V: Version> Action<IdType, Data, V> {
+    /// Merges the next action on the same id into the one in the slot.
+    /// The slot is emptied if the two actions cancel each other out.
+    /// A contradictory sequence is rejected and the slot is left unchanged.
+    /// `created_version` returns the version of the model that a create would write,
+    /// or `None` if the id belongs to a stored model or the version cannot be known.
+    fn coalesce(
+        slot: &mut Option<Self>,
+        next: Self,
+        created_version: impl FnOnce(&IdType) -> Result<Option<V>, TxError>,
+    ) -> Result<(), TxError> {
+        let Some(previous) = slot.take() else {
+            *slot = Some(next);
+            return Ok(());
+        };
+        let (previous, message) = match (previous, next) {
+            // A model saved and then updated is saved with the updated data
+            (Action::Create { .. }, Action::Update { model }) => {
+                *slot = Some(Action::Create {
+                    model: NewModel::new(model.id, model.data),
+                });
+                return Ok(());
+            }
+            // A model saved and then deleted is never written,
+            // but only if the delete targets the saved model and not a stored one
+            (
+                previous @ Action::Create { .. },
+                Action::Delete { version, .. } | Action::DeleteOption { version, .. },
+            ) => match created_version(previous.id()) {
+                Ok(Some(created_version)) if created_version == version => return Ok(()),
+                Ok(_) => (
+                    previous,
+                    "The model is saved in this transaction, so only the saved model can be deleted.",
+                ),
+                Err(err) => {
+                    *slot = Some(previous);
+                    return Err(err);
+                }
+            },
+            (Action::Update { model: previous }, Action::Update { model })
+                if previous.version == model.version =>
+            {
+                *slot = Some(Action::Update { model });
+                return Ok(());
+            }
+            (
+                Action::Update { model: previous },
+                Action::Delete { id, version } | Action::DeleteOption { id, version },
+            ) if previous.version == version => {
+                *slot = Some(Action::Delete { id, version });
+                return Ok(());
+            }
+            // A model deleted and then saved again is replaced by the new one
+            (Action::Delete { id, version }, Action::Create { model }) => {
+                *slot = Some(Action::Update {
+                    model: Model::from((id, version, model.data)),
+                });
+                return Ok(());
+            }
+            (previous @ Action::Create { .. }, Action::Create { .. }) => (
+                previous,
+                "The model is already saved in this transaction.",
+            ),
+            (previous @ Action::Update { .. }, Action::Create { .. }) => (
+                previous,
+                "The model is updated in this transaction so it already exists.",
+            ),
+            (previous @ Action::Update { .. }, _) => (
+                previous,
+                "The model is already updated in this transaction with a different version.",
+            ),
+            (previous @ Action::DeleteOption { .. }, Action::Create { .. }) => (
+                previous,
+                "The model is deleted with delete_option in this transaction so it might not exist.",
+            ),
+            (previous @ (Action::Delete { .. } | Action::DeleteOption { .. }), _) => (
+                previous,
+                "The model is already deleted in this transaction.",
+            ),
+        };
+        let id = previous.id().to_key_string();
+        *slot = Some(previous);
+        Err(TxError::ConflictingActionsError {
+            id,
+            message: message.to_owned(),
+        })
+    }
+}
+
+/// Adds the action to the actions of a transaction, coalescing it with the previous action on the same id.
+/// See `Action::coalesce` for `created_version`.
+pub(crate) fn push_action<IdType: Key + Clone + Eq + Hash, Data, V: Version>(
+    actions: &mut Vec<Option<Action<IdType, Data, V>>>,
+    positions: &mut HashMap<IdType, usize>,
+    action: Action<IdType, Data, V>,
+    created_version: impl FnOnce(&IdType) -> Result<Option<V>, TxError>,
+) -> Result<(), TxError> {
+    match positions.get(action.id()) {
+        Some(&position) => Action::coalesce(&mut actions[position], action, created_version),
+        None => {
+            positions.insert(action.id().clone(), actions.len());
+            actions.push(Some(action));
+            Ok(())
+        }
+    }
+}
+
+pub(crate) type TxAction<Data, B> =
+    Action<<B as Backend<Data>>::IdType, Data, <B as Backend<Data>>::VersionType>;
+
+/// The receipt of the commit of a transaction.
+pub type TxReceipt<Data, B> =
+    Receipt<<B as Backend<Data>>::IdType, <B as Backend<Data>>::VersionType>;
+
+type TxChange<Data, B> = (
+    <B as Backend<Data>>::IdType,
+    Option<<B as Backend<Data>>::VersionType>,
+);
+
+pub(crate) const COMMIT_PANIC_MESSAGE: &str = "Cannot commit the transaction";
+
+/// Defines how the commit validates the actions of a transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ValidationMode {
+    /// The commit fails with the error of the first action that does not pass the validation.
+    #[default]
+    FailFast,
+    /// The commit validates every action and fails with a `TxError::Conflicts`
+    /// that lists all the actions that do not pass the validation.
+    Exhaustive,
+}
 
 pub struct Tx<Data, B: Backend<Data>> {
-    actions: Vec<Action<B::IdType, Data>>,
-    backend: Ref<RefCell<B>>,
+    // A slot is emptied when its actions cancel each other out
+    actions: Vec<Option<TxAction<Data, B>>>,
+    positions: HashMap<B::IdType, usize>,
+    // The ids of the models deleted and then saved again, that are written as new models
+    replaced: HashSet<B::IdType>,
+    db: IcTx<Data, B>,
+    lock_owner: Option<LockOwner>,
+    validation_mode: ValidationMode,
+    idempotency_key: Option<String>,
     completed: bool,
-    phantom_data: PhantomData<Data>,
 }
 
 impl<Data, B: Backend<Data>> Tx<Data, B> {
-    pub(crate) fn new(backend: Ref<RefCell<B>>) -> Self {
+    pub(crate) fn new(db: IcTx<Data, B>) -> Self {
         Self {
             actions: vec![],
-            backend,
+            positions: HashMap::default(),
+            replaced: HashSet::default(),
+            validation_mode: db.validation_mode,
+            db,
+            lock_owner: None,
+            idempotency_key: None,
             completed: false,
-            phantom_data: PhantomData,
         }
     }
 
+    /// Sets how the commit of this transaction validates the actions.
+    pub fn with_validation_mode(mut self, validation_mode: ValidationMode) -> Self {
+        self.validation_mode = validation_mode;
+        self
+    }
+
+    /// Makes the commit idempotent: the key is stored with the receipt of the commit,
+    /// and a later commit with the same key returns that receipt instead of applying its changes.
+    /// The key is kept until the TTL of the database elapses. A failed commit does not store the key.
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
     /// Fetches a model from the database.
     /// Returns an error if no model is found with the specified id.
-    pub fn fetch_one(&mut self, id: &B::IdType) -> Result<Model<B::IdType, Data>, TxError> {
-        let result = self.backend.borrow().fetch_one(id);
+    pub fn fetch_one(&mut self, id: &B::IdType) -> Result<BackendModel<Data, B>, TxError> {
+        let result = self.db.fetch_one(id);
         /*
         match &result {
             Ok(model) => {
@@ -56,8 +238,8 @@ impl<Data, B: Backend<Data>> Tx<Data, B> {
     pub fn fetch_option_one(
         &mut self,
         id: &B::IdType,
-    ) -> Result<Option<Model<B::IdType, Data>>, TxError> {
-        let result = self.backend.borrow().fetch_option_one(id);
+    ) -> Result<Option<BackendModel<Data, B>>, TxError> {
+        let result = self.db.fetch_option_one(id);
         /*
         match &result {
             Ok(Some(model)) => {
@@ -71,123 +253,679 @@ impl<Data, B: Backend<Data>> Tx<Data, B> {
 
     /// Updates a model of the database.
     /// The transaction will fail if the model does not exist or if the model version does not match.
-    pub fn update(&mut self, model: Model<B::IdType, Data>) -> Result<(), TxError> {
-        self.actions.push(Action::Update { model });
-        Ok(())
+    pub fn update(&mut self, model: BackendModel<Data, B>) -> Result<(), TxError> {
+        self.push(Action::Update { model })
     }
 
     /// Deletes a model from the database.
     /// The transaction will fail if the model does not exist or if the model version does not match.
-    pub fn delete(&mut self, model: Model<B::IdType, Data>) -> Result<(), TxError> {
-        self.actions.push(Action::Delete {
+    pub fn delete(&mut self, model: BackendModel<Data, B>) -> Result<(), TxError> {
+        self.push(Action::Delete {
             id: model.id,
             version: model.version,
-        });
-        Ok(())
+        })
     }
 
     /// Deletes a model from the database.
     /// The transaction will fail if the model version does not match but it will succeed if the model does not exist.
-    pub fn delete_option(&mut self, model: Model<B::IdType, Data>) -> Result<(), TxError> {
-        self.actions.push(Action::DeleteOption {
+    pub fn delete_option(&mut self, model: BackendModel<Data, B>) -> Result<(), TxError> {
+        self.push(Action::DeleteOption {
             id: model.id,
             version: model.version,
-        });
-        Ok(())
+        })
     }
 
     /// Creates a new model in the database.
     /// The transaction will fail if a model with the same ID already exists.
     pub fn save(&mut self, model: NewModel<B::IdType, Data>) -> Result<(), TxError> {
-        self.actions.push(Action::Create { model });
+        self.push(Action::Create { model })
+    }
+
+    /// Takes the actions out of the transaction, e.g. to let a participant of a distributed transaction apply them.
+    /// The transaction is completed and its locks are released.
+    pub(crate) fn into_actions(mut self) -> Vec<TxAction<Data, B>> {
+        self.completed = true;
+        self.release_locks();
+        self.actions.drain(..).flatten().collect()
+    }
+
+    /// Adds the action to the transaction, coalescing it with the previous action on the same id.
+    pub(crate) fn push(&mut self, action: TxAction<Data, B>) -> Result<(), TxError> {
+        let replaced = match (self.pending_action(action.id()), &action) {
+            (Some(Action::Delete { .. }), Action::Create { model }) => Some(model.id.clone()),
+            _ => None,
+        };
+        let backend = &self.db.backend;
+        push_action(&mut self.actions, &mut self.positions, action, |id| {
+            let backend = backend.borrow();
+            match backend.fetch_option_version(id)? {
+                Some(_) => Ok(None),
+                None => initial_version(&*backend, id).map(Some),
+            }
+        })?;
+        if let Some(id) = replaced {
+            self.replaced.insert(id);
+        }
         Ok(())
     }
 
-    /// Commits the transaction. Panics if any error
-    pub fn commit(mut self) {
-        self.inner_commit().expect(COMMIT_PANIC_MESSAGE)
+    pub(crate) fn pending_action(&self, id: &B::IdType) -> Option<&TxAction<Data, B>> {
+        self.positions
+            .get(id)
+            .and_then(|&position| self.actions[position].as_ref())
     }
 
-    fn inner_commit(&mut self) -> Result<(), TxError> {
-        if self.completed {
+    /// Returns the model of the pending update of the id, if any.
+    pub(crate) fn pending_update_mut(
+        &mut self,
+        id: &B::IdType,
+    ) -> Option<&mut BackendModel<Data, B>> {
+        match self
+            .positions
+            .get(id)
+            .map(|&position| &mut self.actions[position])
+        {
+            Some(Some(Action::Update { model })) => Some(model),
+            _ => None,
+        }
+    }
+
+    /// Takes an exclusive lease on the id until the transaction completes or the lease expires.
+    /// While the lease is valid, other transactions cannot write the model with this id.
+    /// Fails if the lock manager is not enabled or if another transaction holds the lease.
+    pub fn lock(&mut self, id: &B::IdType, lease: Duration) -> Result<(), TxError> {
+        let locks = self.db.locks.as_ref().ok_or_else(|| TxError::LockError {
+            id: id.to_key_string(),
+            message: "The lock manager is not enabled.".to_owned(),
+        })?;
+        let mut locks = locks.borrow_mut();
+        let owner = *self.lock_owner.get_or_insert_with(|| locks.next_owner());
+        locks.acquire(id, owner, (self.db.clock)(), lease)
+    }
+
+    /// Locks the ids of the pending actions until the transaction ends, if the lock manager is enabled.
+    /// The locks never expire, e.g. those of a prepared transaction must be held until the decision arrives.
+    pub(crate) fn hold_pending(&mut self) -> Result<(), TxError> {
+        if self.db.locks.is_none() {
             return Ok(());
         }
+        let ids: Vec<_> = self
+            .actions
+            .iter()
+            .flatten()
+            .map(|action| action.id().clone())
+            .collect();
+        for id in ids {
+            self.lock(&id, Duration::MAX)?;
+        }
+        Ok(())
+    }
+
+    /// Commits the transaction. Panics if any error.
+    /// In a canister the panic traps and reverts the call, including the failed commit in the metrics:
+    /// use `try_commit` to count it.
+    pub fn commit(self)
+    where
+        Data: 'static,
+        B: 'static,
+    {
+        self.try_commit().expect(COMMIT_PANIC_MESSAGE);
+    }
+
+    /// Commits the transaction. Returns an error and persists nothing if any check fails.
+    /// With the `sync` feature, it also fails if the thread is already committing to the database,
+    /// e.g. from a validator or a view.
+    pub fn try_commit(mut self) -> Result<(), TxError>
+    where
+        Data: 'static,
+        B: 'static,
+    {
+        self.inner_commit().map(|_| ())
+    }
 
+    /// Commits the transaction and returns its receipt.
+    /// Returns an error and persists nothing if any check fails.
+    pub fn try_commit_with_receipt(mut self) -> Result<TxReceipt<Data, B>, TxError>
+    where
+        Data: 'static,
+        B: 'static,
+    {
+        self.inner_commit()
+    }
+
+    fn inner_commit(&mut self) -> Result<TxReceipt<Data, B>, TxError>
+    where
+        Data: 'static,
+        B: 'static,
+    {
+        let now = (self.db.clock)();
+        if self.completed {
+            return Ok(Receipt {
+                committed_at: now,
+                changes: vec![],
+                replayed: false,
+            });
+        }
+
+        let _guard = commit_guard(&self.db.commit_lock)
+            .inspect_err(|err| self.db.metrics.borrow_mut().record_failed_commit(err))?;
         self.completed = true;
 
-        let mut backend = self.backend.borrow_mut();
+        if let Some(key) = &self.idempotency_key {
+            let receipt = self.db.idempotency_keys.borrow_mut().get(key, now);
+            if let Some(receipt) = receipt {
+                self.release_locks();
+                return Ok(receipt);
+            }
+        }
 
-        // Step 1: check that models have the expected version
-        for action in &self.actions {
-            match action {
-                Action::Create { model } => {
-                    if backend.fetch_option_version(&model.id)?.is_some() {
-                        return Err(TxError::SaveError { message: format!("Cannot save model with id [{}] because the id is already in use.", model.id) });
-                    }
-                },
-                // Action::Read { id, version } => {
-                //    if let Some(fetch_version) = self.backend.fetch_option_version(id)? {
-                //        return Err(TxError::SaveError { message: format!("Cannot save model with id [{}] because the id is already in use.", model.id) });
-                //    }
-                // }
-                Action::Update { model } => {
-                    match backend.fetch_option_version(&model.id)? {
-                        Some(fetch_version) if fetch_version == model.version => (),
-                        Some(fetch_version) => return Err(TxError::UpdateOptimisticLockError { message: format!("Cannot update model with id [{}]. Expected version [{}], version found [{}]", model.id, model.version, fetch_version) }),
-                        None => return Err(TxError::UpdateError { message: format!("Cannot update model with id [{}] because it does not exist.", model.id) }),
-                    }
-                },
-                Action::Delete { id, version } => {
-                    match backend.fetch_option_version(id)? {
-                        Some(fetch_version) if fetch_version == *version => (),
-                        Some(fetch_version) => return Err(TxError::DeleteOptimisticLockError { message: format!("Cannot delete model with id [{}]. Expected version [{}], version found [{}]", id, version, fetch_version) }),
-                        None => return Err(TxError::DeleteError { message: format!("Cannot delete model with id [{}] because it does not exist.", id) }),
-                    }
-                },
-                Action::DeleteOption { id, version } => {
-                    match backend.fetch_option_version(id)? {
-                        Some(fetch_version) if fetch_version == *version => (),
-                        Some(fetch_version) => return Err(TxError::DeleteOptimisticLockError { message: format!("Cannot delete model with id [{}]. Expected version [{}], version found [{}]", id, version, fetch_version) }),
-                        None => (),
+        let actions = self.actions.iter().flatten().count();
+        let result = self.apply(now);
+        self.release_locks();
+        match &result {
+            Ok(_) => self.db.metrics.borrow_mut().record_commit(actions),
+            Err(err) => self.db.metrics.borrow_mut().record_failed_commit(err),
+        }
+        let receipt = Receipt {
+            committed_at: now,
+            changes: result?,
+            replayed: false,
+        };
+        // The key is stored in the same message as the changes, so they are persisted together
+        if let Some(key) = self.idempotency_key.take() {
+            self.db
+                .idempotency_keys
+                .borrow_mut()
+                .insert(key, receipt.clone(), now);
+        }
+        Ok(receipt)
+    }
+
+    fn validate(&self, backend: &B, action: &TxAction<Data, B>, now: u64) -> Result<(), TxError> {
+        if let Some(locks) = &self.db.locks {
+            locks.borrow().check(action.id(), self.lock_owner, now)?;
+        }
+
+        match action {
+            Action::Create { model } => {
+                if backend.fetch_option_version(&model.id)?.is_some() {
+                    return Err(TxError::SaveError {
+                        id: model.id.to_key_string(),
+                        message: "The id is already in use.".to_owned(),
+                    });
+                }
+                initial_version(backend, &model.id)?;
+            }
+            // Action::Read { id, version } => {
+            //    if let Some(fetch_version) = self.backend.fetch_option_version(id)? {
+            //        return Err(TxError::SaveError { message: format!("Cannot save model with id [{}] because the id is already in use.", model.id) });
+            //    }
+            // }
+            Action::Update { model } => match backend.fetch_option_version(&model.id)? {
+                Some(fetch_version) if fetch_version == model.version => {
+                    if model.version.next().is_none() {
+                        return Err(version_overflow_error(&model.id, model.version));
                     }
                 }
+                Some(fetch_version) => {
+                    return Err(TxError::UpdateOptimisticLockError {
+                        id: model.id.to_key_string(),
+                        expected: model.version.into(),
+                        found: fetch_version.into(),
+                    })
+                }
+                None => {
+                    return Err(TxError::UpdateError {
+                        id: model.id.to_key_string(),
+                        message: "It does not exist.".to_owned(),
+                    })
+                }
+            },
+            Action::Delete { id, version } => match backend.fetch_option_version(id)? {
+                Some(fetch_version) if fetch_version == *version => (),
+                Some(fetch_version) => {
+                    return Err(TxError::DeleteOptimisticLockError {
+                        id: id.to_key_string(),
+                        expected: (*version).into(),
+                        found: fetch_version.into(),
+                    })
+                }
+                None => {
+                    return Err(TxError::DeleteError {
+                        id: id.to_key_string(),
+                        message: "It does not exist.".to_owned(),
+                    })
+                }
+            },
+            Action::DeleteOption { id, version } => match backend.fetch_option_version(id)? {
+                Some(fetch_version) if fetch_version == *version => (),
+                Some(fetch_version) => {
+                    return Err(TxError::DeleteOptimisticLockError {
+                        id: id.to_key_string(),
+                        expected: (*version).into(),
+                        found: fetch_version.into(),
+                    })
+                }
+                None => (),
+            },
+        }
+
+        if let Action::Create {
+            model: NewModel { id, data },
+        }
+        | Action::Update {
+            model: Model { id, data, .. },
+        } = action
+        {
+            if let Some(data_id) = &self.db.data_id {
+                let data_id = data_id(data);
+                if data_id != *id {
+                    return Err(TxError::ValidationError {
+                        id: id.to_key_string(),
+                        reason: format!("The data has the id [{}].", data_id.display()),
+                    });
+                }
+            }
+            for validator in &self.db.validators {
+                validator(data).map_err(|reason| TxError::ValidationError {
+                    id: id.to_key_string(),
+                    reason,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the created and updated models reference existing models.
+    fn validate_references(
+        &self,
+        action: &TxAction<Data, B>,
+        pending: &PendingIds<'_>,
+    ) -> Result<(), TxError> {
+        if let Action::Create {
+            model: NewModel { id, data },
+        }
+        | Action::Update {
+            model: Model { id, data, .. },
+        } = action
+        {
+            for (_, reference) in &self.db.references {
+                reference(data, pending).map_err(|message| TxError::ReferenceError {
+                    id: id.to_key_string(),
+                    message,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, now: u64) -> Result<Vec<TxChange<Data, B>>, TxError>
+    where
+        Data: 'static,
+        B: 'static,
+    {
+        let mut cascades = self.prepare(now)?;
+        let changes = self.write(now)?;
+        cascades.write(now)?;
+        Ok(changes)
+    }
+
+    /// Step 1: checks that models have the expected version, are not locked by other transactions,
+    /// that their data is valid and that the deletes do not break the references of other collections.
+    /// Returns the changes that the deletes cascade to the referencing collections, already checked.
+    pub(crate) fn prepare(&self, now: u64) -> Result<Cascades, TxError>
+    where
+        Data: 'static,
+        B: 'static,
+    {
+        self.check(now)?;
+
+        let deleted_ids: Vec<_> = self
+            .actions
+            .iter()
+            .flatten()
+            .filter(|action| matches!(action, Action::Delete { .. } | Action::DeleteOption { .. }))
+            .map(|action| action.id().clone())
+            .collect();
+        let mut cascades = Cascades::default();
+        if !deleted_ids.is_empty() {
+            let pending = PendingIds::new(&self.db.backend, self);
+            // The references can reach this collection again, so its references are not locked while they run
+            let references = self.db.referenced_by.borrow().clone();
+            for reference in references {
+                reference(&deleted_ids, &pending, &mut cascades)?;
+            }
+            cascades.prepare(&pending, now)?;
+        }
+        Ok(cascades)
+    }
+
+    /// Checks the actions of the transaction, without the references to the deleted ids.
+    pub(crate) fn check(&self, now: u64) -> Result<(), TxError>
+    where
+        Data: 'static,
+        B: 'static,
+    {
+        let pending = PendingIds::new(&self.db.backend, self);
+        let mut conflicts = vec![];
+        for action in self.actions.iter().flatten() {
+            let result = self.validate(&*self.db.backend.borrow(), action, now);
+            // The references read the parent backend, that is this one for a self reference,
+            // so they are checked once the lock of this backend is released
+            let result = result.and_then(|()| self.validate_references(action, &pending));
+            if let Err(error) = result {
+                match self.validation_mode {
+                    ValidationMode::FailFast => return Err(error),
+                    ValidationMode::Exhaustive => conflicts.push(Conflict {
+                        id: action.id().to_key_string(),
+                        reason: error,
+                    }),
+                }
             }
         }
+        if !conflicts.is_empty() {
+            return Err(TxError::Conflicts(conflicts));
+        }
+
+        let backend = self.db.backend.borrow();
+        let reader = TxReader {
+            backend: &*backend,
+            tx: self,
+        };
+        for (name, check) in &self.db.global_checks {
+            check(&reader).map_err(|reason| TxError::GlobalCheckError {
+                name: name.clone(),
+                reason,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Step 2: applies the changes and returns the new version of the written models
+    fn write(&mut self, now: u64) -> Result<Vec<TxChange<Data, B>>, TxError> {
+        let mut backend = self.db.backend.borrow_mut();
+        let author = match self.db.metadata {
+            true => (self.db.author)(),
+            false => None,
+        };
+        let schema_version = self.db.schema_version();
+        let actions: Vec<_> = self.actions.drain(..).flatten().collect();
+        let mut changes = Vec::with_capacity(actions.len());
+
+        // The previous data is read and migrated before the first write, so a failed migration writes nothing
+        let previous = actions
+            .iter()
+            .map(|action| stored_data(&self.db, &*backend, action.id()))
+            .collect::<Result<Vec<_>, _>>()?;
+        // The metadata of the updated models is always the stored one, never the one sent with the model,
+        // e.g. by the logic canister of a storage canister, and it is dropped if the metadata is disabled
+        let stored_metadata = actions
+            .iter()
+            .map(|action| match action {
+                Action::Update { model } if self.db.metadata => Ok(backend
+                    .fetch_option_one(&model.id)?
+                    .and_then(|stored| stored.metadata)),
+                _ => Ok(None),
+            })
+            .collect::<Result<Vec<_>, TxError>>()?;
 
-        for action in self.actions.drain(..) {
+        for (action, stored_metadata) in actions.into_iter().zip(stored_metadata) {
             match action {
-                Action::Create { model } => backend.save(model)?,
+                Action::Create { model } => {
+                    let version = initial_version(&*backend, &model.id)?;
+                    let mut model = Model::from((model.id, version, model.data));
+                    model.schema_version = schema_version;
+                    if self.db.metadata {
+                        model.metadata = Some(Metadata::created(now, author));
+                    }
+                    changes.push((model.id.clone(), Some(version)));
+                    backend.save(model)?
+                }
                 // Action::Read { .. } => (),
-                Action::Update { model } => backend.update(model.into_new_version())?,
-                Action::Delete { id, version: _ } => backend.delete(&id)?,
+                Action::Update { model } => {
+                    let (id, version) = (model.id.clone(), model.version);
+                    let mut model = model
+                        .into_new_version()
+                        .ok_or_else(|| version_overflow_error(&id, version))?;
+                    model.schema_version = schema_version;
+                    if self.db.metadata && self.replaced.contains(&id) {
+                        // The model was deleted and saved again, so it is a new model
+                        model.metadata = Some(Metadata::created(now, author));
+                    } else if self.db.metadata {
+                        model.metadata = Some(match stored_metadata {
+                            Some(metadata) => metadata.updated(now, author),
+                            None => Metadata::created(now, author),
+                        });
+                    } else {
+                        model.metadata = None;
+                    }
+                    changes.push((id, Some(model.version)));
+                    backend.update(model)?
+                }
+                Action::Delete { id, version: _ } => {
+                    backend.delete(&id)?;
+                    changes.push((id, None))
+                }
                 Action::DeleteOption { id, version: _ } => {
-                    backend.delete_option(&id).map(|_| ())?
+                    backend.delete_option(&id)?;
+                    changes.push((id, None))
                 }
             }
         }
 
-        Ok(())
+        // The views and the indexes are updated only once all the models are written
+        for ((id, version), previous) in changes.iter().zip(previous) {
+            let added = match version {
+                Some(_) => stored_data(&self.db, &*backend, id)?,
+                None => None,
+            };
+            self.db.views.apply(previous.as_ref(), added.as_ref());
+            self.db.indexes.apply(id, previous.as_ref(), added.as_ref());
+        }
+
+        Ok(changes)
+    }
+
+    /// Checks the transaction and its cascades as the commit does, and locks the ids changed by the cascades
+    /// in the collections with a lock manager until the prepared transaction is committed or dropped.
+    /// The returned transaction is written by `PreparedTx::commit` without checking it again,
+    /// e.g. once the coordinator of a distributed transaction decided to commit it.
+    pub(crate) fn prepare_commit(self, now: u64) -> Result<PreparedTx<Data, B>, TxError>
+    where
+        Data: 'static,
+        B: 'static,
+    {
+        let _guard = commit_guard(&self.db.commit_lock)?;
+        let mut cascades = self.prepare(now)?;
+        cascades.hold()?;
+        Ok(PreparedTx { tx: self, cascades })
+    }
+
+    /// Writes the changes of a transaction checked during the commit of another one, e.g. the deletes of a cascade.
+    /// They are written right after the changes of the committed transaction.
+    pub(crate) fn write_checked(&mut self, now: u64) -> Result<(), TxError> {
+        self.completed = true;
+        self.write(now).map(|_| ())
     }
 
     pub fn rollback(mut self) {
         self.completed = true;
-        // Do nothing
+        self.release_locks();
+        self.db.metrics.borrow_mut().record_rollback();
+    }
+
+    fn release_locks(&mut self) {
+        if let (Some(locks), Some(owner)) = (&self.db.locks, self.lock_owner.take()) {
+            locks.borrow_mut().release_all(owner, (self.db.clock)());
+        }
+    }
+}
+
+/// A transaction checked with its cascades by `Tx::prepare_commit`.
+/// Dropping it discards the changes and releases the locks.
+pub(crate) struct PreparedTx<Data, B: Backend<Data>> {
+    tx: Tx<Data, B>,
+    cascades: Cascades,
+}
+
+impl<Data, B: Backend<Data>> PreparedTx<Data, B> {
+    /// Writes the checked changes and their cascades without checking them again:
+    /// their ids are locked since the prepare, so no other transaction changed them.
+    pub(crate) fn commit(self, now: u64) -> Result<(), TxError> {
+        let PreparedTx {
+            mut tx,
+            mut cascades,
+        } = self;
+        let _guard = commit_guard(&tx.db.commit_lock)?;
+        let actions = tx.actions.iter().flatten().count();
+        let result = tx.write_checked(now).and_then(|()| cascades.write(now));
+        tx.release_locks();
+        match &result {
+            Ok(()) => tx.db.metrics.borrow_mut().record_commit(actions),
+            Err(err) => tx.db.metrics.borrow_mut().record_failed_commit(err),
+        }
+        result
+    }
+
+    /// Discards the changes and releases the locks.
+    pub(crate) fn rollback(self) {
+        self.tx.rollback()
+    }
+}
+
+// A transaction dropped without a commit or a rollback does not keep its leases until they expire
+impl<Data, B: Backend<Data>> Drop for Tx<Data, B> {
+    fn drop(&mut self) {
+        self.release_locks();
+    }
+}
+
+/// A read only view of the database as it would be after the commit of a transaction.
+/// The pending actions of the transaction are applied on top of the models stored in the backend.
+pub struct TxReader<'a, Data, B: Backend<Data>> {
+    backend: &'a B,
+    tx: &'a Tx<Data, B>,
+}
+
+impl<Data: Clone, B: Backend<Data>> TxReader<'_, Data, B> {
+    /// Fetches a model.
+    /// Returns an error if no model is found with the specified id.
+    pub fn fetch_one(&self, id: &B::IdType) -> Result<BackendModel<Data, B>, TxError> {
+        self.fetch_option_one(id)?
+            .ok_or_else(|| TxError::FetchNotFoundError {
+                id: id.to_key_string(),
+            })
+    }
+
+    /// Fetches a model.
+    pub fn fetch_option_one(
+        &self,
+        id: &B::IdType,
+    ) -> Result<Option<BackendModel<Data, B>>, TxError> {
+        match self.pending_action(id) {
+            Some(action) => self.pending_model(action),
+            None => self
+                .backend
+                .fetch_option_one(id)?
+                .map(|model| migrate(&self.tx.db.migrations, model))
+                .transpose(),
+        }
+    }
+
+    /// Fetches all the models.
+    pub fn fetch_all(&self) -> Result<Vec<BackendModel<Data, B>>, TxError> {
+        let mut models = vec![];
+        for model in self.backend.fetch_all()? {
+            if self.pending_action(&model.id).is_none() {
+                models.push(migrate(&self.tx.db.migrations, model)?);
+            }
+        }
+        for action in self.tx.actions.iter().flatten() {
+            models.extend(self.pending_model(action)?);
+        }
+        Ok(models)
+    }
+
+    /// Returns the ids of the models changed by the transaction.
+    pub fn changed_ids(&self) -> impl Iterator<Item = &B::IdType> {
+        self.tx.actions.iter().flatten().map(Action::id)
+    }
+
+    fn pending_action(&self, id: &B::IdType) -> Option<&TxAction<Data, B>> {
+        self.tx.pending_action(id)
+    }
+
+    fn pending_model(
+        &self,
+        action: &TxAction<Data, B>,
+    ) -> Result<Option<BackendModel<Data, B>>, TxError> {
+        let mut model = match action {
+            Action::Create { model } => Model::from((
+                model.id.clone(),
+                initial_version(self.backend, &model.id)?,
+                model.data.clone(),
+            )),
+            Action::Update { model } => model
+                .clone()
+                .into_new_version()
+                .ok_or_else(|| version_overflow_error(&model.id, model.version))?,
+            Action::Delete { .. } | Action::DeleteOption { .. } => return Ok(None),
+        };
+        model.schema_version = self.tx.db.schema_version();
+        Ok(Some(model))
+    }
+}
+
+/// Returns the stored data of a model, needed to update the materialized views and the indexes.
+fn stored_data<Data, B: Backend<Data>>(
+    db: &IcTx<Data, B>,
+    backend: &B,
+    id: &B::IdType,
+) -> Result<Option<Data>, TxError> {
+    if db.views.is_empty() && db.indexes.is_empty() {
+        return Ok(None);
+    }
+    match backend.fetch_option_one(id)? {
+        Some(model) => Ok(Some(migrate(&db.migrations, model)?.data)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the version of a newly created model.
+/// If the id belonged to a deleted model, the version continues from the one of its tombstone
+/// so that a stale copy of the deleted model can never match the new one.
+fn initial_version<Data, B: Backend<Data>>(
+    backend: &B,
+    id: &B::IdType,
+) -> Result<B::VersionType, TxError> {
+    match backend.fetch_option_tombstone(id)? {
+        Some(version) => version
+            .next()
+            .ok_or_else(|| version_overflow_error(id, version)),
+        None => Ok(B::VersionType::default()),
+    }
+}
+
+fn version_overflow_error<IdType: Key, V: Version>(id: &IdType, version: V) -> TxError {
+    TxError::VersionOverflowError {
+        id: id.to_key_string(),
+        version: version.into(),
     }
 }
 
 #[cfg(test)]
 mod test {
 
-    use std::rc::Rc;
+    use ic_principal::Principal;
 
-    use crate::{backend::hashmap::HashmapBackend, db::IcTx};
+    use crate::{backend::hashmap::HashmapBackend, metrics::MetricsSnapshot, Cell, Ref, RefCell};
 
     use super::*;
 
     #[test]
     fn should_commit_a_tx() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let model = NewModel { id: 1, data: 1123 };
 
         // Act
@@ -209,7 +947,7 @@ mod test {
     #[test]
     fn should_rollback_a_tx() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let model = NewModel { id: 1, data: 1123 };
 
         // Act
@@ -225,19 +963,15 @@ mod test {
 
     #[test]
     #[should_panic]
+    #[allow(clippy::assertions_on_constants)]
     fn commit_should_panic_if_failure() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
 
         // Act
         {
             let mut tx = db.tx();
-            tx.update(Model {
-                id: 1,
-                version: 12,
-                data: 1123,
-            })
-            .unwrap();
+            tx.update(Model::from((1, 12, 1123))).unwrap();
             assert!(true, "The update should succeed");
 
             tx.commit();
@@ -248,7 +982,7 @@ mod test {
     #[test]
     fn commit_should_fail_if_concurrent_creation() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let model_1 = NewModel { id: 1, data: 1111 };
 
         let model_2 = NewModel { id: 1, data: 2222 };
@@ -279,7 +1013,7 @@ mod test {
     #[test]
     fn commit_should_fail_if_concurrent_update() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let model_1 = NewModel { id: 1, data: 1111 };
         {
             let mut tx = db.tx();
@@ -294,12 +1028,8 @@ mod test {
 
         let tx_2_result = {
             let mut tx_2 = db.tx();
-            tx_2.update(Model {
-                id: model_1.id,
-                version: model_1.version,
-                data: 2222,
-            })
-            .unwrap();
+            tx_2.update(Model::from((model_1.id, model_1.version, 2222)))
+                .unwrap();
             tx_2.inner_commit()
         };
 
@@ -319,7 +1049,7 @@ mod test {
     #[test]
     fn commit_should_fail_if_concurrent_delete() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let model_1 = NewModel { id: 1, data: 1111 };
         {
             let mut tx = db.tx();
@@ -352,7 +1082,7 @@ mod test {
     #[test]
     fn save_should_save_a_model() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let model = NewModel { id: 1, data: 1123 };
 
         // Act
@@ -374,7 +1104,7 @@ mod test {
     #[test]
     fn save_should_fail_if_key_exists() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let model = NewModel { id: 1, data: 1123 };
 
         // Act
@@ -401,7 +1131,7 @@ mod test {
     #[test]
     fn fetch_one_should_fail_if_missing() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
 
         // Act
         let fetched_model_0 = db.fetch_one(&0);
@@ -415,7 +1145,7 @@ mod test {
     #[test]
     fn fetch_option_one_should_return_none_if_missing() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
 
         // Act
         let fetched_model_0 = db.fetch_option_one(&0).unwrap();
@@ -429,7 +1159,7 @@ mod test {
     #[test]
     fn update_should_update_a_model() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let model = NewModel { id: 1, data: 1111 };
         {
             let mut tx = db.tx();
@@ -459,12 +1189,8 @@ mod test {
     #[test]
     fn update_should_fail_if_id_does_not_exists() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
-        let model = Model {
-            id: 1,
-            version: 0,
-            data: 1111,
-        };
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let model = Model::from((1, 0, 1111));
 
         // Act
         let update_result = {
@@ -479,10 +1205,27 @@ mod test {
         assert!(fetched_model.is_none());
     }
 
+    #[test]
+    fn delete_should_fail_if_id_does_not_exists() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let model = Model::from((1, 0, 1111));
+
+        // Act
+        let delete_result = {
+            let mut tx = db.tx();
+            tx.delete(model).unwrap();
+            tx.inner_commit()
+        };
+
+        // Assert
+        assert!(matches!(delete_result, Err(TxError::DeleteError { id, .. }) if id == "1"));
+    }
+
     #[test]
     fn update_should_fail_if_version_mismatch() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let model = NewModel { id: 1, data: 1111 };
         {
             let mut tx = db.tx();
@@ -518,7 +1261,14 @@ mod test {
         assert_eq!(0, fetched_model_0.version);
 
         assert!(result_1.is_ok());
-        assert!(result_2.is_err());
+        assert_eq!(
+            Err(TxError::UpdateOptimisticLockError {
+                id: "1".to_owned(),
+                expected: 0,
+                found: 1
+            }),
+            result_2
+        );
         assert!(result_3.is_ok());
 
         assert_eq!(model.id, fetched_model_1.id);
@@ -528,10 +1278,48 @@ mod test {
         assert_eq!(2, fetched_model_2.version);
     }
 
+    #[test]
+    fn update_should_fail_if_version_overflows() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(
+            HashmapBackend::<i32, i32>::with_map([(1, Model::from((1, u32::MAX, 1111)))].into()),
+        )));
+        let db_u64 = IcTx::new(Ref::new(RefCell::new(
+            HashmapBackend::<i32, i32, u64>::with_map(
+                [(1, Model::from((1, u64::from(u32::MAX), 1111)))].into(),
+            ),
+        )));
+
+        // Act
+        let update_result = {
+            let mut tx = db.tx();
+            tx.update(db.fetch_one(&1).unwrap()).unwrap();
+            tx.inner_commit()
+        };
+        let update_result_u64 = {
+            let mut tx = db_u64.tx();
+            tx.update(db_u64.fetch_one(&1).unwrap()).unwrap();
+            tx.inner_commit()
+        };
+
+        // Assert
+        assert!(matches!(
+            update_result,
+            Err(TxError::VersionOverflowError { .. })
+        ));
+        assert_eq!(u32::MAX, db.fetch_one(&1).unwrap().version);
+
+        assert!(update_result_u64.is_ok());
+        assert_eq!(
+            u64::from(u32::MAX) + 1,
+            db_u64.fetch_one(&1).unwrap().version
+        );
+    }
+
     #[test]
     fn delete_should_delete_an_existing_model() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let new_model = NewModel { id: 1, data: 1123 };
         {
             let mut tx = db.tx();
@@ -562,10 +1350,49 @@ mod test {
         assert!(delete_result_2.is_err());
     }
 
+    #[test]
+    fn update_should_fail_if_model_was_deleted_and_created_again() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let new_model = NewModel { id: 1, data: 1111 };
+        {
+            let mut tx = db.tx();
+            tx.save(new_model.clone()).unwrap();
+            tx.commit();
+        }
+        let stale_model = db.fetch_one(&new_model.id).unwrap();
+
+        // Act
+        {
+            let mut tx = db.tx();
+            tx.delete(stale_model.clone()).unwrap();
+            tx.commit();
+        }
+        {
+            let mut tx = db.tx();
+            tx.save(new_model.clone()).unwrap();
+            tx.commit();
+        }
+        let update_result = {
+            let mut tx = db.tx();
+            tx.update(stale_model.clone()).unwrap();
+            tx.inner_commit()
+        };
+        let fetched_model = db.fetch_one(&new_model.id).unwrap();
+
+        // Assert
+        assert_eq!(0, stale_model.version);
+        assert!(matches!(
+            update_result,
+            Err(TxError::UpdateOptimisticLockError { .. })
+        ));
+        assert_eq!(1, fetched_model.version);
+    }
+
     #[test]
     fn delete_should_fail_if_version_does_not_match() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let new_model = NewModel { id: 1, data: 1123 };
         {
             let mut tx = db.tx();
@@ -597,7 +1424,7 @@ mod test {
     #[test]
     fn delete_option_should_delete_a_model() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let new_model = NewModel { id: 1, data: 1123 };
         {
             let mut tx = db.tx();
@@ -631,7 +1458,7 @@ mod test {
     #[test]
     fn delete_option_should_fail_if_version_does_not_match() {
         // Arrange
-        let db = IcTx::new(Rc::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
         let new_model = NewModel { id: 1, data: 1123 };
         {
             let mut tx = db.tx();
@@ -659,4 +1486,544 @@ mod test {
         assert!(delete_result_1.is_err());
         assert!(fetched_after.is_some());
     }
+
+    #[test]
+    fn lock_should_fail_if_lock_manager_is_not_enabled() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+
+        // Act
+        let result = db.tx().lock(&1, Duration::from_secs(10));
+
+        // Assert
+        assert!(matches!(result, Err(TxError::LockError { .. })));
+    }
+
+    #[test]
+    fn commit_should_fail_if_model_is_locked_by_another_tx() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())))
+            .with_lock_manager();
+        let model = NewModel { id: 1, data: 1111 };
+        {
+            let mut tx = db.tx();
+            tx.save(model.clone()).unwrap();
+            tx.commit();
+        }
+        let model = db.fetch_one(&model.id).unwrap();
+
+        // Act
+        let mut tx_1 = db.tx();
+        tx_1.lock(&model.id, Duration::from_secs(10)).unwrap();
+
+        let lock_result = db.tx().lock(&model.id, Duration::from_secs(10));
+        let tx_2_result = {
+            let mut tx_2 = db.tx();
+            tx_2.update(model.clone()).unwrap();
+            tx_2.inner_commit()
+        };
+
+        tx_1.update(model.clone()).unwrap();
+        let tx_1_result = tx_1.inner_commit();
+
+        // Assert
+        assert!(matches!(lock_result, Err(TxError::LockError { .. })));
+        assert!(matches!(tx_2_result, Err(TxError::LockError { .. })));
+        assert!(tx_1_result.is_ok());
+        assert!(!db.is_locked(&model.id));
+        assert_eq!(1, db.fetch_one(&model.id).unwrap().version);
+    }
+
+    #[test]
+    fn rollback_should_release_the_locks() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())))
+            .with_lock_manager();
+        let mut tx_1 = db.tx();
+        tx_1.lock(&1, Duration::from_secs(10)).unwrap();
+
+        // Act
+        let locked_before = db.is_locked(&1);
+        tx_1.rollback();
+        let locked_after = db.is_locked(&1);
+
+        // Assert
+        assert!(locked_before);
+        assert!(!locked_after);
+    }
+
+    #[test]
+    fn dropped_tx_should_release_the_locks() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())))
+            .with_lock_manager();
+        let mut tx_1 = db.tx();
+        tx_1.lock(&1, Duration::from_secs(10)).unwrap();
+
+        // Act
+        drop(tx_1);
+        let mut tx_2 = db.tx();
+        let lock = tx_2.lock(&1, Duration::from_secs(10));
+
+        // Assert
+        assert!(lock.is_ok());
+    }
+
+    #[test]
+    fn expired_lease_should_not_block_other_txs() {
+        // Arrange
+        let now = Ref::new(Cell::new(1_000));
+        let clock = now.clone();
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())))
+            .with_lock_manager()
+            .with_clock(move || clock.get());
+
+        // Simulates a message that trapped after taking the lease
+        let mut tx_1 = db.tx();
+        tx_1.lock(&1, Duration::from_nanos(100)).unwrap();
+        std::mem::forget(tx_1);
+
+        // Act
+        let result_before_expiry = {
+            let mut tx = db.tx();
+            tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+            tx.inner_commit()
+        };
+        now.set(1_100);
+        let result_after_expiry = {
+            let mut tx = db.tx();
+            tx.save(NewModel { id: 1, data: 2222 }).unwrap();
+            tx.inner_commit()
+        };
+
+        // Assert
+        assert!(matches!(
+            result_before_expiry,
+            Err(TxError::LockError { .. })
+        ));
+        assert!(result_after_expiry.is_ok());
+        assert_eq!(2222, db.fetch_one(&1).unwrap().data);
+    }
+
+    #[test]
+    fn commit_should_maintain_the_metadata() {
+        // Arrange
+        let now = Ref::new(Cell::new(1_000));
+        let clock = now.clone();
+        let author = Ref::new(Cell::new(Principal::anonymous()));
+        let caller = author.clone();
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())))
+            .with_metadata()
+            .with_clock(move || clock.get())
+            .with_author(move || Some(caller.get()));
+        let alice = Principal::from_slice(&[1]);
+
+        // Act
+        {
+            let mut tx = db.tx();
+            tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+            tx.save(NewModel { id: 2, data: 2222 }).unwrap();
+            tx.commit();
+        }
+        let created_model = db.fetch_one(&1).unwrap();
+
+        now.set(2_000);
+        author.set(alice);
+        {
+            let mut tx = db.tx();
+            tx.update(created_model.clone()).unwrap();
+            tx.update(Model::from((2, 0, 3333))).unwrap();
+            tx.commit();
+        }
+        let updated_model = db.fetch_one(&1).unwrap();
+        let updated_by_hand_model = db.fetch_one(&2).unwrap();
+
+        // Assert
+        let created_metadata = Metadata {
+            created_at: 1_000,
+            updated_at: 1_000,
+            created_by: Some(Principal::anonymous()),
+            updated_by: Some(Principal::anonymous()),
+        };
+        let updated_metadata = Metadata {
+            updated_at: 2_000,
+            updated_by: Some(alice),
+            ..created_metadata.clone()
+        };
+        assert_eq!(Some(&created_metadata), created_model.metadata());
+        assert_eq!(Some(&updated_metadata), updated_model.metadata());
+        assert_eq!(Some(&updated_metadata), updated_by_hand_model.metadata());
+    }
+
+    #[test]
+    fn commit_should_ignore_the_metadata_sent_with_the_model() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())))
+            .with_metadata()
+            .with_clock(|| 1_000)
+            .with_author(|| Some(Principal::anonymous()));
+        let mut tx = db.tx();
+        tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+        tx.commit();
+        let mut model = db.fetch_one(&1).unwrap();
+        model.metadata = Some(Metadata {
+            created_at: 0,
+            updated_at: 0,
+            created_by: Some(Principal::from_slice(&[1])),
+            updated_by: Some(Principal::from_slice(&[1])),
+        });
+
+        // Act
+        let result = db.commit_actions(vec![Action::Update { model }]);
+
+        // Assert
+        assert!(result.is_ok());
+        assert_eq!(
+            Some(&Metadata {
+                created_at: 1_000,
+                updated_at: 1_000,
+                created_by: Some(Principal::anonymous()),
+                updated_by: Some(Principal::anonymous()),
+            }),
+            db.fetch_one(&1).unwrap().metadata()
+        );
+    }
+
+    #[test]
+    fn commit_should_drop_the_metadata_sent_with_the_model_if_disabled() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let mut tx = db.tx();
+        tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+        tx.commit();
+        let mut model = db.fetch_one(&1).unwrap();
+        model.metadata = Some(Metadata {
+            created_at: 0,
+            updated_at: 0,
+            created_by: Some(Principal::from_slice(&[1])),
+            updated_by: Some(Principal::from_slice(&[1])),
+        });
+
+        // Act
+        let result = db.commit_actions(vec![Action::Update { model }]);
+
+        // Assert
+        assert!(result.is_ok());
+        assert!(db.fetch_one(&1).unwrap().metadata().is_none());
+    }
+
+    #[test]
+    fn commit_should_not_set_the_metadata_if_disabled() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+        tx.commit();
+
+        // Assert
+        assert!(db.fetch_one(&1).unwrap().metadata().is_none());
+    }
+
+    #[test]
+    fn commit_should_report_every_conflict() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        {
+            let mut tx = db.tx();
+            for id in 0..5 {
+                tx.save(NewModel { id, data: 1111 }).unwrap();
+            }
+            tx.commit();
+        }
+        let stale_models = (0..4)
+            .map(|id| db.fetch_one(&id).unwrap())
+            .collect::<Vec<_>>();
+        {
+            let mut tx = db.tx();
+            tx.update(stale_models[1].clone()).unwrap();
+            tx.update(stale_models[3].clone()).unwrap();
+            tx.commit();
+        }
+
+        // Act
+        let fail_fast_result = {
+            let mut tx = db.tx();
+            for model in &stale_models {
+                tx.update(model.clone()).unwrap();
+            }
+            tx.inner_commit()
+        };
+        let exhaustive_result = {
+            let mut tx = db.tx().with_validation_mode(ValidationMode::Exhaustive);
+            for model in &stale_models {
+                tx.update(model.clone()).unwrap();
+            }
+            tx.save(NewModel { id: 4, data: 2222 }).unwrap();
+            tx.inner_commit()
+        };
+
+        // Assert
+        assert!(matches!(
+            fail_fast_result,
+            Err(TxError::UpdateOptimisticLockError { .. })
+        ));
+        assert_eq!(
+            Err(TxError::Conflicts(vec![
+                Conflict {
+                    id: "1".to_owned(),
+                    reason: TxError::UpdateOptimisticLockError {
+                        id: "1".to_owned(),
+                        expected: 0,
+                        found: 1
+                    }
+                },
+                Conflict {
+                    id: "3".to_owned(),
+                    reason: TxError::UpdateOptimisticLockError {
+                        id: "3".to_owned(),
+                        expected: 0,
+                        found: 1
+                    }
+                },
+                Conflict {
+                    id: "4".to_owned(),
+                    reason: TxError::SaveError {
+                        id: "4".to_owned(),
+                        message: "The id is already in use.".to_owned()
+                    }
+                },
+            ])),
+            exhaustive_result
+        );
+        for id in 0..5 {
+            assert_eq!(1111, db.fetch_one(&id).unwrap().data);
+        }
+    }
+
+    #[test]
+    fn save_and_update_should_be_coalesced() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+        tx.update(Model::from((1, 2222))).unwrap();
+        tx.save(NewModel { id: 2, data: 1111 }).unwrap();
+        tx.delete(Model::from((2, 1111))).unwrap();
+        tx.commit();
+
+        // Assert
+        let fetched_model = db.fetch_one(&1).unwrap();
+        assert_eq!(2222, fetched_model.data);
+        assert_eq!(0, fetched_model.version);
+        assert!(db.fetch_option_one(&2).unwrap().is_none());
+    }
+
+    #[test]
+    fn update_and_delete_should_be_coalesced() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        {
+            let mut tx = db.tx();
+            tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+            tx.save(NewModel { id: 2, data: 1111 }).unwrap();
+            tx.commit();
+        }
+        let model_1 = db.fetch_one(&1).unwrap();
+        let model_2 = db.fetch_one(&2).unwrap();
+
+        // Act
+        let mut tx = db.tx();
+        tx.update(model_1.clone()).unwrap();
+        tx.update(Model::from((1, 0, 2222))).unwrap();
+        tx.update(model_2.clone()).unwrap();
+        tx.delete_option(model_2.clone()).unwrap();
+        tx.commit();
+
+        // Assert
+        let fetched_model = db.fetch_one(&1).unwrap();
+        assert_eq!(2222, fetched_model.data);
+        assert_eq!(1, fetched_model.version);
+        assert!(db.fetch_option_one(&2).unwrap().is_none());
+    }
+
+    #[test]
+    fn delete_and_save_should_replace_the_model() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        {
+            let mut tx = db.tx();
+            tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+            tx.commit();
+        }
+        let model = db.fetch_one(&1).unwrap();
+
+        // Act
+        let mut tx = db.tx();
+        tx.delete(model).unwrap();
+        tx.save(NewModel { id: 1, data: 2222 }).unwrap();
+        tx.commit();
+
+        // Assert
+        let fetched_model = db.fetch_one(&1).unwrap();
+        assert_eq!(2222, fetched_model.data);
+        assert_eq!(1, fetched_model.version);
+    }
+
+    #[test]
+    fn delete_and_save_should_reset_the_metadata() {
+        // Arrange
+        let now = Ref::new(Cell::new(1_000));
+        let clock = now.clone();
+        let author = Ref::new(Cell::new(Principal::anonymous()));
+        let caller = author.clone();
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())))
+            .with_metadata()
+            .with_clock(move || clock.get())
+            .with_author(move || Some(caller.get()));
+        let alice = Principal::from_slice(&[1]);
+        {
+            let mut tx = db.tx();
+            tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+            tx.commit();
+        }
+        let model = db.fetch_one(&1).unwrap();
+
+        // Act
+        now.set(2_000);
+        author.set(alice);
+        let mut tx = db.tx();
+        tx.delete(model).unwrap();
+        tx.save(NewModel { id: 1, data: 2222 }).unwrap();
+        tx.commit();
+
+        // Assert
+        assert_eq!(
+            Some(&Metadata {
+                created_at: 2_000,
+                updated_at: 2_000,
+                created_by: Some(alice),
+                updated_by: Some(alice),
+            }),
+            db.fetch_one(&1).unwrap().metadata()
+        );
+    }
+
+    #[test]
+    fn save_and_delete_of_a_stored_model_should_be_rejected() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        {
+            let mut tx = db.tx();
+            tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+            tx.commit();
+        }
+        let stored_model = db.fetch_one(&1).unwrap();
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel { id: 1, data: 2222 }).unwrap();
+        let delete_stored = tx.delete(stored_model);
+        tx.save(NewModel { id: 2, data: 2222 }).unwrap();
+        let delete_other_version = tx.delete(Model::from((2, 5, 2222)));
+        let result = tx.try_commit();
+
+        // Assert
+        assert!(matches!(
+            delete_stored,
+            Err(TxError::ConflictingActionsError { .. })
+        ));
+        assert!(matches!(
+            delete_other_version,
+            Err(TxError::ConflictingActionsError { .. })
+        ));
+        assert!(matches!(result, Err(TxError::SaveError { .. })));
+        assert_eq!(1111, db.fetch_one(&1).unwrap().data);
+        assert!(db.fetch_option_one(&2).unwrap().is_none());
+    }
+
+    #[test]
+    fn contradictory_actions_should_be_rejected() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        {
+            let mut tx = db.tx();
+            tx.save(NewModel { id: 2, data: 1111 }).unwrap();
+            tx.commit();
+        }
+        let model = db.fetch_one(&2).unwrap();
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+        let double_save = tx.save(NewModel { id: 1, data: 2222 });
+        tx.update(model.clone()).unwrap();
+        let update_with_other_version = tx.update(Model::from((2, 1, 2222)));
+        let save_after_update = tx.save(NewModel { id: 2, data: 2222 });
+        tx.delete(model.clone()).unwrap();
+        let update_after_delete = tx.update(model.clone());
+        tx.commit();
+
+        // Assert
+        assert!(matches!(
+            double_save,
+            Err(TxError::ConflictingActionsError { .. })
+        ));
+        assert!(matches!(
+            update_with_other_version,
+            Err(TxError::ConflictingActionsError { .. })
+        ));
+        assert!(matches!(
+            save_after_update,
+            Err(TxError::ConflictingActionsError { .. })
+        ));
+        assert!(matches!(
+            update_after_delete,
+            Err(TxError::ConflictingActionsError { .. })
+        ));
+        // The rejected actions leave the previous ones unchanged
+        assert_eq!(1111, db.fetch_one(&1).unwrap().data);
+        assert!(db.fetch_option_one(&2).unwrap().is_none());
+    }
+
+    #[test]
+    fn commits_and_rollbacks_should_update_the_metrics() {
+        // Arrange
+        let db = IcTx::new(Ref::new(RefCell::new(HashmapBackend::<i32, i32>::new())));
+        let mut tx = db.tx();
+        tx.save(NewModel { id: 1, data: 1111 }).unwrap();
+        tx.save(NewModel { id: 2, data: 2222 }).unwrap();
+        tx.commit();
+        let model = db.fetch_one(&1).unwrap();
+
+        // Act
+        let mut tx = db.tx();
+        tx.update(model.clone()).unwrap();
+        tx.commit();
+        let mut tx = db.tx();
+        tx.update(model).unwrap();
+        let conflict = tx.try_commit();
+        let mut tx = db.tx();
+        tx.save(NewModel { id: 3, data: 3333 }).unwrap();
+        tx.rollback();
+
+        // Assert
+        assert!(conflict.is_err());
+        assert_eq!(
+            MetricsSnapshot {
+                records: 2,
+                commits: 2,
+                failed_commits: 1,
+                rollbacks: 1,
+                committed_actions: 3,
+                max_actions_per_tx: 2,
+                conflicts: vec![("UpdateOptimisticLockError".to_owned(), 1)],
+                hottest_conflicts: vec![("1".to_owned(), 1)],
+            },
+            db.metrics().unwrap()
+        );
+    }
 }
@@ -0,0 +1,251 @@
+use crate::{shared::shared, tx::TxReader, Ref};
+
+/// A function that checks an invariant on the data of the models.
+/// It returns the reason of the failure if the data is not valid.
+pub type Validator<Data> = Ref<shared!(Fn(&Data) -> Result<(), String>)>;
+
+/// A function that returns the id contained in the data of the models, e.g. the `#[id]` field of an entity.
+pub type DataId<Data, IdType> = Ref<shared!(Fn(&Data) -> IdType)>;
+
+/// A function that checks an invariant spanning several models.
+/// It reads the database as it would be after the commit and returns the reason of the failure
+/// if the invariant does not hold.
+pub type GlobalCheck<Data, B> = Ref<shared!(Fn(&TxReader<Data, B>) -> Result<(), String>)>;
+
+#[cfg(test)]
+mod test {
+
+    use crate::{
+        backend::hashmap::HashmapBackend,
+        db::IcTx,
+        error::{Conflict, TxError},
+        model::NewModel,
+        test_utils::new_db,
+        tx::ValidationMode,
+        Ref, RefCell,
+    };
+
+    const MAX_TOKENS: u32 = 100;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct User {
+        username: String,
+        tokens: u32,
+    }
+
+    fn user(username: &str, tokens: u32) -> User {
+        User {
+            username: username.to_owned(),
+            tokens,
+        }
+    }
+
+    fn validated_db() -> IcTx<User, HashmapBackend<u32, User>> {
+        new_db()
+            .with_validator(|user: &User| match user.tokens <= MAX_TOKENS {
+                true => Ok(()),
+                false => Err(format!("The tokens cannot be more than {MAX_TOKENS}.")),
+            })
+            .with_validator(|user: &User| match user.username.is_empty() {
+                true => Err("The username cannot be empty.".to_owned()),
+                false => Ok(()),
+            })
+    }
+
+    #[test]
+    fn commit_should_fail_if_saved_data_is_not_valid() {
+        // Arrange
+        let db = validated_db();
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, user("", 10))).unwrap();
+        let result = tx.try_commit();
+
+        // Assert
+        assert_eq!(
+            Err(TxError::ValidationError {
+                id: "1".to_owned(),
+                reason: "The username cannot be empty.".to_owned()
+            }),
+            result
+        );
+        assert!(db.fetch_option_one(&1).unwrap().is_none());
+    }
+
+    #[test]
+    fn commit_should_fail_if_updated_data_is_not_valid() {
+        // Arrange
+        let db = validated_db();
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, user("ufoscout", 10))).unwrap();
+        tx.save(NewModel::new(2, user("other", 10))).unwrap();
+        tx.commit();
+
+        // Act
+        let mut tx = db.tx();
+        let mut model = db.fetch_one(&1).unwrap();
+        model.data.tokens = MAX_TOKENS + 1;
+        tx.update(model).unwrap();
+        let mut model = db.fetch_one(&2).unwrap();
+        model.data.tokens = MAX_TOKENS;
+        tx.update(model).unwrap();
+        let result = tx
+            .with_validation_mode(ValidationMode::Exhaustive)
+            .try_commit();
+
+        // Assert
+        assert_eq!(
+            Err(TxError::Conflicts(vec![Conflict {
+                id: "1".to_owned(),
+                reason: TxError::ValidationError {
+                    id: "1".to_owned(),
+                    reason: format!("The tokens cannot be more than {MAX_TOKENS}.")
+                }
+            }])),
+            result
+        );
+        assert_eq!(10, db.fetch_one(&1).unwrap().data.tokens);
+        assert_eq!(10, db.fetch_one(&2).unwrap().data.tokens);
+    }
+
+    fn new_bank() -> IcTx<User, HashmapBackend<u32, User>> {
+        IcTx::new(Ref::new(RefCell::new(HashmapBackend::<u32, User>::new()))).with_global_check(
+            "total_tokens",
+            |reader| {
+                let total: u32 = reader
+                    .fetch_all()
+                    .map_err(|err| err.to_string())?
+                    .iter()
+                    .map(|model| model.data.tokens)
+                    .sum();
+                match total == 100 {
+                    true => Ok(()),
+                    false => Err(format!("The total tokens are {total} instead of 100.")),
+                }
+            },
+        )
+    }
+
+    fn transfer(
+        db: &IcTx<User, HashmapBackend<u32, User>>,
+        from: u32,
+        to: u32,
+        withdrawn: u32,
+        deposited: u32,
+    ) -> Result<(), TxError> {
+        let mut tx = db.tx();
+        let mut from = tx.fetch_one(&from)?;
+        let mut to = tx.fetch_one(&to)?;
+        from.data.tokens -= withdrawn;
+        to.data.tokens += deposited;
+        tx.update(from)?;
+        tx.update(to)?;
+        tx.try_commit()
+    }
+
+    #[test]
+    fn global_check_should_see_the_pending_actions() {
+        // Arrange
+        let db = new_bank();
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, user("alice", 60))).unwrap();
+        tx.save(NewModel::new(2, user("bob", 40))).unwrap();
+        tx.commit();
+
+        // Act
+        let valid_transfer = transfer(&db, 1, 2, 10, 10);
+        let invalid_transfer = transfer(&db, 1, 2, 10, 11);
+
+        // Assert
+        assert!(valid_transfer.is_ok());
+        assert_eq!(
+            Err(TxError::GlobalCheckError {
+                name: "total_tokens".to_owned(),
+                reason: "The total tokens are 101 instead of 100.".to_owned()
+            }),
+            invalid_transfer
+        );
+        assert_eq!(50, db.fetch_one(&1).unwrap().data.tokens);
+        assert_eq!(50, db.fetch_one(&2).unwrap().data.tokens);
+    }
+
+    #[test]
+    fn global_check_should_see_created_and_deleted_models() {
+        // Arrange
+        let db = new_bank();
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, user("alice", 100))).unwrap();
+        tx.commit();
+
+        // Act
+        let mut tx = db.tx();
+        tx.delete(db.fetch_one(&1).unwrap()).unwrap();
+        tx.save(NewModel::new(2, user("bob", 100))).unwrap();
+        let move_result = tx.try_commit();
+
+        let mut tx = db.tx();
+        tx.delete(db.fetch_one(&2).unwrap()).unwrap();
+        let delete_result = tx.try_commit();
+
+        // Assert
+        assert!(move_result.is_ok());
+        assert!(matches!(
+            delete_result,
+            Err(TxError::GlobalCheckError { .. })
+        ));
+        assert!(db.fetch_option_one(&1).unwrap().is_none());
+        assert_eq!(100, db.fetch_one(&2).unwrap().data.tokens);
+    }
+
+    #[test]
+    fn reader_should_return_the_models_after_the_commit() {
+        // Arrange
+        let checked = Ref::new(RefCell::new(false));
+        let backend = Ref::new(RefCell::new(HashmapBackend::<u32, User>::new()));
+        let checked_by_reader = checked.clone();
+        let db = IcTx::new(backend.clone()).with_global_check("reader", move |reader| {
+            let updated = reader.fetch_one(&1).unwrap();
+            assert_eq!(1, updated.version());
+            assert_eq!(20, updated.data.tokens);
+            assert_eq!(0, reader.fetch_one(&2).unwrap().version());
+            assert!(reader.fetch_option_one(&3).unwrap().is_none());
+            assert_eq!(2, reader.fetch_all().unwrap().len());
+            assert_eq!(vec![&1, &2, &3], reader.changed_ids().collect::<Vec<_>>());
+            *checked_by_reader.borrow_mut() = true;
+            Ok(())
+        });
+        let mut tx = IcTx::new(backend).tx();
+        tx.save(NewModel::new(1, user("alice", 10))).unwrap();
+        tx.save(NewModel::new(3, user("carol", 10))).unwrap();
+        tx.commit();
+
+        // Act
+        let mut tx = db.tx();
+        let mut model = db.fetch_one(&1).unwrap();
+        model.data.tokens = 20;
+        tx.update(model).unwrap();
+        tx.save(NewModel::new(2, user("bob", 10))).unwrap();
+        tx.delete(db.fetch_one(&3).unwrap()).unwrap();
+        tx.commit();
+
+        // Assert
+        assert!(*checked.borrow());
+    }
+
+    #[test]
+    fn commit_should_succeed_if_data_is_valid() {
+        // Arrange
+        let db = validated_db();
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, user("ufoscout", MAX_TOKENS)))
+            .unwrap();
+        let result = tx.try_commit();
+
+        // Assert
+        assert!(result.is_ok());
+        assert_eq!(MAX_TOKENS, db.fetch_one(&1).unwrap().data.tokens);
+    }
+}
@@ -0,0 +1,255 @@
+use std::{any::Any, collections::HashMap};
+
+use crate::{shared::shared, Ref, RefCell, Shareable};
+
+/// A materialized view, the fold of the data of all the models kept up to date by the commits.
+trait View<Data>: Shareable {
+    fn add(&mut self, data: &Data);
+    fn remove(&mut self, data: &Data);
+    fn reset(&mut self);
+    fn state(&self) -> &dyn Any;
+}
+
+/// Folds the data of a model into the state of a view.
+type Fold<S, Data> = Box<shared!(Fn(&mut S, &Data))>;
+
+type ViewMap<Data> = HashMap<String, Box<dyn View<Data>>>;
+
+struct FoldView<Data, S> {
+    initial: S,
+    state: S,
+    add: Fold<S, Data>,
+    remove: Fold<S, Data>,
+}
+
+impl<Data, S: Clone + Shareable + 'static> View<Data> for FoldView<Data, S> {
+    fn add(&mut self, data: &Data) {
+        (self.add)(&mut self.state, data)
+    }
+
+    fn remove(&mut self, data: &Data) {
+        (self.remove)(&mut self.state, data)
+    }
+
+    fn reset(&mut self) {
+        self.state = self.initial.clone();
+    }
+
+    fn state(&self) -> &dyn Any {
+        &self.state
+    }
+}
+
+/// The materialized views of a database, shared by all its clones.
+pub(crate) struct Views<Data>(Ref<RefCell<ViewMap<Data>>>);
+
+impl<Data> Clone for Views<Data> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<Data> Default for Views<Data> {
+    fn default() -> Self {
+        Self(Ref::default())
+    }
+}
+
+impl<Data: 'static> Views<Data> {
+    pub(crate) fn insert<S: Clone + Shareable + 'static>(
+        &self,
+        name: &str,
+        initial: S,
+        add: impl Fn(&mut S, &Data) + Shareable + 'static,
+        remove: impl Fn(&mut S, &Data) + Shareable + 'static,
+    ) {
+        self.0.borrow_mut().insert(
+            name.to_owned(),
+            Box::new(FoldView {
+                state: initial.clone(),
+                initial,
+                add: Box::new(add),
+                remove: Box::new(remove),
+            }),
+        );
+    }
+}
+
+impl<Data> Views<Data> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
+    /// Updates the views with the change of a model: the removed data is the previous one
+    /// and the added data is the new one.
+    pub(crate) fn apply(&self, removed: Option<&Data>, added: Option<&Data>) {
+        for view in self.0.borrow_mut().values_mut() {
+            if let Some(data) = removed {
+                view.remove(data);
+            }
+            if let Some(data) = added {
+                view.add(data);
+            }
+        }
+    }
+
+    /// Recomputes the views from the data of all the models.
+    pub(crate) fn rebuild<'a>(&self, data: impl Iterator<Item = &'a Data>)
+    where
+        Data: 'a,
+    {
+        let mut views = self.0.borrow_mut();
+        views.values_mut().for_each(|view| view.reset());
+        for data in data {
+            views.values_mut().for_each(|view| view.add(data));
+        }
+    }
+
+    pub(crate) fn get<S: Clone + 'static>(&self, name: &str) -> Option<S> {
+        self.0
+            .borrow()
+            .get(name)
+            .and_then(|view| view.state().downcast_ref::<S>().cloned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::collections::BTreeMap;
+
+    use crate::{
+        backend::{hashmap::HashmapBackend, Backend},
+        db::IcTx,
+        error::TxError,
+        model::{Model, NewModel},
+        test_utils::new_db,
+        Ref, RefCell,
+    };
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct User {
+        status: &'static str,
+        tokens: u64,
+    }
+
+    fn user(status: &'static str, tokens: u64) -> User {
+        User { status, tokens }
+    }
+
+    fn db_with_views() -> IcTx<User, HashmapBackend<u32, User>> {
+        new_db()
+            .with_view(
+                "total_tokens",
+                0u64,
+                |total, user: &User| *total += user.tokens,
+                |total, user: &User| *total -= user.tokens,
+            )
+            .with_view(
+                "count_by_status",
+                BTreeMap::<&'static str, u32>::new(),
+                |counts, user: &User| *counts.entry(user.status).or_default() += 1,
+                |counts, user: &User| *counts.entry(user.status).or_default() -= 1,
+            )
+    }
+
+    #[test]
+    fn views_should_be_updated_by_the_commits() {
+        // Arrange
+        let db = db_with_views();
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, user("active", 10))).unwrap();
+        tx.save(NewModel::new(2, user("active", 20))).unwrap();
+        tx.save(NewModel::new(3, user("banned", 30))).unwrap();
+        tx.commit();
+
+        let mut tx = db.tx();
+        let mut model = db.fetch_one(&1).unwrap();
+        model.data = user("banned", 15);
+        tx.update(model).unwrap();
+        tx.delete(db.fetch_one(&3).unwrap()).unwrap();
+        tx.commit();
+
+        let mut tx = db.tx();
+        tx.save(NewModel::new(4, user("active", 100))).unwrap();
+        tx.rollback();
+
+        // Assert
+        assert_eq!(Some(35u64), db.view("total_tokens"));
+        assert_eq!(
+            Some(BTreeMap::from([("active", 1u32), ("banned", 1)])),
+            db.view("count_by_status")
+        );
+    }
+
+    #[test]
+    fn view_should_return_none_if_name_or_type_are_wrong() {
+        // Arrange
+        let db = db_with_views();
+
+        // Act
+        let missing = db.view::<u64>("missing");
+        let wrong_type = db.view::<u32>("total_tokens");
+
+        // Assert
+        assert!(missing.is_none());
+        assert!(wrong_type.is_none());
+    }
+
+    #[test]
+    fn views_should_be_rebuilt_from_scratch() {
+        // Arrange
+        let backend = Ref::new(RefCell::new(HashmapBackend::<u32, User>::new()));
+        let mut tx = IcTx::new(backend.clone()).tx();
+        tx.save(NewModel::new(1, user("active", 10))).unwrap();
+        tx.save(NewModel::new(2, user("active", 20))).unwrap();
+        tx.commit();
+        backend.borrow_mut().delete(&2).unwrap();
+
+        let db = IcTx::new(backend).with_view(
+            "total_tokens",
+            0u64,
+            |total, user: &User| *total += user.tokens,
+            |total, user: &User| *total -= user.tokens,
+        );
+        let before_rebuild = db.view::<u64>("total_tokens");
+
+        // Act
+        db.rebuild_views().unwrap();
+
+        // Assert
+        assert_eq!(Some(0), before_rebuild);
+        assert_eq!(Some(10u64), db.view("total_tokens"));
+    }
+
+    #[test]
+    fn views_should_not_change_if_the_commit_fails_while_writing() {
+        // Arrange
+        let backend = Ref::new(RefCell::new(HashmapBackend::<u32, User>::new()));
+        // The model is written with a schema version unknown to the database below
+        let mut tx = IcTx::new(backend.clone())
+            .with_migration(|user: User| user)
+            .tx();
+        tx.save(NewModel::new(2, user("active", 20))).unwrap();
+        tx.commit();
+        let db = IcTx::new(backend).with_view(
+            "total_tokens",
+            0u64,
+            |total, user: &User| *total += user.tokens,
+            |total, user: &User| *total -= user.tokens,
+        );
+
+        // Act
+        let mut tx = db.tx();
+        tx.save(NewModel::new(1, user("active", 10))).unwrap();
+        tx.delete(Model::from((2, 0, user("active", 20)))).unwrap();
+        let result = tx.try_commit();
+
+        // Assert
+        assert!(matches!(result, Err(TxError::MigrationError { .. })));
+        assert!(db.fetch_option_one(&1).unwrap().is_none());
+        assert_eq!(Some(0u64), db.view("total_tokens"));
+    }
+}
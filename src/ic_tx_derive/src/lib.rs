@@ -0,0 +1,226 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident, Type};
+
+/// Implements `ic_tx::entity::Entity` and generates a typed repository named after the struct, e.g. `UserRepo`.
+///
+/// The field marked with `#[id]` is the id of the models, and the commits fail if a model has a different id.
+/// Each field marked with `#[index]` or
+/// `#[index(unique)]` gets a secondary index named after it and a `fetch_by_<field>` method in the repository.
+/// The type of an indexed field must implement `Ord`: the derive rejects the floats with a dedicated error.
+#[proc_macro_derive(Entity, attributes(id, index))]
+pub fn derive_entity(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+struct IndexedField {
+    name: Ident,
+    ty: Type,
+    unique: bool,
+}
+
+/// Finds a float in the type, as the index keys must implement `Ord`.
+/// The check is syntactic, so the other unsupported types are still reported by the compiler.
+fn find_float(ty: &Type) -> Option<&Ident> {
+    match ty {
+        Type::Path(path) => path.path.segments.iter().find_map(|segment| {
+            if segment.ident == "f32" || segment.ident == "f64" {
+                return Some(&segment.ident);
+            }
+            let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+                return None;
+            };
+            args.args.iter().find_map(|arg| match arg {
+                syn::GenericArgument::Type(ty) => find_float(ty),
+                _ => None,
+            })
+        }),
+        Type::Tuple(tuple) => tuple.elems.iter().find_map(find_float),
+        Type::Array(array) => find_float(&array.elem),
+        Type::Reference(reference) => find_float(&reference.elem),
+        Type::Paren(paren) => find_float(&paren.elem),
+        Type::Group(group) => find_float(&group.elem),
+        _ => None,
+    }
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2, Error> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input,
+            "Entity can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(Error::new_spanned(
+            &input,
+            "Entity requires a struct with named fields",
+        ));
+    };
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "Entity cannot be derived for generic structs",
+        ));
+    }
+
+    let mut id = None;
+    let mut indexes = vec![];
+    for field in &fields.named {
+        let name = field.ident.clone().expect("named fields have a name");
+        for attr in &field.attrs {
+            if attr.path().is_ident("id") {
+                attr.meta.require_path_only()?;
+                if id.is_some() {
+                    return Err(Error::new_spanned(
+                        attr,
+                        "Entity requires a single #[id] field",
+                    ));
+                }
+                id = Some((name.clone(), field.ty.clone()));
+            } else if attr.path().is_ident("index") {
+                let mut unique = false;
+                if !matches!(attr.meta, syn::Meta::Path(_)) {
+                    attr.parse_nested_meta(|meta| {
+                        if meta.path.is_ident("unique") {
+                            unique = true;
+                            Ok(())
+                        } else {
+                            Err(meta.error("unsupported index option, expected `unique`"))
+                        }
+                    })?;
+                }
+                if let Some(float) = find_float(&field.ty) {
+                    let attr = match unique {
+                        true => "#[index(unique)]",
+                        false => "#[index]",
+                    };
+                    return Err(Error::new_spanned(
+                        &field.ty,
+                        format!("{attr} requires a field type that implements `Ord`, `{float}` does not"),
+                    ));
+                }
+                indexes.push(IndexedField {
+                    name: name.clone(),
+                    ty: field.ty.clone(),
+                    unique,
+                });
+            }
+        }
+    }
+    let Some((id_name, id_type)) = id else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "Entity requires a field marked with #[id]",
+        ));
+    };
+
+    let vis = &input.vis;
+    let entity = &input.ident;
+    let repo = format_ident!("{entity}Repo");
+    let repo_doc =
+        format!("The typed repository of [`{entity}`], generated by `#[derive(Entity)]`.");
+
+    let registrations = indexes.iter().map(|IndexedField { name, ty, unique }| {
+        let index = name.to_string();
+        let with_index = match unique {
+            true => quote!(with_unique_index),
+            false => quote!(with_index),
+        };
+        quote! {
+            let db = db.#with_index(#index, |entity: &#entity| -> #ty { ::std::clone::Clone::clone(&entity.#name) });
+        }
+    });
+
+    let fetch_by = indexes.iter().map(|IndexedField { name, ty, unique }| {
+        let index = name.to_string();
+        let method = format_ident!("fetch_by_{name}");
+        match unique {
+            true => {
+                let doc = format!("Fetches the model with the specified `{name}`, if any.");
+                quote! {
+                    #[doc = #doc]
+                    pub fn #method(&self, #name: &#ty) -> ::std::result::Result<::std::option::Option<::ic_tx::backend::BackendModel<#entity, B>>, ::ic_tx::error::TxError> {
+                        ::std::result::Result::Ok(self.db.query().index_eq(#index, #name).fetch()?.into_iter().next())
+                    }
+                }
+            }
+            false => {
+                let doc = format!("Fetches the models with the specified `{name}`.");
+                quote! {
+                    #[doc = #doc]
+                    pub fn #method(&self, #name: &#ty) -> ::std::result::Result<::std::vec::Vec<::ic_tx::backend::BackendModel<#entity, B>>, ::ic_tx::error::TxError> {
+                        self.db.query().index_eq(#index, #name).fetch()
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl ::ic_tx::entity::Entity for #entity {
+            type Id = #id_type;
+
+            fn id(&self) -> Self::Id {
+                ::std::clone::Clone::clone(&self.#id_name)
+            }
+
+            fn register<B: ::ic_tx::backend::Backend<Self, IdType = Self::Id>>(
+                db: ::ic_tx::db::IcTx<Self, B>,
+            ) -> ::ic_tx::db::IcTx<Self, B>
+            where
+                Self::Id: ::ic_tx::Shareable,
+            {
+                let db = db.with_data_id(<Self as ::ic_tx::entity::Entity>::id);
+                #(#registrations)*
+                db
+            }
+        }
+
+        #[doc = #repo_doc]
+        #vis struct #repo<B: ::ic_tx::backend::Backend<#entity, IdType = #id_type>> {
+            db: ::ic_tx::db::IcTx<#entity, B>,
+        }
+
+        impl<B: ::ic_tx::backend::Backend<#entity, IdType = #id_type>> ::std::clone::Clone for #repo<B> {
+            fn clone(&self) -> Self {
+                Self { db: ::std::clone::Clone::clone(&self.db) }
+            }
+        }
+
+        impl<B: ::ic_tx::backend::Backend<#entity, IdType = #id_type>> #repo<B> {
+            /// Wraps the database, registering the indexes and the constraints of the entity.
+            /// If the database already contains models, the indexes must be initialized with `rebuild_indexes`.
+            pub fn new(db: ::ic_tx::db::IcTx<#entity, B>) -> Self {
+                Self { db: <#entity as ::ic_tx::entity::Entity>::register(db) }
+            }
+
+            /// Returns the wrapped database.
+            pub fn db(&self) -> &::ic_tx::db::IcTx<#entity, B> {
+                &self.db
+            }
+
+            /// Starts a new atomic transaction
+            pub fn tx(&self) -> ::ic_tx::tx::Tx<#entity, B> {
+                self.db.tx()
+            }
+
+            /// Fetches a model.
+            /// Returns an error if no model is found with the specified id.
+            pub fn fetch_one(&self, id: &#id_type) -> ::std::result::Result<::ic_tx::backend::BackendModel<#entity, B>, ::ic_tx::error::TxError> {
+                self.db.fetch_one(id)
+            }
+
+            /// Fetches a model.
+            pub fn fetch_option_one(&self, id: &#id_type) -> ::std::result::Result<::std::option::Option<::ic_tx::backend::BackendModel<#entity, B>>, ::ic_tx::error::TxError> {
+                self.db.fetch_option_one(id)
+            }
+
+            #(#fetch_by)*
+        }
+    })
+}
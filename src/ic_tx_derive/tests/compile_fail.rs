@@ -0,0 +1,5 @@
+#[test]
+fn entity_should_reject_invalid_structs() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
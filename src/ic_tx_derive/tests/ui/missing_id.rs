@@ -0,0 +1,9 @@
+use ic_tx::entity::Entity;
+
+#[derive(Clone, Entity)]
+struct User {
+    id: u32,
+    username: String,
+}
+
+fn main() {}
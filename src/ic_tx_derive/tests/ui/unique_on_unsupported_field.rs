@@ -0,0 +1,11 @@
+use ic_tx::entity::Entity;
+
+#[derive(Clone, Entity)]
+struct User {
+    #[id]
+    id: u32,
+    #[index(unique)]
+    score: f64,
+}
+
+fn main() {}
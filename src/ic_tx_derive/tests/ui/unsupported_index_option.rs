@@ -0,0 +1,11 @@
+use ic_tx::entity::Entity;
+
+#[derive(Clone, Entity)]
+struct User {
+    #[id]
+    id: u32,
+    #[index(sorted)]
+    username: String,
+}
+
+fn main() {}
@@ -2,16 +2,46 @@ use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::{query, update};
 use ic_tx::{
     backend::hashmap::HashmapBackend,
-    db::IcTx,
+    error::TxError,
+    idempotency::Receipt,
+    metrics::MetricsSnapshot,
     model::{Model, NewModel},
+    remote::{AsyncIcTx, RemoteBackend},
+    saga::{Saga, SagaId, SagaOrchestrator, SagaRecord},
+    tx::Action,
+    two_phase::{Coordinator, Decision, Participant, RemoteBranch, TxId},
 };
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, time::Duration};
 
-pub type DbType = IcTx<Data, HashmapBackend<u32, Data>>;
+/// Identifies the layout of the data saved to the stable memory on upgrade.
+/// It must be changed whenever `Data` changes in an incompatible way.
+pub const SCHEMA_TAG: &str = "test_canister_a_v1";
+
+/// A database whose data is stored in the storage canister.
+pub type RemoteDbType = AsyncIcTx<Data, RemoteBackend<u32, Data>>;
+
+ic_tx::database! {
+    /// The collections of the canister, saved to the stable memory on upgrade.
+    /// The running sagas are saved too, so they can be resumed after the upgrade.
+    pub struct Database {
+        schema_tag: SCHEMA_TAG,
+        users(memory_id = 0): IcTx<Data, HashmapBackend<u32, Data>> = |db| db.with_lock_manager(),
+        sagas(memory_id = 1): IcTx<SagaRecord<TransferSaga>, HashmapBackend<SagaId, SagaRecord<TransferSaga>>>,
+    }
+    hooks {
+        pre_upgrade: reject_upgrade_in_doubt,
+        post_upgrade: |arg: InitArgs| init(arg),
+    }
+}
 
 thread_local! {
     static CONFIG: RefCell<Config> = RefCell::new(Config::default());
-    pub static DB: DbType = IcTx::new(Rc::new(RefCell::new(HashmapBackend::new())));
+    // The local participant of the distributed transactions coordinated by this canister
+    static PARTICIPANT: Participant<Data, HashmapBackend<u32, Data>> = Participant::new(Database::users());
+    static SAGAS: SagaOrchestrator<TransferSaga, HashmapBackend<SagaId, SagaRecord<TransferSaga>>> =
+        SagaOrchestrator::new(Database::sagas()).with_saga(transfer_saga());
+    static SAGA_TRAP: RefCell<bool> = const { RefCell::new(false) };
+    static COORDINATOR: Coordinator = Coordinator::new(ic_cdk::id().to_text()).with_timeout(Duration::from_secs(30));
 }
 
 #[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
@@ -20,14 +50,25 @@ pub struct Data {
     pub tokens: u32,
 }
 
+/// The context of the saga that reserves the tokens of a user in test_canister_b.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct TransferSaga {
+    pub id: u32,
+    pub tokens: u32,
+    /// Makes the last step fail, to trigger the compensations
+    pub fail: bool,
+}
+
 struct Config {
     pub canister_b_principal: Principal,
+    pub storage_canister_principal: Principal,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             canister_b_principal: Principal::anonymous(),
+            storage_canister_principal: Principal::anonymous(),
         }
     }
 }
@@ -35,6 +76,7 @@ impl Default for Config {
 #[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct InitArgs {
     pub canister_b_principal: Principal,
+    pub storage_canister_principal: Principal,
 }
 
 #[ic_cdk::init]
@@ -42,17 +84,21 @@ fn init(arg: InitArgs) {
     CONFIG.with(|c| {
         c.replace(Config {
             canister_b_principal: arg.canister_b_principal,
+            storage_canister_principal: arg.storage_canister_principal,
         })
     });
 }
 
-fn db() -> DbType {
-    DB.with(|c| (*c).clone())
+/// The distributed transactions are tracked on the heap, so the upgrade is rejected until they are resolved.
+fn reject_upgrade_in_doubt() {
+    if !coordinator().in_doubt().is_empty() || !participant().in_doubt().is_empty() {
+        ic_cdk::trap("Cannot upgrade while distributed transactions are in doubt");
+    }
 }
 
 #[query]
 fn get_user(id: u32) -> Option<Model<u32, Data>> {
-    let db = db();
+    let db = Database::users();
     // Withput opening a transaction, you can only read from the db
     // Data is never locked; all reads and writes are executed in parallel
     db.fetch_option_one(&id).unwrap()
@@ -60,7 +106,7 @@ fn get_user(id: u32) -> Option<Model<u32, Data>> {
 
 #[update]
 fn create_user(id: u32, username: String) {
-    let mut tx = db().tx();
+    let mut tx = Database::users().tx();
     tx.save(NewModel::new(
         id,
         Data {
@@ -76,7 +122,7 @@ fn create_user(id: u32, username: String) {
 
 #[update]
 fn create_user_rollback(id: u32, username: String) {
-    let mut tx = db().tx();
+    let mut tx = Database::users().tx();
     tx.save(NewModel::new(
         id,
         Data {
@@ -97,7 +143,7 @@ fn update_user(id: u32, tokens: u32) {
 
 fn update_user_inner(id: u32, tokens: u32) {
     // Starts a transation
-    let mut tx = db().tx();
+    let mut tx = Database::users().tx();
 
     // Fetches the user data
     let mut user = tx.fetch_one(&id).unwrap();
@@ -110,10 +156,44 @@ fn update_user_inner(id: u32, tokens: u32) {
     tx.commit();
 }
 
+#[update]
+fn update_user_version(id: u32, version: u32, tokens: u32) -> Result<(), TxError> {
+    let mut tx = Database::users().tx();
+
+    // The client provides the version of the user data it has read.
+    // The errors are returned to the caller instead of trapping.
+    let user = tx.fetch_one(&id)?;
+    tx.update(Model::from((
+        id,
+        version,
+        Data {
+            tokens,
+            ..user.data
+        },
+    )))?;
+
+    // Fails if the user data was modified after the client read it
+    tx.try_commit()
+}
+
+#[update]
+fn add_tokens(id: u32, tokens: u32, idempotency_key: String) -> Result<Receipt<u32, u32>, TxError> {
+    // A client that retries the call after a timeout sends the same key,
+    // so the tokens are added only once
+    let mut tx = Database::users().tx().with_idempotency_key(idempotency_key);
+
+    let mut user = tx.fetch_one(&id)?;
+    user.data.tokens += tokens;
+    tx.update(user)?;
+
+    // Returns the receipt of the first commit if the key was already used
+    tx.try_commit_with_receipt()
+}
+
 #[update]
 async fn update_user_concurrent_error(id: u32, tokens: u32) {
     // Starts a transaction
-    let mut tx = db().tx();
+    let mut tx = Database::users().tx();
 
     // Reads user data from the store
     let mut user = tx.fetch_one(&id).unwrap();
@@ -144,5 +224,244 @@ async fn update_user_concurrent_error(id: u32, tokens: u32) {
     tx.commit();
 }
 
+fn remote_db() -> RemoteDbType {
+    let storage_canister_principal = CONFIG.with(|c| c.borrow().storage_canister_principal);
+    AsyncIcTx::new(RemoteBackend::new(storage_canister_principal))
+}
+
+#[update]
+async fn get_remote_user(id: u32) -> Result<Option<Model<u32, Data>>, TxError> {
+    // The data is fetched from the storage canister
+    remote_db().fetch_option_one(&id).await
+}
+
+#[update]
+async fn create_remote_user(id: u32, username: String) -> Result<(), TxError> {
+    let mut tx = remote_db().tx();
+    tx.save(NewModel::new(
+        id,
+        Data {
+            tokens: 0,
+            username,
+        },
+    ))?;
+
+    // The actions are sent to the storage canister that validates and applies them atomically
+    tx.try_commit().await
+}
+
+#[update]
+async fn transfer_remote_tokens(from: u32, to: u32, tokens: u32) -> Result<(), TxError> {
+    let mut tx = remote_db().tx();
+
+    let mut from = tx.fetch_one(&from).await?;
+    let mut to = tx.fetch_one(&to).await?;
+    from.data.tokens = from.data.tokens.saturating_sub(tokens);
+    to.data.tokens += tokens;
+    tx.update(from)?;
+    tx.update(to)?;
+
+    // Both users are updated or none is.
+    // The commit fails if any of them was modified after being fetched.
+    tx.try_commit().await
+}
+
+#[update]
+async fn update_remote_user_version(id: u32, version: u32, tokens: u32) -> Result<(), TxError> {
+    let mut tx = remote_db().tx();
+
+    let user = tx.fetch_one(&id).await?;
+    tx.update(Model::from((
+        id,
+        version,
+        Data {
+            tokens,
+            ..user.data
+        },
+    )))?;
+
+    tx.try_commit().await
+}
+
+fn participant() -> Participant<Data, HashmapBackend<u32, Data>> {
+    PARTICIPANT.with(|p| p.clone())
+}
+
+fn coordinator() -> Coordinator {
+    COORDINATOR.with(|c| c.clone())
+}
+
+/// Moves the tokens of a user to its balance in test_canister_b.
+/// The two canisters are updated atomically by a distributed transaction.
+#[update]
+async fn transfer_to_b(id: u32, tokens: u32, trap_after_prepare: bool) -> Result<(), TxError> {
+    let canister_b_principal = CONFIG.with(|c| c.borrow().canister_b_principal);
+
+    // The balance is read to get its version, the prepare fails if it changes in the meantime
+    let (balance,): (Option<Model<u32, u64>>,) =
+        ic_cdk::call(canister_b_principal, "get_balance", (id,))
+            .await
+            .map_err(|(code, message)| TxError::RemoteError {
+                message: format!("Cannot fetch the balance. Code [{code:?}]. {message}"),
+            })?;
+    let balance_action = match balance {
+        Some(mut balance) => {
+            balance.data += tokens as u64;
+            Action::Update { model: balance }
+        }
+        None => Action::Create {
+            model: NewModel::new(id, tokens as u64),
+        },
+    };
+
+    let mut local_tx = Database::users().tx();
+    let mut user = local_tx.fetch_one(&id)?;
+    user.data.tokens = user
+        .data
+        .tokens
+        .checked_sub(tokens)
+        .ok_or_else(|| TxError::ValidationError {
+            id: id.to_string(),
+            reason: "Not enough tokens.".to_owned(),
+        })?;
+    local_tx.update(user)?;
+
+    let mut tx = coordinator().tx();
+    tx.enlist(participant().branch(local_tx));
+    tx.enlist(RemoteBranch::new(canister_b_principal, vec![balance_action]));
+
+    // Both participants now hold the changes and the locks
+    tx.prepare().await?;
+
+    if trap_after_prepare {
+        // Only the changes after the last await are reverted, the participants stay prepared
+        ic_cdk::trap("Injected failure: the coordinator traps after the prepare");
+    }
+
+    tx.try_commit().await
+}
+
+/// Completes the distributed transactions interrupted by a failure.
+#[update]
+async fn recover_distributed() -> Result<(), TxError> {
+    coordinator().recover().await
+}
+
+#[query]
+fn in_doubt_distributed() -> Vec<TxId> {
+    coordinator().in_doubt()
+}
+
+/// Returns the decision on a distributed transaction to the participants that recover it.
+#[query]
+fn decision(tx_id: TxId) -> Option<Decision> {
+    coordinator().decision(&tx_id)
+}
+
+fn canister_b_principal() -> Principal {
+    CONFIG.with(|c| c.borrow().canister_b_principal)
+}
+
+async fn call_canister_b(method: &str, args: impl candid::utils::ArgumentEncoder) -> Result<(), TxError> {
+    ic_cdk::call(canister_b_principal(), method, args)
+        .await
+        .map_err(|(code, message)| TxError::RemoteError {
+            message: format!("The call to [{method}] failed. Code [{code:?}]. {message}"),
+        })
+}
+
+/// The reliable version of `update_user_concurrent_error`: instead of keeping a transaction
+/// open across the call, every step commits on its own and the failures are undone by the compensations.
+fn transfer_saga() -> Saga<TransferSaga> {
+    Saga::new("transfer")
+        .with_local_step(
+            "withdraw",
+            |saga: &mut TransferSaga| {
+                let mut tx = Database::users().tx();
+                let mut user = tx.fetch_one(&saga.id)?;
+                user.data.tokens = user.data.tokens.checked_sub(saga.tokens).ok_or_else(|| {
+                    TxError::ValidationError {
+                        id: saga.id.to_string(),
+                        reason: "Not enough tokens.".to_owned(),
+                    }
+                })?;
+                tx.update(user)?;
+                tx.try_commit()
+            },
+            |saga: &mut TransferSaga| {
+                let mut tx = Database::users().tx();
+                let mut user = tx.fetch_one(&saga.id)?;
+                user.data.tokens += saga.tokens;
+                tx.update(user)?;
+                tx.try_commit()
+            },
+        )
+        .with_remote_step(
+            "reserve_on_b",
+            |saga: TransferSaga| async move {
+                call_canister_b("reserve", (saga.id, saga.tokens as u64)).await?;
+                Ok(saga)
+            },
+            |saga: TransferSaga| async move {
+                call_canister_b("release", (saga.id,)).await?;
+                Ok(saga)
+            },
+        )
+        .with_local_step(
+            "confirm",
+            |saga: &mut TransferSaga| {
+                if SAGA_TRAP.with(|t| *t.borrow()) {
+                    // Reverts the changes made after the call to test_canister_b
+                    ic_cdk::trap("Injected failure: the saga traps after the reservation");
+                }
+                match saga.fail {
+                    true => Err(TxError::ValidationError {
+                        id: saga.id.to_string(),
+                        reason: "The transfer is refused.".to_owned(),
+                    }),
+                    false => Ok(()),
+                }
+            },
+            |_| Ok(()),
+        )
+}
+
+fn sagas() -> SagaOrchestrator<TransferSaga, HashmapBackend<SagaId, SagaRecord<TransferSaga>>> {
+    SAGAS.with(|s| s.clone())
+}
+
+/// Reserves the tokens of a user in test_canister_b with a saga.
+#[update]
+async fn transfer_with_saga(id: u32, tokens: u32, fail: bool) -> Result<(), TxError> {
+    sagas()
+        .start("transfer", TransferSaga { id, tokens, fail })
+        .await
+        .map(|_| ())
+}
+
+/// Resumes the sagas interrupted by a trap or an upgrade.
+#[update]
+async fn recover_sagas() -> Result<(), TxError> {
+    sagas().recover().await
+}
+
+#[query]
+fn get_sagas() -> Vec<Model<SagaId, SagaRecord<TransferSaga>>> {
+    Database::sagas().query().fetch().unwrap()
+}
+
+/// Makes the sagas trap after the reservation in test_canister_b.
+#[update]
+fn set_saga_trap(enabled: bool) {
+    SAGA_TRAP.with(|t| t.replace(enabled));
+}
+
+/// Returns the metrics of the collections of the database.
+/// The commits that trap, e.g. `Tx::commit` on error, are reverted with their call and are not counted.
+#[query]
+fn metrics() -> Vec<(String, MetricsSnapshot)> {
+    Database::metrics().unwrap()
+}
+
 // Enable Candid export
 ic_cdk::export_candid!();
@@ -1,6 +1,8 @@
-use ic_tx::model::Model;
+use std::time::Duration;
+
+use ic_tx::{error::TxError, model::Model, saga::SagaStatus, two_phase::Decision};
 use test_canister_a::Data;
-use utils::PocketIcTestContext;
+use utils::{Failure, PocketIcTestContext};
 
 mod utils;
 
@@ -103,3 +105,478 @@ mod utils;
             result
         )
     }
+
+    #[tokio::test]
+    async fn update_user_version_should_return_the_conflict() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+
+        let id = 33311;
+        let username = "ufo";
+
+        ctx.create_user(id, username.to_string()).await;
+
+        // Act
+        let first_update = ctx.update_user_version(id, 0, 10).await;
+        let second_update = ctx.update_user_version(id, 0, 20).await;
+        let missing_user_update = ctx.update_user_version(id + 1, 0, 20).await;
+
+        let result = ctx.get_user(id).await;
+
+        // Assert
+        assert_eq!(Ok(()), first_update);
+        assert_eq!(
+            Err(TxError::UpdateOptimisticLockError {
+                id: id.to_string(),
+                expected: 0,
+                found: 1
+            }),
+            second_update
+        );
+        assert_eq!(
+            Err(TxError::FetchNotFoundError {
+                id: (id + 1).to_string()
+            }),
+            missing_user_update
+        );
+        assert_eq!(
+            Some(Model::from((
+                id,
+                1,
+                Data {
+                    username: username.to_string(),
+                    tokens: 10
+                }
+            ))),
+            result
+        )
+    }
+
+    #[tokio::test]
+    async fn users_should_survive_a_canister_upgrade() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        let username = "ufoscout";
+        ctx.create_user(id, username.to_string()).await;
+        ctx.update_user(id, 10).await;
+
+        // Act
+        ctx.upgrade_canister_a().await;
+        let result = ctx.get_user(id).await;
+
+        // Assert
+        assert_eq!(
+            Some(Model::from((
+                id,
+                1,
+                Data {
+                    username: username.to_string(),
+                    tokens: 10
+                }
+            ))),
+            result
+        )
+    }
+
+    #[tokio::test]
+    async fn remote_users_should_be_stored_in_the_storage_canister() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        let username = "ufoscout";
+
+        // Act
+        let create = ctx.create_remote_user(id, username.to_string()).await;
+        let duplicated_create = ctx.create_remote_user(id, username.to_string()).await;
+        let remote_result = ctx.get_remote_user(id).await;
+        let local_result = ctx.get_user(id).await;
+
+        // Assert
+        assert_eq!(Ok(()), create);
+        assert!(matches!(duplicated_create, Err(TxError::SaveError { .. })));
+        assert_eq!(
+            Ok(Some(Model::from((
+                id,
+                Data {
+                    username: username.to_string(),
+                    tokens: 0
+                }
+            )))),
+            remote_result
+        );
+        assert_eq!(None, local_result);
+    }
+
+    #[tokio::test]
+    async fn remote_tx_should_be_applied_atomically() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let alice = 1;
+        let bob = 2;
+        ctx.create_remote_user(alice, "alice".to_string()).await.unwrap();
+        ctx.create_remote_user(bob, "bob".to_string()).await.unwrap();
+        ctx.update_remote_user_version(alice, 0, 100).await.unwrap();
+
+        // Act
+        let transfer = ctx.transfer_remote_tokens(alice, bob, 30).await;
+        let stale_update = ctx.update_remote_user_version(alice, 0, 1000).await;
+        let missing_user_transfer = ctx.transfer_remote_tokens(alice, bob + 1, 30).await;
+
+        let alice = ctx.get_remote_user(alice).await.unwrap().unwrap();
+        let bob = ctx.get_remote_user(bob).await.unwrap().unwrap();
+
+        // Assert
+        assert_eq!(Ok(()), transfer);
+        assert_eq!(
+            Err(TxError::UpdateOptimisticLockError {
+                id: alice.id.to_string(),
+                expected: 0,
+                found: 2
+            }),
+            stale_update
+        );
+        assert!(matches!(
+            missing_user_transfer,
+            Err(TxError::FetchNotFoundError { .. })
+        ));
+        assert_eq!(70, alice.data.tokens);
+        assert_eq!(2, alice.version());
+        assert_eq!(30, bob.data.tokens);
+        assert_eq!(1, bob.version());
+    }
+
+    #[tokio::test]
+    async fn distributed_tx_should_update_both_canisters() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.update_user(id, 100).await;
+
+        // Act
+        let first_transfer = ctx.transfer_to_b(id, 30, false).await.unwrap();
+        let second_transfer = ctx.transfer_to_b(id, 20, false).await.unwrap();
+        let too_large_transfer = ctx.transfer_to_b(id, 1000, false).await.unwrap();
+
+        // Assert
+        assert_eq!(Ok(()), first_transfer);
+        assert_eq!(Ok(()), second_transfer);
+        assert!(matches!(too_large_transfer, Err(TxError::ValidationError { .. })));
+        assert_eq!(50, ctx.get_user(id).await.unwrap().data.tokens);
+        assert_eq!(50, ctx.get_balance_b(id).await.unwrap().data);
+        assert!(ctx.in_doubt_distributed().await.is_empty());
+        assert!(ctx.in_doubt_b().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn distributed_tx_should_abort_if_a_participant_fails_to_prepare() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.update_user(id, 100).await;
+        ctx.set_failure_b(Some(Failure::Prepare)).await;
+
+        // Act
+        let result = ctx.transfer_to_b(id, 30, false).await.unwrap();
+
+        // Assert
+        assert!(matches!(result, Err(TxError::RemoteError { .. })));
+        assert_eq!(100, ctx.get_user(id).await.unwrap().data.tokens);
+        assert_eq!(None, ctx.get_balance_b(id).await);
+        assert!(ctx.in_doubt_distributed().await.is_empty());
+
+        // The locks of the aborted transaction are released
+        ctx.update_user(id, 10).await;
+        assert_eq!(10, ctx.get_user(id).await.unwrap().data.tokens);
+    }
+
+    #[tokio::test]
+    async fn participant_should_recover_a_commit_it_failed_to_apply() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.update_user(id, 100).await;
+        ctx.set_failure_b(Some(Failure::Commit)).await;
+
+        // Act
+        let result = ctx.transfer_to_b(id, 30, false).await.unwrap();
+        let in_doubt_b = ctx.in_doubt_b().await;
+        ctx.set_failure_b(None).await;
+        let recover_b = ctx.recover_in_doubt_b().await;
+        let recover_a = ctx.recover_distributed().await;
+
+        // Assert
+        assert!(matches!(result, Err(TxError::TwoPhaseCommitError { .. })));
+        assert_eq!(1, in_doubt_b.len());
+        assert_eq!(Ok(()), recover_b);
+        assert_eq!(Ok(()), recover_a);
+        assert_eq!(70, ctx.get_user(id).await.unwrap().data.tokens);
+        assert_eq!(30, ctx.get_balance_b(id).await.unwrap().data);
+        assert!(ctx.in_doubt_b().await.is_empty());
+        assert!(ctx.in_doubt_distributed().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn participant_should_reject_the_calls_of_other_principals() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+
+        // Act
+        let result = ctx.reserve_b(111, 30).await;
+
+        // Assert
+        assert!(result.is_err());
+        assert_eq!(None, ctx.get_reservation_b(111).await);
+    }
+
+    #[tokio::test]
+    async fn participant_should_commit_a_tx_prepared_before_an_upgrade() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.update_user(id, 100).await;
+        ctx.set_failure_b(Some(Failure::Commit)).await;
+        let _ = ctx.transfer_to_b(id, 30, false).await.unwrap();
+        let in_doubt_before_upgrade = ctx.in_doubt_b().await;
+
+        // Act
+        ctx.upgrade_canister_b().await;
+        let in_doubt_after_upgrade = ctx.in_doubt_b().await;
+        let recover_b = ctx.recover_in_doubt_b().await;
+        let recover_a = ctx.recover_distributed().await;
+
+        // Assert
+        assert_eq!(1, in_doubt_before_upgrade.len());
+        assert_eq!(in_doubt_before_upgrade, in_doubt_after_upgrade);
+        assert_eq!(Ok(()), recover_b);
+        assert_eq!(Ok(()), recover_a);
+        assert_eq!(70, ctx.get_user(id).await.unwrap().data.tokens);
+        assert_eq!(30, ctx.get_balance_b(id).await.unwrap().data);
+        assert!(ctx.in_doubt_b().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn coordinator_should_recover_a_tx_interrupted_by_a_trap() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.update_user(id, 100).await;
+
+        // Act
+        let result = ctx.transfer_to_b(id, 30, true).await;
+        let in_doubt_a = ctx.in_doubt_distributed().await;
+        let in_doubt_b = ctx.in_doubt_b().await;
+        // The transactions being prepared are aborted only after the timeout
+        ctx.advance_time(Duration::from_secs(31)).await;
+        let recover = ctx.recover_distributed().await;
+
+        // Assert
+        assert!(result.is_err());
+        assert_eq!(in_doubt_a, in_doubt_b);
+        assert_eq!(1, in_doubt_a.len());
+        assert_eq!(Ok(()), recover);
+        assert!(ctx.in_doubt_b().await.is_empty());
+        assert_eq!(100, ctx.get_user(id).await.unwrap().data.tokens);
+        assert_eq!(None, ctx.get_balance_b(id).await);
+    }
+
+    #[tokio::test]
+    async fn prepared_tx_should_ask_the_decision_after_the_timeout() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.update_user(id, 100).await;
+        let _ = ctx.transfer_to_b(id, 30, true).await;
+
+        // Act
+        ctx.advance_time(Duration::from_secs(31)).await;
+        let in_doubt_b = ctx.in_doubt_b().await;
+        let recover_b = ctx.recover_in_doubt_b().await;
+        let recover_a = ctx.recover_distributed().await;
+        let transfer = ctx.transfer_to_b(id, 30, false).await.unwrap();
+
+        // Assert
+        assert_eq!(1, in_doubt_b.len());
+        assert_eq!(Ok(()), recover_b);
+        assert_eq!(Ok(()), recover_a);
+        assert_eq!(Ok(()), transfer);
+        assert_eq!(70, ctx.get_user(id).await.unwrap().data.tokens);
+        assert_eq!(30, ctx.get_balance_b(id).await.unwrap().data);
+    }
+
+    #[tokio::test]
+    async fn controller_should_resolve_a_prepared_tx_if_the_coordinator_is_lost() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.update_user(id, 100).await;
+        let _ = ctx.transfer_to_b(id, 30, true).await;
+        let tx_id = ctx.in_doubt_b().await.remove(0);
+
+        // Act
+        let resolve_before_timeout = ctx.resolve_in_doubt_b(tx_id.clone(), Decision::Abort).await;
+        ctx.advance_time(Duration::from_secs(31)).await;
+        let resolve_after_timeout = ctx.resolve_in_doubt_b(tx_id, Decision::Abort).await;
+
+        // Assert
+        assert!(matches!(resolve_before_timeout, Err(TxError::TwoPhaseCommitError { .. })));
+        assert_eq!(Ok(()), resolve_after_timeout);
+        assert!(ctx.in_doubt_b().await.is_empty());
+        assert_eq!(None, ctx.get_balance_b(id).await);
+    }
+
+    #[tokio::test]
+    async fn upgrade_should_be_rejected_while_a_distributed_tx_is_in_doubt() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.update_user(id, 100).await;
+        let _ = ctx.transfer_to_b(id, 30, true).await;
+
+        // Act
+        let upgrade_in_doubt = ctx.try_upgrade_canister_a().await;
+        let in_doubt = ctx.in_doubt_distributed().await;
+        ctx.advance_time(Duration::from_secs(31)).await;
+        let recover = ctx.recover_distributed().await;
+        let upgrade_after_recover = ctx.try_upgrade_canister_a().await;
+
+        // Assert
+        assert!(!upgrade_in_doubt);
+        assert_eq!(1, in_doubt.len());
+        assert_eq!(Ok(()), recover);
+        assert!(upgrade_after_recover);
+        assert_eq!(100, ctx.get_user(id).await.unwrap().data.tokens);
+    }
+
+    #[tokio::test]
+    async fn saga_should_complete_all_the_steps() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.update_user(id, 100).await;
+
+        // Act
+        let result = ctx.transfer_with_saga(id, 30, false).await.unwrap();
+
+        // Assert
+        assert_eq!(Ok(()), result);
+        assert_eq!(70, ctx.get_user(id).await.unwrap().data.tokens);
+        assert_eq!(Some(30), ctx.get_reservation_b(id).await);
+        let sagas = ctx.get_sagas().await;
+        assert_eq!(1, sagas.len());
+        assert_eq!(SagaStatus::Completed, sagas[0].data.status);
+    }
+
+    #[tokio::test]
+    async fn failed_saga_should_be_compensated() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.update_user(id, 100).await;
+
+        // Act
+        let result = ctx.transfer_with_saga(id, 30, true).await.unwrap();
+
+        // Assert
+        assert!(matches!(result, Err(TxError::ValidationError { .. })));
+        assert_eq!(100, ctx.get_user(id).await.unwrap().data.tokens);
+        assert_eq!(None, ctx.get_reservation_b(id).await);
+        let sagas = ctx.get_sagas().await;
+        assert_eq!(SagaStatus::Compensated, sagas[0].data.status);
+        assert_eq!(0, sagas[0].data.step);
+    }
+
+    #[tokio::test]
+    async fn saga_interrupted_by_a_trap_should_be_resumed_after_an_upgrade() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.update_user(id, 100).await;
+        ctx.set_saga_trap(true).await;
+
+        // Act
+        let result = ctx.transfer_with_saga(id, 30, false).await;
+        let interrupted_sagas = ctx.get_sagas().await;
+        ctx.upgrade_canister_a().await;
+        let recover = ctx.recover_sagas().await;
+
+        // Assert
+        assert!(result.is_err());
+        assert_eq!(SagaStatus::Running, interrupted_sagas[0].data.status);
+        assert_eq!(1, interrupted_sagas[0].data.step);
+        assert_eq!(Ok(()), recover);
+        assert_eq!(70, ctx.get_user(id).await.unwrap().data.tokens);
+        assert_eq!(Some(30), ctx.get_reservation_b(id).await);
+        assert_eq!(SagaStatus::Completed, ctx.get_sagas().await[0].data.status);
+    }
+
+    #[tokio::test]
+    async fn retried_call_should_not_apply_the_tx_twice() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+
+        // Act
+        let first_call = ctx.add_tokens(id, 10, "request_1").await.unwrap();
+        let retried_call = ctx.add_tokens(id, 10, "request_1").await.unwrap();
+        let other_call = ctx.add_tokens(id, 5, "request_2").await.unwrap();
+
+        // Assert
+        assert!(!first_call.replayed);
+        assert!(retried_call.replayed);
+        assert_eq!(first_call.changes, retried_call.changes);
+        assert_eq!(vec![(id, Some(1))], first_call.changes);
+        assert_eq!(vec![(id, Some(2))], other_call.changes);
+        assert_eq!(15, ctx.get_user(id).await.unwrap().data.tokens);
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys_should_survive_a_canister_upgrade() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+        ctx.add_tokens(id, 10, "request_1").await.unwrap();
+
+        // Act
+        ctx.upgrade_canister_a().await;
+        let retried_call = ctx.add_tokens(id, 10, "request_1").await.unwrap();
+
+        // Assert
+        assert!(retried_call.replayed);
+        assert_eq!(10, ctx.get_user(id).await.unwrap().data.tokens);
+    }
+
+    #[tokio::test]
+    async fn metrics_should_count_the_commits_and_the_conflicts() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        let id = 111;
+        ctx.create_user(id, "ufoscout".to_string()).await;
+
+        // Act
+        ctx.update_user_version(id, 0, 10).await.unwrap();
+        ctx.update_user_version(id, 0, 20).await.unwrap_err();
+        let metrics = ctx.metrics().await;
+
+        // Assert
+        let (name, users) = &metrics[0];
+        assert_eq!("users", name);
+        assert_eq!(1, users.records);
+        assert_eq!(2, users.commits);
+        assert_eq!(1, users.failed_commits);
+        assert_eq!(vec![("UpdateOptimisticLockError".to_string(), 1)], users.conflicts);
+        assert_eq!(vec![(id.to_string(), 1)], users.hottest_conflicts);
+    }
@@ -1,20 +1,43 @@
-use std::sync::OnceLock;
+use std::{sync::{Arc, OnceLock}, time::Duration};
 
-use candid::{CandidType, Encode, Principal};
+use candid::{CandidType, Deserialize, Encode, Principal};
 use ic_mple_client::*;
 use ic_mple_pocket_ic::{pocket_ic::nonblocking::PocketIc, *};
-use ic_tx::model::Model;
-use test_canister_a::{Data, InitArgs};
+use ic_tx::{error::TxError, idempotency::Receipt, metrics::MetricsSnapshot, model::Model, saga::{SagaId, SagaRecord}, two_phase::{Decision, TxId}};
+use test_canister_a::{Data, InitArgs, TransferSaga};
 
 pub fn alice() -> Principal {
     Principal::from_text("sgymv-uiaaa-aaaaa-aaaia-cai").unwrap()
 }
 
+/// The init arguments of test_storage_canister
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct StorageInitArgs {
+    pub writers: Vec<Principal>,
+}
+
+/// The init arguments of test_canister_b
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct CanisterBInitArgs {
+    pub coordinators: Vec<Principal>,
+}
+
+/// The failures that can be injected in test_canister_b
+#[derive(Debug, Clone, Copy, CandidType, Deserialize)]
+pub enum Failure {
+    Prepare,
+    Commit,
+}
+
 #[derive(Clone)]
 pub struct PocketIcTestContext {
     pub client: PocketIcClient,
+    pub canister_b_client: PocketIcClient,
+    /// Calls test_canister_b as its controller
+    pub canister_b_controller_client: PocketIcClient,
     // canister_a_principal: Principal,
-    // pub canister_a_args: InitArgs,
+    pub canister_a_args: InitArgs,
+    pub canister_b_args: CanisterBInitArgs,
     // canister_b_principal: Principal,
 }
 
@@ -50,6 +73,20 @@ impl PocketIcTestContext {
         ).await
     }
 
+    pub async fn update_user_version(&self, id: u32, version: u32, tokens: u32) -> Result<(), TxError> {
+        self.client.update(
+            "update_user_version",
+            (id, version, tokens)
+        ).await.unwrap()
+    }
+
+    pub async fn add_tokens(&self, id: u32, tokens: u32, idempotency_key: &str) -> Result<Receipt<u32, u32>, TxError> {
+        self.client.update(
+            "add_tokens",
+            (id, tokens, idempotency_key)
+        ).await.unwrap()
+    }
+
     pub async fn get_user(&self, id: u32) -> Option<Model<u32, Data>> {
         self.client.query(
             "get_user",
@@ -58,6 +95,174 @@ impl PocketIcTestContext {
         .await.unwrap()
     }
 
+    pub async fn get_remote_user(&self, id: u32) -> Result<Option<Model<u32, Data>>, TxError> {
+        self.client.update(
+            "get_remote_user",
+            (id, ),
+        )
+        .await.unwrap()
+    }
+
+    pub async fn create_remote_user(&self, id: u32, username: String) -> Result<(), TxError> {
+        self.client.update(
+            "create_remote_user",
+            (id, username)
+        )
+        .await.unwrap()
+    }
+
+    pub async fn transfer_remote_tokens(&self, from: u32, to: u32, tokens: u32) -> Result<(), TxError> {
+        self.client.update(
+            "transfer_remote_tokens",
+            (from, to, tokens)
+        ).await.unwrap()
+    }
+
+    pub async fn update_remote_user_version(&self, id: u32, version: u32, tokens: u32) -> Result<(), TxError> {
+        self.client.update(
+            "update_remote_user_version",
+            (id, version, tokens)
+        ).await.unwrap()
+    }
+
+    pub async fn transfer_to_b(&self, id: u32, tokens: u32, trap_after_prepare: bool) -> CanisterClientResult<Result<(), TxError>> {
+        self.client.update(
+            "transfer_to_b",
+            (id, tokens, trap_after_prepare)
+        ).await
+    }
+
+    pub async fn recover_distributed(&self) -> Result<(), TxError> {
+        self.client.update(
+            "recover_distributed",
+            ()
+        ).await.unwrap()
+    }
+
+    pub async fn in_doubt_distributed(&self) -> Vec<TxId> {
+        self.client.query(
+            "in_doubt_distributed",
+            ()
+        ).await.unwrap()
+    }
+
+    pub async fn get_balance_b(&self, id: u32) -> Option<Model<u32, u64>> {
+        self.canister_b_client.query(
+            "get_balance",
+            (id, )
+        ).await.unwrap()
+    }
+
+    pub async fn set_failure_b(&self, failure: Option<Failure>) {
+        self.canister_b_client.update(
+            "set_failure",
+            (failure, )
+        ).await.unwrap()
+    }
+
+    pub async fn in_doubt_b(&self) -> Vec<TxId> {
+        self.canister_b_client.query(
+            "in_doubt",
+            ()
+        ).await.unwrap()
+    }
+
+    pub async fn recover_in_doubt_b(&self) -> Result<(), TxError> {
+        self.canister_b_client.update(
+            "recover_in_doubt",
+            (self.client.canister, )
+        ).await.unwrap()
+    }
+
+    pub async fn resolve_in_doubt_b(&self, tx_id: TxId, decision: Decision) -> Result<(), TxError> {
+        self.canister_b_controller_client.update(
+            "resolve_in_doubt",
+            (tx_id, decision)
+        ).await.unwrap()
+    }
+
+    pub async fn transfer_with_saga(&self, id: u32, tokens: u32, fail: bool) -> CanisterClientResult<Result<(), TxError>> {
+        self.client.update(
+            "transfer_with_saga",
+            (id, tokens, fail)
+        ).await
+    }
+
+    pub async fn recover_sagas(&self) -> Result<(), TxError> {
+        self.client.update(
+            "recover_sagas",
+            ()
+        ).await.unwrap()
+    }
+
+    pub async fn get_sagas(&self) -> Vec<Model<SagaId, SagaRecord<TransferSaga>>> {
+        self.client.query(
+            "get_sagas",
+            ()
+        ).await.unwrap()
+    }
+
+    pub async fn metrics(&self) -> Vec<(String, MetricsSnapshot)> {
+        self.client.query(
+            "metrics",
+            ()
+        ).await.unwrap()
+    }
+
+    pub async fn set_saga_trap(&self, enabled: bool) {
+        self.client.update(
+            "set_saga_trap",
+            (enabled, )
+        ).await.unwrap()
+    }
+
+    pub async fn reserve_b(&self, id: u32, tokens: u64) -> CanisterClientResult<()> {
+        self.canister_b_client.update(
+            "reserve",
+            (id, tokens)
+        ).await
+    }
+
+    pub async fn get_reservation_b(&self, id: u32) -> Option<u64> {
+        self.canister_b_client.query(
+            "get_reservation",
+            (id, )
+        ).await.unwrap()
+    }
+
+    pub async fn advance_time(&self, duration: Duration) {
+        self.client.client().advance_time(duration).await;
+        self.client.client().tick().await;
+    }
+
+    /// Returns true if the upgrade succeeds
+    pub async fn try_upgrade_canister_a(&self) -> bool {
+        let args = Encode!(&self.canister_a_args).expect("failed to encode item to candid");
+        self.client
+            .client()
+            .upgrade_canister(self.client.canister, get_canister_a_bytecode(), args, None)
+            .await
+            .is_ok()
+    }
+
+    pub async fn upgrade_canister_a(&self) {
+        let args = Encode!(&self.canister_a_args).expect("failed to encode item to candid");
+        self.client
+            .client()
+            .upgrade_canister(self.client.canister, get_canister_a_bytecode(), args, None)
+            .await
+            .unwrap()
+    }
+
+    pub async fn upgrade_canister_b(&self) {
+        let args = Encode!(&self.canister_b_args).expect("failed to encode item to candid");
+        self.canister_b_client
+            .client()
+            .upgrade_canister(self.canister_b_client.canister, get_canister_b_bytecode(), args, None)
+            .await
+            .unwrap()
+    }
+
     pub async fn new() -> Self {
         let client = get_pocket_ic_client()
             .with_nns_subnet()
@@ -66,19 +271,40 @@ impl PocketIcTestContext {
             .build_async()
             .await;
 
-        let canister_b_principal = deploy_canister(&client, get_canister_b_bytecode(), &()).await;
+        // test_canister_b and the storage canister accept only the calls of test_canister_a, so it is created first
+        let canister_a_principal = create_canister(&client).await;
+        let canister_b_args = CanisterBInitArgs {
+            coordinators: vec![canister_a_principal],
+        };
+        let canister_b_principal =
+            deploy_canister(&client, get_canister_b_bytecode(), &canister_b_args).await;
+        let storage_args = StorageInitArgs {
+            writers: vec![canister_a_principal],
+        };
+        let storage_canister_principal =
+            deploy_canister(&client, get_storage_canister_bytecode(), &storage_args).await;
         let canister_a_args = InitArgs {
             canister_b_principal,
+            storage_canister_principal,
         };
-        let canister_a_principal =
-            deploy_canister(&client, get_canister_a_bytecode(), &canister_a_args).await;
+        install_canister(&client, canister_a_principal, get_canister_a_bytecode(), &canister_a_args).await;
 
         
 
+        let client = Arc::new(client);
+
         PocketIcTestContext {
-            client: PocketIcClient::from_client(client, canister_a_principal, alice()),
+            client: PocketIcClient::from_client(client.clone(), canister_a_principal, alice()),
+            canister_b_client: PocketIcClient::from_client(client.clone(), canister_b_principal, alice()),
+            // The canisters are created by the anonymous principal, which is their controller
+            canister_b_controller_client: PocketIcClient::from_client(
+                client,
+                canister_b_principal,
+                Principal::anonymous(),
+            ),
             // canister_a_principal,
-            // canister_a_args,
+            canister_a_args,
+            canister_b_args,
             // canister_b_principal,
         }
     }
@@ -89,13 +315,27 @@ async fn deploy_canister<T: CandidType>(
     bytecode: Vec<u8>,
     args: &T,
 ) -> Principal {
-    let args = Encode!(args).expect("failed to encode item to candid");
+    let canister = create_canister(client).await;
+    install_canister(client, canister, bytecode, args).await;
+    canister
+}
+
+async fn create_canister(client: &PocketIc) -> Principal {
     let canister = client.create_canister().await;
     client.add_cycles(canister, 10_u128.pow(12)).await;
+    canister
+}
+
+async fn install_canister<T: CandidType>(
+    client: &PocketIc,
+    canister: Principal,
+    bytecode: Vec<u8>,
+    args: &T,
+) {
+    let args = Encode!(args).expect("failed to encode item to candid");
     client
         .install_canister(canister, bytecode, args, None)
         .await;
-    canister
 }
 
 fn get_canister_a_bytecode() -> Vec<u8> {
@@ -115,3 +355,12 @@ fn get_canister_b_bytecode() -> Vec<u8> {
         })
         .to_owned()
 }
+
+fn get_storage_canister_bytecode() -> Vec<u8> {
+    static CANISTER_BYTECODE: OnceLock<Vec<u8>> = OnceLock::new();
+    CANISTER_BYTECODE
+        .get_or_init(|| {
+            load_wasm_bytes("../../target/wasm32-unknown-unknown/release/test_storage_canister.wasm")
+        })
+        .to_owned()
+}
@@ -1,14 +1,92 @@
+use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::{query, update};
-use std::cell::RefCell;
+use ic_tx::{
+    backend::hashmap::HashmapBackend,
+    db::IcTx,
+    error::TxError,
+    model::Model,
+    tx::Action,
+    two_phase::{Decision, Participant, ParticipantRecord, TxId},
+};
+use std::{cell::RefCell, collections::HashMap, time::Duration};
+
+pub type DbType = IcTx<u64, HashmapBackend<u32, u64>>;
+
+/// Identifies the layout of the data saved to the stable memory on upgrade.
+pub const SCHEMA_TAG: &str = "test_canister_b_v1";
+
+ic_tx::database! {
+    /// The collections of the canister, saved to the stable memory on upgrade.
+    pub struct Database {
+        schema_tag: SCHEMA_TAG,
+        /// The balances are changed only by the distributed transactions coordinated by test_canister_a
+        balances(memory_id = 0): IcTx<u64, HashmapBackend<u32, u64>> = |db| db.with_lock_manager(),
+        /// The prepared and the resolved transactions, so they survive an upgrade before the decision
+        participant_log(memory_id = 1): IcTx<ParticipantRecord<u32, u64, u32>, HashmapBackend<TxId, ParticipantRecord<u32, u64, u32>>>,
+    }
+    hooks {
+        post_upgrade: |arg: InitArgs| {
+            init(arg);
+            participant().restore().expect("failed to restore the prepared transactions");
+        },
+    }
+}
 
 thread_local! {
-    static COUNTER: RefCell<u64> = RefCell::new(999_999_999);
+    static COORDINATORS: RefCell<Vec<Principal>> = const { RefCell::new(vec![]) };
+    static COUNTER: RefCell<u64> = const { RefCell::new(999_999_999) };
+    static FAILURE: RefCell<Option<Failure>> = const { RefCell::new(None) };
+    static RESERVATIONS: RefCell<HashMap<u32, u64>> = RefCell::new(HashMap::new());
+    pub static PARTICIPANT: Participant<u64, HashmapBackend<u32, u64>> =
+        Participant::new(Database::balances()).with_log(Database::participant_log());
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct InitArgs {
+    /// The canisters allowed to prepare and resolve the transactions. The controllers are always allowed.
+    pub coordinators: Vec<Principal>,
+}
+
+/// A failure injected to test the recovery of the distributed transactions.
+#[derive(Debug, Clone, Copy, PartialEq, CandidType, Deserialize)]
+pub enum Failure {
+    /// Traps when a transaction is prepared
+    Prepare,
+    /// Traps when a prepared transaction is committed
+    Commit,
+}
+
+fn participant() -> Participant<u64, HashmapBackend<u32, u64>> {
+    PARTICIPANT.with(|p| p.clone())
+}
+
+#[ic_cdk::init]
+fn init(arg: InitArgs) {
+    COORDINATORS.with(|coordinators| *coordinators.borrow_mut() = arg.coordinators);
+}
+
+/// Rejects the calls of the canisters that are neither coordinators nor controllers.
+fn caller_is_coordinator() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller)
+        || COORDINATORS.with(|coordinators| coordinators.borrow().contains(&caller))
+    {
+        Ok(())
+    } else {
+        Err(format!("The caller [{caller}] is not a coordinator."))
+    }
+}
+
+fn trap_on(failure: Failure) {
+    if FAILURE.with(|f| *f.borrow()) == Some(failure) {
+        ic_cdk::trap(&format!("Injected failure: {failure:?}"));
+    }
 }
 
 /// Get the value of the counter.
 #[query]
 fn get_counter() -> u64 {
-    COUNTER.with(|c| (*c.borrow()).clone())
+    COUNTER.with(|c| *c.borrow())
 }
 
 /// Increment the value of the counter.
@@ -17,5 +95,78 @@ fn inc() {
     COUNTER.with(|counter| *counter.borrow_mut() += 1);
 }
 
+/// Reserves tokens for a user. Calling it again with the same arguments does nothing,
+/// so it can be repeated by a saga that is resumed.
+#[update(guard = "caller_is_coordinator")]
+fn reserve(id: u32, tokens: u64) {
+    RESERVATIONS.with(|r| r.borrow_mut().insert(id, tokens));
+}
+
+/// Releases the tokens reserved for a user.
+#[update(guard = "caller_is_coordinator")]
+fn release(id: u32) {
+    RESERVATIONS.with(|r| r.borrow_mut().remove(&id));
+}
+
+#[query]
+fn get_reservation(id: u32) -> Option<u64> {
+    RESERVATIONS.with(|r| r.borrow().get(&id).copied())
+}
+
+/// Sets the failure to inject, or removes it.
+#[update]
+fn set_failure(failure: Option<Failure>) {
+    FAILURE.with(|f| f.replace(failure));
+}
+
+#[query]
+fn get_balance(id: u32) -> Option<Model<u32, u64>> {
+    participant().db().fetch_option_one(&id).unwrap()
+}
+
+#[update(guard = "caller_is_coordinator")]
+fn prepare(tx_id: TxId, actions: Vec<Action<u32, u64, u32>>, timeout: u64) -> Result<(), TxError> {
+    trap_on(Failure::Prepare);
+    participant().prepare(&tx_id, actions, Duration::from_nanos(timeout))
+}
+
+#[update(guard = "caller_is_coordinator")]
+fn commit_prepared(tx_id: TxId) -> Result<(), TxError> {
+    trap_on(Failure::Commit);
+    participant().commit(&tx_id)
+}
+
+#[update(guard = "caller_is_coordinator")]
+fn abort_prepared(tx_id: TxId) -> Result<(), TxError> {
+    participant().abort(&tx_id)
+}
+
+#[query]
+fn in_doubt() -> Vec<TxId> {
+    participant().in_doubt()
+}
+
+/// Resolves the in-doubt transactions asking the coordinator for their decision.
+#[update]
+async fn recover_in_doubt(coordinator: Principal) -> Result<(), TxError> {
+    participant().recover(coordinator).await
+}
+
+/// Rejects the calls of the principals that are not controllers.
+fn caller_is_controller() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) {
+        Ok(())
+    } else {
+        Err(format!("The caller [{caller}] is not a controller."))
+    }
+}
+
+/// Resolves a timed out transaction without the coordinator, e.g. when the coordinator is lost.
+#[update(guard = "caller_is_controller")]
+fn resolve_in_doubt(tx_id: TxId, decision: Decision) -> Result<(), TxError> {
+    participant().resolve_manually(&tx_id, decision)
+}
+
 // Enable Candid export
 ic_cdk::export_candid!();
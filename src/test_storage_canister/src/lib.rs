@@ -0,0 +1,71 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use ic_tx::{
+    backend::hashmap::HashmapBackend,
+    error::TxError,
+    model::Model,
+    tx::Action,
+};
+use std::cell::RefCell;
+
+/// Identifies the layout of the data saved to the stable memory on upgrade.
+pub const SCHEMA_TAG: &str = "test_storage_canister_v1";
+
+ic_tx::database! {
+    /// The collections of the canister, saved to the stable memory on upgrade.
+    pub struct Database {
+        schema_tag: SCHEMA_TAG,
+        users(memory_id = 0): IcTx<Data, HashmapBackend<u32, Data>>,
+    }
+    hooks {
+        post_upgrade: |arg: InitArgs| init(arg),
+    }
+}
+
+thread_local! {
+    static WRITERS: RefCell<Vec<Principal>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct InitArgs {
+    /// The canisters allowed to commit, e.g. the logic canisters. The controllers are always allowed.
+    pub writers: Vec<Principal>,
+}
+
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct Data {
+    pub username: String,
+    pub tokens: u32,
+}
+
+#[ic_cdk::init]
+fn init(arg: InitArgs) {
+    WRITERS.with(|writers| *writers.borrow_mut() = arg.writers);
+}
+
+/// Fetches a model. Called by the `RemoteBackend` of the logic canisters.
+#[query]
+fn fetch_option_one(id: u32) -> Result<Option<Model<u32, Data>>, TxError> {
+    Database::users().fetch_option_one(&id)
+}
+
+/// Rejects the calls of the canisters that are neither writers nor controllers.
+fn caller_is_writer() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) || WRITERS.with(|writers| writers.borrow().contains(&caller)) {
+        Ok(())
+    } else {
+        Err(format!("The caller [{caller}] is not allowed to commit."))
+    }
+}
+
+/// Validates and applies the actions of a remote transaction.
+/// There are no await points, so no other call can interleave and the actions are applied atomically.
+/// The metadata sent by the caller is always ignored: this database does not keep any, so it is dropped.
+#[update(guard = "caller_is_writer")]
+fn commit(actions: Vec<Action<u32, Data, u32>>) -> Result<(), TxError> {
+    Database::users().commit_actions(actions)
+}
+
+// Enable Candid export
+ic_cdk::export_candid!();
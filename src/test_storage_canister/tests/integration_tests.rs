@@ -0,0 +1,104 @@
+use ic_tx::{
+    error::TxError,
+    model::{Model, NewModel},
+    tx::Action,
+};
+use test_storage_canister::Data;
+use utils::PocketIcTestContext;
+
+mod utils;
+
+    fn data(username: &str, tokens: u32) -> Data {
+        Data {
+            username: username.to_string(),
+            tokens,
+        }
+    }
+
+    #[tokio::test]
+    async fn commit_should_apply_the_actions() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+
+        // Act
+        let create = ctx.commit(vec![
+            Action::Create { model: NewModel::new(1, data("alice", 10)) },
+            Action::Create { model: NewModel::new(2, data("bob", 20)) },
+        ]).await;
+        let update = ctx.commit(vec![
+            Action::Update { model: Model::from((1, data("alice", 15))) },
+            Action::Delete { id: 2, version: 0 },
+        ]).await;
+
+        // Assert
+        assert_eq!(Ok(()), create);
+        assert_eq!(Ok(()), update);
+        assert_eq!(
+            Ok(Some(Model::from((1, 1, data("alice", 15))))),
+            ctx.fetch_option_one(1).await
+        );
+        assert_eq!(Ok(None), ctx.fetch_option_one(2).await);
+    }
+
+    #[tokio::test]
+    async fn commit_should_apply_nothing_if_any_action_fails() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        ctx.commit(vec![
+            Action::Create { model: NewModel::new(1, data("alice", 10)) },
+        ]).await.unwrap();
+
+        // Act
+        let result = ctx.commit(vec![
+            Action::Create { model: NewModel::new(2, data("bob", 20)) },
+            Action::Update { model: Model::from((1, 3, data("alice", 15))) },
+        ]).await;
+
+        // Assert
+        assert_eq!(
+            Err(TxError::UpdateOptimisticLockError {
+                id: "1".to_string(),
+                expected: 3,
+                found: 0
+            }),
+            result
+        );
+        assert_eq!(Ok(None), ctx.fetch_option_one(2).await);
+        assert_eq!(
+            Ok(Some(Model::from((1, data("alice", 10))))),
+            ctx.fetch_option_one(1).await
+        );
+    }
+
+    #[tokio::test]
+    async fn data_should_survive_a_canister_upgrade() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+        ctx.commit(vec![
+            Action::Create { model: NewModel::new(1, data("alice", 10)) },
+        ]).await.unwrap();
+
+        // Act
+        ctx.upgrade_canister().await;
+
+        // Assert
+        assert_eq!(
+            Ok(Some(Model::from((1, data("alice", 10))))),
+            ctx.fetch_option_one(1).await
+        );
+    }
+
+    #[tokio::test]
+    async fn commit_should_be_rejected_if_the_caller_is_not_a_writer() {
+        // Arrange
+        let ctx = PocketIcTestContext::new().await;
+
+        // Act
+        let result = ctx.commit_as(utils::bob(), vec![
+            Action::Create { model: NewModel::new(1, data("bob", 10)) },
+        ]).await;
+
+        // Assert
+        assert!(result.is_err());
+        assert_eq!(Ok(None), ctx.fetch_option_one(1).await);
+    }
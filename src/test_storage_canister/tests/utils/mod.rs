@@ -0,0 +1,93 @@
+use std::sync::OnceLock;
+
+use candid::{Encode, Principal};
+use ic_mple_client::*;
+use ic_mple_pocket_ic::*;
+use ic_tx::{error::TxError, model::Model, tx::Action};
+use test_storage_canister::{Data, InitArgs};
+
+pub fn alice() -> Principal {
+    Principal::from_text("sgymv-uiaaa-aaaaa-aaaia-cai").unwrap()
+}
+
+pub fn bob() -> Principal {
+    Principal::from_slice(&[42])
+}
+
+fn init_args() -> InitArgs {
+    InitArgs {
+        writers: vec![alice()],
+    }
+}
+
+#[derive(Clone)]
+pub struct PocketIcTestContext {
+    pub client: PocketIcClient,
+}
+
+impl PocketIcTestContext {
+
+    pub async fn fetch_option_one(&self, id: u32) -> Result<Option<Model<u32, Data>>, TxError> {
+        self.client.query(
+            "fetch_option_one",
+            (id, ),
+        )
+        .await.unwrap()
+    }
+
+    pub async fn commit(&self, actions: Vec<Action<u32, Data, u32>>) -> Result<(), TxError> {
+        self.client.update(
+            "commit",
+            (actions, )
+        )
+        .await.unwrap()
+    }
+
+    pub async fn commit_as(&self, caller: Principal, actions: Vec<Action<u32, Data, u32>>) -> Result<Result<(), TxError>, CanisterClientError> {
+        let mut client = self.client.clone();
+        client.caller = caller;
+        client.update(
+            "commit",
+            (actions, )
+        )
+        .await
+    }
+
+    pub async fn upgrade_canister(&self) {
+        let args = Encode!(&init_args()).expect("failed to encode item to candid");
+        self.client
+            .client()
+            .upgrade_canister(self.client.canister, get_storage_canister_bytecode(), args, None)
+            .await
+            .unwrap()
+    }
+
+    pub async fn new() -> Self {
+        let client = get_pocket_ic_client()
+            .with_nns_subnet()
+            .with_ii_subnet()
+            .with_application_subnet()
+            .build_async()
+            .await;
+
+        let args = Encode!(&init_args()).expect("failed to encode item to candid");
+        let canister = client.create_canister().await;
+        client.add_cycles(canister, 10_u128.pow(12)).await;
+        client
+            .install_canister(canister, get_storage_canister_bytecode(), args, None)
+            .await;
+
+        PocketIcTestContext {
+            client: PocketIcClient::from_client(client, canister, alice()),
+        }
+    }
+}
+
+fn get_storage_canister_bytecode() -> Vec<u8> {
+    static CANISTER_BYTECODE: OnceLock<Vec<u8>> = OnceLock::new();
+    CANISTER_BYTECODE
+        .get_or_init(|| {
+            load_wasm_bytes("../../target/wasm32-unknown-unknown/release/test_storage_canister.wasm")
+        })
+        .to_owned()
+}